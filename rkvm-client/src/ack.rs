@@ -0,0 +1,53 @@
+//! Acknowledging clipboard and other misc packets, so the server knows they arrived.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use anyhow::Result;
+use rkvm_protocol::Ack;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+/// Ids handled most recently, so a packet the server sent again isn't handled twice
+const REMEMBERED: usize = 64;
+
+static TX: Mutex<Option<UnboundedSender<u64>>> = Mutex::new(None);
+
+static HANDLED: Mutex<VecDeque<u64>> = Mutex::new(VecDeque::new());
+
+/// Tells the server packet `id` was handled.
+pub fn ack(id: u64) {
+    if let Some(tx) = &*TX.lock().unwrap() {
+        let _ = tx.send(id);
+    }
+}
+
+/// Forgets the packets handled, for a new connection, whose ids start over.
+pub fn reset() {
+    HANDLED.lock().unwrap().clear();
+}
+
+/// Whether packet `id` was handled already, noting it as handled if not.
+pub fn is_repeat(id: u64) -> bool {
+    let mut handled = HANDLED.lock().unwrap();
+    if handled.contains(&id) {
+        return true;
+    }
+
+    if handled.len() == REMEMBERED {
+        handled.pop_front();
+    }
+    handled.push_back(id);
+    false
+}
+
+/// Sends acks to the server until the stream fails.
+pub async fn report(mut stream: crate::client::Upstream) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    *TX.lock().unwrap() = Some(tx);
+
+    while let Some(id) = rx.recv().await {
+        let ack = Ack { id }.to_vec();
+        stream.write(&ack).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use rkvm_protocol::ActivityReport;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Input within this long after an injected event is assumed to be the injected event itself.
+const INJECTION_SLACK: Duration = Duration::from_millis(100);
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Milliseconds since `START` of the last event we injected, 0 if none yet.
+static LAST_INJECTED_MS: AtomicU64 = AtomicU64::new(0);
+
+fn elapsed_ms() -> u64 {
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// Records that a remote input event was just injected.
+pub fn mark_injected() {
+    LAST_INJECTED_MS.store(elapsed_ms().max(1), Ordering::Relaxed);
+}
+
+#[cfg(target_os = "windows")]
+fn system_idle() -> Option<Duration> {
+    use windows::Win32::{
+        System::SystemInformation::GetTickCount,
+        UI::Input::KeyboardAndMouse::{GetLastInputInfo, LASTINPUTINFO},
+    };
+
+    let mut info = LASTINPUTINFO {
+        cbSize: std::mem::size_of::<LASTINPUTINFO>() as u32,
+        dwTime: 0,
+    };
+
+    unsafe {
+        if !GetLastInputInfo(&mut info).as_bool() {
+            return None;
+        }
+
+        Some(Duration::from_millis(
+            GetTickCount().wrapping_sub(info.dwTime) as u64,
+        ))
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn system_idle() -> Option<Duration> {
+    None
+}
+
+/// Time since the last local (not injected) input, if it can be determined.
+pub fn local_idle() -> Option<Duration> {
+    let idle = system_idle()?;
+
+    let last_injected = LAST_INJECTED_MS.load(Ordering::Relaxed);
+    if last_injected != 0 {
+        let since_injected = Duration::from_millis(elapsed_ms().saturating_sub(last_injected));
+        if since_injected <= idle + INJECTION_SLACK {
+            // The most recent input was ours, so we can't tell when the user last acted
+            return None;
+        }
+    }
+
+    Some(idle)
+}
+
+/// Periodically reports local idle time until the stream fails.
+pub async fn report(mut stream: crate::client::Upstream) -> Result<()> {
+    let mut interval = tokio::time::interval(REPORT_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let report = ActivityReport {
+            idle_ms: local_idle().map(|d| d.as_millis() as u64),
+        }
+        .to_vec();
+
+        stream.write(&report).await?;
+    }
+}
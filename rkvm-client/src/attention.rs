@@ -0,0 +1,102 @@
+//! Getting the attention of whoever sits at the other machine, and showing when the server
+//! wants ours.
+//!
+//! Asking goes at most once every few seconds, like on the server.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use quinn::Connection;
+use rkvm_protocol::UpstreamKind;
+
+const MIN_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Connection to ask the server over, replaced on every connection
+static CONNECTION: Mutex<Option<Connection>> = Mutex::new(None);
+
+/// When the server was last asked for attention
+static LAST: Mutex<Option<Instant>> = Mutex::new(None);
+
+pub fn set_connection(connection: Connection) {
+    *CONNECTION.lock().unwrap() = Some(connection);
+}
+
+/// Asks for the attention of whoever sits at the server.
+pub async fn request() -> Result<()> {
+    let connection = CONNECTION.lock().unwrap().clone();
+    let connection = match connection {
+        Some(connection) if connection.close_reason().is_none() => connection,
+        _ => anyhow::bail!("not connected"),
+    };
+
+    {
+        let mut last = LAST.lock().unwrap();
+        if last.is_some_and(|at| at.elapsed() < MIN_INTERVAL) {
+            log::info!("Asked for attention too recently");
+            return Ok(());
+        }
+        *last = Some(Instant::now());
+    }
+    let mut stream = crate::client::open_upstream(&connection, UpstreamKind::Attention).await?;
+    stream.finish().await?;
+
+    log::info!("Asked the server for attention");
+    Ok(())
+}
+
+/// Flashes the screen border, and chimes if `chime`, as the server asked for attention.
+///
+/// Without the overlay outside Windows, a desktop notification is shown instead.
+pub fn show(chime: bool) {
+    log::info!("The server asks for attention");
+
+    #[cfg(target_os = "windows")]
+    {
+        crate::overlay::flash();
+        if chime {
+            beep();
+        }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    std::thread::spawn(move || {
+        if let Err(e) = notify(chime) {
+            log::warn!("Failed to show attention notification: {}", e);
+        }
+    });
+}
+
+#[cfg(target_os = "windows")]
+fn beep() {
+    use windows::Win32::{
+        System::Diagnostics::Debug::MessageBeep, UI::WindowsAndMessaging::MB_ICONASTERISK,
+    };
+
+    unsafe { MessageBeep(MB_ICONASTERISK) };
+}
+
+#[cfg(not(target_os = "windows"))]
+fn notify(chime: bool) -> std::io::Result<std::process::ExitStatus> {
+    use std::process::Command;
+
+    const SUMMARY: &str = "The server asks for attention";
+
+    if cfg!(target_os = "macos") {
+        let sound = if chime { " sound name \"Glass\"" } else { "" };
+        let script = format!(
+            "display notification \"{}\" with title \"rkvm\"{}",
+            SUMMARY, sound
+        );
+        return Command::new("osascript").arg("-e").arg(script).status();
+    }
+
+    let mut command = Command::new("notify-send");
+    command.args(["--urgency=critical", "--app-name=rkvm", SUMMARY]);
+    if chime {
+        command.arg("--hint=string:sound-name:bell");
+    }
+    command.status()
+}
@@ -0,0 +1,231 @@
+//! Starting the client when the user logs in.
+//!
+//! The entry runs the client with the arguments it was started with this time, so a custom
+//! `--config` keeps working.
+
+use std::ffi::OsString;
+
+use anyhow::Result;
+
+const NAME: &str = "rkvm-client";
+
+/// The executable followed by its arguments.
+fn command_line() -> Result<Vec<OsString>> {
+    let mut command = vec![std::env::current_exe()?.into_os_string()];
+    command.extend(std::env::args_os().skip(1));
+
+    Ok(command)
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use std::ffi::OsString;
+
+    use anyhow::Result;
+    use windows::{
+        core::HSTRING,
+        Win32::System::Registry::{
+            RegDeleteKeyValueW, RegGetValueW, RegSetKeyValueW, HKEY_CURRENT_USER, REG_SZ,
+            RRF_RT_REG_SZ,
+        },
+    };
+
+    const RUN_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Run";
+
+    pub fn is_enabled() -> bool {
+        let result = unsafe {
+            RegGetValueW(
+                HKEY_CURRENT_USER,
+                &HSTRING::from(RUN_KEY),
+                &HSTRING::from(super::NAME),
+                RRF_RT_REG_SZ,
+                None,
+                None,
+                None,
+            )
+        };
+        result.is_ok()
+    }
+
+    pub fn enable(command: &[OsString]) -> Result<()> {
+        let line = command
+            .iter()
+            .map(|a| format!("\"{}\"", a.to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let value: Vec<u16> = line.encode_utf16().chain(Some(0)).collect();
+
+        unsafe {
+            RegSetKeyValueW(
+                HKEY_CURRENT_USER,
+                &HSTRING::from(RUN_KEY),
+                &HSTRING::from(super::NAME),
+                REG_SZ.0,
+                Some(value.as_ptr() as _),
+                (value.len() * 2) as u32,
+            )
+            .ok()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn disable() -> Result<()> {
+        unsafe {
+            RegDeleteKeyValueW(
+                HKEY_CURRENT_USER,
+                &HSTRING::from(RUN_KEY),
+                &HSTRING::from(super::NAME),
+            )
+            .ok()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::{ffi::OsString, path::PathBuf};
+
+    use anyhow::{Context, Result};
+
+    fn plist() -> Result<PathBuf> {
+        let home = std::env::var_os("HOME").context("HOME is not set")?;
+        Ok(PathBuf::from(home)
+            .join("Library/LaunchAgents")
+            .join(format!("{}.plist", super::NAME)))
+    }
+
+    fn escape(s: &str) -> String {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    }
+
+    pub fn is_enabled() -> bool {
+        plist().is_ok_and(|p| p.exists())
+    }
+
+    pub fn enable(command: &[OsString]) -> Result<()> {
+        let arguments: String = command
+            .iter()
+            .map(|a| {
+                format!(
+                    "        <string>{}</string>\n",
+                    escape(&a.to_string_lossy())
+                )
+            })
+            .collect();
+        let contents = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+{}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+</dict>
+</plist>
+"#,
+            super::NAME,
+            arguments
+        );
+
+        let path = plist()?;
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, contents)?;
+
+        Ok(())
+    }
+
+    pub fn disable() -> Result<()> {
+        std::fs::remove_file(plist()?)?;
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+mod platform {
+    use std::{ffi::OsString, path::PathBuf};
+
+    use anyhow::{Context, Result};
+
+    fn desktop_file() -> Result<PathBuf> {
+        let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => {
+                PathBuf::from(std::env::var_os("HOME").context("HOME is not set")?).join(".config")
+            }
+        };
+
+        Ok(config_dir
+            .join("autostart")
+            .join(format!("{}.desktop", super::NAME)))
+    }
+
+    /// Quotes an argument for the `Exec` key of a desktop entry.
+    fn quote(arg: &str) -> String {
+        let mut quoted = String::from("\"");
+        for c in arg.chars() {
+            if matches!(c, '"' | '`' | '$' | '\\') {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    pub fn is_enabled() -> bool {
+        desktop_file().is_ok_and(|p| p.exists())
+    }
+
+    pub fn enable(command: &[OsString]) -> Result<()> {
+        let exec = command
+            .iter()
+            .map(|a| quote(&a.to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let contents = format!(
+            "[Desktop Entry]\nType=Application\nName=RKVM Client\nExec={}\nX-GNOME-Autostart-enabled=true\n",
+            exec
+        );
+
+        let path = desktop_file()?;
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, contents)?;
+
+        Ok(())
+    }
+
+    pub fn disable() -> Result<()> {
+        std::fs::remove_file(desktop_file()?)?;
+        Ok(())
+    }
+}
+
+/// Whether the client is set to start at login.
+pub fn is_enabled() -> bool {
+    platform::is_enabled()
+}
+
+pub fn set_enabled(enabled: bool) -> Result<()> {
+    if enabled == is_enabled() {
+        return Ok(());
+    }
+
+    if enabled {
+        platform::enable(&command_line()?)?;
+        log::info!("Enabled start at login");
+    } else {
+        platform::disable()?;
+        log::info!("Disabled start at login");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,225 @@
+//! Capture of this machine's own input for reverse control.
+//!
+//! When enabled, tapping Right Ctrl switches between using the input locally and sending
+//! it to the server, the same way the server's hotkey works.
+
+use std::sync::Mutex;
+
+use anyhow::Result;
+use rkvm_protocol::{Event, Packet};
+use tokio::sync::mpsc;
+
+/// Where captured events go, replaced on every connection.
+static SINK: Mutex<Option<mpsc::UnboundedSender<Event>>> = Mutex::new(None);
+
+/// Windows scan code of Right Ctrl, which toggles capturing.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+const TOGGLE_KEY: u16 = 0xe01d;
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn send(event: Event) {
+    if let Some(sink) = SINK.lock().unwrap().as_ref() {
+        let _ = sink.send(event);
+    }
+}
+
+/// Forwards captured input to the server until the stream fails.
+pub async fn forward(mut stream: crate::client::Upstream) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    *SINK.lock().unwrap() = Some(tx);
+
+    let mut id = 0u64;
+    while let Some(event) = rx.recv().await {
+        let packet = Packet::new(id, event).to_vec();
+        stream.write(&packet).await?;
+        id = id.wrapping_add(1);
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+mod hooks {
+    use std::sync::Mutex;
+
+    use rkvm_protocol::{Event, MouseButton};
+    use windows::Win32::{
+        Foundation::{LPARAM, LRESULT, POINT, WPARAM},
+        System::Threading::GetCurrentThreadId,
+        UI::WindowsAndMessaging::{
+            CallNextHookEx, GetCursorPos, GetMessageW, PeekMessageW, PostThreadMessageW,
+            SetWindowsHookExW, UnhookWindowsHookEx, HC_ACTION, KBDLLHOOKSTRUCT, LLKHF_EXTENDED,
+            LLKHF_INJECTED, LLMHF_INJECTED, MSG, MSLLHOOKSTRUCT, PM_NOREMOVE, WH_KEYBOARD_LL,
+            WH_MOUSE_LL, WM_KEYDOWN, WM_LBUTTONDOWN, WM_LBUTTONUP, WM_MBUTTONDOWN, WM_MBUTTONUP,
+            WM_MOUSEHWHEEL, WM_MOUSEMOVE, WM_MOUSEWHEEL, WM_QUIT, WM_RBUTTONDOWN, WM_RBUTTONUP,
+            WM_SYSKEYDOWN,
+        },
+    };
+
+    use super::{send, TOGGLE_KEY};
+
+    /// Cursor position when capturing started; `Some` while capturing.
+    static ANCHOR: Mutex<Option<POINT>> = Mutex::new(None);
+
+    fn start_capture() {
+        let mut pos = POINT::default();
+        unsafe {
+            GetCursorPos(&mut pos);
+        }
+        *ANCHOR.lock().unwrap() = Some(pos);
+        log::info!("Sending local input to the server");
+    }
+
+    fn stop_capture() {
+        *ANCHOR.lock().unwrap() = None;
+        log::info!("Using local input locally");
+    }
+
+    fn capturing() -> bool {
+        ANCHOR.lock().unwrap().is_some()
+    }
+
+    unsafe extern "system" fn keyboard_proc(code: i32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+        if code == HC_ACTION as i32 {
+            let info = &*(lparam.0 as *const KBDLLHOOKSTRUCT);
+
+            // Events we injected ourselves on behalf of the server pass through
+            if info.flags.0 & LLKHF_INJECTED.0 == 0 {
+                let pressed = matches!(wparam.0 as u32, WM_KEYDOWN | WM_SYSKEYDOWN);
+                let mut key = info.scanCode as u16;
+                if info.flags.0 & LLKHF_EXTENDED.0 != 0 {
+                    key |= 0xe000;
+                }
+
+                if key == TOGGLE_KEY {
+                    if !pressed {
+                        if capturing() {
+                            stop_capture();
+                        } else {
+                            start_capture();
+                        }
+                    }
+                    return LRESULT(1);
+                }
+
+                if capturing() {
+                    send(Event::key(key, pressed));
+                    return LRESULT(1);
+                }
+            }
+        }
+
+        CallNextHookEx(None, code, wparam, lparam)
+    }
+
+    unsafe extern "system" fn mouse_proc(code: i32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+        let anchor = *ANCHOR.lock().unwrap();
+
+        if let (true, Some(anchor)) = (code == HC_ACTION as i32, anchor) {
+            let info = &*(lparam.0 as *const MSLLHOOKSTRUCT);
+
+            if info.flags & LLMHF_INJECTED == 0 {
+                let wheel = (info.mouseData >> 16) as u16 as i16 as i32 / 120;
+
+                let event = match wparam.0 as u32 {
+                    // The cursor stays at the anchor since moves are swallowed
+                    WM_MOUSEMOVE => Some(Event::MouseMotion {
+                        dx: info.pt.x - anchor.x,
+                        dy: info.pt.y - anchor.y,
+                    }),
+                    WM_MOUSEWHEEL => Some(Event::MouseWheel { dx: 0, dy: -wheel }),
+                    WM_MOUSEHWHEEL => Some(Event::MouseWheel { dx: wheel, dy: 0 }),
+                    WM_LBUTTONDOWN | WM_LBUTTONUP => Some(Event::MouseButton {
+                        button: MouseButton::Left,
+                        pressed: wparam.0 as u32 == WM_LBUTTONDOWN,
+                    }),
+                    WM_RBUTTONDOWN | WM_RBUTTONUP => Some(Event::MouseButton {
+                        button: MouseButton::Right,
+                        pressed: wparam.0 as u32 == WM_RBUTTONDOWN,
+                    }),
+                    WM_MBUTTONDOWN | WM_MBUTTONUP => Some(Event::MouseButton {
+                        button: MouseButton::Middle,
+                        pressed: wparam.0 as u32 == WM_MBUTTONDOWN,
+                    }),
+                    _ => None,
+                };
+
+                if let Some(event) = event {
+                    send(event);
+                }
+                return LRESULT(1);
+            }
+        }
+
+        CallNextHookEx(None, code, wparam, lparam)
+    }
+
+    /// Installs the hooks and pumps messages for them until told to quit. `started` gets
+    /// the id of the thread to tell, once the hooks are in.
+    pub fn run(started: std::sync::mpsc::Sender<u32>) {
+        unsafe {
+            let keyboard = match SetWindowsHookExW(WH_KEYBOARD_LL, Some(keyboard_proc), None, 0) {
+                Ok(hook) => hook,
+                Err(e) => {
+                    log::error!("Failed to install input hooks: {}", e);
+                    return;
+                }
+            };
+            let mouse = match SetWindowsHookExW(WH_MOUSE_LL, Some(mouse_proc), None, 0) {
+                Ok(hook) => hook,
+                Err(e) => {
+                    log::error!("Failed to install input hooks: {}", e);
+                    UnhookWindowsHookEx(keyboard);
+                    return;
+                }
+            };
+
+            // Makes the message queue, which a quit can't be posted to before
+            let mut msg = MSG::default();
+            PeekMessageW(&mut msg, None, 0, 0, PM_NOREMOVE);
+            let _ = started.send(GetCurrentThreadId());
+
+            while GetMessageW(&mut msg, None, 0, 0).as_bool() {}
+
+            UnhookWindowsHookEx(keyboard);
+            UnhookWindowsHookEx(mouse);
+            *ANCHOR.lock().unwrap() = None;
+        }
+    }
+
+    /// Has the thread [`run`] is on remove the hooks and return.
+    pub fn quit(thread: u32) {
+        unsafe {
+            PostThreadMessageW(thread, WM_QUIT, WPARAM(0), LPARAM(0));
+        }
+    }
+}
+
+/// Thread the hooks run on, while they're installed.
+#[cfg(target_os = "windows")]
+static THREAD: Mutex<Option<u32>> = Mutex::new(None);
+
+/// Starts capturing local input on a background thread, unless it already runs.
+pub fn start() {
+    #[cfg(target_os = "windows")]
+    {
+        let mut thread = THREAD.lock().unwrap();
+        if thread.is_none() {
+            let (tx, rx) = std::sync::mpsc::channel();
+            std::thread::spawn(move || hooks::run(tx));
+            *thread = rx.recv().ok();
+        }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    log::warn!("Reverse control is only supported on Windows");
+}
+
+/// Removes what [`start`] installed, so local input is left alone again.
+pub fn stop() {
+    #[cfg(target_os = "windows")]
+    if let Some(thread) = THREAD.lock().unwrap().take() {
+        hooks::quit(thread);
+        log::info!("Stopped capturing local input");
+    }
+}
@@ -1,19 +1,48 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    borrow::Cow,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
-use arboard::{Clipboard, ImageData};
-use enigo::{Enigo, KeyboardControllable, MouseControllable};
+use anyhow::{Context, Result};
+use arboard::Clipboard;
+#[cfg(not(target_os = "windows"))]
+use arboard::ImageData;
+#[cfg(not(target_os = "windows"))]
+use enigo::KeyboardControllable;
+use enigo::{Enigo, MouseControllable};
 use keycode::KeyMap;
-use quinn::{ClientConfig, Endpoint, TransportConfig};
-use tokio::io::{AsyncReadExt, BufReader};
+use quinn::{ClientConfig, Connection, Endpoint, RecvStream, SendStream, TransportConfig};
+use rkvm_protocol::{
+    ClientHello, ClipboardSeal, GenericEvent, ImageFormat, Reopens, ScreenLayout, ServerHello,
+    StreamTag, TextNormalization, Timeouts, UpstreamKind,
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    sync::watch,
+};
+
+use crate::{offer::TransferLimits, Config};
 
 #[cfg(target_os = "windows")]
-fn convert_keycode(code: u16) -> Option<u16> {
+fn convert_keycode(keymap: &KeyMap) -> Option<u16> {
+    use windows::Win32::UI::Input::KeyboardAndMouse;
+
+    // Both are scan code 0x45, so mapping it can't tell them apart
+    match keymap.id {
+        keycode::KeyMappingId::Pause => return Some(KeyboardAndMouse::VK_PAUSE.0),
+        keycode::KeyMappingId::NumLock => return Some(KeyboardAndMouse::VK_NUMLOCK.0),
+        _ => {}
+    }
+
     let vk = unsafe {
-        windows::Win32::UI::Input::KeyboardAndMouse::MapVirtualKeyW(
-            code as u32,
-            windows::Win32::UI::Input::KeyboardAndMouse::MAPVK_VSC_TO_VK_EX,
-        )
+        KeyboardAndMouse::MapVirtualKeyW(keymap.win as u32, KeyboardAndMouse::MAPVK_VSC_TO_VK_EX)
     };
     if vk == 0 {
         None
@@ -23,44 +52,426 @@ fn convert_keycode(code: u16) -> Option<u16> {
 }
 
 #[cfg(not(target_os = "windows"))]
-fn convert_keycode(code: u16) -> Option<u16> {
-    Some(code)
+fn convert_keycode(keymap: &KeyMap) -> Option<u16> {
+    if cfg!(target_os = "macos") {
+        Some(keymap.mac)
+    } else {
+        Some(keymap.xkb)
+    }
 }
 
+/// Presses or releases `vk`, flagged as extended so that e.g. numpad Enter and the arrow
+/// keys aren't taken for their twins on the main block or the numpad.
 #[cfg(target_os = "windows")]
-fn move_mouse_relative(_enigo: &mut Enigo, dx: i32, dy: i32) {
-    use windows::Win32::{
-        Foundation::POINT,
-        UI::{
-            Input::KeyboardAndMouse,
-            WindowsAndMessaging::{GetCursorPos, GetSystemMetrics, SetCursorPos},
-        },
+fn send_key(_enigo: &mut Enigo, vk: u16, scan_code: u16, extended: bool, pressed: bool) {
+    use windows::Win32::UI::Input::KeyboardAndMouse;
+
+    let mut flags = KeyboardAndMouse::KEYBD_EVENT_FLAGS(0);
+    if extended {
+        flags |= KeyboardAndMouse::KEYEVENTF_EXTENDEDKEY;
+    }
+    if !pressed {
+        flags |= KeyboardAndMouse::KEYEVENTF_KEYUP;
+    }
+
+    let mut key_input = KeyboardAndMouse::INPUT_0::default();
+    key_input.ki.wVk = KeyboardAndMouse::VIRTUAL_KEY(vk);
+    key_input.ki.wScan = scan_code;
+    key_input.ki.dwFlags = flags;
+
+    let input = KeyboardAndMouse::INPUT {
+        r#type: KeyboardAndMouse::INPUT_KEYBOARD,
+        Anonymous: key_input,
+    };
+
+    crate::inject::send(&[input]);
+}
+
+/// The xkb and macOS key codes are enough to tell every key apart.
+#[cfg(not(target_os = "windows"))]
+fn send_key(enigo: &mut Enigo, key: u16, _scan_code: u16, _extended: bool, pressed: bool) {
+    if pressed {
+        enigo.key_down(enigo::Key::Raw(key));
+    } else {
+        enigo.key_up(enigo::Key::Raw(key));
+    }
+}
+
+/// Presses or releases the key with the Windows scan code `code`, prefix included.
+pub fn inject_key(enigo: &mut Enigo, code: u16, pressed: bool) {
+    let keymap = match KeyMap::from_key_mapping(keycode::KeyMapping::Win(code)) {
+        Ok(keymap) => keymap,
+        Err(_) => return,
+    };
+
+    if let Some(raw_key) = convert_keycode(&keymap) {
+        send_key(enigo, raw_key, code & 0xff, code >> 8 == 0xe0, pressed);
+    }
+}
+
+// Windows scan codes of the lock keys
+const CAPS_LOCK: u16 = 0x3a;
+const NUM_LOCK: u16 = 0xe045;
+const SCROLL_LOCK: u16 = 0x46;
+
+/// Whether the lock with the Windows scan code `code` is on here.
+#[cfg(target_os = "windows")]
+fn is_locked(code: u16) -> Option<bool> {
+    use windows::Win32::UI::Input::KeyboardAndMouse::{
+        GetKeyState, VK_CAPITAL, VK_NUMLOCK, VK_SCROLL,
     };
 
+    let vk = match code {
+        CAPS_LOCK => VK_CAPITAL,
+        NUM_LOCK => VK_NUMLOCK,
+        SCROLL_LOCK => VK_SCROLL,
+        _ => return None,
+    };
+    Some(unsafe { GetKeyState(vk.0 as i32) } & 1 != 0)
+}
+
+/// Enigo can't tell, so locks are left alone.
+#[cfg(not(target_os = "windows"))]
+fn is_locked(_code: u16) -> Option<bool> {
+    None
+}
+
+/// Largest server hello we read, like the server caps what clients send on control streams
+const MAX_HELLO_LEN: u32 = 64 * 1024;
+
+/// Set while the server is in game mode
+static GAME_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Opens clipboard contents the server sealed, with the key it sent this connection
+static SEAL: Mutex<Option<ClipboardSeal>> = Mutex::new(None);
+
+/// Timeouts from the last server hello, applied to the transport of the connections after
+static NEGOTIATED: Mutex<Option<Timeouts>> = Mutex::new(None);
+
+#[cfg(target_os = "windows")]
+pub fn move_mouse_relative(_enigo: &mut Enigo, dx: i32, dy: i32) {
+    use windows::Win32::UI::Input::KeyboardAndMouse;
+
     let mut mouse_input = KeyboardAndMouse::INPUT_0::default();
     mouse_input.mi.dx = dx;
     mouse_input.mi.dy = dy;
-    mouse_input.mi.dwFlags = KeyboardAndMouse::MOUSEEVENTF_MOVE;
+    mouse_input.mi.dwFlags = if GAME_MODE.load(Ordering::Relaxed) {
+        // Games see every single move instead of their sum
+        KeyboardAndMouse::MOUSEEVENTF_MOVE | KeyboardAndMouse::MOUSEEVENTF_MOVE_NOCOALESCE
+    } else {
+        KeyboardAndMouse::MOUSEEVENTF_MOVE
+    };
 
     let input = KeyboardAndMouse::INPUT {
         r#type: KeyboardAndMouse::INPUT_MOUSE,
         Anonymous: mouse_input,
     };
 
-    unsafe {
-        KeyboardAndMouse::SendInput(
-            &[input],
-            std::mem::size_of::<KeyboardAndMouse::INPUT>() as i32,
-        );
-    }
+    crate::inject::send(&[input]);
 }
 
 #[cfg(not(target_os = "windows"))]
-fn move_mouse_relative(enigo: &mut Enigo, dx: i32, dy: i32) {
+pub fn move_mouse_relative(enigo: &mut Enigo, dx: i32, dy: i32) {
     enigo.mouse_move_relative(dx, dy);
 }
 
-async fn handle_stream(stream: quinn::RecvStream) -> Result<()> {
+/// Moves the cursor to `x`, `y` on the virtual desktop, which spans every monitor in
+/// physical pixels.
+#[cfg(target_os = "windows")]
+fn move_mouse_absolute(_enigo: &mut Enigo, x: i32, y: i32) {
+    use windows::Win32::UI::{
+        Input::KeyboardAndMouse,
+        WindowsAndMessaging::{
+            GetSystemMetrics, SM_CXVIRTUALSCREEN, SM_CYVIRTUALSCREEN, SM_XVIRTUALSCREEN,
+            SM_YVIRTUALSCREEN,
+        },
+    };
+
+    let (left, top, width, height) = unsafe {
+        (
+            GetSystemMetrics(SM_XVIRTUALSCREEN),
+            GetSystemMetrics(SM_YVIRTUALSCREEN),
+            GetSystemMetrics(SM_CXVIRTUALSCREEN),
+            GetSystemMetrics(SM_CYVIRTUALSCREEN),
+        )
+    };
+    if width <= 1 || height <= 1 {
+        return;
+    }
+
+    // Absolute moves go from 0 to 65535 across the virtual desktop
+    let normalize = |value: i32, start: i32, size: i32| {
+        ((value - start).clamp(0, size - 1) as i64 * 65535 / (size - 1) as i64) as i32
+    };
+    let mut mouse_input = KeyboardAndMouse::INPUT_0::default();
+    mouse_input.mi.dx = normalize(x, left, width);
+    mouse_input.mi.dy = normalize(y, top, height);
+    mouse_input.mi.dwFlags = KeyboardAndMouse::MOUSEEVENTF_MOVE
+        | KeyboardAndMouse::MOUSEEVENTF_ABSOLUTE
+        | KeyboardAndMouse::MOUSEEVENTF_VIRTUALDESK;
+
+    let input = KeyboardAndMouse::INPUT {
+        r#type: KeyboardAndMouse::INPUT_MOUSE,
+        Anonymous: mouse_input,
+    };
+
+    crate::inject::send(&[input]);
+}
+
+#[cfg(not(target_os = "windows"))]
+fn move_mouse_absolute(enigo: &mut Enigo, x: i32, y: i32) {
+    enigo.mouse_move_to(x, y);
+}
+
+#[cfg(target_os = "windows")]
+fn mouse_button(_enigo: &mut Enigo, button: rkvm_protocol::MouseButton, pressed: bool) {
+    use windows::Win32::UI::Input::KeyboardAndMouse;
+
+    let flags = match (button, pressed) {
+        (rkvm_protocol::MouseButton::Left, true) => KeyboardAndMouse::MOUSEEVENTF_LEFTDOWN,
+        (rkvm_protocol::MouseButton::Left, false) => KeyboardAndMouse::MOUSEEVENTF_LEFTUP,
+        (rkvm_protocol::MouseButton::Middle, true) => KeyboardAndMouse::MOUSEEVENTF_MIDDLEDOWN,
+        (rkvm_protocol::MouseButton::Middle, false) => KeyboardAndMouse::MOUSEEVENTF_MIDDLEUP,
+        (rkvm_protocol::MouseButton::Right, true) => KeyboardAndMouse::MOUSEEVENTF_RIGHTDOWN,
+        (rkvm_protocol::MouseButton::Right, false) => KeyboardAndMouse::MOUSEEVENTF_RIGHTUP,
+    };
+
+    let mut mouse_input = KeyboardAndMouse::INPUT_0::default();
+    mouse_input.mi.dwFlags = flags;
+
+    crate::inject::send(&[KeyboardAndMouse::INPUT {
+        r#type: KeyboardAndMouse::INPUT_MOUSE,
+        Anonymous: mouse_input,
+    }]);
+}
+
+#[cfg(not(target_os = "windows"))]
+fn mouse_button(enigo: &mut Enigo, button: rkvm_protocol::MouseButton, pressed: bool) {
+    let button = match button {
+        rkvm_protocol::MouseButton::Left => enigo::MouseButton::Left,
+        rkvm_protocol::MouseButton::Middle => enigo::MouseButton::Middle,
+        rkvm_protocol::MouseButton::Right => enigo::MouseButton::Right,
+    };
+
+    if pressed {
+        enigo.mouse_down(button);
+    } else {
+        enigo.mouse_up(button);
+    }
+}
+
+/// Scrolls by wheel notches, positive ones right and down.
+#[cfg(target_os = "windows")]
+pub fn mouse_scroll(_enigo: &mut Enigo, dx: i32, dy: i32) {
+    use windows::Win32::UI::{Input::KeyboardAndMouse, WindowsAndMessaging::WHEEL_DELTA};
+
+    let wheel = |flags, notches: i32| {
+        let mut mouse_input = KeyboardAndMouse::INPUT_0::default();
+        mouse_input.mi.dwFlags = flags;
+        mouse_input.mi.mouseData = notches * WHEEL_DELTA as i32;
+
+        KeyboardAndMouse::INPUT {
+            r#type: KeyboardAndMouse::INPUT_MOUSE,
+            Anonymous: mouse_input,
+        }
+    };
+
+    let mut inputs = Vec::new();
+    if dx != 0 {
+        inputs.push(wheel(KeyboardAndMouse::MOUSEEVENTF_HWHEEL, dx));
+    }
+    // The vertical wheel goes up for positive values
+    if dy != 0 {
+        inputs.push(wheel(KeyboardAndMouse::MOUSEEVENTF_WHEEL, -dy));
+    }
+    if !inputs.is_empty() {
+        crate::inject::send(&inputs);
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn mouse_scroll(enigo: &mut Enigo, dx: i32, dy: i32) {
+    if dx != 0 {
+        enigo.mouse_scroll_x(dx);
+    }
+    if dy != 0 {
+        enigo.mouse_scroll_y(dy);
+    }
+}
+
+/// Types `text` as it is, whatever the layout.
+#[cfg(target_os = "windows")]
+fn type_text(_enigo: &mut Enigo, text: &str) {
+    use windows::Win32::UI::Input::KeyboardAndMouse;
+
+    let key = |unit: u16, flags| {
+        let mut key_input = KeyboardAndMouse::INPUT_0::default();
+        key_input.ki.wScan = unit;
+        key_input.ki.dwFlags = KeyboardAndMouse::KEYEVENTF_UNICODE | flags;
+
+        KeyboardAndMouse::INPUT {
+            r#type: KeyboardAndMouse::INPUT_KEYBOARD,
+            Anonymous: key_input,
+        }
+    };
+
+    let mut inputs = Vec::new();
+    let mut buffer = [0; 2];
+    for c in text.chars() {
+        // Both halves of a surrogate pair go down before either goes up
+        let units = c.encode_utf16(&mut buffer);
+        for &unit in units.iter() {
+            inputs.push(key(unit, KeyboardAndMouse::KEYBD_EVENT_FLAGS(0)));
+        }
+        for &unit in units.iter() {
+            inputs.push(key(unit, KeyboardAndMouse::KEYEVENTF_KEYUP));
+        }
+    }
+    if !inputs.is_empty() {
+        crate::inject::send(&inputs);
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn type_text(enigo: &mut Enigo, text: &str) {
+    enigo.key_sequence(text);
+}
+
+/// Multiplies motion by `sensitivity`, keeping fractions of a pixel in `remainder`.
+fn scale_motion(dx: i32, dy: i32, sensitivity: f64, remainder: &mut (f64, f64)) -> (i32, i32) {
+    if sensitivity == 1.0 {
+        return (dx, dy);
+    }
+
+    let fx = dx as f64 * sensitivity + remainder.0;
+    let fy = dy as f64 * sensitivity + remainder.1;
+    *remainder = (fx.fract(), fy.fract());
+    (fx.trunc() as i32, fy.trunc() as i32)
+}
+
+/// Tells how long packets were held up on the way, from the time the server stamped them with.
+///
+/// Once our clock is synced to the server's, that is how long ago they were stamped. Until
+/// then delays are measured against the packet that arrived quickest so far.
+struct Lateness {
+    start: Instant,
+    /// Least difference between our clock and the server's seen, in microseconds
+    baseline: Option<i64>,
+}
+
+impl Lateness {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            baseline: None,
+        }
+    }
+
+    /// Delay of a packet stamped with `time`, which is 0 for packets that weren't.
+    fn of(&mut self, time: u64) -> Duration {
+        if time == 0 {
+            return Duration::ZERO;
+        }
+        if let Some(age) = crate::clock::age(time) {
+            return age;
+        }
+
+        let now = i64::try_from(self.start.elapsed().as_micros()).unwrap_or(i64::MAX);
+        let offset = now.saturating_sub(i64::try_from(time).unwrap_or(i64::MAX));
+        let baseline = self.baseline.map_or(offset, |least| least.min(offset));
+        self.baseline = Some(baseline);
+        Duration::from_micros(offset.saturating_sub(baseline) as u64)
+    }
+}
+
+/// Reads the next packet if it was received in full already, without waiting for more.
+async fn read_ready_packet<R: AsyncRead + Unpin>(
+    stream: &mut BufReader<R>,
+) -> Result<Option<rkvm_protocol::Packet>> {
+    // Polled once, so only what has arrived is looked at
+    std::future::poll_fn(|cx| match Pin::new(&mut *stream).poll_fill_buf(cx) {
+        Poll::Ready(result) => Poll::Ready(result.map(|_| ())),
+        Poll::Pending => Poll::Ready(Ok(())),
+    })
+    .await?;
+
+    let buf = stream.buffer();
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    if buf.len() < 4 + len {
+        return Ok(None);
+    }
+
+    let packet = rkvm_protocol::Packet::from_reader(&buf[4..4 + len], len as u64)?;
+    Pin::new(stream).consume(4 + len);
+    Ok(Some(packet))
+}
+
+/// How to apply what the server sends, from the config.
+#[derive(Debug, Clone, Copy)]
+struct InputOptions {
+    sensitivity: f64,
+    stale_motion: Option<Duration>,
+    order_window: Duration,
+    pacing: bool,
+    clipboard: bool,
+    limits: TransferLimits,
+    text: TextNormalization,
+    pad_ring: crate::pad::PadAction,
+    pad_strip: crate::pad::PadAction,
+}
+
+impl InputOptions {
+    fn new(config: &Config) -> Self {
+        Self {
+            sensitivity: config.sensitivity,
+            stale_motion: (config.stale_motion_ms != 0)
+                .then(|| Duration::from_millis(config.stale_motion_ms)),
+            order_window: Duration::from_millis(config.order_window_ms),
+            pacing: crate::pacing::enabled(config),
+            clipboard: config.clipboard,
+            limits: TransferLimits::new(config),
+            text: config.clipboard_text,
+            pad_ring: config.pad_ring,
+            pad_strip: config.pad_strip,
+        }
+    }
+}
+
+/// Handles a stream the server opened, going by the tag it starts with.
+async fn accept_stream(
+    mut stream: quinn::RecvStream,
+    connection: Connection,
+    config: watch::Receiver<Config>,
+) -> Result<()> {
+    let tag = stream.read_u8().await?;
+    match StreamTag::from_byte(tag) {
+        Some(tag) => handle_stream(stream, connection, config, tag).await,
+        None => {
+            // Dropping it tells the server it isn't read
+            log::debug!("Ignoring stream with unknown tag {}", tag);
+            Ok(())
+        }
+    }
+}
+
+/// Handles the packets the server sends on a stream tagged `tag`.
+async fn handle_stream(
+    stream: quinn::RecvStream,
+    connection: Connection,
+    config: watch::Receiver<Config>,
+    tag: StreamTag,
+) -> Result<()> {
+    let single_stream = tag == StreamTag::Events;
+    // Counted by the stream they came on, which the server numbers them on
+    let counted = |kind: rkvm_protocol::EventKind| match tag {
+        StreamTag::Mouse => crate::sequence::Stream::Mouse,
+        StreamTag::Keyboard => crate::sequence::Stream::Keyboard,
+        StreamTag::Events => crate::sequence::Stream::Single,
+        _ => kind.into(),
+    };
     let mut enigo = Enigo::new();
 
     let mut clipboard = match Clipboard::new() {
@@ -75,12 +486,68 @@ async fn handle_stream(stream: quinn::RecvStream) -> Result<()> {
 
     let mut buf = vec![0u8; 128];
 
+    // Where the cursor was before it was parked
+    let mut parked: Option<(i32, i32)> = None;
+    // Fractions of a pixel left over from scaling motion
+    let mut remainder = (0.0, 0.0);
+    let mut lateness = Lateness::new();
+    let mut pad = crate::pad::Pad::new();
+    // Read while merging motion, to be handled next
+    let mut pending = None;
+
     loop {
-        let len = stream.read_u32().await?;
-        buf.resize(len as usize, 0);
-        stream.read_exact(&mut buf).await?;
+        let mut packet = match pending.take() {
+            Some(packet) => packet,
+            None => {
+                let len = stream.read_u32().await?;
+                buf.resize(len as usize, 0);
+                stream.read_exact(&mut buf).await?;
+                let packet = rkvm_protocol::PacketRef::from_slice(&buf)?;
+                crate::sequence::note(counted(packet.event.kind()), packet.seq);
+                if packet.event.is_clipboard() {
+                    // Put on the clipboard right from the buffer, large as they may be
+                    let options = InputOptions::new(&config.borrow());
+                    receive_clipboard(&mut clipboard, &options, packet);
+                    continue;
+                }
+                rkvm_protocol::Packet::from_slice(&buf)?
+            }
+        };
+        // Read for every packet, so config reloads apply right away
+        let options = InputOptions::new(&config.borrow());
+        let limits = options.limits;
+
+        // After a stall motion arrives in a burst. It is merged into one move, without what
+        // was held up too long to still be wanted
+        let stale = |delay| options.stale_motion.is_some_and(|max| delay > max);
+        let delay = lateness.of(packet.time);
+        if let rkvm_protocol::Event::MouseMotion { dx, dy } = &mut packet.event {
+            if stale(delay) {
+                (*dx, *dy) = (0, 0);
+            }
 
-        let packet = rkvm_protocol::Packet::from_slice(&buf)?;
+            while let Some(next) = read_ready_packet(&mut stream).await? {
+                crate::sequence::note(counted(next.event.kind()), next.seq);
+                match next.event {
+                    rkvm_protocol::Event::MouseMotion { dx: x, dy: y } => {
+                        if !stale(lateness.of(next.time)) {
+                            *dx = dx.saturating_add(x);
+                            *dy = dy.saturating_add(y);
+                        }
+                        packet.time = packet.time.max(next.time);
+                    }
+                    _ => {
+                        pending = Some(next);
+                        break;
+                    }
+                }
+            }
+
+            if (*dx, *dy) == (0, 0) {
+                crate::ordering::handled(rkvm_protocol::EventKind::Mouse, packet.time);
+                continue;
+            }
+        }
 
         if packet.event.is_high_freq() {
             log::trace!("Received event {}: {:?}", packet.id, packet.event);
@@ -88,115 +555,765 @@ async fn handle_stream(stream: quinn::RecvStream) -> Result<()> {
             log::debug!("Received event {}: {:?}", packet.id, packet.event);
         }
 
-        match packet.event {
-            rkvm_protocol::Event::MouseMotion { dx, dy } => {
-                move_mouse_relative(&mut enigo, dx, dy);
-            }
-            rkvm_protocol::Event::MouseWheel { dx, dy } => {
-                if dx != 0 {
-                    enigo.mouse_scroll_x(dx);
-                }
-                if dy != 0 {
-                    enigo.mouse_scroll_y(dy);
+        if packet.event.kind() != rkvm_protocol::EventKind::Misc {
+            crate::activity::mark_injected();
+        }
+
+        let mut ack = match wants_ack(packet.event.kind(), packet.id) {
+            Ok(ack) => ack,
+            Err(()) => continue,
+        };
+
+        // Mouse input means we are active again
+        let is_pointer = !matches!(
+            packet.event,
+            rkvm_protocol::Event::Park | rkvm_protocol::Event::GamepadAxis { .. }
+        );
+        if packet.event.kind() == rkvm_protocol::EventKind::Mouse && is_pointer {
+            if let Some((x, y)) = parked.take() {
+                if !crate::pointer::relative_only() {
+                    move_mouse_absolute(&mut enigo, x, y);
                 }
             }
-            rkvm_protocol::Event::MouseButton { button, pressed } => {
-                let button = match button {
-                    rkvm_protocol::MouseButton::Left => enigo::MouseButton::Left,
-                    rkvm_protocol::MouseButton::Middle => enigo::MouseButton::Middle,
-                    rkvm_protocol::MouseButton::Right => enigo::MouseButton::Right,
-                };
+        }
 
-                if pressed {
-                    enigo.mouse_down(button);
+        let (kind, time) = (packet.event.kind(), packet.time);
+        // Held until handled, so what the other stream has stamped later waits for it. A
+        // single stream is in order already
+        let window = if single_stream {
+            Duration::ZERO
+        } else {
+            options.order_window
+        };
+        let _turn = crate::ordering::wait_turn(&packet.event, time, window).await;
+        match packet.event {
+            // Whatever locked the pointer owns its position
+            rkvm_protocol::Event::Park | rkvm_protocol::Event::MouseAbsolute { .. }
+                if crate::pointer::relative_only() => {}
+            rkvm_protocol::Event::Park if parked.is_none() => {
+                crate::pacing::clear();
+                parked = Some(enigo.mouse_location());
+
+                let (width, height) = enigo.main_display_size();
+                move_mouse_absolute(&mut enigo, width - 1, height - 1);
+            }
+            rkvm_protocol::Event::Park => {}
+            rkvm_protocol::Event::MouseMotion { dx, dy } => {
+                let (dx, dy) = scale_motion(dx, dy, options.sensitivity, &mut remainder);
+                let (dx, dy) = crate::screens::clamp_motion(dx, dy, || enigo.mouse_location());
+                if options.pacing {
+                    crate::pacing::add(dx, dy);
                 } else {
-                    enigo.mouse_up(button);
+                    move_mouse_relative(&mut enigo, dx, dy);
                 }
             }
-            rkvm_protocol::Event::Keyboard { key, pressed } => {
-                let keymap = if let Ok(km) = KeyMap::from_key_mapping(keycode::KeyMapping::Win(key))
-                {
-                    km
-                } else {
-                    continue;
-                };
-
-                let raw_key = if cfg!(target_os = "windows") {
-                    if let Some(vk) = convert_keycode(keymap.win) {
-                        vk
+            rkvm_protocol::Event::MouseAbsolute { x, y } => {
+                crate::pacing::clear();
+                move_mouse_absolute(&mut enigo, x, y);
+            }
+            rkvm_protocol::Event::MouseWheel { dx, dy } => mouse_scroll(&mut enigo, dx, dy),
+            rkvm_protocol::Event::MouseButton { button, pressed } => {
+                mouse_button(&mut enigo, button, pressed)
+            }
+            rkvm_protocol::Event::Keyboard {
+                key,
+                pressed,
+                extended,
+            } => {
+                let code = rkvm_protocol::win_scan_code(key, extended);
+                let keymap =
+                    if let Ok(km) = KeyMap::from_key_mapping(keycode::KeyMapping::Win(code)) {
+                        km
                     } else {
-                        log::warn!("Unknown windows scan code: {}", keymap.win);
                         continue;
-                    }
-                } else if cfg!(target_os = "macos") {
-                    keymap.mac
+                    };
+
+                let raw_key = if let Some(raw_key) = convert_keycode(&keymap) {
+                    raw_key
                 } else {
-                    keymap.xkb
+                    log::warn!("Unknown windows scan code: {}", keymap.win);
+                    continue;
                 };
 
                 if pressed {
                     log::debug!("[{}] Key {:?} pressed", packet.id, keymap.id);
-                    enigo.key_down(enigo::Key::Raw(raw_key));
+                    crate::layout::apply();
                 } else {
                     log::debug!("[{}] Key {:?} released", packet.id, keymap.id);
-                    enigo.key_up(enigo::Key::Raw(raw_key));
                 }
+                send_key(&mut enigo, raw_key, key, extended, pressed);
+            }
+            // Shown rather than typed, as erasing it again isn't safe everywhere
+            rkvm_protocol::Event::Preedit { text } => crate::target::set_preedit(text),
+            rkvm_protocol::Event::Commit { text } => {
+                crate::target::set_preedit(String::new());
+                type_text(&mut enigo, &text);
+            }
+            rkvm_protocol::Event::GamepadButton {
+                pad,
+                button,
+                pressed,
+            } => crate::gamepad::button(pad, button, pressed),
+            rkvm_protocol::Event::GamepadAxis { pad, axis, value } => {
+                crate::gamepad::axis(pad, axis, value)
+            }
+            rkvm_protocol::Event::GamepadRemoved { pad } => crate::gamepad::remove(pad),
+            rkvm_protocol::Event::PadRing { ring, position } => {
+                let notches = pad.ring(ring, position);
+                crate::pad::apply(&mut enigo, options.pad_ring, notches);
+            }
+            rkvm_protocol::Event::PadStrip { strip, position } => {
+                let notches = pad.strip(strip, position);
+                crate::pad::apply(&mut enigo, options.pad_strip, notches);
+            }
+            rkvm_protocol::Event::PadButton { button, pressed } => {
+                let keys = config
+                    .borrow()
+                    .pad_buttons
+                    .get(&button.to_string())
+                    .cloned()
+                    .unwrap_or_default();
+                if keys.is_empty() {
+                    log::debug!("Pad button {} isn't mapped to any keys", button);
+                }
+
+                // Let go in reverse, so modifiers stay down until the key is up
+                let mut codes: Vec<_> = keys.iter().map(|key| KeyMap::from(*key).win).collect();
+                if !pressed {
+                    codes.reverse();
+                }
+                for code in codes {
+                    inject_key(&mut enigo, code, pressed);
+                }
+            }
+            rkvm_protocol::Event::ClipboardOffer { .. } if !options.clipboard => {}
+            rkvm_protocol::Event::ClipboardOffer { id, kind, size } => {
+                let connection = connection.clone();
+                let text = options.text;
+                tokio::spawn(async move {
+                    let offer = crate::offer::handle(connection, limits, text, id, kind, size);
+                    if let Err(e) = offer.await {
+                        log::error!("Failed to fetch offered clipboard: {}", e);
+                    }
+                });
+            }
+            rkvm_protocol::Event::DragEnter { id, data } => {
+                #[cfg(target_os = "windows")]
+                {
+                    // The button went down on the server, before input came here
+                    enigo.mouse_down(enigo::MouseButton::Left);
+                    crate::drag::start(connection.clone(), limits, id, data);
+                }
+
+                #[cfg(not(target_os = "windows"))]
+                log::warn!(
+                    "Ignoring drag {} of {:?}, only Windows can take drags",
+                    id,
+                    data
+                );
             }
-            rkvm_protocol::Event::TextClipboard { content } => {
-                if let Some(c) = &mut clipboard {
-                    if let Err(e) = c.set_text(content) {
-                        log::error!("Failed to set clipboard: {}", e);
+            rkvm_protocol::Event::DragCancel => {
+                #[cfg(target_os = "windows")]
+                crate::drag::cancel();
+            }
+            rkvm_protocol::Event::State {
+                grabbed,
+                active,
+                active_client,
+                modifiers,
+                caps_lock,
+                num_lock,
+                scroll_lock,
+            } => {
+                match (grabbed, active_client) {
+                    (true, Some(client)) => log::info!("Joined while input goes to {}", client),
+                    _ => log::info!("Joined while input stays on the server"),
+                }
+
+                // Released like any other key on the server, so they must be down here too
+                if grabbed && active {
+                    for code in modifiers {
+                        inject_key(&mut enigo, code, true);
                     }
                 }
+
+                for (code, locked) in [
+                    (CAPS_LOCK, caps_lock),
+                    (NUM_LOCK, num_lock),
+                    (SCROLL_LOCK, scroll_lock),
+                ] {
+                    if is_locked(code).is_some_and(|here| here != locked) {
+                        inject_key(&mut enigo, code, true);
+                        inject_key(&mut enigo, code, false);
+                    }
+                }
+            }
+            rkvm_protocol::Event::Target { active } => {
+                crate::target::set(active);
+                crate::overlay::show(active && config.borrow().control_overlay);
+            }
+            rkvm_protocol::Event::GameMode { enabled } => {
+                log::info!("Game mode {}", if enabled { "on" } else { "off" });
+                GAME_MODE.store(enabled, Ordering::Relaxed);
+            }
+            rkvm_protocol::Event::Suspend if config.borrow().suspend_with_server => {
+                // Acked first, as the connection won't outlive this
+                if let Some(id) = ack.take() {
+                    crate::ack::ack(id);
+                }
+                crate::power::suspend();
+            }
+            rkvm_protocol::Event::Suspend => log::info!("Not suspending with the server"),
+            rkvm_protocol::Event::KeyboardLayout { xkb }
+                if config.borrow().follow_server_layout =>
+            {
+                crate::layout::follow(xkb.as_deref());
             }
-            rkvm_protocol::Event::HtmlClipboard { html, plain } => {
-                if let Some(c) = &mut clipboard {
-                    if let Err(e) = c.set_html(html, Some(plain)) {
-                        log::error!("Failed to set clipboard: {}", e);
+            rkvm_protocol::Event::Attention => {
+                crate::attention::show(config.borrow().attention_chime)
+            }
+            rkvm_protocol::Event::KeyboardLayout { xkb: Some(xkb) } => {
+                log::info!("Server switched to keyboard layout {}", xkb)
+            }
+            rkvm_protocol::Event::KeyboardLayout { xkb: None } => {
+                log::info!("Server can't tell its keyboard layout anymore")
+            }
+            rkvm_protocol::Event::ClipboardRequest if !options.clipboard => {
+                log::info!("Not sending the clipboard to the server, clipboard is off")
+            }
+            rkvm_protocol::Event::ClipboardRequest => {
+                // Read right away, before contents sent after the request replace it
+                match get_clipboard(&mut clipboard) {
+                    Some(event) => {
+                        let connection = connection.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = send_clipboard(&connection, event).await {
+                                log::error!("Failed to send clipboard: {}", e);
+                            }
+                        });
+                    }
+                    // Copied files go the way they always do
+                    None => {
+                        tokio::spawn(async {
+                            if let Err(e) = crate::files::send_clipboard().await {
+                                log::error!("Failed to send files: {}", e);
+                            }
+                        });
                     }
                 }
             }
-            rkvm_protocol::Event::ImageClipboard { png } => {
+            event if options.clipboard => set_clipboard(&mut clipboard, &options.text, event),
+            _ => {}
+        }
+        crate::ordering::handled(kind, time);
+
+        if let Some(id) = ack {
+            crate::ack::ack(id);
+        }
+    }
+}
+
+/// Handles clipboard contents still in the buffer they were received into, acking them
+/// like other events.
+fn receive_clipboard(
+    clipboard: &mut Option<Clipboard>,
+    options: &InputOptions,
+    packet: rkvm_protocol::PacketRef,
+) {
+    log::debug!("Received event {}: {:?}", packet.id, packet.event);
+
+    let ack = match wants_ack(packet.event.kind(), packet.id) {
+        Ok(ack) => ack,
+        Err(()) => return,
+    };
+
+    if options.clipboard {
+        set_clipboard(clipboard, &options.text, packet.event);
+    }
+    if let Some(id) = ack {
+        crate::ack::ack(id);
+    }
+}
+
+/// The id to ack once a packet is handled, as misc packets with one want. `Err` if it was
+/// handled already and sent again after our ack got lost, acking it again instead.
+fn wants_ack(kind: rkvm_protocol::EventKind, id: u64) -> Result<Option<u64>, ()> {
+    if kind != rkvm_protocol::EventKind::Misc || id == 0 {
+        return Ok(None);
+    }
+
+    if crate::ack::is_repeat(id) {
+        crate::ack::ack(id);
+        return Err(());
+    }
+
+    Ok(Some(id))
+}
+
+/// Injects the mouse motion the server sends in datagrams while in game mode.
+async fn handle_datagrams(connection: Connection, config: watch::Receiver<Config>) -> Result<()> {
+    let mut enigo = Enigo::new();
+    let mut remainder = (0.0, 0.0);
+
+    loop {
+        let datagram = connection.read_datagram().await?;
+        let packet = rkvm_protocol::Packet::from_reader(&datagram[..], datagram.len() as u64)?;
+        log::trace!("Received datagram {}: {:?}", packet.id, packet.event);
+        crate::sequence::note(crate::sequence::Stream::Datagrams, packet.seq);
+
+        match packet.event {
+            rkvm_protocol::Event::MouseMotion { dx, dy } => {
+                crate::activity::mark_injected();
+
+                let sensitivity = config.borrow().sensitivity;
+                let (dx, dy) = scale_motion(dx, dy, sensitivity, &mut remainder);
+                move_mouse_relative(&mut enigo, dx, dy);
+                crate::ordering::handled(rkvm_protocol::EventKind::Mouse, packet.time);
+            }
+            event => log::warn!("Ignoring unexpected datagram: {:?}", event),
+        }
+    }
+}
+
+/// Puts clipboard contents sent by the server on our own clipboard, owned or still
+/// borrowed from what they were received into.
+pub fn set_clipboard<'a, S, B>(
+    clipboard: &mut Option<Clipboard>,
+    text: &TextNormalization,
+    event: GenericEvent<S, B>,
+) where
+    S: Into<Cow<'a, str>>,
+    B: Into<Cow<'a, [u8]>>,
+{
+    match event {
+        GenericEvent::SealedClipboard { nonce, sealed } => {
+            let sealed = sealed.into().into_owned();
+            let seal = SEAL.lock().unwrap();
+            let opened = seal.as_ref().and_then(|seal| seal.open(nonce, sealed));
+            drop(seal);
+
+            match opened {
+                Some(GenericEvent::SealedClipboard { .. }) | None => {
+                    log::error!("Failed to open sealed clipboard contents")
+                }
+                Some(event) => set_clipboard(clipboard, text, event),
+            }
+        }
+        GenericEvent::TextClipboard { content } => {
+            if let Some(c) = clipboard {
+                if let Err(e) = c.set_text(text.apply(&content.into())) {
+                    log::error!("Failed to set clipboard: {}", e);
+                }
+            }
+        }
+        GenericEvent::HtmlClipboard { html, plain } => {
+            let html: Cow<str> = html.into();
+            let plain = text.apply(&plain.into());
+
+            #[cfg(target_os = "windows")]
+            if let Err(e) = crate::native_clipboard::set_html(&html, &plain) {
+                log::error!("Failed to set clipboard: {}", e);
+            }
+
+            #[cfg(not(target_os = "windows"))]
+            if let Some(c) = clipboard {
+                if let Err(e) = c.set_html(html, Some(plain.into())) {
+                    log::error!("Failed to set clipboard: {}", e);
+                }
+            }
+        }
+        GenericEvent::ImageClipboard { png } => {
+            let png: Cow<[u8]> = png.into();
+
+            #[cfg(target_os = "windows")]
+            if let Err(e) = crate::native_clipboard::set_png(&png) {
+                log::error!("Failed to set clipboard: {}", e);
+            }
+
+            #[cfg(not(target_os = "windows"))]
+            {
                 let png_image = match image::load_from_memory(&png) {
                     Ok(i) => i,
                     Err(e) => {
                         log::error!("Failed to decode clipboard image: {}", e);
-                        continue;
+                        return;
                     }
                 };
 
                 let rgba8 = png_image.into_rgba8();
                 let (width, height) = rgba8.dimensions();
-                let data = rgba8.into_raw();
-
-                if let Some(c) = &mut clipboard {
-                    if let Err(e) = c.set_image(ImageData {
-                        width: width as usize,
-                        height: height as usize,
-                        bytes: std::borrow::Cow::Owned(data),
-                    }) {
-                        log::error!("Failed to set clipboard: {}", e);
-                    }
-                }
+                set_image(clipboard, width, height, Cow::Owned(rgba8.into_raw()));
             }
         }
+        GenericEvent::RawImageClipboard {
+            width,
+            height,
+            stride,
+            rgba,
+        } => {
+            let rgba: Cow<[u8]> = rgba.into();
+            let row = (width as usize).saturating_mul(4);
+            let size = (stride as usize).checked_mul(height as usize);
+            if row == 0 || (stride as usize) < row || size.is_none_or(|size| rgba.len() < size) {
+                log::error!("Malformed clipboard image: {}x{}", width, height);
+                return;
+            }
+
+            let data = if stride as usize == row {
+                rgba
+            } else {
+                rgba.chunks_exact(stride as usize)
+                    .take(height as usize)
+                    .flat_map(|r| &r[..row])
+                    .copied()
+                    .collect()
+            };
+
+            #[cfg(target_os = "windows")]
+            if let Err(e) = crate::native_clipboard::set_rgba(width, height, &data) {
+                log::error!("Failed to set clipboard: {}", e);
+            }
+
+            #[cfg(not(target_os = "windows"))]
+            set_image(clipboard, width, height, data);
+        }
+        _ => {}
     }
 }
 
-pub async fn connect(endpoint: &Endpoint, remote_addr: SocketAddr) -> Result<()> {
+#[cfg(not(target_os = "windows"))]
+fn set_image(clipboard: &mut Option<Clipboard>, width: u32, height: u32, data: Cow<[u8]>) {
+    if let Some(c) = clipboard {
+        if let Err(e) = c.set_image(ImageData {
+            width: width as usize,
+            height: height as usize,
+            bytes: data,
+        }) {
+            log::error!("Failed to set clipboard: {}", e);
+        }
+    }
+}
+
+/// Our clipboard contents as the server takes them, an image before text.
+fn get_clipboard(clipboard: &mut Option<Clipboard>) -> Option<rkvm_protocol::Event> {
+    let c = clipboard.as_mut()?;
+
+    if let Ok(image) = c.get_image() {
+        let width = u32::try_from(image.width).ok();
+        let height = u32::try_from(image.height).ok();
+        match (width, height, width.and_then(|w| w.checked_mul(4))) {
+            (Some(width), Some(height), Some(stride)) => {
+                return Some(rkvm_protocol::Event::RawImageClipboard {
+                    width,
+                    height,
+                    stride,
+                    rgba: image.bytes.into_owned(),
+                });
+            }
+            _ => log::warn!(
+                "Clipboard image too large: {}x{}",
+                image.width,
+                image.height
+            ),
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    match crate::native_clipboard::get_html() {
+        Ok(Some(html)) => {
+            let plain = c.get_text().unwrap_or_default();
+            return Some(rkvm_protocol::Event::HtmlClipboard { html, plain });
+        }
+        Ok(None) => {}
+        Err(e) => log::warn!("Failed to read HTML from the clipboard: {}", e),
+    }
+
+    c.get_text()
+        .ok()
+        .map(|content| rkvm_protocol::Event::TextClipboard { content })
+}
+
+/// Sends the clipboard the server asked for, sealed with its key.
+async fn send_clipboard(connection: &Connection, event: rkvm_protocol::Event) -> Result<()> {
+    let event = match &*SEAL.lock().unwrap() {
+        Some(seal) => seal.seal(&event),
+        None => event,
+    };
+    let mut stream = open_upstream(connection, UpstreamKind::Clipboard).await?;
+
+    let packet = rkvm_protocol::Packet::new(0, event).to_vec();
+    stream.write_u32(packet.len() as u32).await?;
+    stream.write_all(&packet).await?;
+    stream.finish().await?;
+
+    Ok(())
+}
+
+/// Authenticates to the server over a dedicated control stream.
+///
+/// The pre-shared key is never sent; instead we prove knowledge of it with a MAC over
+/// keying material exported from this TLS session, so the proof cannot be replayed.
+async fn handshake(connection: &Connection, config: &Config, screens: ScreenLayout) -> Result<()> {
+    let (mut control_tx, mut control_rx) = connection.open_bi().await.context("Open control")?;
+
+    let auth = if let Some(psk) = &config.psk {
+        let mut exporter = [0u8; rkvm_protocol::AUTH_PROOF_LEN];
+        connection
+            .export_keying_material(&mut exporter, rkvm_protocol::AUTH_EXPORTER_LABEL, b"")
+            .map_err(|_| anyhow::anyhow!("Failed to export keying material"))?;
+
+        Some(rkvm_protocol::auth_proof(psk.as_bytes(), &exporter))
+    } else {
+        None
+    };
+
+    let hello = ClientHello {
+        version: rkvm_protocol::PROTOCOL_VERSION,
+        auth,
+        // Raw pixels can go straight to the clipboard without decoding
+        image_formats: vec![ImageFormat::Rgba, ImageFormat::Png],
+        screens,
+        name: crate::identity::name(config),
+        id: config
+            .id
+            .context("No client id in the config, start the client once as its user")?,
+        layout: crate::layout::current(),
+        timeouts: Timeouts::from_secs(config.keep_alive_secs, config.idle_timeout_secs)
+            .map_err(anyhow::Error::msg)?,
+        single_stream: config.single_stream,
+    }
+    .to_vec();
+    control_tx.write_u32(hello.len() as u32).await?;
+    control_tx.write_all(&hello).await?;
+
+    let len = control_rx.read_u32().await?;
+    if len > MAX_HELLO_LEN {
+        anyhow::bail!("Server hello too large: {} bytes", len);
+    }
+    let mut buf = vec![0u8; len as usize];
+    control_rx.read_exact(&mut buf).await?;
+
+    match ServerHello::from_slice(&buf)? {
+        ServerHello::Accepted {
+            layout,
+            clipboard_key,
+            timeouts,
+            single_stream,
+            xkb_layout,
+        } => {
+            log::info!("Negotiated {}", timeouts);
+            // QUIC can't change them on an open connection, so they apply from the next one
+            *NEGOTIATED.lock().unwrap() = Some(timeouts);
+            crate::layout::set_hint(layout.as_deref());
+            if let Some(xkb) = xkb_layout.filter(|_| config.follow_server_layout) {
+                crate::layout::follow(Some(&xkb));
+            }
+            if single_stream {
+                log::info!("Taking all events on one stream");
+            }
+            *SEAL.lock().unwrap() = clipboard_key.as_ref().map(ClipboardSeal::new);
+            Ok(())
+        }
+        ServerHello::Rejected => anyhow::bail!("Server rejected authentication"),
+        ServerHello::Incompatible { version } => anyhow::bail!(
+            "Server speaks protocol version {}, not {}, update the older end",
+            version,
+            rkvm_protocol::PROTOCOL_VERSION
+        ),
+    }
+}
+
+/// Opens a stream to the server, tagged with what it carries.
+pub async fn open_upstream(connection: &Connection, kind: UpstreamKind) -> Result<SendStream> {
+    let mut stream = connection.open_uni().await?;
+
+    let kind = kind.to_vec();
+    stream.write_u32(kind.len() as u32).await?;
+    stream.write_all(&kind).await?;
+
+    Ok(stream)
+}
+
+/// A stream to the server, opened again when it fails on a live connection.
+pub struct Upstream {
+    connection: Connection,
+    kind: UpstreamKind,
+    stream: SendStream,
+    reopens: Reopens,
+}
+
+impl Upstream {
+    pub async fn open(connection: &Connection, kind: UpstreamKind) -> Result<Self> {
+        Ok(Self {
+            connection: connection.clone(),
+            kind,
+            stream: open_upstream(connection, kind).await?,
+            reopens: Reopens::default(),
+        })
+    }
+
+    /// Writes `data` with its length in front. What was on the way when the stream failed is
+    /// lost, but `data` goes out on the one opened in its place.
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        let error = match write_framed(&mut self.stream, data).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        if self.connection.close_reason().is_some() || !self.reopens.allow() {
+            return Err(error);
+        }
+
+        log::warn!("The {:?} stream failed, reopening it: {}", self.kind, error);
+        self.stream = open_upstream(&self.connection, self.kind).await?;
+        write_framed(&mut self.stream, data).await
+    }
+}
+
+async fn write_framed(stream: &mut SendStream, data: &[u8]) -> Result<()> {
+    stream.write_u32(data.len() as u32).await?;
+    stream.write_all(data).await?;
+    Ok(())
+}
+
+/// Opens a stream to the server that it answers on, tagged with what it carries.
+pub async fn open_upstream_bi(
+    connection: &Connection,
+    kind: UpstreamKind,
+) -> Result<(SendStream, RecvStream)> {
+    let (mut send, recv) = connection.open_bi().await?;
+
+    let kind = kind.to_vec();
+    send.write_u32(kind.len() as u32).await?;
+    send.write_all(&kind).await?;
+
+    Ok((send, recv))
+}
+
+/// Serves one connection until it closes, or until `config_rx` changes how to connect.
+pub async fn connect(
+    endpoint: &Endpoint,
+    remote_addr: SocketAddr,
+    mut config_rx: watch::Receiver<Config>,
+) -> Result<()> {
+    let config = config_rx.borrow_and_update().clone();
     log::info!("Connecting to {:?}", remote_addr);
+    crate::status::connecting(remote_addr);
 
     let connection = endpoint.connect(remote_addr, "localhost")?.await?;
     log::info!("Connection established");
 
+    let pin = crate::screens::Pin::new(&config);
+    let screens = tokio::task::spawn_blocking(move || crate::screens::layout(&pin)).await?;
+    handshake(&connection, &config, screens.clone()).await?;
+    log::info!("Handshake completed");
+    // Older servers don't answer, and delays are then told apart as before
+    let clock_conn = connection.clone();
+    tokio::spawn(async move {
+        if let Err(e) = crate::clock::sync(&clock_conn).await {
+            log::warn!("Failed to sync to the server's clock: {}", e);
+        }
+    });
+    crate::status::connected(remote_addr, connection.clone());
+
+    crate::files::set_connection(connection.clone(), TransferLimits::new(&config));
+    crate::attention::set_connection(connection.clone());
+    #[cfg(target_os = "windows")]
+    crate::inject::set_connection(connection.clone());
+    crate::sequence::reset();
+    crate::ordering::reset();
+    crate::ack::reset();
+
+    let screens_tx = Upstream::open(&connection, UpstreamKind::Screens)
+        .await
+        .context("Open screens tx")?;
+    let screens_config = config_rx.clone();
+    tokio::spawn(async move {
+        if let Err(e) = crate::screens::report(screens_tx, screens, screens_config).await {
+            log::error!("Error reporting screens: {}", e);
+        }
+    });
+
+    let activity_tx = Upstream::open(&connection, UpstreamKind::Activity)
+        .await
+        .context("Open activity tx")?;
+    tokio::spawn(async move {
+        if let Err(e) = crate::activity::report(activity_tx).await {
+            log::error!("Error reporting activity: {}", e);
+        }
+    });
+
+    let pointer_tx = Upstream::open(&connection, UpstreamKind::PointerMode)
+        .await
+        .context("Open pointer mode tx")?;
+    tokio::spawn(async move {
+        if let Err(e) = crate::pointer::report(pointer_tx).await {
+            log::error!("Error reporting pointer mode: {}", e);
+        }
+    });
+
+    let ack_tx = Upstream::open(&connection, UpstreamKind::Acks)
+        .await
+        .context("Open ack tx")?;
+    tokio::spawn(async move {
+        if let Err(e) = crate::ack::report(ack_tx).await {
+            log::error!("Error sending acks: {}", e);
+        }
+    });
+
+    crate::relay::set_enabled(config.relay_logs);
+    if config.relay_logs {
+        let logs_tx = Upstream::open(&connection, UpstreamKind::Logs)
+            .await
+            .context("Open logs tx")?;
+        tokio::spawn(async move {
+            if let Err(e) = crate::relay::report(logs_tx).await {
+                log::error!("Error relaying logs: {}", e);
+            }
+        });
+    }
+
+    if config.reverse_control {
+        let input_tx = Upstream::open(&connection, UpstreamKind::Input)
+            .await
+            .context("Open input tx")?;
+        tokio::spawn(async move {
+            if let Err(e) = crate::capture::forward(input_tx).await {
+                log::error!("Error forwarding local input: {}", e);
+            }
+        });
+    }
+
+    let datagram_conn = connection.clone();
+    let datagram_config = config_rx.clone();
+    tokio::spawn(async move {
+        if let Err(e) = handle_datagrams(datagram_conn, datagram_config).await {
+            log::debug!("Stopped handling datagrams: {}", e);
+        }
+    });
+
+    tokio::spawn(crate::pacing::run(connection.clone(), config_rx.clone()));
+    tokio::spawn(crate::target::follow_quality(
+        connection.clone(),
+        config_rx.clone(),
+    ));
+
     let conn1 = connection.clone();
+    let stream_config = config_rx.clone();
     tokio::spawn(async move {
         loop {
             match conn1.accept_uni().await {
                 Ok(stream) => {
+                    let connection = conn1.clone();
+                    let config = stream_config.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = handle_stream(stream).await {
-                            log::error!("Error handling stream: {}", e);
+                        let result = accept_stream(stream, connection.clone(), config).await;
+                        if let Err(e) = result {
+                            if connection.close_reason().is_none() {
+                                // Dropping it stopped the stream, so the server opens another
+                                log::warn!("Stream failed, the server reopens it: {}", e);
+                            } else {
+                                log::error!("Error handling stream: {}", e);
+                            }
                         }
                     });
                 }
@@ -210,8 +1327,18 @@ pub async fn connect(endpoint: &Endpoint, remote_addr: SocketAddr) -> Result<()>
         conn1.close(0u32.into(), b"Accept failed");
     });
 
-    let reason = connection.closed().await;
-    log::info!("Connection closed: {:?}", reason);
+    tokio::select! {
+        reason = connection.closed() => log::info!("Connection closed: {:?}", reason),
+        _ = crate::reload::reconnect_needed(&mut config_rx, &config) => {
+            log::info!("Connection settings changed, reconnecting");
+            connection.close(0u32.into(), b"Reconnecting");
+        }
+    }
+    crate::gamepad::remove_all();
+    GAME_MODE.store(false, Ordering::Relaxed);
+    crate::sequence::log_summary();
+    crate::target::set(false);
+    crate::overlay::show(false);
 
     Ok(())
 }
@@ -240,18 +1367,125 @@ impl rustls::client::ServerCertVerifier for SkipServerVerification {
     }
 }
 
-pub fn configure_client() -> ClientConfig {
-    let crypto = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_custom_certificate_verifier(SkipServerVerification::new())
-        .with_no_client_auth();
+/// Accepts only the server certificate whose SHA-256 fingerprint was pinned in the config.
+struct PinnedServerVerification {
+    fingerprint: [u8; 32],
+}
+
+impl PinnedServerVerification {
+    fn new(fingerprint: [u8; 32]) -> Arc<Self> {
+        Arc::new(Self { fingerprint })
+    }
+}
 
+impl rustls::client::ServerCertVerifier for PinnedServerVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        if rkvm_protocol::fingerprint_matches(&end_entity.0, &self.fingerprint) {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "Server certificate fingerprint mismatch: got {}",
+                rkvm_protocol::cert_fingerprint(&end_entity.0)
+            )))
+        }
+    }
+}
+
+/// What the server last shortened our keep-alive and idle timeout to.
+pub fn negotiated() -> Option<Timeouts> {
+    *NEGOTIATED.lock().unwrap()
+}
+
+pub fn configure_client(config: &Config) -> Result<ClientConfig> {
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+
+    let crypto = match &config.fingerprint {
+        Some(fingerprint) => {
+            let fingerprint = rkvm_protocol::parse_fingerprint(fingerprint)
+                .context("Invalid server fingerprint in config")?;
+            builder.with_custom_certificate_verifier(PinnedServerVerification::new(fingerprint))
+        }
+        None => {
+            log::warn!("No server fingerprint configured, connection is open to MITM attacks");
+            builder.with_custom_certificate_verifier(SkipServerVerification::new())
+        }
+    };
+    let crypto = match crate::identity::certificate() {
+        Some(certificate) => crypto.with_client_auth_cert(
+            vec![rustls::Certificate(certificate.cert_der.clone())],
+            rustls::PrivateKey(certificate.key_der.clone()),
+        )?,
+        None => crypto.with_no_client_auth(),
+    };
+
+    let mut timeouts = Timeouts::from_secs(config.keep_alive_secs, config.idle_timeout_secs)
+        .map_err(anyhow::Error::msg)?;
+    if let Some(negotiated) = negotiated() {
+        timeouts = timeouts.negotiate(&negotiated);
+    }
     let mut transport = TransportConfig::default();
-    transport.max_idle_timeout(Some(std::time::Duration::from_secs(10).try_into().unwrap()));
-    
+    transport.keep_alive_interval(Some(timeouts.keep_alive()));
+    transport.max_idle_timeout(timeouts.idle().map(TryInto::try_into).transpose()?);
 
     let mut config = ClientConfig::new(Arc::new(crypto));
     config.transport_config(Arc::new(transport));
 
-    config
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use rkvm_protocol::{Event, Packet};
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn framed(packet: &Packet) -> Vec<u8> {
+        let data = packet.to_vec();
+        let mut framed = (data.len() as u32).to_be_bytes().to_vec();
+        framed.extend(data);
+        framed
+    }
+
+    #[test]
+    fn lateness_from_the_quickest_packet() {
+        let mut lateness = Lateness::new();
+        assert_eq!(lateness.of(0), Duration::ZERO);
+
+        // The first is taken as on time, one stamped 10 ms before it arrived as 10 ms late
+        let time = 1_000_000;
+        assert_eq!(lateness.of(time), Duration::ZERO);
+        let late = lateness.of(time - 10_000);
+        assert!(late >= Duration::from_millis(10) && late < Duration::from_millis(100));
+
+        // Stamps that don't fit an i64 don't overflow
+        assert_eq!(lateness.of(u64::MAX), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn read_ready_packet_only_takes_whole_packets() {
+        let (mut tx, rx) = tokio::io::duplex(1024);
+        let mut rx = BufReader::new(rx);
+        let packet = Packet::new(1, Event::MouseMotion { dx: 3, dy: -4 });
+        let data = framed(&packet);
+
+        assert!(read_ready_packet(&mut rx).await.unwrap().is_none());
+
+        // A whole packet and the start of the next, which is left for the stream to finish
+        tx.write_all(&data).await.unwrap();
+        tx.write_all(&data[..data.len() - 1]).await.unwrap();
+        let read = read_ready_packet(&mut rx).await.unwrap().unwrap();
+        assert_eq!(read.id, 1);
+        assert!(matches!(read.event, Event::MouseMotion { dx: 3, dy: -4 }));
+        assert!(read_ready_packet(&mut rx).await.unwrap().is_none());
+        assert_eq!(rx.buffer(), &data[..data.len() - 1]);
+    }
 }
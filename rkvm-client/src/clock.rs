@@ -0,0 +1,129 @@
+//! Our clock, and how far the one the server stamps packets with is from it.
+//!
+//! Right after connecting the server is asked for its clock a few times. Taking each answer
+//! to have been given halfway through its round trip, the median offset is kept, so how
+//! long a packet took to get here can be told from its stamp alone. That is done again every
+//! few minutes, as the clocks drift apart.
+
+use std::{
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use quinn::{Connection, RecvStream, SendStream};
+use rkvm_protocol::{ClockSync, TimeProbe, UpstreamKind};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const PROBES: usize = 8;
+
+/// Spaces probes out, so one burst of traffic doesn't hold up all of them
+const PROBE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How often to sync again while connected
+const RESYNC_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Answers are a few bytes, anything much longer isn't one
+const MAX_PROBE_LEN: u32 = 1024;
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Microseconds the server's clock is ahead of ours, for the current connection once synced
+static OFFSET: Mutex<Option<i64>> = Mutex::new(None);
+
+/// Microseconds on our clock.
+pub fn micros() -> u64 {
+    START.get_or_init(Instant::now).elapsed().as_micros() as u64
+}
+
+/// How far the server's clock is ahead of ours, if synced.
+pub fn offset() -> Option<i64> {
+    *OFFSET.lock().unwrap()
+}
+
+/// How long ago the server stamped a packet with `time`, if the clocks are synced.
+pub fn age(time: u64) -> Option<Duration> {
+    Some(age_at(time, offset()?, micros()))
+}
+
+/// How long before `now` on our clock the server stamped `time`, its clock being `offset`
+/// ahead. Stamps come from the server, so nothing here may overflow.
+fn age_at(time: u64, offset: i64, now: u64) -> Duration {
+    let ours = i64::try_from(time)
+        .unwrap_or(i64::MAX)
+        .saturating_sub(offset);
+    let now = i64::try_from(now).unwrap_or(i64::MAX);
+    Duration::from_micros(now.saturating_sub(ours).max(0) as u64)
+}
+
+/// Syncs to the server on `connection`, forgetting how the last one's clock was, and keeps
+/// doing so until it closes.
+pub async fn sync(connection: &Connection) -> Result<()> {
+    *OFFSET.lock().unwrap() = None;
+
+    let (mut send, mut recv) =
+        crate::client::open_upstream_bi(connection, UpstreamKind::TimeSync).await?;
+    loop {
+        let result = probe(&mut send, &mut recv).await;
+        if connection.close_reason().is_some() {
+            return Ok(());
+        }
+
+        let offset = result?;
+        if let Some(offset) = offset {
+            log::debug!("Server clock is {} us ahead", offset);
+        }
+        *OFFSET.lock().unwrap() = offset;
+
+        tokio::time::sleep(RESYNC_INTERVAL).await;
+    }
+}
+
+/// Asks for the server's clock a few times, returning how far it is ahead.
+async fn probe(send: &mut SendStream, recv: &mut RecvStream) -> Result<Option<i64>> {
+    let mut sync = ClockSync::default();
+    let mut buf = Vec::new();
+    for _ in 0..PROBES {
+        let probe = TimeProbe {
+            client_us: micros(),
+            server_us: 0,
+        }
+        .to_vec();
+        send.write_u32(probe.len() as u32).await?;
+        send.write_all(&probe).await?;
+
+        let len = recv.read_u32().await?;
+        if len > MAX_PROBE_LEN {
+            anyhow::bail!("Clock answer too large: {} bytes", len);
+        }
+        buf.resize(len as usize, 0);
+        recv.read_exact(&mut buf).await?;
+        sync.add(TimeProbe::from_slice(&buf)?, micros());
+
+        tokio::time::sleep(PROBE_INTERVAL).await;
+    }
+
+    Ok(sync.offset())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn age_takes_the_offset_off() {
+        // Stamped at 1000.5 ms on a server 0.5 ms ahead, here at 1010 ms
+        assert_eq!(age_at(1_000_500, 500, 1_010_000), Duration::from_millis(10));
+        // Stamped in our future
+        assert_eq!(age_at(2_000_000, 0, 1_000_000), Duration::ZERO);
+    }
+
+    #[test]
+    fn age_does_not_overflow() {
+        assert_eq!(age_at(u64::MAX, i64::MIN, 0), Duration::ZERO);
+        assert_eq!(
+            age_at(0, i64::MAX, u64::MAX),
+            Duration::from_micros(i64::MAX as u64)
+        );
+    }
+}
@@ -0,0 +1,183 @@
+//! Continuing drags that started on the server, by dragging the same data here.
+
+use std::{
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use quinn::Connection;
+use rkvm_protocol::{DragData, DragFetch, FileKind, Throttle, UpstreamKind};
+use tokio::{io::AsyncWriteExt, runtime::Handle, sync::mpsc};
+use windows_clipboard_files::{
+    unicode_text, FileDescriptor, Format, FormatRegistry, OpenContents, Source, SpillBuffer,
+};
+
+use crate::offer::TransferLimits;
+
+/// Cancels the drag in progress, if any.
+static CANCEL: Mutex<Option<Arc<AtomicBool>>> = Mutex::new(None);
+
+/// Chunks of a fetched file in flight between the runtime and a drop target
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// Reads a fetched file that a task on the runtime receives from the server.
+///
+/// Drop targets read on the drag's thread, which only ever waits for the next chunk.
+struct ChannelRecv {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelRecv {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            // Closed once the server finished the stream
+            match self.chunks.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+/// Receives file `index` of drag `id` into `chunks`, until it ends or nobody reads anymore.
+async fn receive(
+    connection: Connection,
+    limits: TransferLimits,
+    id: u64,
+    index: u32,
+    chunks: &mpsc::Sender<io::Result<Vec<u8>>>,
+) -> Result<()> {
+    let (mut send, mut recv) =
+        crate::client::open_upstream_bi(&connection, UpstreamKind::DragFetch).await?;
+    let fetch = DragFetch { id, index }.to_vec();
+    send.write_u32(u32::try_from(fetch.len())?).await?;
+    send.write_all(&fetch).await?;
+    send.finish().await?;
+
+    let mut throttle = Throttle::new(limits.bandwidth_limit);
+    let mut chunk = vec![0u8; CHUNK_LEN];
+    // `None` once the server finished the stream
+    while let Some(n) = recv.read(&mut chunk).await? {
+        tokio::time::sleep(throttle.delay(n)).await;
+        if chunks.send(Ok(chunk[..n].to_vec())).await.is_err() {
+            let _ = recv.stop(0u32.into());
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn fetch(
+    handle: &Handle,
+    connection: &Connection,
+    limits: TransferLimits,
+    id: u64,
+    index: u32,
+) -> Box<dyn Source> {
+    let (chunks_tx, chunks) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    let connection = connection.clone();
+    handle.spawn(async move {
+        if let Err(e) = receive(connection, limits, id, index, &chunks_tx).await {
+            let _ = chunks_tx.send(Err(io::Error::other(e))).await;
+        }
+    });
+
+    Box::new(SpillBuffer::new(ChannelRecv {
+        chunks,
+        chunk: Vec::new(),
+        pos: 0,
+    }))
+}
+
+fn registry(
+    connection: Connection,
+    limits: TransferLimits,
+    id: u64,
+    data: DragData,
+) -> FormatRegistry {
+    let mut registry = FormatRegistry::new();
+
+    let text = match data {
+        DragData::Text(text) => text,
+        DragData::Uris(uris) => uris.join("\r\n"),
+        DragData::Files(headers) => {
+            let handle = Handle::current();
+            let files = headers
+                .into_iter()
+                .zip(0..)
+                .map(|(header, index)| {
+                    let descriptor = FileDescriptor {
+                        path: header.path.replace('/', "\\"),
+                        size: header.size,
+                        directory: header.kind == FileKind::Directory,
+                        modified: header
+                            .modified
+                            .and_then(|m| UNIX_EPOCH.checked_add(Duration::from_millis(m))),
+                    };
+                    if descriptor.directory {
+                        return (descriptor, None);
+                    }
+
+                    let handle = handle.clone();
+                    let connection = connection.clone();
+                    let open: OpenContents =
+                        Box::new(move || Ok(fetch(&handle, &connection, limits, id, index)));
+                    (descriptor, Some(open))
+                })
+                .collect();
+
+            registry.register_files(files);
+            return registry;
+        }
+    };
+
+    registry.register_delayed(
+        Format::UnicodeText,
+        Box::new(move || Some(unicode_text(&text))),
+    );
+    registry
+}
+
+/// Starts dragging what the server is dragging. The left button must already be down.
+pub fn start(connection: Connection, limits: TransferLimits, id: u64, data: DragData) {
+    let registry = registry(connection, limits, id, data);
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    if let Some(previous) = CANCEL.lock().unwrap().replace(cancel.clone()) {
+        previous.store(true, Ordering::SeqCst);
+    }
+
+    tokio::task::spawn_blocking(
+        move || match crate::native_clipboard::drag(registry, cancel) {
+            Ok(true) => log::info!("Dropped drag from the server"),
+            Ok(false) => log::info!("Drag from the server was cancelled"),
+            Err(e) => log::error!("Failed to drag: {}", e),
+        },
+    );
+}
+
+/// Cancels the drag in progress, if any.
+pub fn cancel() {
+    if let Some(cancel) = CANCEL.lock().unwrap().take() {
+        cancel.store(true, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,372 @@
+//! Sending files copied on this machine to the server.
+//!
+//! A transfer is kept until the server confirms it, and resumed where it left off when we
+//! reconnect.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use quinn::{Connection, RecvStream, SendStream};
+use rkvm_protocol::{
+    chunk_hash, FileHeader, FileKind, FileManifest, FileResponse, Throttle, TransferProgress,
+    UpstreamKind, FILE_CHUNK_LEN,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::offer::TransferLimits;
+
+/// Connection files are sent over, replaced on every connection.
+static CONNECTION: Mutex<Option<(Connection, TransferLimits)>> = Mutex::new(None);
+
+/// Transfer the server hasn't confirmed yet.
+static PENDING: Mutex<Option<Arc<Transfer>>> = Mutex::new(None);
+
+const MAX_RESPONSE_LEN: u32 = 16 * 1024 * 1024;
+
+/// Remembers the connection, and resumes an unfinished transfer over it.
+pub fn set_connection(connection: Connection, limits: TransferLimits) {
+    *CONNECTION.lock().unwrap() = Some((connection.clone(), limits));
+
+    if let Some(transfer) = PENDING.lock().unwrap().clone() {
+        log::info!("Resuming file transfer");
+        tokio::spawn(run(connection, limits, transfer));
+    }
+}
+
+/// Applies new limits to transfers started from now on.
+pub fn set_limits(limits: TransferLimits) {
+    if let Some((_, current)) = CONNECTION.lock().unwrap().as_mut() {
+        *current = limits;
+    }
+}
+
+enum Contents {
+    Directory,
+    Disk(PathBuf),
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    Memory(Vec<u8>),
+}
+
+/// One entry of a transfer and where to read it from.
+struct Entry {
+    header: FileHeader,
+    contents: Contents,
+}
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn millis(time: SystemTime) -> Option<u64> {
+    Some(time.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64)
+}
+
+/// Lists `path`, and everything below it if it is a directory, as `relative` and below.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn walk(path: &Path, relative: String, entries: &mut Vec<Entry>) -> Result<()> {
+    // Links are skipped rather than followed, so they can't loop
+    let metadata = std::fs::symlink_metadata(path).with_context(|| format!("Stat {:?}", path))?;
+    let modified = metadata.modified().ok().and_then(millis);
+
+    if metadata.is_dir() {
+        entries.push(Entry {
+            header: FileHeader {
+                path: relative.clone(),
+                kind: FileKind::Directory,
+                size: 0,
+                modified,
+            },
+            contents: Contents::Directory,
+        });
+
+        let children = std::fs::read_dir(path).with_context(|| format!("List {:?}", path))?;
+        for child in children {
+            let child = child?;
+            let name = child.file_name().to_string_lossy().into_owned();
+            walk(&child.path(), format!("{}/{}", relative, name), entries)?;
+        }
+    } else if metadata.is_file() {
+        entries.push(Entry {
+            header: FileHeader {
+                path: relative,
+                kind: FileKind::File,
+                size: metadata.len(),
+                modified,
+            },
+            contents: Contents::Disk(path.to_owned()),
+        });
+    } else {
+        log::warn!(
+            "Skipping {:?}, only files and directories can be sent",
+            path
+        );
+    }
+
+    Ok(())
+}
+
+/// Lists everything on the clipboard, with directories expanded.
+#[cfg(target_os = "windows")]
+fn list_clipboard() -> Result<Vec<Entry>> {
+    use windows_clipboard_files::ClipboardFile;
+
+    let mut entries = Vec::new();
+
+    for file in crate::native_clipboard::get_files()? {
+        match file {
+            ClipboardFile::Path(path) => {
+                let name = match path.file_name() {
+                    Some(name) => name.to_string_lossy().into_owned(),
+                    None => continue,
+                };
+                walk(&path, name, &mut entries)?;
+            }
+            ClipboardFile::Virtual {
+                path,
+                data,
+                modified,
+            } => entries.push(Entry {
+                header: FileHeader {
+                    path: path.replace('\\', "/"),
+                    kind: FileKind::File,
+                    size: data.len() as u64,
+                    modified: modified.and_then(millis),
+                },
+                contents: Contents::Memory(data),
+            }),
+            ClipboardFile::VirtualDirectory { path } => entries.push(Entry {
+                header: FileHeader {
+                    path: path.replace('\\', "/"),
+                    kind: FileKind::Directory,
+                    size: 0,
+                    modified: None,
+                },
+                contents: Contents::Directory,
+            }),
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn list_clipboard() -> Result<Vec<Entry>> {
+    anyhow::bail!("Sending files is only supported on Windows")
+}
+
+async fn write_frame(stream: &mut SendStream, frame: &[u8]) -> Result<()> {
+    stream.write_u32(frame.len() as u32).await?;
+    stream.write_all(frame).await?;
+
+    Ok(())
+}
+
+async fn read_response(stream: &mut RecvStream) -> Result<FileResponse> {
+    let len = stream.read_u32().await?;
+    if len > MAX_RESPONSE_LEN {
+        anyhow::bail!("Response too large: {} bytes", len);
+    }
+
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+
+    Ok(FileResponse::from_slice(&buf)?)
+}
+
+fn log_progress(p: &TransferProgress) {
+    if p.file_done() {
+        log::info!(
+            "Sent {} ({} of {} bytes total)",
+            p.path,
+            p.total_done,
+            p.total_size
+        );
+    } else {
+        log::trace!("Sending {}: {} of {} bytes", p.path, p.done, p.size);
+    }
+}
+
+/// Everything needed to send a transfer again from any point.
+struct Transfer {
+    manifest: FileManifest,
+    contents: Vec<Contents>,
+}
+
+impl Transfer {
+    fn new(entries: Vec<Entry>) -> Self {
+        // Only needs to tell our own transfers apart
+        let mut hasher = DefaultHasher::new();
+        SystemTime::now().hash(&mut hasher);
+        for entry in &entries {
+            entry.header.path.hash(&mut hasher);
+        }
+
+        let total_size = entries.iter().map(|e| e.header.size).sum();
+        let (headers, contents) = entries.into_iter().map(|e| (e.header, e.contents)).unzip();
+
+        Self {
+            manifest: FileManifest {
+                id: hasher.finish(),
+                entries: headers,
+                total_size,
+            },
+            contents,
+        }
+    }
+}
+
+/// Sends one file from `report.done` on, each chunk followed by its hash.
+async fn send_file(
+    stream: &mut SendStream,
+    contents: &Contents,
+    report: &mut TransferProgress,
+    throttle: &mut Throttle,
+    progress: &crate::progress::Transfer,
+) -> Result<()> {
+    let mut file = match contents {
+        Contents::Directory => return Ok(()),
+        Contents::Disk(path) => {
+            let mut file = tokio::fs::File::open(path)
+                .await
+                .with_context(|| format!("Open {:?}", path))?;
+            file.seek(std::io::SeekFrom::Start(report.done)).await?;
+            Some(file)
+        }
+        Contents::Memory(_) => None,
+    };
+
+    let mut chunk = vec![0u8; FILE_CHUNK_LEN];
+    while report.done < report.size {
+        let len = (report.size - report.done).min(FILE_CHUNK_LEN as u64) as usize;
+
+        let chunk = match (contents, &mut file) {
+            (Contents::Memory(data), _) => {
+                let start = report.done as usize;
+                &data[start..start + len]
+            }
+            (_, Some(file)) => {
+                file.read_exact(&mut chunk[..len])
+                    .await
+                    .with_context(|| format!("{} shrank while it was being sent", report.path))?;
+                &chunk[..len]
+            }
+            _ => unreachable!(),
+        };
+
+        tokio::time::sleep(throttle.delay(len)).await;
+        stream.write_all(chunk).await?;
+        stream.write_all(&chunk_hash(chunk)).await?;
+
+        report.done += len as u64;
+        report.total_done += len as u64;
+        log_progress(report);
+        progress.set_done(report.total_done);
+    }
+
+    Ok(())
+}
+
+async fn send(connection: &Connection, limits: TransferLimits, transfer: &Transfer) -> Result<()> {
+    let (mut send, mut recv) =
+        crate::client::open_upstream_bi(connection, UpstreamKind::Files).await?;
+    // Below input, so a large transfer doesn't lag reverse control
+    send.set_priority(-1)?;
+    write_frame(&mut send, &transfer.manifest.to_vec()).await?;
+
+    let offsets = match read_response(&mut recv).await? {
+        FileResponse::Resume { offsets } => offsets,
+        FileResponse::Declined => {
+            log::warn!(
+                "Server declined {} bytes of files",
+                transfer.manifest.total_size
+            );
+            return Ok(());
+        }
+        response => anyhow::bail!("Unexpected response {:?}", response),
+    };
+    if offsets.len() != transfer.contents.len() {
+        anyhow::bail!(
+            "Server sent {} offsets for {} entries",
+            offsets.len(),
+            transfer.contents.len()
+        );
+    }
+
+    let mut throttle = Throttle::new(limits.bandwidth_limit);
+    let mut total_done = offsets.iter().sum();
+    let progress = crate::progress::start("Sending files".to_owned(), transfer.manifest.total_size);
+    progress.set_done(total_done);
+    let entries = transfer.manifest.entries.iter().zip(&transfer.contents);
+    for ((header, contents), offset) in entries.zip(offsets) {
+        if header.kind == FileKind::Directory || offset >= header.size {
+            continue;
+        }
+
+        let mut report = TransferProgress {
+            path: header.path.clone(),
+            done: offset,
+            size: header.size,
+            total_done,
+            total_size: transfer.manifest.total_size,
+        };
+        tokio::select! {
+            sent = send_file(&mut send, contents, &mut report, &mut throttle, &progress) => sent?,
+            _ = progress.cancelled() => {
+                let _ = send.reset(0u32.into());
+                // Not an error, so the transfer isn't resumed after reconnecting
+                log::info!("Cancelled sending files");
+                return Ok(());
+            }
+        }
+        total_done = report.total_done;
+    }
+
+    match read_response(&mut recv).await? {
+        FileResponse::Complete => {}
+        response => anyhow::bail!("Unexpected response {:?}", response),
+    }
+    send.finish().await?;
+    log::info!("Sent {} files", transfer.manifest.entries.len());
+
+    Ok(())
+}
+
+async fn run(connection: Connection, limits: TransferLimits, transfer: Arc<Transfer>) {
+    match send(&connection, limits, &transfer).await {
+        Ok(()) => {
+            let mut pending = PENDING.lock().unwrap();
+            if pending.as_ref().is_some_and(|p| Arc::ptr_eq(p, &transfer)) {
+                *pending = None;
+            }
+        }
+        Err(e) => log::error!(
+            "Failed to send files, will resume after reconnecting: {}",
+            e
+        ),
+    }
+}
+
+/// Sends the files on the clipboard to the server, which puts them on its own clipboard.
+///
+/// Replaces any transfer that hasn't finished yet.
+pub async fn send_clipboard() -> Result<()> {
+    let entries = tokio::task::spawn_blocking(list_clipboard).await??;
+    if entries.is_empty() {
+        log::info!("No files on the clipboard");
+        return Ok(());
+    }
+
+    let transfer = Arc::new(Transfer::new(entries));
+    *PENDING.lock().unwrap() = Some(transfer.clone());
+
+    let connection = CONNECTION.lock().unwrap().clone();
+    match connection {
+        Some((connection, limits)) => run(connection, limits, transfer).await,
+        None => log::info!("Not connected, the files will be sent once connected"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,398 @@
+//! Virtual gamepads playing back the server's.
+//!
+//! Each shows up as an Xbox 360 controller: through uinput on Linux, and through the ViGEmBus
+//! driver on Windows, which needs `ViGEmClient.dll` next to rkvm-client or on the `PATH`.
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use rkvm_protocol::{GamepadAxis, GamepadButton};
+
+/// Controller state, laid out like ViGEm's `XUSB_REPORT` but with the sticks' Y axes
+/// pointing down as in the protocol
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+struct Report {
+    buttons: u16,
+    left_trigger: u8,
+    right_trigger: u8,
+    left_x: i16,
+    left_y: i16,
+    right_x: i16,
+    right_y: i16,
+}
+
+/// XInput's bit for `button`.
+fn bit(button: GamepadButton) -> u16 {
+    match button {
+        GamepadButton::DpadUp => 0x0001,
+        GamepadButton::DpadDown => 0x0002,
+        GamepadButton::DpadLeft => 0x0004,
+        GamepadButton::DpadRight => 0x0008,
+        GamepadButton::Start => 0x0010,
+        GamepadButton::Back => 0x0020,
+        GamepadButton::LeftThumb => 0x0040,
+        GamepadButton::RightThumb => 0x0080,
+        GamepadButton::LeftBumper => 0x0100,
+        GamepadButton::RightBumper => 0x0200,
+        GamepadButton::Guide => 0x0400,
+        GamepadButton::South => 0x1000,
+        GamepadButton::East => 0x2000,
+        GamepadButton::West => 0x4000,
+        GamepadButton::North => 0x8000,
+    }
+}
+
+struct Pad {
+    report: Report,
+    device: Device,
+}
+
+/// Pads by number, `None` if creating the device failed
+static PADS: Mutex<BTreeMap<u8, Option<Pad>>> = Mutex::new(BTreeMap::new());
+
+/// Changes the state of `pad`, creating its device on first use.
+fn update(pad: u8, change: impl FnOnce(&mut Report)) {
+    let mut pads = PADS.lock().unwrap();
+    let entry = match pads.entry(pad) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let device = match Device::new(pad) {
+                Ok(device) => {
+                    log::info!("Gamepad {} added", pad);
+                    Some(device)
+                }
+                // Not retried until the server removes the pad, as this comes at every event
+                Err(e) => {
+                    log::warn!("Failed to create gamepad {}: {}", pad, e);
+                    None
+                }
+            };
+            entry.insert(device.map(|device| Pad {
+                report: Report::default(),
+                device,
+            }))
+        }
+    };
+
+    if let Some(pad) = entry {
+        let previous = pad.report;
+        change(&mut pad.report);
+        if let Err(e) = pad.device.update(&previous, &pad.report) {
+            log::warn!("Failed to update gamepad: {}", e);
+        }
+    }
+}
+
+pub fn button(pad: u8, button: GamepadButton, pressed: bool) {
+    update(pad, |report| {
+        if pressed {
+            report.buttons |= bit(button);
+        } else {
+            report.buttons &= !bit(button);
+        }
+    });
+}
+
+pub fn axis(pad: u8, axis: GamepadAxis, value: i16) {
+    update(pad, |report| match axis {
+        GamepadAxis::LeftX => report.left_x = value,
+        GamepadAxis::LeftY => report.left_y = value,
+        GamepadAxis::RightX => report.right_x = value,
+        GamepadAxis::RightY => report.right_y = value,
+        // From 0..=i16::MAX
+        GamepadAxis::LeftTrigger => report.left_trigger = (value.max(0) >> 7) as u8,
+        GamepadAxis::RightTrigger => report.right_trigger = (value.max(0) >> 7) as u8,
+    });
+}
+
+pub fn remove(pad: u8) {
+    if let Some(Some(_)) = PADS.lock().unwrap().remove(&pad) {
+        log::info!("Gamepad {} removed", pad);
+    }
+}
+
+/// Unplugs every gamepad, once the server is gone.
+pub fn remove_all() {
+    PADS.lock().unwrap().clear();
+}
+
+#[cfg(target_os = "linux")]
+struct Device {
+    device: evdev::uinput::VirtualDevice,
+}
+
+#[cfg(target_os = "linux")]
+impl Device {
+    const KEYS: [(GamepadButton, evdev::Key); 11] = [
+        (GamepadButton::South, evdev::Key::BTN_SOUTH),
+        (GamepadButton::East, evdev::Key::BTN_EAST),
+        (GamepadButton::North, evdev::Key::BTN_NORTH),
+        (GamepadButton::West, evdev::Key::BTN_WEST),
+        (GamepadButton::LeftBumper, evdev::Key::BTN_TL),
+        (GamepadButton::RightBumper, evdev::Key::BTN_TR),
+        (GamepadButton::Back, evdev::Key::BTN_SELECT),
+        (GamepadButton::Start, evdev::Key::BTN_START),
+        (GamepadButton::Guide, evdev::Key::BTN_MODE),
+        (GamepadButton::LeftThumb, evdev::Key::BTN_THUMBL),
+        (GamepadButton::RightThumb, evdev::Key::BTN_THUMBR),
+    ];
+
+    fn new(pad: u8) -> Result<Self> {
+        use evdev::{
+            uinput::VirtualDeviceBuilder, AbsInfo, AbsoluteAxisType, AttributeSet, BusType,
+            InputId, UinputAbsSetup,
+        };
+
+        let mut keys = AttributeSet::<evdev::Key>::new();
+        for (_, key) in Self::KEYS {
+            keys.insert(key);
+        }
+
+        let stick = AbsInfo::new(0, i16::MIN as i32, i16::MAX as i32, 16, 128, 0);
+        let trigger = AbsInfo::new(0, 0, u8::MAX as i32, 0, 0, 0);
+        let hat = AbsInfo::new(0, -1, 1, 0, 0, 0);
+
+        let name = format!(
+            "{} gamepad {}",
+            rkvm_protocol::VIRTUAL_DEVICE_NAME_PREFIX,
+            pad
+        );
+        let mut builder = VirtualDeviceBuilder::new()?
+            .name(&name)
+            // Those of an Xbox 360 controller, so games know the layout
+            .input_id(InputId::new(BusType::BUS_USB, 0x045e, 0x028e, 0x0110))
+            .with_keys(&keys)?;
+        for (axis, info) in [
+            (AbsoluteAxisType::ABS_X, stick),
+            (AbsoluteAxisType::ABS_Y, stick),
+            (AbsoluteAxisType::ABS_RX, stick),
+            (AbsoluteAxisType::ABS_RY, stick),
+            (AbsoluteAxisType::ABS_Z, trigger),
+            (AbsoluteAxisType::ABS_RZ, trigger),
+            (AbsoluteAxisType::ABS_HAT0X, hat),
+            (AbsoluteAxisType::ABS_HAT0Y, hat),
+        ] {
+            builder = builder.with_absolute_axis(&UinputAbsSetup::new(axis, info))?;
+        }
+
+        Ok(Self {
+            device: builder.build()?,
+        })
+    }
+
+    /// Axis values for `report`, the D-pad being a hat.
+    fn axes(report: &Report) -> [(evdev::AbsoluteAxisType, i32); 8] {
+        use evdev::AbsoluteAxisType;
+
+        let pressed = |button| (report.buttons & bit(button) != 0) as i32;
+        let hat_x = pressed(GamepadButton::DpadRight) - pressed(GamepadButton::DpadLeft);
+        let hat_y = pressed(GamepadButton::DpadDown) - pressed(GamepadButton::DpadUp);
+
+        [
+            (AbsoluteAxisType::ABS_X, report.left_x as i32),
+            (AbsoluteAxisType::ABS_Y, report.left_y as i32),
+            (AbsoluteAxisType::ABS_RX, report.right_x as i32),
+            (AbsoluteAxisType::ABS_RY, report.right_y as i32),
+            (AbsoluteAxisType::ABS_Z, report.left_trigger as i32),
+            (AbsoluteAxisType::ABS_RZ, report.right_trigger as i32),
+            (AbsoluteAxisType::ABS_HAT0X, hat_x),
+            (AbsoluteAxisType::ABS_HAT0Y, hat_y),
+        ]
+    }
+
+    fn update(&mut self, previous: &Report, report: &Report) -> Result<()> {
+        use evdev::{EventType, InputEvent};
+
+        let mut events = Vec::new();
+        for (button, key) in Self::KEYS {
+            let pressed = report.buttons & bit(button) != 0;
+            if pressed != (previous.buttons & bit(button) != 0) {
+                events.push(InputEvent::new(EventType::KEY, key.code(), pressed.into()));
+            }
+        }
+
+        for ((axis, value), (_, old)) in Self::axes(report).into_iter().zip(Self::axes(previous)) {
+            if value != old {
+                events.push(InputEvent::new(EventType::ABSOLUTE, axis.0, value));
+            }
+        }
+
+        self.device.emit(&events)?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod vigem {
+    use std::{ffi::c_void, sync::OnceLock};
+
+    use anyhow::Result;
+    use windows::{
+        core::{s, w},
+        Win32::System::LibraryLoader::{GetProcAddress, LoadLibraryW},
+    };
+
+    use super::Report;
+
+    const VIGEM_ERROR_NONE: u32 = 0x2000_0000;
+
+    type Alloc = unsafe extern "C" fn() -> *mut c_void;
+    type Connect = unsafe extern "C" fn(*mut c_void) -> u32;
+    type TargetCall = unsafe extern "C" fn(*mut c_void, *mut c_void) -> u32;
+    type TargetFree = unsafe extern "C" fn(*mut c_void);
+    type Update = unsafe extern "C" fn(*mut c_void, *mut c_void, Report) -> u32;
+
+    /// A connection to ViGEmBus, through the functions of `ViGEmClient.dll`
+    pub struct Bus {
+        client: *mut c_void,
+        target_x360_alloc: Alloc,
+        target_add: TargetCall,
+        target_remove: TargetCall,
+        target_free: TargetFree,
+        target_x360_update: Update,
+    }
+
+    // The client handle may be used from any thread
+    unsafe impl Send for Bus {}
+    unsafe impl Sync for Bus {}
+
+    static BUS: OnceLock<Option<Bus>> = OnceLock::new();
+
+    /// Connects on first use, and only tries once.
+    pub fn bus() -> Option<&'static Bus> {
+        BUS.get_or_init(|| match unsafe { Bus::connect() } {
+            Ok(bus) => Some(bus),
+            Err(e) => {
+                log::warn!("Gamepads are unavailable without ViGEmBus: {}", e);
+                None
+            }
+        })
+        .as_ref()
+    }
+
+    impl Bus {
+        unsafe fn connect() -> Result<Self> {
+            let library = LoadLibraryW(w!("ViGEmClient.dll"))?;
+            macro_rules! function {
+                ($name:literal as $type:ty) => {
+                    match GetProcAddress(library, s!($name)) {
+                        Some(f) => {
+                            std::mem::transmute::<unsafe extern "system" fn() -> isize, $type>(f)
+                        }
+                        None => anyhow::bail!("ViGEmClient.dll has no {}", $name),
+                    }
+                };
+            }
+
+            let alloc = function!("vigem_alloc" as Alloc);
+            let connect = function!("vigem_connect" as Connect);
+            let bus = Self {
+                client: alloc(),
+                target_x360_alloc: function!("vigem_target_x360_alloc" as Alloc),
+                target_add: function!("vigem_target_add" as TargetCall),
+                target_remove: function!("vigem_target_remove" as TargetCall),
+                target_free: function!("vigem_target_free" as TargetFree),
+                target_x360_update: function!("vigem_target_x360_update" as Update),
+            };
+            if bus.client.is_null() {
+                anyhow::bail!("Failed to allocate client");
+            }
+
+            let error = connect(bus.client);
+            if error != VIGEM_ERROR_NONE {
+                anyhow::bail!("Failed to connect: {:#x}", error);
+            }
+
+            Ok(bus)
+        }
+    }
+
+    /// A virtual Xbox 360 controller plugged into the bus.
+    pub struct Target {
+        bus: &'static Bus,
+        target: *mut c_void,
+    }
+
+    // Only ever used behind the pads' lock
+    unsafe impl Send for Target {}
+
+    impl Target {
+        pub fn new(bus: &'static Bus) -> Result<Self> {
+            unsafe {
+                let target = (bus.target_x360_alloc)();
+                if target.is_null() {
+                    anyhow::bail!("Failed to allocate controller");
+                }
+
+                let error = (bus.target_add)(bus.client, target);
+                if error != VIGEM_ERROR_NONE {
+                    (bus.target_free)(target);
+                    anyhow::bail!("Failed to plug in controller: {:#x}", error);
+                }
+
+                Ok(Self { bus, target })
+            }
+        }
+
+        pub fn update(&self, report: Report) -> Result<()> {
+            let error =
+                unsafe { (self.bus.target_x360_update)(self.bus.client, self.target, report) };
+            if error != VIGEM_ERROR_NONE {
+                anyhow::bail!("ViGEm error {:#x}", error);
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Target {
+        fn drop(&mut self) {
+            unsafe {
+                (self.bus.target_remove)(self.bus.client, self.target);
+                (self.bus.target_free)(self.target);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+struct Device {
+    target: vigem::Target,
+}
+
+#[cfg(target_os = "windows")]
+impl Device {
+    fn new(_pad: u8) -> Result<Self> {
+        let bus = vigem::bus().ok_or_else(|| anyhow::anyhow!("ViGEmBus is unavailable"))?;
+        Ok(Self {
+            target: vigem::Target::new(bus)?,
+        })
+    }
+
+    fn update(&mut self, _previous: &Report, report: &Report) -> Result<()> {
+        // XInput's Y axes point up
+        let report = Report {
+            left_y: report.left_y.saturating_neg(),
+            right_y: report.right_y.saturating_neg(),
+            ..*report
+        };
+        self.target.update(report)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+struct Device;
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+impl Device {
+    fn new(_pad: u8) -> Result<Self> {
+        anyhow::bail!("Gamepads are only supported on Linux and Windows")
+    }
+
+    fn update(&mut self, _previous: &Report, _report: &Report) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+//! How the client introduces itself to the server.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Result};
+use rkvm_protocol::Uuid;
+
+use crate::Config;
+
+const CERT_FILE: &str = "client.crt.der";
+const KEY_FILE: &str = "client.key.der";
+
+/// The certificate and private key the client signs its TLS handshakes with, kept next to
+/// the config file.
+///
+/// The server approves and revokes clients by its fingerprint, which, unlike the id, nobody
+/// else can present.
+pub struct Certificate {
+    pub cert_der: Vec<u8>,
+    pub key_der: Vec<u8>,
+}
+
+static CERTIFICATE: OnceLock<Certificate> = OnceLock::new();
+
+/// Loads the client certificate kept next to `config_path`, generating it the first time.
+pub fn load_certificate(config_path: &Path) -> Result<()> {
+    let (cert_path, key_path) = certificate_paths(config_path);
+
+    let certificate = if cert_path.exists() && key_path.exists() {
+        Certificate {
+            cert_der: std::fs::read(&cert_path).with_context(|| format!("Read {:?}", cert_path))?,
+            key_der: std::fs::read(&key_path).with_context(|| format!("Read {:?}", key_path))?,
+        }
+    } else {
+        let cert = rcgen::generate_simple_self_signed(vec!["rkvm-client".into()])?;
+        let certificate = Certificate {
+            cert_der: cert.serialize_der()?,
+            key_der: cert.serialize_private_key_der(),
+        };
+        write_private(&key_path, &certificate.key_der)?;
+        write_private(&cert_path, &certificate.cert_der)?;
+        log::info!(
+            "Generated client certificate {}",
+            rkvm_protocol::cert_fingerprint(&certificate.cert_der)
+        );
+        certificate
+    };
+
+    let _ = CERTIFICATE.set(certificate);
+    Ok(())
+}
+
+/// The certificate loaded by [`load_certificate`], if it was.
+pub fn certificate() -> Option<&'static Certificate> {
+    CERTIFICATE.get()
+}
+
+fn certificate_paths(config_path: &Path) -> (PathBuf, PathBuf) {
+    (
+        config_path.with_file_name(CERT_FILE),
+        config_path.with_file_name(KEY_FILE),
+    )
+}
+
+/// Writes `data` to `path` readable only by us, renamed into place so a crash midway
+/// leaves no half a key behind.
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    let temp = path.with_extension("der.tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options
+        .open(&temp)
+        .with_context(|| format!("Write {:?}", temp))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path).with_context(|| format!("Write {:?}", path))?;
+    Ok(())
+}
+
+/// Gives the client an id the first time it runs, and keeps it in the config file.
+pub fn ensure_id(config: &mut Config, config_path: &Path) -> Result<()> {
+    if config.id.is_some() {
+        return Ok(());
+    }
+
+    let id = Uuid::new_v4();
+
+    let config_string = std::fs::read_to_string(config_path)?;
+    let mut doc = config_string.parse::<toml_edit::Document>()?;
+    doc["id"] = toml_edit::value(id.to_string());
+    std::fs::write(config_path, doc.to_string())?;
+
+    log::info!("Generated client id {}", id);
+    config.id = Some(id);
+
+    Ok(())
+}
+
+/// Name the server shows for us, the hostname unless one is configured.
+pub fn name(config: &Config) -> String {
+    match &config.name {
+        Some(name) => name.clone(),
+        None => gethostname::gethostname().to_string_lossy().into_owned(),
+    }
+}
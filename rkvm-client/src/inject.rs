@@ -0,0 +1,85 @@
+//! Telling the server when input can't be injected, so keys don't just vanish without a
+//! word while the secure desktop of a UAC prompt or the lock screen is up.
+//!
+//! Only a `SendInput` that inserts nothing is noticed. Input UIPI keeps from an elevated
+//! window in front is dropped without an error, so that case goes unreported.
+//!
+//! Only the first failure in a row is reported. Once input goes through again, the next
+//! one is reported too.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+
+use anyhow::Result;
+use quinn::Connection;
+use rkvm_protocol::{InjectError, UpstreamKind};
+use tokio::io::AsyncWriteExt;
+use windows::Win32::{
+    Foundation::ERROR_ACCESS_DENIED,
+    UI::Input::KeyboardAndMouse::{SendInput, INPUT},
+};
+
+/// Connection to report over, replaced on every connection
+static CONNECTION: Mutex<Option<Connection>> = Mutex::new(None);
+
+/// Set from a failure until input goes through again
+static FAILING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_connection(connection: Connection) {
+    *CONNECTION.lock().unwrap() = Some(connection);
+    FAILING.store(false, Ordering::Relaxed);
+}
+
+/// Injects `inputs` in one go, noting whether it worked.
+pub fn send(inputs: &[INPUT]) {
+    let inserted = unsafe { SendInput(inputs, std::mem::size_of::<INPUT>() as i32) };
+    check(inserted);
+}
+
+/// Notes what `SendInput` returned, the number of inputs it inserted.
+pub fn check(inserted: u32) {
+    if inserted != 0 {
+        if FAILING.swap(false, Ordering::Relaxed) {
+            log::info!("Injecting input works again");
+        }
+        return;
+    }
+    if FAILING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let error = windows::core::Error::from_win32();
+    let error = InjectError {
+        // What Windows says while the secure desktop has input
+        refused: error.code() == ERROR_ACCESS_DENIED.to_hresult(),
+        message: error.message().to_string(),
+    };
+    log::warn!("Failed to inject input: {}", error.message);
+
+    let runtime = match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => runtime,
+        Err(_) => return,
+    };
+    runtime.spawn(async move {
+        if let Err(e) = report(error).await {
+            log::debug!("Failed to report injection error: {}", e);
+        }
+    });
+}
+
+async fn report(error: InjectError) -> Result<()> {
+    let connection = CONNECTION.lock().unwrap().clone();
+    let connection = match connection {
+        Some(connection) if connection.close_reason().is_none() => connection,
+        _ => anyhow::bail!("not connected"),
+    };
+
+    let mut stream = crate::client::open_upstream(&connection, UpstreamKind::InjectError).await?;
+    let error = error.to_vec();
+    stream.write_u32(error.len() as u32).await?;
+    stream.write_all(&error).await?;
+    stream.finish().await?;
+    Ok(())
+}
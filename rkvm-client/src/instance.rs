@@ -0,0 +1,134 @@
+//! Keeping a single client running, since two would inject every event twice.
+//!
+//! Launching the client again asks the running one to show itself instead.
+
+use anyhow::Result;
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use std::sync::Mutex;
+
+    use anyhow::Result;
+    use windows::{
+        core::w,
+        Win32::{
+            Foundation::{CloseHandle, GetLastError, ERROR_ALREADY_EXISTS, HANDLE},
+            System::Threading::{
+                CreateEventW, CreateMutexW, OpenEventW, SetEvent, WaitForSingleObject,
+                EVENT_MODIFY_STATE, INFINITE,
+            },
+        },
+    };
+
+    /// Held for as long as this is the running instance.
+    static MUTEX: Mutex<Option<HANDLE>> = Mutex::new(None);
+
+    pub fn acquire(on_activate: impl Fn() + Send + 'static) -> Result<bool> {
+        unsafe {
+            let mutex = CreateMutexW(None, true, w!("Local\\rkvm-client"))?;
+            if GetLastError() == ERROR_ALREADY_EXISTS {
+                CloseHandle(mutex);
+
+                if let Ok(event) =
+                    OpenEventW(EVENT_MODIFY_STATE, false, w!("Local\\rkvm-client-activate"))
+                {
+                    SetEvent(event);
+                    CloseHandle(event);
+                }
+                return Ok(false);
+            }
+            *MUTEX.lock().unwrap() = Some(mutex);
+
+            let event = CreateEventW(None, false, false, w!("Local\\rkvm-client-activate"))?;
+            std::thread::spawn(move || loop {
+                WaitForSingleObject(event, INFINITE);
+                on_activate();
+            });
+        }
+
+        Ok(true)
+    }
+
+    pub fn release() {
+        if let Some(mutex) = MUTEX.lock().unwrap().take() {
+            unsafe { CloseHandle(mutex) };
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+mod platform {
+    use std::{
+        os::unix::net::{UnixListener, UnixStream},
+        path::PathBuf,
+        sync::Mutex,
+    };
+
+    use anyhow::Result;
+
+    /// Socket we listen on, removed when giving up the instance.
+    static SOCKET: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+    /// Where the socket called `name` goes, in the runtime directory of the user.
+    pub fn socket_path(name: &str) -> PathBuf {
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => PathBuf::from(dir).join(format!("{}.sock", name)),
+            None => {
+                let user = std::env::var("USER").unwrap_or_default();
+                std::env::temp_dir().join(format!("{}-{}.sock", name, user))
+            }
+        }
+    }
+
+    pub fn acquire(on_activate: impl Fn() + Send + 'static) -> Result<bool> {
+        let path = socket_path("rkvm-client");
+
+        let listener = match UnixListener::bind(&path) {
+            Ok(listener) => listener,
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                // Connecting is all it takes to activate the running instance
+                if UnixStream::connect(&path).is_ok() {
+                    return Ok(false);
+                }
+
+                // Left behind by an instance that crashed
+                std::fs::remove_file(&path)?;
+                UnixListener::bind(&path)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        *SOCKET.lock().unwrap() = Some(path);
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stream.is_ok() {
+                    on_activate();
+                }
+            }
+        });
+
+        Ok(true)
+    }
+
+    pub fn release() {
+        if let Some(path) = SOCKET.lock().unwrap().take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub use platform::socket_path;
+
+/// Becomes the running instance, calling `on_activate` whenever the client is launched
+/// again.
+///
+/// Returns `false` if another instance is running, after asking it to show itself.
+pub fn acquire(on_activate: impl Fn() + Send + 'static) -> Result<bool> {
+    platform::acquire(on_activate)
+}
+
+/// Lets another instance start, before quitting.
+pub fn release() {
+    platform::release()
+}
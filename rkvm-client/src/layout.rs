@@ -0,0 +1,214 @@
+//! Typing in the keyboard layout the server asks for.
+//!
+//! Keys arrive as scan codes, which our own layout turns into characters. When it differs
+//! from the one the server's keyboard is labelled for, AltGr combinations and dead keys
+//! come out wrong, so the server may name a Windows layout id like `00000407` (German).
+//! The foreground window is switched to it whenever a key arrives.
+//!
+//! Without one named, the layout the server detected it types in may be followed instead,
+//! going by its XKB name.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(target_os = "windows")]
+use std::sync::Mutex;
+
+#[cfg(target_os = "windows")]
+use windows::Win32::UI::TextServices::HKL;
+
+/// Layout the server asked for, `None` to leave the layout alone
+#[cfg(target_os = "windows")]
+static HINT: Mutex<Option<HKL>> = Mutex::new(None);
+
+/// Whether the server named a layout, which goes before the one it types in
+static NAMED: AtomicBool = AtomicBool::new(false);
+
+/// Windows layout ids of XKB layouts, and of a few of their variants
+const WINDOWS_IDS: &[(&str, &str)] = &[
+    ("us(dvorak)", "00010409"),
+    ("us(intl)", "00020409"),
+    ("us", "00000409"),
+    ("gb", "00000809"),
+    ("ie", "00001809"),
+    ("de", "00000407"),
+    ("at", "00000407"),
+    ("ch(fr)", "0000100C"),
+    ("ch", "00000807"),
+    ("fr", "0000040C"),
+    ("be", "0000080C"),
+    ("ca", "00001009"),
+    ("es", "0000040A"),
+    ("latam", "0000080A"),
+    ("pt", "00000816"),
+    ("br", "00010416"),
+    ("it", "00000410"),
+    ("nl", "00000413"),
+    ("dk", "00000406"),
+    ("no", "00000414"),
+    ("se", "0000041D"),
+    ("fi", "0000040B"),
+    ("is", "0000040F"),
+    ("pl", "00000415"),
+    ("cz", "00000405"),
+    ("sk", "0000041B"),
+    ("hu", "0000040E"),
+    ("ro", "00010418"),
+    ("si", "00000424"),
+    ("hr", "0000041A"),
+    ("rs", "00000C1A"),
+    ("gr", "00000408"),
+    ("tr", "0000041F"),
+    ("ru", "00000419"),
+    ("ua", "00000422"),
+    ("by", "00000423"),
+    ("bg", "00000402"),
+    ("ee", "00000425"),
+    ("lv", "00000426"),
+    ("lt", "00010427"),
+    ("il", "0000040D"),
+    ("ara", "00000401"),
+    ("jp", "00000411"),
+    ("kr", "00000412"),
+    ("cn", "00000804"),
+    ("tw", "00000404"),
+];
+
+/// The Windows layout id for the XKB layout `xkb`, or for the layout of its variant.
+pub fn windows_id(xkb: &str) -> Option<&'static str> {
+    let base = xkb.split('(').next().unwrap_or(xkb);
+    [xkb, base].into_iter().find_map(|name| {
+        WINDOWS_IDS
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, id)| *id)
+    })
+}
+
+/// Id of the layout we type in, to report to the server.
+#[cfg(target_os = "windows")]
+pub fn current() -> Option<String> {
+    use windows::Win32::UI::Input::KeyboardAndMouse::GetKeyboardLayoutNameW;
+
+    let mut name = [0u16; 9];
+    if !unsafe { GetKeyboardLayoutNameW(&mut name) }.as_bool() {
+        return None;
+    }
+
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Some(String::from_utf16_lossy(&name[..len]))
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn current() -> Option<String> {
+    None
+}
+
+/// Starts typing in `layout` the server named, or stops switching if `None`.
+pub fn set_hint(layout: Option<&str>) {
+    NAMED.store(layout.is_some(), Ordering::Relaxed);
+    load(layout);
+}
+
+/// Starts typing in the XKB layout `xkb` the server types in, unless it named one. Leaves
+/// the layout alone again if `None`, as the server can't tell.
+pub fn follow(xkb: Option<&str>) {
+    if NAMED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    match xkb.map(|xkb| (xkb, windows_id(xkb))) {
+        Some((_, Some(id))) => load(Some(id)),
+        Some((xkb, None)) => log::warn!("No Windows layout known for keyboard layout {}", xkb),
+        None => load(None),
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn load(layout: Option<&str>) {
+    use windows::{
+        core::HSTRING,
+        Win32::UI::Input::KeyboardAndMouse::{LoadKeyboardLayoutW, KLF_NOTELLSHELL},
+    };
+
+    let hkl = layout.and_then(|layout| {
+        match unsafe { LoadKeyboardLayoutW(&HSTRING::from(layout), KLF_NOTELLSHELL) } {
+            Ok(hkl) => {
+                log::info!("Typing in keyboard layout {}", layout);
+                Some(hkl)
+            }
+            Err(e) => {
+                log::warn!("Failed to load keyboard layout {}: {}", layout, e);
+                None
+            }
+        }
+    });
+    *HINT.lock().unwrap() = hkl;
+}
+
+#[cfg(not(target_os = "windows"))]
+fn load(layout: Option<&str>) {
+    if let Some(layout) = layout {
+        log::warn!(
+            "Server asked for keyboard layout {}, which is only followed on Windows",
+            layout
+        );
+    }
+}
+
+/// Switches the foreground window to the layout asked for, if it uses another one.
+#[cfg(target_os = "windows")]
+pub fn apply() {
+    use windows::Win32::{
+        Foundation::{LPARAM, WPARAM},
+        UI::{
+            Input::KeyboardAndMouse::GetKeyboardLayout,
+            WindowsAndMessaging::{
+                GetForegroundWindow, GetWindowThreadProcessId, PostMessageW,
+                WM_INPUTLANGCHANGEREQUEST,
+            },
+        },
+    };
+
+    let hkl = match *HINT.lock().unwrap() {
+        Some(hkl) => hkl,
+        None => return,
+    };
+
+    unsafe {
+        let window = GetForegroundWindow();
+        if window.0 == 0 {
+            return;
+        }
+
+        let thread = GetWindowThreadProcessId(window, None);
+        if GetKeyboardLayout(thread) != hkl {
+            PostMessageW(window, WM_INPUTLANGCHANGEREQUEST, WPARAM(0), LPARAM(hkl.0));
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn apply() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_id_of_layout() {
+        assert_eq!(windows_id("de"), Some("00000407"));
+        assert_eq!(windows_id("us"), Some("00000409"));
+        assert_eq!(windows_id("xx"), None);
+    }
+
+    #[test]
+    fn windows_id_of_variant() {
+        assert_eq!(windows_id("us(dvorak)"), Some("00010409"));
+        assert_eq!(windows_id("ch(fr)"), Some("0000100C"));
+    }
+
+    #[test]
+    fn windows_id_of_unknown_variant() {
+        assert_eq!(windows_id("de(nodeadkeys)"), Some("00000407"));
+        assert_eq!(windows_id("xx(yy)"), None);
+    }
+}
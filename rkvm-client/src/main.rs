@@ -3,10 +3,10 @@
     windows_subsystem = "windows"
 )]
 
-use std::{net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use quinn::Endpoint;
 use serde::Deserialize;
@@ -18,7 +18,45 @@ use tao::{
     TrayId,
 };
 
+mod ack;
+mod activity;
+mod attention;
+mod autostart;
+mod capture;
 mod client;
+mod clock;
+#[cfg(target_os = "windows")]
+mod drag;
+mod files;
+mod gamepad;
+mod identity;
+#[cfg(target_os = "windows")]
+mod inject;
+mod instance;
+mod layout;
+#[cfg(target_os = "windows")]
+mod native_clipboard;
+mod offer;
+mod ordering;
+mod overlay;
+mod pacing;
+mod pad;
+mod pairing;
+mod pointer;
+mod power;
+mod priority;
+mod progress;
+mod relay;
+mod reload;
+mod sample;
+mod screens;
+mod secrets;
+mod sequence;
+#[cfg(target_os = "windows")]
+mod service;
+mod settings;
+mod status;
+mod target;
 
 fn load_icon(png_data: &[u8]) -> Result<tao::system_tray::Icon> {
     let (icon_rgba, icon_width, icon_height) = {
@@ -37,12 +75,139 @@ fn load_icon(png_data: &[u8]) -> Result<tao::system_tray::Icon> {
     )?)
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 struct Config {
     /// IP address or domain name of the server
     address: String,
     /// Port on the server to connect to
     port: u16,
+    /// Pre-shared key configured on the server, if any
+    psk: Option<String>,
+    /// SHA-256 fingerprint of the server certificate; any certificate is accepted if unset
+    fingerprint: Option<String>,
+    /// Keep `psk` and `fingerprint` in the OS credential store instead of this file
+    #[serde(default = "default_true")]
+    use_keyring: bool,
+    /// Tapping Right Ctrl sends this machine's keyboard and mouse to the server
+    #[serde(default)]
+    reverse_control: bool,
+    /// Most seconds between keep-alives and before a silent connection is given up on, 0
+    /// for no idle timeout, as far as the server allows
+    #[serde(default = "default_keep_alive_secs")]
+    keep_alive_secs: u64,
+    #[serde(default = "default_idle_timeout_secs")]
+    idle_timeout_secs: u64,
+    /// Clipboard contents up to this many bytes are accepted without asking
+    #[serde(default = "default_auto_accept_size")]
+    auto_accept_size: u64,
+    /// Cap in bytes per second for clipboard and file transfers, so input stays responsive
+    #[serde(default)]
+    bandwidth_limit: Option<u64>,
+    /// Multiplies mouse motion from the server
+    #[serde(default = "default_sensitivity")]
+    sensitivity: f64,
+    /// Mouse motion held up on the way for longer than this many milliseconds is dropped, never
+    /// if 0
+    #[serde(default = "default_stale_motion_ms")]
+    stale_motion_ms: u64,
+    /// Modifiers, clicks and scrolling wait up to this many milliseconds for what the server
+    /// sent before them on other streams, never if 0
+    #[serde(default = "default_order_window_ms")]
+    order_window_ms: u64,
+    /// Ask the server for every event on one stream, in the order sent, instead of mouse
+    /// motion going ahead of the rest
+    #[serde(default)]
+    single_stream: bool,
+    /// Move the cursor this many times a second, spreading out motion that arrives unevenly.
+    /// Off if 0
+    #[serde(default)]
+    motion_pacing_hz: u32,
+    /// Put what the server copies on this machine's clipboard
+    #[serde(default = "default_true")]
+    clipboard: bool,
+    /// How text from the server's clipboard is cleaned up
+    #[serde(default)]
+    clipboard_text: rkvm_protocol::TextNormalization,
+    /// Draw a border around the screens while the server's input goes here
+    #[serde(default)]
+    control_overlay: bool,
+    /// Report only this monitor to the server, "primary" or the number Windows gives it,
+    /// and keep motion on it
+    #[serde(default)]
+    pin_monitor: Option<String>,
+    /// Report only this rectangle to the server, in pixels of the virtual desktop, and keep
+    /// motion inside it. Takes over from `pin_monitor`
+    #[serde(default)]
+    pin_region: Option<screens::Region>,
+    /// DPI scale to report for every monitor, where the system doesn't tell
+    #[serde(default)]
+    monitor_scale: Option<f32>,
+    /// Chime as well as flash the screen border when the server asks for attention
+    #[serde(default = "default_true")]
+    attention_chime: bool,
+    /// Suspend when the server asks to, as its lid closes
+    #[serde(default = "default_true")]
+    suspend_with_server: bool,
+    /// Type in the layout the server's keyboard types in, unless it names one in its config.
+    /// Windows only
+    #[serde(default)]
+    follow_server_layout: bool,
+    /// Send warnings and errors to the server, which writes them to its log
+    #[serde(default)]
+    relay_logs: bool,
+    /// Run the threads injecting input at a higher priority, on Windows. Read at startup
+    #[serde(default)]
+    input_priority: bool,
+    /// What turning a ring of a tablet pad on the server does
+    #[serde(default = "default_pad_action")]
+    pad_ring: pad::PadAction,
+    /// What sliding along a strip of a tablet pad on the server does
+    #[serde(default = "default_pad_action")]
+    pad_strip: pad::PadAction,
+    /// Keys pressed by the buttons of a tablet pad on the server, by button number
+    #[serde(default)]
+    pad_buttons: HashMap<String, Vec<keycode::KeyMappingId>>,
+    /// Where the connection to the server shows as degraded or poor
+    #[serde(default)]
+    link_quality: target::QualityConfig,
+    /// Name the server shows for this machine, the hostname if unset
+    #[serde(default)]
+    name: Option<String>,
+    /// Identifies this machine to the server, generated on first run
+    #[serde(default)]
+    id: Option<rkvm_protocol::Uuid>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_pad_action() -> pad::PadAction {
+    pad::PadAction::Scroll
+}
+
+fn default_keep_alive_secs() -> u64 {
+    5
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    10
+}
+
+fn default_auto_accept_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_sensitivity() -> f64 {
+    1.0
+}
+
+fn default_stale_motion_ms() -> u64 {
+    200
+}
+
+fn default_order_window_ms() -> u64 {
+    4
 }
 
 #[derive(Parser, Debug)]
@@ -54,23 +219,91 @@ struct Args {
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
+    /// Run as the Windows service, see `install-service`
+    #[cfg(target_os = "windows")]
+    #[arg(long, hide = true)]
+    service: bool,
+    /// Run without a tray icon or clipboard, as the service does on the login screen
+    #[cfg(target_os = "windows")]
+    #[arg(long, hide = true)]
+    agent: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-async fn tokio_main(config: Config) -> Result<()> {
-    let remote_addr = SocketAddr::new(config.address.parse()?, config.port);
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Add a server from a pairing code printed by `rkvm-server pair`
+    Pair {
+        /// The `rkvm://` pairing code
+        code: String,
+    },
+    /// Print a config file with every option at its default, explained
+    GenerateConfig {
+        /// Server address to fill in
+        #[arg(long)]
+        address: Option<String>,
+        /// Server port to fill in
+        #[arg(long, default_value_t = 12334)]
+        port: u16,
+        /// Write it to this file instead, which must not exist yet
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Move the psk and fingerprint from the config file to the system credential store
+    StoreSecrets,
+    /// Print what the running client is up to: the connection, packets received and the
+    /// last error
+    Status,
+    /// Install a service that lets the server control the login and lock screens
+    #[cfg(target_os = "windows")]
+    InstallService,
+    /// Remove the service added by `install-service`
+    #[cfg(target_os = "windows")]
+    UninstallService,
+}
 
+async fn tokio_main(mut config_rx: tokio::sync::watch::Receiver<Config>) -> Result<()> {
     let mut endpoint = Endpoint::client("0.0.0.0:0".parse().unwrap())?;
-    endpoint.set_default_client_config(client::configure_client());
 
+    // With the timeouts the server last negotiated, which the transport applies
+    let mut configured = None;
     let mut sleep_secs = 1;
 
     loop {
-        if let Err(e) = client::connect(&endpoint, remote_addr).await {
+        let config = config_rx.borrow_and_update().clone();
+        let remote_addr = SocketAddr::new(config.address.parse()?, config.port);
+        let wanted = (config.clone(), client::negotiated());
+        if configured.as_ref() != Some(&wanted) {
+            endpoint.set_default_client_config(client::configure_client(&config)?);
+            configured = Some(wanted);
+        }
+
+        if config.reverse_control {
+            capture::start();
+        } else {
+            capture::stop();
+        }
+
+        if let Err(e) = client::connect(&endpoint, remote_addr, config_rx.clone()).await {
             log::error!("Error handling connection: {}", e);
         }
+        status::disconnected();
+
+        if reload::needs_reconnect(&config, &config_rx.borrow()) {
+            sleep_secs = 1;
+            continue;
+        }
 
         log::info!("Reconnecting in {} seconds", sleep_secs);
-        tokio::time::sleep(std::time::Duration::from_secs(sleep_secs)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(sleep_secs)) => {}
+            _ = reload::reconnect_needed(&mut config_rx, &config) => {
+                sleep_secs = 1;
+                continue;
+            }
+        }
         sleep_secs *= 2;
         if sleep_secs > 30 {
             sleep_secs = 30;
@@ -81,13 +314,12 @@ async fn tokio_main(config: Config) -> Result<()> {
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    let mut logger = simple_logger::SimpleLogger::new();
-    if args.verbose {
-        logger = logger.with_level(log::LevelFilter::Trace);
+    let level = if args.verbose {
+        log::LevelFilter::Trace
     } else {
-        logger = logger.with_level(log::LevelFilter::Info);
-    }
-    logger.init()?;
+        log::LevelFilter::Info
+    };
+    relay::init(simple_logger::SimpleLogger::new().with_level(level), level)?;
 
     let config_path = if let Some(p) = args.config {
         p
@@ -95,50 +327,175 @@ fn main() -> Result<()> {
         std::env::current_exe()?.with_file_name("config.toml")
     };
 
-    let config_string = std::fs::read_to_string(config_path)?;
-    let config: Config = toml::from_str(&config_string)?;
+    match &args.command {
+        Some(Command::Pair { code }) => return pairing::apply(code, &config_path),
+        Some(Command::GenerateConfig {
+            address,
+            port,
+            output,
+        }) => return sample::generate(address.as_deref(), *port, output.as_deref()),
+        Some(Command::StoreSecrets) => return secrets::store(&config_path),
+        Some(Command::Status) => return status::print(),
+        #[cfg(target_os = "windows")]
+        Some(Command::InstallService) => return service::install(&config_path),
+        #[cfg(target_os = "windows")]
+        Some(Command::UninstallService) => return service::uninstall(),
+        None => {}
+    }
 
-    let tokio_rt = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap();
+    #[cfg(target_os = "windows")]
+    if args.service {
+        return service::run(config_path);
+    }
+
+    #[cfg(target_os = "windows")]
+    if args.agent {
+        reload::set_agent();
+    }
+
+    #[cfg_attr(not(target_os = "windows"), allow(unused_mut))]
+    let mut config = reload::load(&config_path)?;
+    // Queued until connected, and sent then
+    relay::set_enabled(config.relay_logs);
+    identity::load_certificate(&config_path)?;
+
+    #[cfg(target_os = "windows")]
+    if args.agent {
+        // Nobody to ask at the login screen, and nothing of theirs to paste into
+        config.clipboard = false;
+        config.reverse_control = false;
+
+        let tokio_rt = priority::runtime(config.input_priority)?;
+        let config_rx = reload::init(config_path, config);
+        return tokio_rt.block_on(tokio_main(config_rx));
+    }
+
+    let activate_path = config_path.clone();
+    if !instance::acquire(move || settings::open(&activate_path))? {
+        log::info!("Client is already running");
+        return Ok(());
+    }
+
+    #[cfg(target_os = "windows")]
+    screens::init();
+
+    let input_priority = config.input_priority;
+    let config_rx = reload::init(config_path.clone(), config);
+    if let Err(e) = reload::watch() {
+        log::warn!("Not watching the config for changes: {}", e);
+    }
+
+    let tokio_rt = priority::runtime(input_priority).unwrap();
+    tokio_rt.spawn(async {
+        if let Err(e) = status::serve().await {
+            log::warn!("Not answering status requests: {}", e);
+        }
+    });
     tokio_rt.spawn(async move {
-        if let Err(e) = tokio_main(config).await {
+        if let Err(e) = tokio_main(config_rx).await {
             log::error!("Error in tokio_main: {}", e);
 
+            #[cfg(target_os = "windows")]
+            native_clipboard::close();
             std::process::exit(1);
         }
     });
 
-    let event_loop = EventLoop::new();
+    let event_loop = EventLoop::<()>::with_user_event();
+    target::set_proxy(event_loop.create_proxy());
 
     let main_tray_id = TrayId::new("main-tray");
     let mut tray_menu = ContextMenu::new();
+    let settings_item = tray_menu.add_item(MenuItemAttributes::new("Settings..."));
+    let pair_item = tray_menu.add_item(MenuItemAttributes::new("Add server from clipboard"));
+    let reload_item = tray_menu.add_item(MenuItemAttributes::new("Reload config"));
+    let send_files_item =
+        tray_menu.add_item(MenuItemAttributes::new("Send copied files to server"));
+    let attention_item = tray_menu.add_item(MenuItemAttributes::new("Get attention at server"));
+    let mut cancel_item =
+        tray_menu.add_item(MenuItemAttributes::new("Cancel transfer").with_enabled(false));
+    #[cfg(not(target_os = "windows"))]
+    let mut accept_item =
+        tray_menu.add_item(MenuItemAttributes::new("Accept offered clipboard").with_enabled(false));
+    #[cfg(not(target_os = "windows"))]
+    let mut decline_item = tray_menu
+        .add_item(MenuItemAttributes::new("Decline offered clipboard").with_enabled(false));
+    let mut autostart_item = tray_menu
+        .add_item(MenuItemAttributes::new("Start at login").with_selected(autostart::is_enabled()));
     let quit_item = tray_menu.add_item(MenuItemAttributes::new("Quit"));
 
     let icon = load_icon(include_bytes!("./icon.png"))?;
 
-    let system_tray = SystemTrayBuilder::new(icon, Some(tray_menu))
+    let mut system_tray = SystemTrayBuilder::new(icon, Some(tray_menu))
         .with_id(main_tray_id)
-        .with_tooltip("RKVM Client")
+        .with_tooltip(&target::tooltip())
         .build(&event_loop)
         .unwrap();
 
     event_loop.run(move |event, _event_loop, control_flow| {
         let _ = tokio_rt;
-        let _ = system_tray;
 
         *control_flow = ControlFlow::Wait;
 
         match event {
             tao::event::Event::NewEvents(StartCause::Init) => {}
+            tao::event::Event::UserEvent(()) => {
+                system_tray.set_tooltip(&target::tooltip());
+                cancel_item.set_enabled(progress::is_running());
+                #[cfg(not(target_os = "windows"))]
+                {
+                    accept_item.set_enabled(offer::is_pending());
+                    decline_item.set_enabled(offer::is_pending());
+                }
+            }
             tao::event::Event::MenuEvent {
                 menu_id,
                 // specify only context menu's
                 origin: tao::menu::MenuType::ContextMenu,
                 ..
             } => {
-                if menu_id == quit_item.clone().id() {
+                #[cfg(not(target_os = "windows"))]
+                if menu_id == accept_item.clone().id() || menu_id == decline_item.clone().id() {
+                    offer::answer(menu_id == accept_item.clone().id());
+                    return;
+                }
+
+                if menu_id == settings_item.clone().id() {
+                    settings::open(&config_path);
+                } else if menu_id == pair_item.clone().id() {
+                    let code = arboard::Clipboard::new().and_then(|mut c| c.get_text());
+                    match code.map_err(anyhow::Error::from) {
+                        Ok(code) => match pairing::apply(&code, &config_path) {
+                            Ok(_) => reload::reload(),
+                            Err(e) => log::error!("Failed to add server: {}", e),
+                        },
+                        Err(e) => log::error!("Failed to read clipboard: {}", e),
+                    }
+                } else if menu_id == reload_item.clone().id() {
+                    reload::reload();
+                } else if menu_id == send_files_item.clone().id() {
+                    tokio_rt.spawn(async {
+                        if let Err(e) = files::send_clipboard().await {
+                            log::error!("Failed to send files: {}", e);
+                        }
+                    });
+                } else if menu_id == attention_item.clone().id() {
+                    tokio_rt.spawn(async {
+                        if let Err(e) = attention::request().await {
+                            log::error!("Failed to ask for attention: {}", e);
+                        }
+                    });
+                } else if menu_id == cancel_item.clone().id() {
+                    progress::cancel();
+                } else if menu_id == autostart_item.clone().id() {
+                    if let Err(e) = autostart::set_enabled(!autostart::is_enabled()) {
+                        log::error!("Failed to change start at login: {}", e);
+                    }
+                    autostart_item.set_selected(autostart::is_enabled());
+                } else if menu_id == quit_item.clone().id() {
+                    instance::release();
+                    #[cfg(target_os = "windows")]
+                    native_clipboard::close();
                     *control_flow = ControlFlow::Exit;
                 }
             }
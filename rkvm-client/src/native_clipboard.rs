@@ -0,0 +1,145 @@
+//! Delay-rendered clipboard on Windows, so images are only converted when someone pastes.
+
+use std::sync::{atomic::AtomicBool, Arc, Mutex};
+
+use anyhow::{Context, Result};
+use windows_clipboard_files::{
+    dib_from_rgba, html_format, unicode_text, Clipboard, ClipboardFile, Format, FormatRegistry,
+    Render,
+};
+
+/// Opened on first use, and kept until [`close`]
+static CLIPBOARD: Mutex<Option<Arc<Clipboard>>> = Mutex::new(None);
+
+fn clipboard() -> Option<Arc<Clipboard>> {
+    let mut clipboard = CLIPBOARD.lock().unwrap();
+    if clipboard.is_none() {
+        match Clipboard::new() {
+            Ok(c) => *clipboard = Some(Arc::new(c)),
+            Err(e) => log::error!("Failed to open native clipboard: {}", e),
+        }
+    }
+
+    clipboard.clone()
+}
+
+/// Lets go of the clipboard before exiting, rendering what we put on it so it stays
+/// there. Statics are never dropped, so this must be called.
+pub fn close() {
+    let clipboard = CLIPBOARD.lock().unwrap().take();
+    drop(clipboard);
+}
+
+fn set(formats: Vec<(Format, Render)>) -> Result<()> {
+    clipboard()
+        .context("Native clipboard unavailable")?
+        .set_delayed(formats)?;
+
+    Ok(())
+}
+
+fn take(data: Arc<Vec<u8>>) -> Vec<u8> {
+    Arc::try_unwrap(data).unwrap_or_else(|data| (*data).clone())
+}
+
+/// `png` is copied once, into what goes on the clipboard.
+pub fn set_png(png: &[u8]) -> Result<()> {
+    let png = Arc::new(png.to_vec());
+    let dib_png = png.clone();
+
+    set(vec![
+        (Format::Png, Box::new(move || Some(take(png)))),
+        (
+            Format::Dib,
+            Box::new(move || {
+                let image = match image::load_from_memory(&dib_png) {
+                    Ok(i) => i.into_rgba8(),
+                    Err(e) => {
+                        log::error!("Failed to decode clipboard image: {}", e);
+                        return None;
+                    }
+                };
+
+                let (width, height) = image.dimensions();
+                dib_from_rgba(width, height, &image)
+            }),
+        ),
+    ])
+}
+
+/// `rgba` must be tightly packed. It is encoded as a DIB right away, which the PNG is
+/// encoded from if someone pastes one.
+pub fn set_rgba(width: u32, height: u32, rgba: &[u8]) -> Result<()> {
+    let dib = dib_from_rgba(width, height, rgba).context("Clipboard image too short")?;
+    let dib = Arc::new(dib);
+    let png_dib = dib.clone();
+
+    set(vec![
+        (Format::Dib, Box::new(move || Some(take(dib)))),
+        (
+            Format::Png,
+            Box::new(move || {
+                let rgba = rgba_from_dib(width, height, &png_dib);
+                let image = image::RgbaImage::from_raw(width, height, rgba)?;
+
+                let mut png = Vec::new();
+                let cursor = &mut std::io::Cursor::new(&mut png);
+                if let Err(e) = image.write_to(cursor, image::ImageFormat::Png) {
+                    log::error!("Failed to encode clipboard image: {}", e);
+                    return None;
+                }
+
+                Some(png)
+            }),
+        ),
+    ])
+}
+
+/// The pixels of a DIB from [`dib_from_rgba`], back in top-down RGBA rows.
+fn rgba_from_dib(width: u32, height: u32, dib: &[u8]) -> Vec<u8> {
+    const HEADER_LEN: usize = 40;
+
+    let row = width as usize * 4;
+    let mut rgba = Vec::with_capacity(row * height as usize);
+    for line in dib[HEADER_LEN..].chunks_exact(row).rev() {
+        for pixel in line.chunks_exact(4) {
+            rgba.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+        }
+    }
+
+    rgba
+}
+
+pub fn set_html(html: &str, plain: &str) -> Result<()> {
+    let html = html_format(html);
+    let plain = unicode_text(plain);
+
+    set(vec![
+        (Format::Html, Box::new(move || Some(html))),
+        (Format::UnicodeText, Box::new(move || Some(plain))),
+    ])
+}
+
+/// The HTML fragment on the clipboard, if another application copied one.
+pub fn get_html() -> Result<Option<String>> {
+    let html = clipboard()
+        .context("Native clipboard unavailable")?
+        .get_html()?;
+
+    Ok(html)
+}
+
+pub fn get_files() -> Result<Vec<ClipboardFile>> {
+    let files = clipboard()
+        .context("Native clipboard unavailable")?
+        .get_files()?;
+
+    Ok(files)
+}
+
+/// Drags `registry` from under the cursor until the left button goes up or `cancel` is set.
+///
+/// Returns whether it was dropped. Blocks for the whole drag.
+pub fn drag(registry: FormatRegistry, cancel: Arc<AtomicBool>) -> Result<bool> {
+    Ok(windows_clipboard_files::drag(registry, cancel)?)
+}
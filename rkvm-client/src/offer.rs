@@ -0,0 +1,212 @@
+//! Clipboard contents the server offers instead of sending, because they are large.
+
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use arboard::Clipboard;
+use quinn::Connection;
+use rkvm_protocol::{ClipboardFetch, PacketRef, TextNormalization, Throttle, UpstreamKind};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// How much we move without asking, and how fast.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferLimits {
+    /// Offers up to this many bytes are accepted without asking
+    pub auto_accept_size: u64,
+    /// Bytes per second, unlimited if `None`
+    pub bandwidth_limit: Option<u64>,
+}
+
+impl TransferLimits {
+    pub fn new(config: &crate::Config) -> Self {
+        Self {
+            auto_accept_size: config.auto_accept_size,
+            bandwidth_limit: config.bandwidth_limit,
+        }
+    }
+}
+
+/// Leaves room for the packet framing around the offered contents.
+const PACKET_OVERHEAD: u64 = 1024;
+
+const CHUNK_LEN: usize = 1024 * 1024;
+
+/// Kept open once used, as some platforms drop what we put on it with the last handle
+static CLIPBOARD: Mutex<Option<Clipboard>> = Mutex::new(None);
+
+/// Asks whoever is at this machine whether to accept the offer.
+#[cfg(target_os = "windows")]
+fn confirm(kind: &str, size: u64) -> bool {
+    use windows::{
+        core::HSTRING,
+        Win32::{
+            Foundation::HWND,
+            UI::WindowsAndMessaging::{
+                MessageBoxW, IDYES, MB_ICONQUESTION, MB_SETFOREGROUND, MB_TOPMOST, MB_YESNO,
+            },
+        },
+    };
+
+    let text = format!(
+        "The server copied {:.1} MB of {}. Paste it on this machine?",
+        size as f64 / 1e6,
+        kind
+    );
+    let style = MB_YESNO | MB_ICONQUESTION | MB_SETFOREGROUND | MB_TOPMOST;
+    let result =
+        unsafe { MessageBoxW(HWND(0), &HSTRING::from(text), &HSTRING::from("rkvm"), style) };
+
+    result == IDYES
+}
+
+/// The offer waiting to be accepted or declined from the tray
+#[cfg(not(target_os = "windows"))]
+static PENDING: Mutex<Option<Pending>> = Mutex::new(None);
+
+#[cfg(not(target_os = "windows"))]
+struct Pending {
+    kind: String,
+    size: u64,
+    answer: tokio::sync::oneshot::Sender<bool>,
+}
+
+/// How long an offer waits in the tray before it is declined
+#[cfg(not(target_os = "windows"))]
+const PROMPT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Shows the offer in the tray until it is accepted or declined there, or times out.
+#[cfg(not(target_os = "windows"))]
+async fn confirm(kind: &str, size: u64) -> bool {
+    let (answer, answered) = tokio::sync::oneshot::channel();
+    // A newer offer replaces the one shown, which is declined as the sender drops
+    *PENDING.lock().unwrap() = Some(Pending {
+        kind: kind.to_owned(),
+        size,
+        answer,
+    });
+    crate::target::refresh_tray();
+
+    let accepted = match tokio::time::timeout(PROMPT_TIMEOUT, answered).await {
+        Ok(answer) => answer.unwrap_or(false),
+        Err(_) => {
+            log::info!("Nobody answered the offer of {} bytes of {}", size, kind);
+            PENDING.lock().unwrap().take();
+            false
+        }
+    };
+    crate::target::refresh_tray();
+
+    accepted
+}
+
+/// Answers the offer shown in the tray.
+#[cfg(not(target_os = "windows"))]
+pub fn answer(accept: bool) {
+    match PENDING.lock().unwrap().take() {
+        Some(pending) => {
+            let _ = pending.answer.send(accept);
+        }
+        None => log::info!("No offer to answer"),
+    }
+}
+
+/// Whether an offer is shown, and can be answered.
+#[cfg(not(target_os = "windows"))]
+pub fn is_pending() -> bool {
+    PENDING.lock().unwrap().is_some()
+}
+
+/// Tray tooltip line for the offer shown, if any.
+#[cfg(not(target_os = "windows"))]
+pub fn describe() -> Option<String> {
+    let pending = PENDING.lock().unwrap();
+    let pending = pending.as_ref()?;
+
+    Some(format!(
+        "Offered {:.1} MB of {}",
+        pending.size as f64 / 1_000_000.0,
+        pending.kind
+    ))
+}
+
+/// Accepts or declines an offer, and puts the contents on the clipboard if accepted.
+pub async fn handle(
+    connection: Connection,
+    limits: TransferLimits,
+    text: TextNormalization,
+    id: u64,
+    kind: String,
+    size: u64,
+) -> Result<()> {
+    if size > limits.auto_accept_size {
+        #[cfg(target_os = "windows")]
+        let accepted = {
+            let prompt_kind = kind.clone();
+            tokio::task::spawn_blocking(move || confirm(&prompt_kind, size)).await?
+        };
+        #[cfg(not(target_os = "windows"))]
+        let accepted = confirm(&kind, size).await;
+
+        if !accepted {
+            log::info!("Declined {} bytes of {}", size, kind);
+            return Ok(());
+        }
+    }
+
+    let (mut send, mut recv) =
+        crate::client::open_upstream_bi(&connection, UpstreamKind::ClipboardFetch).await?;
+    let fetch = ClipboardFetch { id }.to_vec();
+    send.write_u32(u32::try_from(fetch.len())?).await?;
+    send.write_all(&fetch).await?;
+    send.finish().await?;
+
+    let len = match recv.read_u32().await {
+        Ok(len) => len as u64,
+        // The server had newer contents by the time we asked
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            log::info!("Offered clipboard was replaced before it was fetched");
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if len > size + PACKET_OVERHEAD {
+        anyhow::bail!("Offered {} bytes, but {} arrived", size, len);
+    }
+
+    let mut packet = vec![0u8; len as usize];
+    let mut throttle = Throttle::new(limits.bandwidth_limit);
+    let transfer = crate::progress::start(format!("Receiving {}", kind), len);
+    let mut done = 0;
+    for chunk in packet.chunks_mut(CHUNK_LEN) {
+        tokio::time::sleep(throttle.delay(chunk.len())).await;
+        tokio::select! {
+            read = recv.read_exact(chunk) => read?,
+            _ = transfer.cancelled() => {
+                let _ = recv.stop(0u32.into());
+                log::info!("Cancelled receiving {}", kind);
+                return Ok(());
+            }
+        }
+
+        done += chunk.len() as u64;
+        transfer.set_done(done);
+    }
+    drop(transfer);
+
+    log::info!("Received {} bytes of {}", size, kind);
+
+    tokio::task::spawn_blocking(move || {
+        let packet = PacketRef::from_slice(&packet).context("Decode offered clipboard")?;
+        let mut clipboard = CLIPBOARD.lock().unwrap();
+        if clipboard.is_none() {
+            *clipboard = Clipboard::new()
+                .map_err(|e| log::error!("Failed to open clipboard: {}", e))
+                .ok();
+        }
+        crate::client::set_clipboard(&mut clipboard, &text, packet.event);
+        anyhow::Ok(())
+    })
+    .await??;
+
+    Ok(())
+}
@@ -0,0 +1,175 @@
+//! Keeping modifiers and clicks in the order the server sent them.
+//!
+//! Keyboard and mouse events arrive on streams of their own, so a click may overtake the
+//! Ctrl press that went before it, or the release after it. A modifier, click or scroll
+//! waits until the other stream has handled something stamped later, for a few
+//! milliseconds at most. One waiting behind an earlier event of the other stream still
+//! lets that go first, and one ahead of it goes at once.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use keycode::{KeyMap, KeyMapping};
+use rkvm_protocol::{Event, EventKind};
+use tokio::{sync::Notify, time::Instant};
+
+/// Latest stamp handled on the mouse and keyboard streams
+static HANDLED: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+/// Stamp of the event each stream holds back, 0 if none
+static WAITING: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+static CHANGED: Notify = Notify::const_new();
+
+fn index(kind: EventKind) -> Option<usize> {
+    match kind {
+        EventKind::Mouse => Some(0),
+        EventKind::Keyboard => Some(1),
+        EventKind::Misc => None,
+    }
+}
+
+/// Whether `event` means something different when it overtakes one of the other stream.
+fn is_correlated(event: &Event) -> bool {
+    match *event {
+        // Lets go of the buttons, so it goes after the last of them
+        Event::MouseButton { .. } | Event::MouseWheel { .. } | Event::GamepadRemoved { .. } => true,
+        Event::Keyboard { key, extended, .. } => {
+            let code = rkvm_protocol::win_scan_code(key, extended);
+            KeyMap::from_key_mapping(KeyMapping::Win(code)).is_ok_and(|k| k.modifier.is_some())
+        }
+        _ => false,
+    }
+}
+
+/// Held while an event is handled, so events of the other stream stamped later wait for it.
+pub struct Turn(Option<usize>);
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        if let Some(i) = self.0 {
+            WAITING[i].store(0, Ordering::SeqCst);
+            CHANGED.notify_waiters();
+        }
+    }
+}
+
+/// Waits for the turn of `event`, stamped with `time`, if it is correlated.
+pub async fn wait_turn(event: &Event, time: u64, window: Duration) -> Turn {
+    let (ours, theirs) = match index(event.kind()) {
+        Some(i) if time != 0 && !window.is_zero() && is_correlated(event) => (i, 1 - i),
+        _ => return Turn(None),
+    };
+
+    WAITING[ours].store(time, Ordering::SeqCst);
+    let deadline = Instant::now() + window;
+    // Behind an earlier event of the other stream, which goes by its own deadline
+    let limit = deadline + window;
+    loop {
+        let changed = CHANGED.notified();
+        if HANDLED[theirs].load(Ordering::SeqCst) >= time {
+            break;
+        }
+
+        // The other stream is past us already, as it is in order
+        let waiting = WAITING[theirs].load(Ordering::SeqCst);
+        if waiting > time {
+            break;
+        }
+        let until = if waiting != 0 { limit } else { deadline };
+        if Instant::now() >= until {
+            break;
+        }
+        let _ = tokio::time::timeout_at(until, changed).await;
+    }
+    Turn(Some(ours))
+}
+
+/// Records that an event stamped with `time` on the stream for `kind` was handled.
+pub fn handled(kind: EventKind, time: u64) {
+    if let Some(i) = index(kind) {
+        HANDLED[i].fetch_max(time, Ordering::SeqCst);
+        CHANGED.notify_waiters();
+    }
+}
+
+/// Forgets the last connection's stamps, which the next one's don't follow on from.
+pub fn reset() {
+    for stamp in HANDLED.iter().chain(&WAITING) {
+        stamp.store(0, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rkvm_protocol::MouseButton;
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    /// The stamps are global, so tests take turns
+    static LOCK: Mutex<()> = Mutex::const_new(());
+
+    const WINDOW: Duration = Duration::from_millis(200);
+
+    fn click() -> Event {
+        Event::MouseButton {
+            button: MouseButton::Left,
+            pressed: true,
+        }
+    }
+
+    fn ctrl() -> Event {
+        Event::Keyboard {
+            key: 0x1d,
+            pressed: true,
+            extended: false,
+        }
+    }
+
+    async fn timed(event: Event, time: u64) -> Duration {
+        let start = Instant::now();
+        let _turn = wait_turn(&event, time, WINDOW).await;
+        start.elapsed()
+    }
+
+    #[tokio::test]
+    async fn click_waits_for_the_other_stream() {
+        let _lock = LOCK.lock().await;
+        reset();
+
+        assert!(timed(click(), 10).await >= WINDOW);
+
+        let waiting = tokio::spawn(timed(click(), 20));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        handled(EventKind::Keyboard, 21);
+        assert!(waiting.await.unwrap() < WINDOW);
+    }
+
+    #[tokio::test]
+    async fn later_stamp_on_the_other_stream_lets_it_go() {
+        let _lock = LOCK.lock().await;
+        reset();
+
+        // Ctrl, stamped later, waits for the click before it to be handled
+        let ctrl = tokio::spawn(timed(ctrl(), 20));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(timed(click(), 10).await < WINDOW / 2);
+        handled(EventKind::Mouse, 10);
+        // Motion after the click
+        handled(EventKind::Mouse, 25);
+        assert!(ctrl.await.unwrap() < WINDOW);
+    }
+
+    #[tokio::test]
+    async fn motion_and_unstamped_events_go_at_once() {
+        let _lock = LOCK.lock().await;
+        reset();
+
+        let motion = Event::MouseMotion { dx: 1, dy: 1 };
+        assert!(timed(motion, 10).await < WINDOW / 2);
+        assert!(timed(click(), 0).await < WINDOW / 2);
+    }
+}
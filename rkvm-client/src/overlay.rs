@@ -0,0 +1,233 @@
+//! A border around the screens while the server's input goes here, so whoever sits at this
+//! machine knows another one is driving it.
+//!
+//! On Windows it is a layered window over the whole desktop that lets clicks through. It
+//! also flashes when the server asks for attention.
+
+#[cfg(target_os = "windows")]
+mod window {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, OnceLock,
+        },
+        time::Duration,
+    };
+
+    use anyhow::{Context, Result};
+    use windows::{
+        core::w,
+        Win32::{
+            Foundation::{COLORREF, HWND, LPARAM, LRESULT, RECT, WPARAM},
+            Graphics::Gdi::{
+                BeginPaint, CreateSolidBrush, DeleteObject, EndPaint, FillRect, GetStockObject,
+                BLACK_BRUSH, HBRUSH, HGDIOBJ, PAINTSTRUCT,
+            },
+            System::LibraryLoader::GetModuleHandleW,
+            UI::WindowsAndMessaging::{
+                CreateWindowExW, DefWindowProcW, DispatchMessageW, GetClientRect, GetMessageW,
+                GetSystemMetrics, RegisterClassW, SetLayeredWindowAttributes, SetWindowPos,
+                ShowWindowAsync, TranslateMessage, HMENU, HWND_TOPMOST, LWA_ALPHA, LWA_COLORKEY,
+                MSG, SM_CXVIRTUALSCREEN, SM_CYVIRTUALSCREEN, SM_XVIRTUALSCREEN, SM_YVIRTUALSCREEN,
+                SWP_NOACTIVATE, SW_HIDE, SW_SHOWNOACTIVATE, WM_DISPLAYCHANGE, WM_PAINT, WNDCLASSW,
+                WS_EX_LAYERED, WS_EX_NOACTIVATE, WS_EX_TOOLWINDOW, WS_EX_TOPMOST,
+                WS_EX_TRANSPARENT, WS_POPUP,
+            },
+        },
+    };
+
+    /// Width of the border, in pixels
+    const BORDER: i32 = 6;
+    /// Orange, as `0x00bbggrr`
+    const COLOR: COLORREF = COLORREF(0x0000_80ff);
+    const ALPHA: u8 = 200;
+
+    /// Times the border flashes
+    const FLASHES: usize = 4;
+    const FLASH_INTERVAL: Duration = Duration::from_millis(250);
+
+    /// The overlay window, or 0 if it couldn't be created
+    static WINDOW: OnceLock<isize> = OnceLock::new();
+
+    /// Whether the overlay is shown, apart from flashes
+    static SHOWN: AtomicBool = AtomicBool::new(false);
+
+    pub fn show(visible: bool) {
+        SHOWN.store(visible, Ordering::Relaxed);
+        set_visible(visible);
+    }
+
+    pub fn flash() {
+        std::thread::spawn(|| {
+            for _ in 0..FLASHES {
+                set_visible(!SHOWN.load(Ordering::Relaxed));
+                std::thread::sleep(FLASH_INTERVAL);
+                set_visible(SHOWN.load(Ordering::Relaxed));
+                std::thread::sleep(FLASH_INTERVAL);
+            }
+        });
+    }
+
+    fn set_visible(visible: bool) {
+        // Only created once it is first shown
+        let hwnd = match (visible, WINDOW.get()) {
+            (true, _) => HWND(*WINDOW.get_or_init(spawn)),
+            (false, Some(&hwnd)) => HWND(hwnd),
+            (false, None) => return,
+        };
+        if hwnd.0 == 0 {
+            return;
+        }
+
+        let command = if visible { SW_SHOWNOACTIVATE } else { SW_HIDE };
+        unsafe { ShowWindowAsync(hwnd, command) };
+    }
+
+    /// Creates the window on a thread of its own, which keeps it painted.
+    fn spawn() -> isize {
+        let (tx, rx) = mpsc::channel();
+
+        std::thread::spawn(move || unsafe {
+            let hwnd = match create() {
+                Ok(hwnd) => hwnd,
+                Err(e) => {
+                    log::warn!("Failed to create control overlay: {:#}", e);
+                    let _ = tx.send(0);
+                    return;
+                }
+            };
+            let _ = tx.send(hwnd.0);
+
+            let mut msg = MSG::default();
+            while GetMessageW(&mut msg, HWND(0), 0, 0).as_bool() {
+                TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+        });
+
+        rx.recv().unwrap_or(0)
+    }
+
+    unsafe fn create() -> Result<HWND> {
+        let instance = GetModuleHandleW(None)?;
+        let class = WNDCLASSW {
+            lpfnWndProc: Some(window_proc),
+            hInstance: instance,
+            lpszClassName: w!("rkvm-overlay"),
+            ..Default::default()
+        };
+        RegisterClassW(&class);
+
+        let hwnd = CreateWindowExW(
+            WS_EX_LAYERED | WS_EX_TRANSPARENT | WS_EX_TOPMOST | WS_EX_TOOLWINDOW | WS_EX_NOACTIVATE,
+            w!("rkvm-overlay"),
+            w!("RKVM Client Overlay"),
+            WS_POPUP,
+            0,
+            0,
+            0,
+            0,
+            HWND(0),
+            HMENU(0),
+            instance,
+            None,
+        );
+        if hwnd.0 == 0 {
+            return Err(windows::core::Error::from_win32()).context("Create overlay window");
+        }
+
+        // Everything black is keyed out, leaving only the border
+        SetLayeredWindowAttributes(hwnd, COLORREF(0), ALPHA, LWA_COLORKEY | LWA_ALPHA)
+            .ok()
+            .context("Make overlay translucent")?;
+        cover_desktop(hwnd);
+
+        Ok(hwnd)
+    }
+
+    unsafe fn cover_desktop(hwnd: HWND) {
+        SetWindowPos(
+            hwnd,
+            HWND_TOPMOST,
+            GetSystemMetrics(SM_XVIRTUALSCREEN),
+            GetSystemMetrics(SM_YVIRTUALSCREEN),
+            GetSystemMetrics(SM_CXVIRTUALSCREEN),
+            GetSystemMetrics(SM_CYVIRTUALSCREEN),
+            SWP_NOACTIVATE,
+        );
+    }
+
+    unsafe fn paint(hwnd: HWND) {
+        let mut paint = PAINTSTRUCT::default();
+        let dc = BeginPaint(hwnd, &mut paint);
+
+        let mut rect = RECT::default();
+        GetClientRect(hwnd, &mut rect);
+        FillRect(dc, &rect, HBRUSH(GetStockObject(BLACK_BRUSH).0));
+
+        let brush = CreateSolidBrush(COLOR);
+        let (left, top, right, bottom) = (rect.left, rect.top, rect.right, rect.bottom);
+        for strip in [
+            RECT {
+                bottom: top + BORDER,
+                ..rect
+            },
+            RECT {
+                top: bottom - BORDER,
+                ..rect
+            },
+            RECT {
+                right: left + BORDER,
+                ..rect
+            },
+            RECT {
+                left: right - BORDER,
+                ..rect
+            },
+        ] {
+            FillRect(dc, &strip, brush);
+        }
+        DeleteObject(HGDIOBJ(brush.0));
+
+        EndPaint(hwnd, &paint);
+    }
+
+    unsafe extern "system" fn window_proc(
+        hwnd: HWND,
+        msg: u32,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        match msg {
+            WM_PAINT => {
+                paint(hwnd);
+                LRESULT(0)
+            }
+            // Monitors came, went or changed resolution
+            WM_DISPLAYCHANGE => {
+                cover_desktop(hwnd);
+                LRESULT(0)
+            }
+            _ => DefWindowProcW(hwnd, msg, wparam, lparam),
+        }
+    }
+}
+
+/// Shows the overlay, or hides it.
+#[cfg(target_os = "windows")]
+pub fn show(visible: bool) {
+    window::show(visible);
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn show(visible: bool) {
+    if visible {
+        log::warn!("The control overlay is only shown on Windows");
+    }
+}
+
+/// Flashes the border a few times, then leaves it as it was.
+#[cfg(target_os = "windows")]
+pub fn flash() {
+    window::flash();
+}
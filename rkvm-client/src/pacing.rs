@@ -0,0 +1,94 @@
+//! Evening out mouse motion that arrives unevenly, when `motion_pacing_hz` is set.
+//!
+//! Motion is collected and a share of it moved on every tick, so jitter on the network
+//! makes the cursor glide instead of stutter and then jump.
+
+use std::{sync::Mutex, time::Duration};
+
+use enigo::Enigo;
+use quinn::Connection;
+use tokio::{sync::watch, time::MissedTickBehavior};
+
+use crate::Config;
+
+/// Collected motion is spread over about this many ticks.
+const SPREAD_TICKS: f64 = 3.0;
+
+/// How often motion left over from turning pacing off is checked for.
+const IDLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shortest tick, however high `motion_pacing_hz` is set.
+const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Motion not moved yet
+static BACKLOG: Mutex<(i32, i32)> = Mutex::new((0, 0));
+
+/// Whether motion should go through [`add`] instead of being moved right away.
+pub fn enabled(config: &Config) -> bool {
+    config.motion_pacing_hz != 0
+}
+
+/// Queues motion to be moved over the next ticks.
+pub fn add(dx: i32, dy: i32) {
+    let mut backlog = BACKLOG.lock().unwrap();
+    backlog.0 = backlog.0.saturating_add(dx);
+    backlog.1 = backlog.1.saturating_add(dy);
+}
+
+/// Motion queued but not moved yet.
+pub fn backlog() -> (i32, i32) {
+    *BACKLOG.lock().unwrap()
+}
+
+/// Drops motion not moved yet, as the cursor was put somewhere else.
+pub fn clear() {
+    *BACKLOG.lock().unwrap() = (0, 0);
+}
+
+/// Part of `backlog` to move in one tick, at least a pixel while any is left.
+fn step(backlog: i32, share: f64) -> i32 {
+    match (backlog as f64 * share) as i32 {
+        0 => backlog.signum(),
+        step => step,
+    }
+}
+
+/// Moves queued motion on every tick, until `connection` closes.
+pub async fn run(connection: Connection, config: watch::Receiver<Config>) {
+    let mut enigo = Enigo::new();
+    let mut hz = 0;
+    let mut interval = tokio::time::interval(IDLE_INTERVAL);
+
+    loop {
+        let wanted = config.borrow().motion_pacing_hz;
+        if wanted != hz {
+            hz = wanted;
+            let period = match hz {
+                0 => IDLE_INTERVAL,
+                hz => Duration::from_secs_f64(1.0 / hz as f64).max(MIN_INTERVAL),
+            };
+            interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        }
+
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = connection.closed() => break,
+        }
+
+        // Without pacing anything left over goes at once
+        let share = if hz == 0 { 1.0 } else { 1.0 / SPREAD_TICKS };
+        let (dx, dy) = {
+            let mut backlog = BACKLOG.lock().unwrap();
+            let (dx, dy) = (step(backlog.0, share), step(backlog.1, share));
+            backlog.0 -= dx;
+            backlog.1 -= dy;
+            (dx, dy)
+        };
+        if (dx, dy) != (0, 0) {
+            crate::client::move_mouse_relative(&mut enigo, dx, dy);
+        }
+    }
+
+    clear();
+}
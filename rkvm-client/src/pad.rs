@@ -0,0 +1,117 @@
+//! Tablet pad rings and strips from the server, turned into scrolling or zooming.
+//!
+//! The server sends where the finger is, so each ring and strip remembers its last position
+//! and turns the distance moved since into wheel notches. Pad buttons press keys, which
+//! `handle_stream` types like any other.
+
+use enigo::Enigo;
+use serde::Deserialize;
+
+use crate::client::{inject_key, mouse_scroll};
+
+/// Degrees a ring turns for one notch.
+const RING_NOTCH: f64 = 15.0;
+
+/// Fraction of a strip to slide along for one notch.
+const STRIP_NOTCH: f64 = 0.05;
+
+/// Windows scan code of the left Ctrl
+const CONTROL: u16 = 0x1d;
+
+/// What a ring or strip does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PadAction {
+    Scroll,
+    HorizontalScroll,
+    /// Ctrl and the wheel
+    Zoom,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    Ring(u32),
+    Strip(u32),
+}
+
+/// Where each ring and strip was last touched, and the fraction of a notch left over.
+#[derive(Default)]
+pub struct Pad {
+    touched: Vec<(Control, f64, f64)>,
+}
+
+impl Pad {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Notches `ring` turned clockwise since the last event, negative for counterclockwise.
+    pub fn ring(&mut self, ring: u32, position: Option<f64>) -> i32 {
+        self.moved(Control::Ring(ring), position, |last, position| {
+            // Positions go counterclockwise, and wrap around at the top
+            let delta = (last - position).rem_euclid(360.0);
+            let delta = if delta > 180.0 { delta - 360.0 } else { delta };
+            delta / RING_NOTCH
+        })
+    }
+
+    /// Notches `strip` was slid down or right since the last event, negative for up or left.
+    pub fn strip(&mut self, strip: u32, position: Option<f64>) -> i32 {
+        self.moved(Control::Strip(strip), position, |last, position| {
+            (position - last) / STRIP_NOTCH
+        })
+    }
+
+    fn moved(
+        &mut self,
+        control: Control,
+        position: Option<f64>,
+        notches: impl FnOnce(f64, f64) -> f64,
+    ) -> i32 {
+        let index = self.touched.iter().position(|(c, _, _)| *c == control);
+        let position = match (position, index) {
+            (Some(position), _) => position,
+            // The finger lifted, so the next touch starts over
+            (None, Some(index)) => {
+                self.touched.remove(index);
+                return 0;
+            }
+            (None, None) => return 0,
+        };
+
+        let index = match index {
+            Some(index) => index,
+            // The first touch only says where the finger is
+            None => {
+                self.touched.push((control, position, 0.0));
+                return 0;
+            }
+        };
+
+        let (_, last, remainder) = &mut self.touched[index];
+        let total = *remainder + notches(*last, position);
+        *last = position;
+        *remainder = total.fract();
+        total.trunc() as i32
+    }
+}
+
+/// Scrolls or zooms by `notches`, positive ones down or in.
+pub fn apply(enigo: &mut Enigo, action: PadAction, notches: i32) {
+    if notches == 0 {
+        return;
+    }
+
+    match action {
+        PadAction::Scroll => mouse_scroll(enigo, 0, notches),
+        PadAction::HorizontalScroll => mouse_scroll(enigo, notches, 0),
+        PadAction::Zoom => {
+            inject_key(enigo, CONTROL, true);
+            // Wheel up zooms in
+            mouse_scroll(enigo, 0, -notches);
+            inject_key(enigo, CONTROL, false);
+        }
+        PadAction::None => {}
+    }
+}
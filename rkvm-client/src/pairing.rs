@@ -0,0 +1,39 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use rkvm_protocol::Pairing;
+
+use crate::secrets;
+
+/// Adds the server described by a pairing code to the config file, pinning its fingerprint.
+///
+/// Other settings in the file are preserved; the file is created if it doesn't exist yet.
+pub fn apply(code: &str, config_path: &Path) -> Result<()> {
+    let pairing = Pairing::from_uri(code).context("Invalid pairing code")?;
+
+    let mut doc = match std::fs::read_to_string(config_path) {
+        Ok(s) => s.parse::<toml_edit::Document>()?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => toml_edit::Document::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    doc["address"] = toml_edit::value(pairing.address.as_str());
+    doc["port"] = toml_edit::value(i64::from(pairing.port));
+    doc["fingerprint"] = toml_edit::value(rkvm_protocol::format_fingerprint(&pairing.fingerprint));
+    if let Some(psk) = &pairing.psk {
+        doc["psk"] = toml_edit::value(psk.as_str());
+    }
+
+    std::fs::write(config_path, doc.to_string())?;
+    log::info!(
+        "Added server {}:{} to {:?}",
+        pairing.address,
+        pairing.port,
+        config_path
+    );
+
+    // Move the freshly written secrets into the keyring right away
+    secrets::store(config_path)?;
+
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! Noticing programs that lock the pointer, like games and virtual machines.
+//!
+//! They read relative motion only, so warping the cursor or holding it at the screen edges
+//! breaks them. While one has the pointer we ask the server for relative motion only, and
+//! ignore whatever would move the cursor to a position.
+//!
+//! Only Windows and X11 tell when the pointer is locked. Under Wayland and on macOS it is
+//! never noticed.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use anyhow::Result;
+use rkvm_protocol::PointerMode;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+static RELATIVE_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Whether a program has the pointer locked, as of the last check.
+pub fn relative_only() -> bool {
+    RELATIVE_ONLY.load(Ordering::Relaxed)
+}
+
+/// Locking hides the cursor and confines it to less than the desktop, usually a window.
+#[cfg(target_os = "windows")]
+fn locked() -> bool {
+    use windows::Win32::{
+        Foundation::RECT,
+        UI::WindowsAndMessaging::{
+            GetClipCursor, GetCursorInfo, GetSystemMetrics, CURSORINFO, CURSOR_SHOWING,
+            SM_CXVIRTUALSCREEN, SM_CYVIRTUALSCREEN, SM_XVIRTUALSCREEN, SM_YVIRTUALSCREEN,
+        },
+    };
+
+    unsafe {
+        let mut info = CURSORINFO {
+            cbSize: std::mem::size_of::<CURSORINFO>() as u32,
+            ..Default::default()
+        };
+        if !GetCursorInfo(&mut info).as_bool() || info.flags.0 & CURSOR_SHOWING.0 != 0 {
+            return false;
+        }
+
+        let mut clip = RECT::default();
+        if !GetClipCursor(&mut clip).as_bool() {
+            return false;
+        }
+
+        let x = GetSystemMetrics(SM_XVIRTUALSCREEN);
+        let y = GetSystemMetrics(SM_YVIRTUALSCREEN);
+        let desktop = RECT {
+            left: x,
+            top: y,
+            right: x + GetSystemMetrics(SM_CXVIRTUALSCREEN),
+            bottom: y + GetSystemMetrics(SM_CYVIRTUALSCREEN),
+        };
+        clip != desktop
+    }
+}
+
+/// Locking hides the cursor and grabs the pointer, which X11 only tells when asked to grab
+/// it ourselves. So that is only tried while the cursor is hidden, and let go of at once.
+#[cfg(target_os = "linux")]
+fn locked() -> bool {
+    use std::sync::OnceLock;
+
+    use x11rb::{
+        connection::Connection,
+        protocol::{
+            xfixes::ConnectionExt as _,
+            xproto::{ConnectionExt as _, GrabMode, GrabStatus},
+        },
+        rust_connection::RustConnection,
+        CURRENT_TIME, NONE,
+    };
+
+    static X11: OnceLock<Option<(RustConnection, usize)>> = OnceLock::new();
+    let (conn, screen) = match X11.get_or_init(|| {
+        std::env::var_os("DISPLAY")?;
+        let (conn, screen) = x11rb::connect(None).ok()?;
+        conn.xfixes_query_version(5, 0).ok()?.reply().ok()?;
+        Some((conn, screen))
+    }) {
+        Some((conn, screen)) => (conn, *screen),
+        None => return false,
+    };
+
+    let check = || -> Result<bool> {
+        let cursor = conn.xfixes_get_cursor_image()?.reply()?;
+        if cursor.cursor_image.iter().any(|pixel| pixel >> 24 != 0) {
+            return Ok(false);
+        }
+
+        let root = conn.setup().roots[screen].root;
+        let grab = conn
+            .grab_pointer(
+                false,
+                root,
+                0u16,
+                GrabMode::ASYNC,
+                GrabMode::ASYNC,
+                NONE,
+                NONE,
+                CURRENT_TIME,
+            )?
+            .reply()?;
+        if grab.status == GrabStatus::SUCCESS {
+            conn.ungrab_pointer(CURRENT_TIME)?;
+            conn.flush()?;
+        }
+        Ok(grab.status == GrabStatus::ALREADY_GRABBED)
+    };
+    check().unwrap_or(false)
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+fn locked() -> bool {
+    false
+}
+
+/// Tells the server whenever the pointer gets locked or released, until the stream fails.
+pub async fn report(mut stream: crate::client::Upstream) -> Result<()> {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    RELATIVE_ONLY.store(false, Ordering::Relaxed);
+
+    loop {
+        interval.tick().await;
+
+        let locked = locked();
+        if locked == relative_only() {
+            continue;
+        }
+
+        if locked {
+            log::info!("Pointer locked, asking for relative motion only");
+        } else {
+            log::info!("Pointer released");
+        }
+        let mode = PointerMode {
+            relative_only: locked,
+        }
+        .to_vec();
+        stream.write(&mode).await?;
+
+        RELATIVE_ONLY.store(locked, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,30 @@
+//! Putting this machine to sleep when the server does.
+
+/// Suspends this machine, logging why if it can't.
+#[cfg(target_os = "windows")]
+pub fn suspend() {
+    use windows::Win32::{Foundation::BOOLEAN, System::Power::SetSuspendState};
+
+    log::info!("Suspending with the server");
+    // Sleep rather than hibernate, and let wake events through
+    let no = BOOLEAN::from(false);
+    if !unsafe { SetSuspendState(no, no, no) }.as_bool() {
+        log::error!(
+            "Failed to suspend: {}",
+            windows::core::Error::from_win32().message()
+        );
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn suspend() {
+    log::info!("Suspending with the server");
+    let status = std::process::Command::new("systemctl")
+        .arg("suspend")
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => log::error!("Failed to suspend: systemctl exited with {}", status),
+        Err(e) => log::error!("Failed to suspend: {}", e),
+    }
+}
@@ -0,0 +1,31 @@
+//! Raising the priority of the threads injecting input, so it keeps up while this machine
+//! is busy.
+//!
+//! Input is injected from the runtime's threads, so all of them are raised. Only Windows
+//! lets a user raise a thread without further privileges.
+
+use tokio::runtime::{Builder, Runtime};
+
+/// The runtime, with its threads raised if `raise` is set.
+pub fn runtime(raise: bool) -> std::io::Result<Runtime> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all();
+    if raise {
+        builder.on_thread_start(raise_current);
+    }
+    builder.build()
+}
+
+#[cfg(target_os = "windows")]
+fn raise_current() {
+    use windows::Win32::System::Threading::{
+        GetCurrentThread, SetThreadPriority, THREAD_PRIORITY_HIGHEST,
+    };
+
+    if !unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_HIGHEST) }.as_bool() {
+        log::warn!("Failed to raise the priority of an input thread");
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn raise_current() {}
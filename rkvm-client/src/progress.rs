@@ -0,0 +1,113 @@
+//! Progress of large clipboard and file transfers, shown in the tray, which can cancel them.
+//!
+//! Only the transfer started last is shown. Each one ends when its [`Transfer`] is
+//! dropped, so transfers that fail are cleared as well.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+struct Progress {
+    /// What is moving and which way, like "Receiving image/png"
+    what: String,
+    done: u64,
+    total: u64,
+    cancel: Arc<Notify>,
+}
+
+static CURRENT: Mutex<Option<Progress>> = Mutex::new(None);
+
+/// A transfer shown in the tray until dropped.
+pub struct Transfer {
+    cancel: Arc<Notify>,
+}
+
+fn percent(done: u64, total: u64) -> u64 {
+    (done * 100).checked_div(total).unwrap_or(100)
+}
+
+/// Shows a transfer of `total` bytes in the tray.
+pub fn start(what: String, total: u64) -> Transfer {
+    let cancel = Arc::new(Notify::new());
+    *CURRENT.lock().unwrap() = Some(Progress {
+        what,
+        done: 0,
+        total,
+        cancel: cancel.clone(),
+    });
+    crate::target::refresh_tray();
+
+    Transfer { cancel }
+}
+
+impl Transfer {
+    fn is_current(&self, progress: &Option<Progress>) -> bool {
+        progress
+            .as_ref()
+            .is_some_and(|p| Arc::ptr_eq(&p.cancel, &self.cancel))
+    }
+
+    /// Notes that `done` bytes made it so far.
+    pub fn set_done(&self, done: u64) {
+        let mut current = CURRENT.lock().unwrap();
+        if !self.is_current(&current) {
+            return;
+        }
+
+        let progress = current.as_mut().unwrap();
+        // Only whole percents are shown, so the tray doesn't wake up for every chunk
+        let changed = percent(done, progress.total) != percent(progress.done, progress.total);
+        progress.done = done;
+        drop(current);
+
+        if changed {
+            crate::target::refresh_tray();
+        }
+    }
+
+    /// Completes once the transfer is cancelled from the tray.
+    pub async fn cancelled(&self) {
+        self.cancel.notified().await
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        let mut current = CURRENT.lock().unwrap();
+        if self.is_current(&current) {
+            *current = None;
+            drop(current);
+            crate::target::refresh_tray();
+        }
+    }
+}
+
+/// Whether a transfer is shown, and can be cancelled.
+pub fn is_running() -> bool {
+    CURRENT.lock().unwrap().is_some()
+}
+
+/// Cancels the transfer shown in the tray.
+pub fn cancel() {
+    match &*CURRENT.lock().unwrap() {
+        Some(progress) => {
+            log::info!("Cancelling: {}", progress.what);
+            // Keeps a permit if nothing is waiting right now, so the next check sees it
+            progress.cancel.notify_one();
+        }
+        None => log::info!("No transfer to cancel"),
+    }
+}
+
+/// Tray tooltip line for the transfer shown, if any.
+pub fn describe() -> Option<String> {
+    let current = CURRENT.lock().unwrap();
+    let progress = current.as_ref()?;
+
+    Some(format!(
+        "{}: {}% of {:.1} MB",
+        progress.what,
+        percent(progress.done, progress.total),
+        progress.total as f64 / 1_000_000.0
+    ))
+}
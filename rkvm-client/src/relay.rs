@@ -0,0 +1,106 @@
+//! Relaying warnings and errors to the server, which writes them to its own log, for
+//! clients nobody watches the output of.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::Result;
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use rkvm_protocol::{LogLevel, LogRecord};
+use simple_logger::SimpleLogger;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+/// Records kept while not connected, the oldest dropped first
+const MAX_QUEUED: usize = 256;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static QUEUE: Mutex<VecDeque<LogRecord>> = Mutex::new(VecDeque::new());
+
+/// Wakes the stream of the current connection for new records
+static TX: Mutex<Option<UnboundedSender<()>>> = Mutex::new(None);
+
+/// Logs through `logger` up to `level`, keeping warnings and errors for the server, and
+/// the last error for `status`.
+pub fn init(logger: SimpleLogger, level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(Relay { logger }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Turns relaying on or off, as the `relay_logs` option says.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        QUEUE.lock().unwrap().clear();
+    }
+}
+
+struct Relay {
+    logger: SimpleLogger,
+}
+
+impl Log for Relay {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.logger.log(record);
+
+        if record.level() == Level::Error && self.enabled(record.metadata()) {
+            crate::status::note_error(record.args().to_string());
+        }
+        if !ENABLED.load(Ordering::Relaxed) || !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = match record.level() {
+            Level::Error => LogLevel::Error,
+            Level::Warn => LogLevel::Warn,
+            _ => return,
+        };
+
+        let mut queue = QUEUE.lock().unwrap();
+        if queue.len() == MAX_QUEUED {
+            queue.pop_front();
+        }
+        queue.push_back(LogRecord {
+            level,
+            target: record.target().to_owned(),
+            message: record.args().to_string(),
+        });
+        drop(queue);
+
+        if let Some(tx) = &*TX.lock().unwrap() {
+            let _ = tx.send(());
+        }
+    }
+
+    fn flush(&self) {
+        self.logger.flush();
+    }
+}
+
+/// Sends records to the server, starting with those kept since the last connection, until
+/// the stream fails.
+pub async fn report(mut stream: crate::client::Upstream) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    *TX.lock().unwrap() = Some(tx);
+
+    loop {
+        let records = std::mem::take(&mut *QUEUE.lock().unwrap());
+        for record in records {
+            let record = record.to_vec();
+            stream.write(&record).await?;
+        }
+
+        if rx.recv().await.is_none() {
+            return Ok(());
+        }
+    }
+}
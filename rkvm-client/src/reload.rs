@@ -0,0 +1,186 @@
+//! Applying edits to the config file without restarting.
+//!
+//! Changing how to reach the server reconnects. Everything else, like mouse speed or the
+//! clipboard toggle, applies from the next event on.
+
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, OnceLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::watch;
+
+use crate::{identity, offer::TransferLimits, secrets, Config};
+
+/// Editors write a file in several steps, so wait for them to finish.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// The config file and where to publish it, set once at startup.
+static CONFIG: OnceLock<(PathBuf, watch::Sender<Config>)> = OnceLock::new();
+
+/// Set in the service's agent, which runs as SYSTEM with a config file that isn't its own.
+static AGENT: AtomicBool = AtomicBool::new(false);
+
+/// Loads as the service's agent from now on, leaving the config file and keyring alone.
+#[cfg(target_os = "windows")]
+pub fn set_agent() {
+    AGENT.store(true, Ordering::Relaxed);
+}
+
+/// Reads the config file, filling in secrets from the credential store.
+pub fn load(config_path: &Path) -> Result<Config> {
+    let config_string = std::fs::read_to_string(config_path)?;
+    let mut config: Config = toml::from_str(&config_string)?;
+    if AGENT.load(Ordering::Relaxed) {
+        #[cfg(target_os = "windows")]
+        secrets::resolve_for_service(&mut config)?;
+    } else {
+        secrets::resolve(&mut config)?;
+        identity::ensure_id(&mut config, config_path)?;
+    }
+
+    // Caught here, so a bad edit leaves the running connection alone
+    config
+        .address
+        .parse::<IpAddr>()
+        .context("Invalid server address")?;
+    if let Some(fingerprint) = &config.fingerprint {
+        rkvm_protocol::parse_fingerprint(fingerprint).context("Invalid server fingerprint")?;
+    }
+    rkvm_protocol::Timeouts::from_secs(config.keep_alive_secs, config.idle_timeout_secs)
+        .map_err(anyhow::Error::msg)?;
+
+    Ok(config)
+}
+
+/// Publishes `config` as loaded from `config_path`, and returns a handle that sees reloads.
+pub fn init(config_path: PathBuf, config: Config) -> watch::Receiver<Config> {
+    let (tx, rx) = watch::channel(config);
+    if CONFIG.set((config_path, tx)).is_err() {
+        panic!("Config initialized twice");
+    }
+
+    rx
+}
+
+/// Reads the config file again and applies it if it changed.
+pub fn reload() {
+    let (config_path, tx) = match CONFIG.get() {
+        Some(c) => c,
+        None => return,
+    };
+
+    let config = match load(config_path) {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("Failed to reload config: {}", e);
+            return;
+        }
+    };
+
+    let limits = TransferLimits::new(&config);
+    let changed = tx.send_if_modified(|current| {
+        if *current == config {
+            return false;
+        }
+
+        *current = config;
+        true
+    });
+
+    if changed {
+        crate::files::set_limits(limits);
+        log::info!("Reloaded config");
+    }
+}
+
+/// Reloads whenever the config file changes on disk.
+pub fn watch() -> Result<()> {
+    let config_path = match CONFIG.get() {
+        Some((p, _)) => p,
+        None => anyhow::bail!("Config not initialized"),
+    };
+    let file_name = config_path.file_name().context("No config file name")?;
+    let dir = match config_path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    // Watching the directory keeps working when editors replace the file
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+    let file_name = file_name.to_owned();
+    std::thread::spawn(move || {
+        let _watcher = watcher;
+
+        while let Ok(event) = rx.recv() {
+            let ours = match event {
+                Ok(event) => event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == Some(file_name.as_os_str())),
+                Err(e) => {
+                    log::warn!("Error watching config: {}", e);
+                    false
+                }
+            };
+            if !ours {
+                continue;
+            }
+
+            let closed = settle(&rx);
+            reload();
+            if closed {
+                break;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Waits out the rest of a burst of events. Returns whether the watcher went away.
+fn settle(rx: &mpsc::Receiver<notify::Result<notify::Event>>) -> bool {
+    loop {
+        match rx.recv_timeout(DEBOUNCE) {
+            Ok(_) => {}
+            Err(mpsc::RecvTimeoutError::Timeout) => return false,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return true,
+        }
+    }
+}
+
+/// Whether going from `old` to `new` needs a new connection.
+pub fn needs_reconnect(old: &Config, new: &Config) -> bool {
+    old.address != new.address
+        || old.port != new.port
+        || old.psk != new.psk
+        || old.fingerprint != new.fingerprint
+        || old.reverse_control != new.reverse_control
+        || old.keep_alive_secs != new.keep_alive_secs
+        || old.idle_timeout_secs != new.idle_timeout_secs
+        || old.single_stream != new.single_stream
+}
+
+/// Resolves once the config changes in a way that `current`'s connection can't follow.
+pub async fn reconnect_needed(rx: &mut watch::Receiver<Config>, current: &Config) {
+    loop {
+        if rx.changed().await.is_err() {
+            // Nothing can change anymore
+            std::future::pending::<()>().await;
+        }
+
+        if needs_reconnect(current, &rx.borrow_and_update()) {
+            return;
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! A commented config file to start from, for `generate-config`.
+
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    default_auto_accept_size, default_idle_timeout_secs, default_keep_alive_secs,
+    default_order_window_ms, default_sensitivity, default_stale_motion_ms,
+};
+
+/// A config file with every option at its default, for the server at `address`.
+pub fn sample(address: Option<&str>, port: u16) -> String {
+    let address = match address {
+        Some(address) => format!("address = {:?}", address),
+        None => "address = \"192.168.1.10\"".to_owned(),
+    };
+
+    let quality = crate::target::QualityConfig::default();
+
+    format!(
+        r#"# IP address of the server
+{address}
+
+# Port the server listens on
+port = {port}
+
+# Name the server shows for this machine, the hostname if unset
+# name = "laptop"
+
+# Pre-shared key configured on the server, if any
+# psk = "correct horse battery staple"
+
+# SHA-256 fingerprint of the server certificate, as `rkvm-server cert show-fingerprint`
+# prints it. Without one any certificate is accepted, which is open to MITM attacks
+# fingerprint = "AB:CD:..."
+
+# Look up psk and fingerprint in the system credential store when they aren't in this file.
+# `rkvm-client store-secrets` moves them there
+use_keyring = true
+
+# Tapping Right Ctrl sends this machine's keyboard and mouse to the server
+reverse_control = false
+
+# Most seconds between keep-alives, and before a silent connection to the server is given
+# up on, 0 for never. Keep-alives must come more often. The server's own settings cap them,
+# so longer ones only help if it allows them.
+# Longer keep-alives save battery, shorter timeouts notice a lost server sooner
+keep_alive_secs = {keep_alive_secs}
+idle_timeout_secs = {idle_timeout_secs}
+
+# Clipboard contents up to this many bytes are accepted without asking
+auto_accept_size = {auto_accept_size}
+
+# Cap in bytes per second for clipboard and file transfers, so input stays responsive
+# bandwidth_limit = 10485760
+
+# Multiplies mouse motion from the server
+sensitivity = {sensitivity:?}
+
+# Mouse motion held up on the way for longer than this many milliseconds is dropped
+# instead of replayed once the network recovers. 0 never drops any
+stale_motion_ms = {stale_motion_ms}
+
+# Keyboard and mouse arrive separately, so a click could overtake the Ctrl press before it.
+# Modifiers, clicks and scrolling wait up to this many milliseconds for what the server sent
+# before them. 0 never waits
+order_window_ms = {order_window_ms}
+
+# Take every event on one stream, strictly in the order the server sent it, instead of
+# mouse motion going ahead of keys and the clipboard. A large clipboard then holds up input
+single_stream = false
+
+# Move the cursor this many times a second, like the refresh rate of the display, spreading
+# out motion that arrives unevenly over a few moves. Smoother on a jittery network, at the
+# cost of a few moves of delay. 0 moves it as soon as motion arrives
+motion_pacing_hz = 0
+
+# Put what the server copies on this machine's clipboard
+clipboard = true
+
+# Draw a border around the screens while the server's input goes here, so whoever sits at
+# this machine knows another one is driving it. Only on Windows
+control_overlay = false
+
+# Keep input from the server on one monitor, "primary" or the number Windows Display
+# settings give it, like "2". Numbers only work on Windows
+# pin_monitor = "primary"
+
+# Or on a rectangle of the screens, in pixels, for only part of a monitor within view
+# pin_region = {{ x = 0, y = 0, width = 1920, height = 1080 }}
+
+# DPI scale of the monitors, for the server's scale_motion. Windows tells each monitor's,
+# elsewhere GDK_SCALE or QT_SCALE_FACTOR is used, or 1
+# monitor_scale = 2.0
+
+# Chime when the server asks for the attention of whoever sits here, besides flashing the
+# border around the screens. Windows only
+attention_chime = true
+
+# Suspend when the server asks to, if it is set up to when its lid closes
+suspend_with_server = true
+
+# Switch to the keyboard layout the server types in, and whenever it switches, so keys
+# come out the same as typed there. A layout named in the server's config goes first.
+# Windows only
+follow_server_layout = false
+
+# Send warnings and errors to the server, which writes them to its log tagged with the name
+# of this machine. Handy when nobody sees the output here
+relay_logs = false
+
+# Run the threads injecting input at a higher priority, so it keeps up while this machine
+# is busy. Windows only, read at startup
+input_priority = false
+
+# What the rings and strips of a tablet pad on the server do: "scroll",
+# "horizontal_scroll", "zoom" or "none"
+pad_ring = "scroll"
+pad_strip = "scroll"
+
+# Keys the pad buttons press, by button number
+[pad_buttons]
+# 0 = ["ControlLeft", "UsZ"]
+
+# How text from the server's clipboard is cleaned up. Line endings become "native" ones,
+# CRLF on Windows, or "crlf", "lf", or are left alone with "keep"
+[clipboard_text]
+line_endings = "native"
+# Drop the NUL characters some programs copy along at the end
+strip_trailing_nul = true
+# Drop a byte order mark at the start
+strip_bom = true
+
+# Round trip time in milliseconds and packet loss in percent where the connection to the
+# server shows as degraded in the tray, and where as poor
+[link_quality]
+degraded_rtt_ms = {degraded_rtt_ms}
+degraded_loss_percent = {degraded_loss_percent}
+poor_rtt_ms = {poor_rtt_ms}
+poor_loss_percent = {poor_loss_percent}
+"#,
+        keep_alive_secs = default_keep_alive_secs(),
+        idle_timeout_secs = default_idle_timeout_secs(),
+        auto_accept_size = default_auto_accept_size(),
+        sensitivity = default_sensitivity(),
+        stale_motion_ms = default_stale_motion_ms(),
+        order_window_ms = default_order_window_ms(),
+        degraded_rtt_ms = quality.degraded_rtt_ms,
+        degraded_loss_percent = quality.degraded_loss_percent,
+        poor_rtt_ms = quality.poor_rtt_ms,
+        poor_loss_percent = quality.poor_loss_percent,
+    )
+}
+
+/// Prints the sample config, or writes it to `output` if that doesn't exist yet.
+pub fn generate(address: Option<&str>, port: u16, output: Option<&Path>) -> Result<()> {
+    let config = sample(address, port);
+
+    match output {
+        Some(path) => {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .with_context(|| format!("Create {:?}", path))?;
+            file.write_all(config.as_bytes())?;
+        }
+        None => print!("{}", config),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn sample_has_the_defaults() {
+        let sample: Config = toml::from_str(&sample(Some("10.0.0.2"), 5258)).unwrap();
+        let minimal: Config = toml::from_str("address = \"10.0.0.2\"\nport = 5258").unwrap();
+        assert_eq!(sample, minimal);
+    }
+
+    #[test]
+    fn sample_without_address_parses() {
+        let sample: Config = toml::from_str(&sample(None, 5258)).unwrap();
+        assert_eq!(sample.address, "192.168.1.10");
+    }
+}
@@ -0,0 +1,298 @@
+//! The monitors of this machine, reported to the server so it knows where the cursor can go.
+
+use std::{sync::Mutex, time::Duration};
+
+use anyhow::Result;
+use rkvm_protocol::{Monitor, ScreenLayout};
+use serde::Deserialize;
+use tokio::sync::watch;
+
+use crate::Config;
+
+/// How often to look for display changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Makes Windows report physical pixels instead of scaling them for us. Call before
+/// creating any window.
+#[cfg(target_os = "windows")]
+pub fn init() {
+    use windows::Win32::UI::HiDpi::{
+        SetProcessDpiAwarenessContext, DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2,
+    };
+
+    if !unsafe { SetProcessDpiAwarenessContext(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2) }
+        .as_bool()
+    {
+        log::warn!("Failed to make the client DPI aware, positions may be scaled");
+    }
+}
+
+/// Whether `pin`, "primary" or the number Windows gives a display, picks this monitor.
+fn is_pinned(pin: &str, monitor: &Monitor, device: &str) -> bool {
+    if pin.eq_ignore_ascii_case("primary") {
+        return monitor.primary;
+    }
+
+    // Device names look like \\.\DISPLAY2
+    let display = device.trim_start_matches(r"\\.\");
+    display.eq_ignore_ascii_case(&format!("DISPLAY{}", pin)) || device.eq_ignore_ascii_case(pin)
+}
+
+/// The monitors with their device names.
+#[cfg(target_os = "windows")]
+fn monitors() -> Vec<(Monitor, String)> {
+    use windows::Win32::{
+        Foundation::{BOOL, LPARAM, RECT},
+        Graphics::Gdi::{
+            EnumDisplayMonitors, GetMonitorInfoW, HDC, HMONITOR, MONITORINFO, MONITORINFOEXW,
+        },
+        UI::HiDpi::{GetDpiForMonitor, MDT_EFFECTIVE_DPI},
+    };
+
+    const MONITORINFOF_PRIMARY: u32 = 1;
+
+    unsafe extern "system" fn callback(
+        monitor: HMONITOR,
+        _hdc: HDC,
+        _rect: *mut RECT,
+        monitors: LPARAM,
+    ) -> BOOL {
+        let monitors = &mut *(monitors.0 as *mut Vec<(Monitor, String)>);
+
+        let mut info = MONITORINFOEXW::default();
+        info.monitorInfo.cbSize = std::mem::size_of::<MONITORINFOEXW>() as u32;
+        let info_ptr = &mut info as *mut MONITORINFOEXW as *mut MONITORINFO;
+        if !GetMonitorInfoW(monitor, info_ptr).as_bool() {
+            return true.into();
+        }
+
+        let (mut dpi_x, mut dpi_y) = (96, 96);
+        let _ = GetDpiForMonitor(monitor, MDT_EFFECTIVE_DPI, &mut dpi_x, &mut dpi_y);
+
+        let rect = info.monitorInfo.rcMonitor;
+        let device = &info.szDevice;
+        let len = device.iter().position(|c| *c == 0).unwrap_or(device.len());
+        monitors.push((
+            Monitor {
+                x: rect.left,
+                y: rect.top,
+                width: (rect.right - rect.left) as u32,
+                height: (rect.bottom - rect.top) as u32,
+                scale: dpi_x as f32 / 96.0,
+                primary: info.monitorInfo.dwFlags & MONITORINFOF_PRIMARY != 0,
+            },
+            String::from_utf16_lossy(&device[..len]),
+        ));
+
+        true.into()
+    }
+
+    let mut monitors: Vec<(Monitor, String)> = Vec::new();
+    unsafe {
+        EnumDisplayMonitors(
+            HDC(0),
+            None,
+            Some(callback),
+            LPARAM(&mut monitors as *mut Vec<(Monitor, String)> as isize),
+        );
+    }
+
+    monitors
+}
+
+/// The scale toolkits are told to draw at, as the desktop has no other way to tell.
+#[cfg(not(target_os = "windows"))]
+fn desktop_scale() -> f32 {
+    ["GDK_SCALE", "QT_SCALE_FACTOR"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok()?.trim().parse::<f32>().ok())
+        .find(|scale| *scale > 0.0)
+        .unwrap_or(1.0)
+}
+
+/// Only the main display is known elsewhere.
+#[cfg(not(target_os = "windows"))]
+fn monitors() -> Vec<(Monitor, String)> {
+    use enigo::{Enigo, MouseControllable};
+
+    let (width, height) = Enigo::new().main_display_size();
+    if width <= 0 || height <= 0 {
+        return Vec::new();
+    }
+
+    let monitor = Monitor {
+        x: 0,
+        y: 0,
+        width: width as u32,
+        height: height as u32,
+        scale: desktop_scale(),
+        primary: true,
+    };
+    vec![(monitor, String::new())]
+}
+
+/// Part of the screens to keep input from the server on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl From<&Monitor> for Region {
+    fn from(monitor: &Monitor) -> Self {
+        Self {
+            x: monitor.x,
+            y: monitor.y,
+            width: monitor.width,
+            height: monitor.height,
+        }
+    }
+}
+
+impl Region {
+    /// Last column in it, as far right as fits if it reaches past the desktop.
+    fn right(&self) -> i32 {
+        self.x.saturating_add_unsigned(self.width.saturating_sub(1))
+    }
+
+    /// Last row in it, as far down as fits if it reaches past the desktop.
+    fn bottom(&self) -> i32 {
+        self.y
+            .saturating_add_unsigned(self.height.saturating_sub(1))
+    }
+
+    fn contains(&self, x: i32, y: i32) -> bool {
+        self.width > 0
+            && self.height > 0
+            && (self.x..=self.right()).contains(&x)
+            && (self.y..=self.bottom()).contains(&y)
+    }
+}
+
+/// Where input is pinned to by the config, if anywhere.
+#[derive(Debug, Clone, Default)]
+pub struct Pin {
+    monitor: Option<String>,
+    region: Option<Region>,
+    /// Reported for every monitor instead of what the system tells
+    scale: Option<f32>,
+}
+
+impl Pin {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            monitor: config.pin_monitor.clone(),
+            region: config.pin_region,
+            scale: config.monitor_scale.filter(|scale| *scale > 0.0),
+        }
+    }
+}
+
+/// What input is pinned to, as last reported
+static BOUNDS: Mutex<Option<Region>> = Mutex::new(None);
+
+/// The monitors, or only what `pin` picks of them. The server keeps the cursor on the
+/// monitors it is told of, and motion is held to them here as well.
+pub fn layout(pin: &Pin) -> ScreenLayout {
+    let mut monitors = monitors();
+    if let Some(scale) = pin.scale {
+        for (monitor, _) in &mut monitors {
+            monitor.scale = scale;
+        }
+    }
+    let pinned = match (pin.region, &pin.monitor) {
+        (Some(region), _) => {
+            // Scaled like the monitor it starts on
+            let (x, y) = (region.x, region.y);
+            let scale = monitors
+                .iter()
+                .find(|(m, _)| Region::from(m).contains(x, y))
+                .map_or(1.0, |(m, _)| m.scale);
+            Some(Monitor {
+                x,
+                y,
+                width: region.width,
+                height: region.height,
+                scale,
+                primary: true,
+            })
+        }
+        (None, Some(name)) => {
+            let found = monitors
+                .iter()
+                .find(|(m, device)| is_pinned(name, m, device));
+            if found.is_none() {
+                log::warn!("No monitor {} to pin input to, using all of them", name);
+            }
+            found.map(|(monitor, _)| Monitor {
+                primary: true,
+                ..monitor.clone()
+            })
+        }
+        (None, None) => None,
+    };
+
+    *BOUNDS.lock().unwrap() = pinned.as_ref().map(Region::from);
+    match pinned {
+        Some(monitor) => ScreenLayout {
+            monitors: vec![monitor],
+        },
+        None => ScreenLayout {
+            monitors: monitors.into_iter().map(|(monitor, _)| monitor).collect(),
+        },
+    }
+}
+
+/// Motion by `dx`, `dy` from where the cursor is, shortened to stay on what input is
+/// pinned to. The cursor is only located if it is.
+///
+/// Motion [`crate::pacing`] holds back counts as moved already, since it will be.
+pub fn clamp_motion(dx: i32, dy: i32, location: impl FnOnce() -> (i32, i32)) -> (i32, i32) {
+    let bounds = match *BOUNDS.lock().unwrap() {
+        Some(bounds) => bounds,
+        None => return (dx, dy),
+    };
+    let (x, y) = location();
+    let (queued_x, queued_y) = crate::pacing::backlog();
+    let (x, y) = (x.saturating_add(queued_x), y.saturating_add(queued_y));
+    // Left wherever it is, if it got off it some other way
+    if !bounds.contains(x, y) {
+        return (dx, dy);
+    }
+
+    (
+        x.saturating_add(dx)
+            .clamp(bounds.x, bounds.right())
+            .saturating_sub(x),
+        y.saturating_add(dy)
+            .clamp(bounds.y, bounds.bottom())
+            .saturating_sub(y),
+    )
+}
+
+/// Sends the layout whenever it differs from `last`, until the stream fails.
+pub async fn report(
+    mut stream: crate::client::Upstream,
+    mut last: ScreenLayout,
+    config: watch::Receiver<Config>,
+) -> Result<()> {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let pin = Pin::new(&config.borrow());
+        let layout = tokio::task::spawn_blocking(move || layout(&pin)).await?;
+        if layout == last {
+            continue;
+        }
+
+        log::info!("Displays changed: {:?}", layout.monitors);
+        let report = layout.to_vec();
+        stream.write(&report).await?;
+
+        last = layout;
+    }
+}
@@ -0,0 +1,264 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::Config;
+
+const SERVICE: &str = "rkvm-client";
+
+#[derive(Debug, Clone, Copy)]
+enum Secret {
+    Psk,
+    Fingerprint,
+}
+
+impl Secret {
+    const ALL: [Secret; 2] = [Secret::Psk, Secret::Fingerprint];
+
+    /// Name of the field in `config.toml`.
+    fn key(self) -> &'static str {
+        match self {
+            Secret::Psk => "psk",
+            Secret::Fingerprint => "fingerprint",
+        }
+    }
+
+    fn slot(self, config: &mut Config) -> &mut Option<String> {
+        match self {
+            Secret::Psk => &mut config.psk,
+            Secret::Fingerprint => &mut config.fingerprint,
+        }
+    }
+}
+
+/// Secrets are stored per server, so switching `address` doesn't reuse another server's key.
+fn entry(config: &Config, secret: Secret) -> keyring::Result<keyring::Entry> {
+    let user = format!("{}@{}:{}", secret.key(), config.address, config.port);
+    keyring::Entry::new(SERVICE, &user)
+}
+
+/// Fills in secrets missing from the config from the OS credential store, writing nothing.
+///
+/// Secrets still in plaintext in the config file are used as they are, see [`store`] for
+/// moving them. If the store is unavailable, plaintext values keep working.
+pub fn resolve(config: &mut Config) -> Result<()> {
+    if !config.use_keyring {
+        return Ok(());
+    }
+
+    for secret in Secret::ALL {
+        if secret.slot(config).is_some() {
+            log::warn!(
+                "{} is in plaintext in the config, `rkvm-client store-secrets` moves it to the system keyring",
+                secret.key()
+            );
+            continue;
+        }
+
+        let entry = match entry(config, secret) {
+            Ok(e) => e,
+            Err(e) => {
+                log::warn!("System keyring unavailable: {}", e);
+                return Ok(());
+            }
+        };
+        match entry.get_password() {
+            Ok(value) => *secret.slot(config) = Some(value),
+            Err(keyring::Error::NoEntry) => {}
+            Err(e) => {
+                log::warn!("Failed to read {} from keyring: {}", secret.key(), e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves secrets in plaintext in the config file at `config_path` to the OS credential
+/// store, removing them from the file. Those that can't be stored stay where they are.
+pub fn store(config_path: &Path) -> Result<()> {
+    let mut config: Config = toml::from_str(&std::fs::read_to_string(config_path)?)?;
+    if !config.use_keyring {
+        log::warn!("Not moving secrets, use_keyring is off");
+        return Ok(());
+    }
+
+    let mut moved = Vec::new();
+    for secret in Secret::ALL {
+        let value = match secret.slot(&mut config).clone() {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let stored = entry(&config, secret).and_then(|entry| entry.set_password(&value));
+        match stored {
+            Ok(_) => moved.push(secret),
+            Err(e) => log::warn!("Failed to store {} in keyring: {}", secret.key(), e),
+        }
+    }
+
+    if !moved.is_empty() {
+        remove_from_config(config_path, &moved)?;
+        log::info!(
+            "Moved {:?} from {:?} to the system keyring",
+            moved,
+            config_path
+        );
+    }
+
+    Ok(())
+}
+
+/// Removes the given keys while preserving the rest of the file, comments included.
+fn remove_from_config(config_path: &Path, secrets: &[Secret]) -> Result<()> {
+    let config_string = std::fs::read_to_string(config_path)?;
+    let mut doc = config_string.parse::<toml_edit::Document>()?;
+
+    for secret in secrets {
+        doc.remove(secret.key());
+    }
+
+    std::fs::write(config_path, doc.to_string())?;
+
+    Ok(())
+}
+
+/// Stores or forgets the pinned fingerprint, wherever `resolve` would look for it.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub fn set_fingerprint(
+    config: &Config,
+    config_path: &Path,
+    fingerprint: Option<&str>,
+) -> Result<()> {
+    let store = config
+        .use_keyring
+        .then(|| entry(config, Secret::Fingerprint));
+
+    match (fingerprint, store) {
+        (Some(fingerprint), Some(Ok(entry))) if entry.set_password(fingerprint).is_ok() => {
+            remove_from_config(config_path, &[Secret::Fingerprint])?;
+        }
+        (Some(fingerprint), _) => {
+            let config_string = std::fs::read_to_string(config_path)?;
+            let mut doc = config_string.parse::<toml_edit::Document>()?;
+            doc[Secret::Fingerprint.key()] = toml_edit::value(fingerprint);
+            std::fs::write(config_path, doc.to_string())?;
+        }
+        (None, store) => {
+            if let Some(Ok(entry)) = store {
+                match entry.delete_password() {
+                    Ok(_) | Err(keyring::Error::NoEntry) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            remove_from_config(config_path, &[Secret::Fingerprint])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Where `install-service` leaves the secrets for the service's agent, which runs as SYSTEM
+/// and can't read the user's keyring.
+#[cfg(target_os = "windows")]
+fn service_path() -> std::path::PathBuf {
+    let data = std::env::var_os("ProgramData").unwrap_or_else(|| r"C:\ProgramData".into());
+    Path::new(&data).join("rkvm-client").join("secrets.toml")
+}
+
+/// Only SYSTEM and administrators may read the secrets left for the agent
+#[cfg(target_os = "windows")]
+const SERVICE_SDDL: &str = "D:P(A;OICI;FA;;;SY)(A;OICI;FA;;;BA)";
+
+#[cfg(target_os = "windows")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ServiceSecrets {
+    psk: Option<String>,
+    fingerprint: Option<String>,
+}
+
+/// Copies the secrets of `config`, as resolved for the user, to where the agent finds them.
+#[cfg(target_os = "windows")]
+pub fn save_for_service(config: &Config) -> Result<()> {
+    let path = service_path();
+    let dir = path.parent().unwrap();
+    std::fs::create_dir_all(dir)?;
+    restrict(dir)?;
+
+    let secrets = ServiceSecrets {
+        psk: config.psk.clone(),
+        fingerprint: config.fingerprint.clone(),
+    };
+    std::fs::write(&path, toml::to_string(&secrets)?)?;
+
+    Ok(())
+}
+
+/// Removes what [`save_for_service`] left.
+#[cfg(target_os = "windows")]
+pub fn forget_for_service() -> Result<()> {
+    match std::fs::remove_file(service_path()) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Fills in secrets for the agent from what [`save_for_service`] left, writing nothing.
+#[cfg(target_os = "windows")]
+pub fn resolve_for_service(config: &mut Config) -> Result<()> {
+    let path = service_path();
+    let secrets: ServiceSecrets = match std::fs::read_to_string(&path) {
+        Ok(s) => toml::from_str(&s)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::warn!("No secrets at {:?}, run install-service again", path);
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if config.psk.is_none() {
+        config.psk = secrets.psk;
+    }
+    if config.fingerprint.is_none() {
+        config.fingerprint = secrets.fingerprint;
+    }
+
+    Ok(())
+}
+
+/// Sets [`SERVICE_SDDL`] as the DACL of `dir`, inherited by what's put in it.
+#[cfg(target_os = "windows")]
+fn restrict(dir: &Path) -> Result<()> {
+    use anyhow::Context;
+    use windows::{
+        core::HSTRING,
+        Win32::Security::{
+            Authorization::{
+                ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+            },
+            SetFileSecurityW, DACL_SECURITY_INFORMATION, PROTECTED_DACL_SECURITY_INFORMATION,
+            PSECURITY_DESCRIPTOR,
+        },
+    };
+
+    let mut descriptor = PSECURITY_DESCRIPTOR::default();
+    unsafe {
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            &HSTRING::from(SERVICE_SDDL),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            None,
+        )
+        .context("Build secrets DACL")?;
+        SetFileSecurityW(
+            &HSTRING::from(dir),
+            DACL_SECURITY_INFORMATION | PROTECTED_DACL_SECURITY_INFORMATION,
+            descriptor,
+        )
+        .ok()
+        .with_context(|| format!("Restrict access to {}", dir.display()))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,114 @@
+//! Counting packets lost or reordered on the way, from the sequence numbers of each stream.
+
+use std::sync::Mutex;
+
+use rkvm_protocol::EventKind;
+
+/// Where packets arrive, each counted on its own by the server.
+#[derive(Debug, Clone, Copy)]
+pub enum Stream {
+    Mouse,
+    Keyboard,
+    Misc,
+    /// Every kind of event, when the server was asked for one stream
+    Single,
+    Datagrams,
+}
+
+impl From<EventKind> for Stream {
+    fn from(kind: EventKind) -> Self {
+        match kind {
+            EventKind::Mouse => Stream::Mouse,
+            EventKind::Keyboard => Stream::Keyboard,
+            EventKind::Misc => Stream::Misc,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Gaps {
+    /// Sequence number expected next
+    next: u64,
+    received: u64,
+    lost: u64,
+    reordered: u64,
+}
+
+const EMPTY: Gaps = Gaps {
+    next: 1,
+    received: 0,
+    lost: 0,
+    reordered: 0,
+};
+
+const STREAMS: [Stream; 5] = [
+    Stream::Mouse,
+    Stream::Keyboard,
+    Stream::Misc,
+    Stream::Single,
+    Stream::Datagrams,
+];
+
+/// Counts of the current connection, by stream
+static GAPS: Mutex<[Gaps; 5]> = Mutex::new([EMPTY; 5]);
+
+/// Starts counting afresh, for a new connection.
+pub fn reset() {
+    *GAPS.lock().unwrap() = [EMPTY; 5];
+}
+
+/// Notes the arrival of packet `seq` on `stream`.
+pub fn note(stream: Stream, seq: u64) {
+    // Outside of the counts
+    if seq == 0 {
+        return;
+    }
+
+    let mut gaps = GAPS.lock().unwrap();
+    let gaps = &mut gaps[stream as usize];
+    gaps.received += 1;
+
+    if seq >= gaps.next {
+        let lost = seq - gaps.next;
+        if lost > 0 {
+            // Streams are reliable, so it was the server that gave up on them
+            if let Stream::Datagrams = stream {
+                log::debug!("{} {:?} packets lost", lost, stream);
+            } else {
+                log::warn!("{} {:?} packets lost", lost, stream);
+            }
+        }
+
+        gaps.lost += lost;
+        gaps.next = seq + 1;
+    } else {
+        // Counted as lost when a later one overtook it
+        log::debug!("{:?} packet {} arrived out of order", stream, seq);
+        gaps.lost = gaps.lost.saturating_sub(1);
+        gaps.reordered += 1;
+    }
+}
+
+/// How many packets each stream that got any received, lost and got out of order.
+pub fn summary() -> Vec<String> {
+    let gaps = *GAPS.lock().unwrap();
+
+    STREAMS
+        .iter()
+        .map(|&stream| (stream, gaps[stream as usize]))
+        .filter(|(_, gaps)| gaps.received > 0)
+        .map(|(stream, gaps)| {
+            format!(
+                "{:?}: {} packets received, {} lost, {} out of order",
+                stream, gaps.received, gaps.lost, gaps.reordered
+            )
+        })
+        .collect()
+}
+
+/// Logs the [`summary`].
+pub fn log_summary() {
+    for line in summary() {
+        log::info!("{}", line);
+    }
+}
@@ -0,0 +1,320 @@
+//! Running as a Windows service, so the server can control the login and lock screens.
+//!
+//! Services live in session 0 and can't reach any desktop, so the service starts a headless
+//! agent on the console session's Winlogon desktop whenever nobody is working in it: nobody
+//! is logged in, or the session is locked. The tray client takes over again on unlock.
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::{mpsc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use windows::{
+    core::PWSTR,
+    Win32::{
+        Foundation::{CloseHandle, HANDLE},
+        Security::{
+            DuplicateTokenEx, SecurityImpersonation, SetTokenInformation, TokenPrimary,
+            TokenSessionId, TOKEN_ALL_ACCESS, TOKEN_DUPLICATE, TOKEN_QUERY,
+        },
+        System::{
+            RemoteDesktop::{WTSGetActiveConsoleSessionId, WTSQueryUserToken},
+            Threading::{
+                CreateProcessAsUserW, GetCurrentProcess, GetExitCodeProcess, OpenProcessToken,
+                TerminateProcess, CREATE_NO_WINDOW, PROCESS_INFORMATION, STARTUPINFOW,
+            },
+        },
+    },
+};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+        SessionChangeReason,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+const SERVICE_NAME: &str = "rkvm-client";
+
+/// `GetExitCodeProcess` reports this while the process runs.
+const STILL_ACTIVE: u32 = 259;
+
+/// How often to check that the agent is still running.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Config file the agent is started with, set before the dispatcher runs.
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Registers the service to start at boot, with `config_path`, and starts it. The secrets
+/// are copied for it as they are now, so run again after pairing anew.
+pub fn install(config_path: &Path) -> Result<()> {
+    let config_path = config_path
+        .canonicalize()
+        .with_context(|| format!("Find {}", config_path.display()))?;
+
+    // Done here, as the user, since the agent runs as SYSTEM and writes nothing of theirs
+    let config = crate::reload::load(&config_path)?;
+    crate::identity::load_certificate(&config_path)?;
+    crate::secrets::save_for_service(&config)?;
+
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: "RKVM Client".into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments: vec![
+            "--config".into(),
+            config_path.into_os_string(),
+            "--service".into(),
+        ],
+        dependencies: Vec::new(),
+        // LocalSystem, which may start processes in other sessions
+        account_name: None,
+        account_password: None,
+    };
+
+    let service =
+        manager.create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)?;
+    service.set_description("Lets an rkvm server control the login and lock screens")?;
+    service.start::<&str>(&[])?;
+
+    log::info!("Installed and started the {} service", SERVICE_NAME);
+    Ok(())
+}
+
+/// Stops and removes the service.
+pub fn uninstall() -> Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    service.delete()?;
+    crate::secrets::forget_for_service()?;
+
+    log::info!("Removed the {} service", SERVICE_NAME);
+    Ok(())
+}
+
+/// Hands the process over to the service control manager. Only returns once stopped.
+pub fn run(config_path: PathBuf) -> Result<()> {
+    let _ = CONFIG_PATH.set(config_path);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
+
+    Ok(())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        log::error!("Service failed: {}", e);
+    }
+}
+
+enum Control {
+    Stop,
+    Session(SessionChangeReason),
+}
+
+fn status(state: ServiceState, controls_accepted: ServiceControlAccept) -> ServiceStatus {
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    }
+}
+
+fn run_service() -> Result<()> {
+    let config_path = CONFIG_PATH.get().context("No config path")?;
+
+    let (tx, rx) = mpsc::channel();
+    let handler = move |control| match control {
+        ServiceControl::Stop => {
+            let _ = tx.send(Control::Stop);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::SessionChange(change) => {
+            let _ = tx.send(Control::Session(change.reason));
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status_handle = service_control_handler::register(SERVICE_NAME, handler)?;
+    status_handle.set_service_status(status(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SESSION_CHANGE,
+    ))?;
+
+    // Nobody logged in yet is the usual case when starting at boot
+    let mut wanted = !user_logged_on();
+    let mut agent: Option<Agent> = None;
+
+    loop {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(Control::Stop) => break,
+            Ok(Control::Session(reason)) => match reason {
+                SessionChangeReason::SessionLock | SessionChangeReason::SessionLogoff => {
+                    wanted = true;
+                }
+                SessionChangeReason::SessionUnlock | SessionChangeReason::SessionLogon => {
+                    wanted = false;
+                }
+                // Another session took the console, so the agent has to move to it
+                SessionChangeReason::ConsoleConnect => {
+                    agent = None;
+                    wanted = !user_logged_on();
+                }
+                _ => {}
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        if !wanted {
+            agent = None;
+        } else if !agent.as_ref().is_some_and(Agent::is_running) {
+            agent = match Agent::spawn(config_path) {
+                Ok(agent) => Some(agent),
+                Err(e) => {
+                    log::error!("Failed to start agent: {}", e);
+                    None
+                }
+            };
+        }
+    }
+
+    drop(agent);
+    status_handle
+        .set_service_status(status(ServiceState::Stopped, ServiceControlAccept::empty()))?;
+
+    Ok(())
+}
+
+/// Whether someone is logged in on the console, locked or not.
+fn user_logged_on() -> bool {
+    unsafe {
+        let mut token = HANDLE::default();
+        if WTSQueryUserToken(WTSGetActiveConsoleSessionId(), &mut token).as_bool() {
+            CloseHandle(token);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A headless client on the Winlogon desktop, stopped when dropped.
+struct Agent {
+    process: HANDLE,
+}
+
+impl Agent {
+    fn spawn(config_path: &Path) -> Result<Self> {
+        let session = unsafe { WTSGetActiveConsoleSessionId() };
+        if session == u32::MAX {
+            anyhow::bail!("No console session");
+        }
+
+        let mut command_line: Vec<u16> = format!(
+            "\"{}\" --config \"{}\" --agent",
+            std::env::current_exe()?.display(),
+            config_path.display()
+        )
+        .encode_utf16()
+        .chain(Some(0))
+        .collect();
+        let mut desktop: Vec<u16> = "winsta0\\winlogon".encode_utf16().chain(Some(0)).collect();
+
+        unsafe {
+            // Our own SYSTEM token, moved to the console session
+            let mut own = HANDLE::default();
+            OpenProcessToken(GetCurrentProcess(), TOKEN_DUPLICATE | TOKEN_QUERY, &mut own).ok()?;
+            let mut token = HANDLE::default();
+            let duplicated = DuplicateTokenEx(
+                own,
+                TOKEN_ALL_ACCESS,
+                None,
+                SecurityImpersonation,
+                TokenPrimary,
+                &mut token,
+            );
+            CloseHandle(own);
+            duplicated.ok()?;
+
+            let spawned = SetTokenInformation(
+                token,
+                TokenSessionId,
+                &session as *const u32 as _,
+                std::mem::size_of::<u32>() as u32,
+            )
+            .ok()
+            .and_then(|_| {
+                let startup = STARTUPINFOW {
+                    cb: std::mem::size_of::<STARTUPINFOW>() as u32,
+                    lpDesktop: PWSTR(desktop.as_mut_ptr()),
+                    ..Default::default()
+                };
+                let mut info = PROCESS_INFORMATION::default();
+                CreateProcessAsUserW(
+                    token,
+                    None,
+                    PWSTR(command_line.as_mut_ptr()),
+                    None,
+                    None,
+                    false,
+                    CREATE_NO_WINDOW,
+                    None,
+                    None,
+                    &startup,
+                    &mut info,
+                )
+                .ok()?;
+
+                CloseHandle(info.hThread);
+                Ok(info.hProcess)
+            });
+            CloseHandle(token);
+
+            let process = spawned?;
+            log::info!("Started agent in session {}", session);
+            Ok(Self { process })
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        let mut code = 0;
+        unsafe { GetExitCodeProcess(self.process, &mut code).as_bool() && code == STILL_ACTIVE }
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        unsafe {
+            TerminateProcess(self.process, 0);
+            CloseHandle(self.process);
+        }
+    }
+}
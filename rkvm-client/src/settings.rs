@@ -0,0 +1,430 @@
+//! A settings window, so the config file doesn't have to be edited by hand.
+//!
+//! Saving writes `config.toml`, keeping comments and unknown keys, and reloads it. Outside
+//! Windows the config file is opened in the default editor instead, and reloaded when saved.
+
+#[cfg(not(target_os = "windows"))]
+use std::path::Path;
+
+#[cfg(target_os = "windows")]
+mod window {
+    use std::{
+        cell::RefCell,
+        path::{Path, PathBuf},
+        sync::atomic::{AtomicIsize, Ordering},
+    };
+
+    use anyhow::{Context, Result};
+    use windows::{
+        core::{w, HSTRING, PCWSTR},
+        Win32::{
+            Foundation::{HWND, LPARAM, LRESULT, WPARAM},
+            Graphics::Gdi::{GetStockObject, COLOR_BTNFACE, DEFAULT_GUI_FONT, HBRUSH},
+            System::LibraryLoader::GetModuleHandleW,
+            UI::WindowsAndMessaging::{
+                CreateWindowExW, DefWindowProcW, DestroyWindow, DispatchMessageW, GetMessageW,
+                GetWindowTextLengthW, GetWindowTextW, IsDialogMessageW, LoadCursorW, MessageBoxW,
+                PostQuitMessage, RegisterClassW, SendMessageW, SetForegroundWindow,
+                TranslateMessage, BM_GETCHECK, BM_SETCHECK, BS_AUTOCHECKBOX, BS_DEFPUSHBUTTON,
+                BS_PUSHBUTTON, CW_USEDEFAULT, ES_AUTOHSCROLL, ES_NUMBER, HMENU, IDCANCEL,
+                IDC_ARROW, MB_ICONWARNING, MB_OK, MSG, WINDOW_EX_STYLE, WINDOW_STYLE, WM_CLOSE,
+                WM_COMMAND, WM_DESTROY, WM_SETFONT, WNDCLASSW, WS_BORDER, WS_CAPTION, WS_CHILD,
+                WS_OVERLAPPED, WS_SYSMENU, WS_TABSTOP, WS_VISIBLE,
+            },
+        },
+    };
+
+    use crate::{secrets, Config};
+
+    /// The values the window edits.
+    struct Settings {
+        address: String,
+        port: u16,
+        fingerprint: Option<String>,
+        sensitivity: f64,
+        clipboard: bool,
+        autostart: bool,
+    }
+
+    impl Settings {
+        fn load(config_path: &Path) -> Result<(Config, Self)> {
+            let config_string = std::fs::read_to_string(config_path)?;
+            let mut config: Config = toml::from_str(&config_string)?;
+            secrets::resolve(&mut config)?;
+
+            let settings = Self {
+                address: config.address.clone(),
+                port: config.port,
+                fingerprint: config.fingerprint.clone(),
+                sensitivity: config.sensitivity,
+                clipboard: config.clipboard,
+                autostart: crate::autostart::is_enabled(),
+            };
+
+            Ok((config, settings))
+        }
+
+        fn save(&self, config: &Config, config_path: &Path) -> Result<()> {
+            let config_string = std::fs::read_to_string(config_path)?;
+            let mut doc = config_string.parse::<toml_edit::Document>()?;
+
+            doc["address"] = toml_edit::value(self.address.as_str());
+            doc["port"] = toml_edit::value(i64::from(self.port));
+            doc["sensitivity"] = toml_edit::value(self.sensitivity);
+            doc["clipboard"] = toml_edit::value(self.clipboard);
+            std::fs::write(config_path, doc.to_string())?;
+
+            // Secrets are stored per server, so this goes under the new address
+            let config = Config {
+                address: self.address.clone(),
+                port: self.port,
+                ..config.clone()
+            };
+            secrets::set_fingerprint(&config, config_path, self.fingerprint.as_deref())?;
+
+            crate::autostart::set_enabled(self.autostart)?;
+
+            Ok(())
+        }
+    }
+
+    const ID_SAVE: u16 = 1;
+    const BST_CHECKED: isize = 1;
+
+    /// The open window, so a second click on the tray item brings it up instead.
+    static OPEN: AtomicIsize = AtomicIsize::new(0);
+
+    struct Controls {
+        address: HWND,
+        port: HWND,
+        fingerprint: HWND,
+        sensitivity: HWND,
+        clipboard: HWND,
+        autostart: HWND,
+    }
+
+    struct Dialog {
+        config_path: PathBuf,
+        config: Config,
+        controls: Controls,
+    }
+
+    thread_local! {
+        static DIALOG: RefCell<Option<Dialog>> = const { RefCell::new(None) };
+    }
+
+    unsafe fn control(
+        parent: HWND,
+        class: PCWSTR,
+        text: &str,
+        style: u32,
+        (x, y, width, height): (i32, i32, i32, i32),
+        id: u16,
+    ) -> HWND {
+        let hwnd = CreateWindowExW(
+            WINDOW_EX_STYLE(0),
+            class,
+            &HSTRING::from(text),
+            WS_CHILD | WS_VISIBLE | WINDOW_STYLE(style),
+            x,
+            y,
+            width,
+            height,
+            parent,
+            HMENU(id as isize),
+            GetModuleHandleW(None).unwrap_or_default(),
+            None,
+        );
+        SendMessageW(
+            hwnd,
+            WM_SETFONT,
+            WPARAM(GetStockObject(DEFAULT_GUI_FONT).0 as usize),
+            LPARAM(1),
+        );
+        hwnd
+    }
+
+    unsafe fn label(parent: HWND, text: &str, y: i32) {
+        control(parent, w!("STATIC"), text, 0, (15, y + 3, 110, 20), 0);
+    }
+
+    unsafe fn edit(parent: HWND, text: &str, style: u32, rect: (i32, i32, i32, i32)) -> HWND {
+        let style = (WS_BORDER | WS_TABSTOP).0 | ES_AUTOHSCROLL as u32 | style;
+        control(parent, w!("EDIT"), text, style, rect, 0)
+    }
+
+    unsafe fn checkbox(parent: HWND, text: &str, checked: bool, y: i32) -> HWND {
+        let style = WS_TABSTOP.0 | BS_AUTOCHECKBOX as u32;
+        let hwnd = control(parent, w!("BUTTON"), text, style, (15, y, 250, 20), 0);
+        SendMessageW(hwnd, BM_SETCHECK, WPARAM(checked as usize), LPARAM(0));
+        hwnd
+    }
+
+    unsafe fn text(hwnd: HWND) -> String {
+        let mut buf = vec![0u16; GetWindowTextLengthW(hwnd) as usize + 1];
+        let len = GetWindowTextW(hwnd, &mut buf);
+        String::from_utf16_lossy(&buf[..len as usize])
+            .trim()
+            .to_owned()
+    }
+
+    unsafe fn checked(hwnd: HWND) -> bool {
+        SendMessageW(hwnd, BM_GETCHECK, WPARAM(0), LPARAM(0)).0 == BST_CHECKED
+    }
+
+    fn warn(hwnd: HWND, message: &str) {
+        unsafe {
+            MessageBoxW(
+                hwnd,
+                &HSTRING::from(message),
+                w!("RKVM Client"),
+                MB_OK | MB_ICONWARNING,
+            );
+        }
+    }
+
+    /// Reads the controls back, or says what's wrong with them.
+    unsafe fn read(controls: &Controls) -> Result<Settings, &'static str> {
+        let address = text(controls.address);
+        if address.is_empty() {
+            return Err("Enter the address of the server.");
+        }
+
+        let port = text(controls.port)
+            .parse()
+            .map_err(|_| "The port must be a number from 1 to 65535.")?;
+
+        let fingerprint = Some(text(controls.fingerprint)).filter(|f| !f.is_empty());
+        if fingerprint
+            .as_deref()
+            .is_some_and(|f| rkvm_protocol::parse_fingerprint(f).is_none())
+        {
+            return Err("The fingerprint must be 32 hex bytes, like the server prints it.");
+        }
+
+        let sensitivity = text(controls.sensitivity)
+            .parse::<f64>()
+            .ok()
+            .filter(|s| *s > 0.0 && s.is_finite())
+            .ok_or("Mouse speed must be a number above 0, 1 leaves it unchanged.")?;
+
+        Ok(Settings {
+            address,
+            port,
+            fingerprint,
+            sensitivity,
+            clipboard: checked(controls.clipboard),
+            autostart: checked(controls.autostart),
+        })
+    }
+
+    fn save(hwnd: HWND) {
+        let saved = DIALOG.with(|dialog| {
+            let dialog = dialog.borrow();
+            let dialog = dialog.as_ref()?;
+
+            let settings = match unsafe { read(&dialog.controls) } {
+                Ok(s) => s,
+                Err(message) => {
+                    warn(hwnd, message);
+                    return None;
+                }
+            };
+
+            match settings.save(&dialog.config, &dialog.config_path) {
+                Ok(_) => Some(()),
+                Err(e) => {
+                    warn(hwnd, &format!("Failed to save settings: {}", e));
+                    None
+                }
+            }
+        });
+
+        if saved.is_some() {
+            crate::reload::reload();
+            unsafe { DestroyWindow(hwnd) };
+        }
+    }
+
+    unsafe extern "system" fn window_proc(
+        hwnd: HWND,
+        msg: u32,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        match msg {
+            WM_COMMAND => {
+                match (wparam.0 & 0xffff) as u16 {
+                    ID_SAVE => save(hwnd),
+                    id if id == IDCANCEL.0 as u16 => {
+                        DestroyWindow(hwnd);
+                    }
+                    _ => {}
+                }
+                LRESULT(0)
+            }
+            WM_CLOSE => {
+                DestroyWindow(hwnd);
+                LRESULT(0)
+            }
+            WM_DESTROY => {
+                PostQuitMessage(0);
+                LRESULT(0)
+            }
+            _ => DefWindowProcW(hwnd, msg, wparam, lparam),
+        }
+    }
+
+    unsafe fn create(settings: &Settings) -> Result<(HWND, Controls)> {
+        let instance = GetModuleHandleW(None)?;
+        let class = WNDCLASSW {
+            lpfnWndProc: Some(window_proc),
+            hInstance: instance,
+            lpszClassName: w!("rkvm-settings"),
+            hbrBackground: HBRUSH((COLOR_BTNFACE.0 + 1) as isize),
+            hCursor: LoadCursorW(None, IDC_ARROW)?,
+            ..Default::default()
+        };
+        // Fails harmlessly if the window was opened before
+        RegisterClassW(&class);
+
+        let hwnd = CreateWindowExW(
+            WINDOW_EX_STYLE(0),
+            w!("rkvm-settings"),
+            w!("RKVM Client Settings"),
+            WS_OVERLAPPED | WS_CAPTION | WS_SYSMENU | WS_VISIBLE,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            430,
+            270,
+            HWND(0),
+            HMENU(0),
+            instance,
+            None,
+        );
+        if hwnd.0 == 0 {
+            return Err(windows::core::Error::from_win32()).context("Create settings window");
+        }
+
+        label(hwnd, "Server", 15);
+        let address = edit(hwnd, &settings.address, 0, (130, 15, 180, 22));
+        let port = edit(
+            hwnd,
+            &settings.port.to_string(),
+            ES_NUMBER as u32,
+            (320, 15, 80, 22),
+        );
+
+        label(hwnd, "Fingerprint", 50);
+        let fingerprint = edit(
+            hwnd,
+            settings.fingerprint.as_deref().unwrap_or_default(),
+            0,
+            (130, 50, 270, 22),
+        );
+
+        label(hwnd, "Mouse speed", 85);
+        let sensitivity = edit(
+            hwnd,
+            &settings.sensitivity.to_string(),
+            0,
+            (130, 85, 80, 22),
+        );
+
+        let clipboard = checkbox(
+            hwnd,
+            "Receive the server's clipboard",
+            settings.clipboard,
+            120,
+        );
+        let autostart = checkbox(hwnd, "Start at login", settings.autostart, 145);
+
+        let buttons = WS_TABSTOP.0;
+        control(
+            hwnd,
+            w!("BUTTON"),
+            "Save",
+            buttons | BS_DEFPUSHBUTTON as u32,
+            (230, 190, 80, 26),
+            ID_SAVE,
+        );
+        control(
+            hwnd,
+            w!("BUTTON"),
+            "Cancel",
+            buttons | BS_PUSHBUTTON as u32,
+            (320, 190, 80, 26),
+            IDCANCEL.0 as u16,
+        );
+
+        let controls = Controls {
+            address,
+            port,
+            fingerprint,
+            sensitivity,
+            clipboard,
+            autostart,
+        };
+        Ok((hwnd, controls))
+    }
+
+    fn run(config_path: PathBuf) -> Result<()> {
+        let (config, settings) = Settings::load(&config_path)?;
+
+        unsafe {
+            let (hwnd, controls) = create(&settings)?;
+            OPEN.store(hwnd.0, Ordering::SeqCst);
+            DIALOG.with(|d| {
+                *d.borrow_mut() = Some(Dialog {
+                    config_path,
+                    config,
+                    controls,
+                })
+            });
+
+            let mut msg = MSG::default();
+            while GetMessageW(&mut msg, HWND(0), 0, 0).as_bool() {
+                // Lets Tab and Enter move between and press the controls
+                if !IsDialogMessageW(hwnd, &msg).as_bool() {
+                    TranslateMessage(&msg);
+                    DispatchMessageW(&msg);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn open(config_path: &Path) {
+        let open = HWND(OPEN.load(Ordering::SeqCst));
+        if open.0 != 0 {
+            unsafe { SetForegroundWindow(open) };
+            return;
+        }
+
+        let config_path = config_path.to_owned();
+        std::thread::spawn(move || {
+            if let Err(e) = run(config_path) {
+                log::error!("Failed to show settings: {}", e);
+            }
+            OPEN.store(0, Ordering::SeqCst);
+        });
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub use window::open;
+
+/// Opens the config file in the default editor.
+#[cfg(not(target_os = "windows"))]
+pub fn open(config_path: &Path) {
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+
+    if let Err(e) = std::process::Command::new(opener).arg(config_path).spawn() {
+        log::error!("Failed to open {:?}: {}", config_path, e);
+    }
+}
@@ -0,0 +1,210 @@
+//! What the running client is up to, for `rkvm-client status` to print.
+//!
+//! The running instance answers on a Unix socket, or a named pipe on Windows, with the
+//! status as text and hangs up.
+
+use std::{io::Read, net::SocketAddr, sync::Mutex, time::Instant};
+
+use anyhow::{Context, Result};
+use quinn::Connection;
+use rkvm_protocol::format_duration;
+use tokio::io::AsyncWriteExt;
+
+#[cfg(not(target_os = "windows"))]
+const SOCKET_NAME: &str = "rkvm-client-status";
+
+#[cfg(target_os = "windows")]
+const PIPE_NAME: &str = r"\\.\pipe\rkvm-client-status";
+
+/// Only the user running the client, and SYSTEM, may read the pipe
+#[cfg(target_os = "windows")]
+const PIPE_SDDL: &str = "D:P(A;;GA;;;SY)(A;;GA;;;OW)";
+
+enum State {
+    Disconnected,
+    Connecting(SocketAddr),
+    Connected {
+        addr: SocketAddr,
+        since: Instant,
+        connection: Connection,
+    },
+}
+
+struct Status {
+    state: State,
+    /// Last error logged, and when
+    last_error: Option<(String, Instant)>,
+}
+
+static STATUS: Mutex<Status> = Mutex::new(Status {
+    state: State::Disconnected,
+    last_error: None,
+});
+
+pub fn connecting(addr: SocketAddr) {
+    STATUS.lock().unwrap().state = State::Connecting(addr);
+}
+
+pub fn connected(addr: SocketAddr, connection: Connection) {
+    STATUS.lock().unwrap().state = State::Connected {
+        addr,
+        since: Instant::now(),
+        connection,
+    };
+}
+
+pub fn disconnected() {
+    STATUS.lock().unwrap().state = State::Disconnected;
+}
+
+/// Remembers `message` as the last error, called for every error logged.
+pub fn note_error(message: String) {
+    STATUS.lock().unwrap().last_error = Some((message, Instant::now()));
+}
+
+fn report() -> String {
+    let status = STATUS.lock().unwrap();
+    let mut lines = Vec::new();
+
+    match &status.state {
+        State::Disconnected => lines.push("State: disconnected".to_owned()),
+        State::Connecting(addr) => lines.push(format!("State: connecting to {}", addr)),
+        State::Connected {
+            addr,
+            since,
+            connection,
+        } => {
+            let rtt = connection.rtt().as_secs_f64() * 1000.0;
+            lines.push(format!(
+                "State: connected to {} for {}",
+                addr,
+                format_duration(since.elapsed())
+            ));
+            lines.push(format!("Round trip: {:.1} ms", rtt));
+            lines.push(format!("Quality: {:?}", crate::target::quality()));
+            if let Some(offset) = crate::clock::offset() {
+                let offset = offset as f64 / 1000.0;
+                lines.push(format!("Server clock: {:.1} ms ahead", offset));
+            }
+        }
+    }
+
+    lines.extend(crate::sequence::summary());
+
+    match &status.last_error {
+        Some((message, at)) => lines.push(format!(
+            "Last error, {} ago: {}",
+            format_duration(at.elapsed()),
+            message
+        )),
+        None => lines.push("No errors".to_owned()),
+    }
+
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// Answers `rkvm-client status` until the client quits.
+#[cfg(not(target_os = "windows"))]
+pub async fn serve() -> Result<()> {
+    let path = crate::instance::socket_path(SOCKET_NAME);
+    // Only the running instance gets here, so one already there is left over
+    let _ = std::fs::remove_file(&path);
+    let listener =
+        tokio::net::UnixListener::bind(&path).with_context(|| format!("Bind {:?}", path))?;
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let _ = stream.write_all(report().as_bytes()).await;
+    }
+}
+
+/// Answers `rkvm-client status` until the client quits.
+#[cfg(target_os = "windows")]
+pub async fn serve() -> Result<()> {
+    let mut server = create_pipe(true).context("Create status pipe")?;
+
+    loop {
+        server.connect().await?;
+        let mut connected = server;
+        server = create_pipe(false)?;
+
+        let _ = connected.write_all(report().as_bytes()).await;
+    }
+}
+
+/// Creates an instance of the status pipe, with [`PIPE_SDDL`] as its DACL.
+#[cfg(target_os = "windows")]
+fn create_pipe(first: bool) -> Result<tokio::net::windows::named_pipe::NamedPipeServer> {
+    use std::{ffi::c_void, sync::OnceLock};
+
+    use tokio::net::windows::named_pipe::ServerOptions;
+    use windows::{
+        core::HSTRING,
+        Win32::{
+            Foundation::FALSE,
+            Security::{
+                Authorization::{
+                    ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+                },
+                PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES,
+            },
+        },
+    };
+
+    // Converted once and kept, by address as pointers can't be shared
+    static DESCRIPTOR: OnceLock<usize> = OnceLock::new();
+    let descriptor = match DESCRIPTOR.get() {
+        Some(descriptor) => *descriptor,
+        None => {
+            let mut descriptor = PSECURITY_DESCRIPTOR::default();
+            unsafe {
+                ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                    &HSTRING::from(PIPE_SDDL),
+                    SDDL_REVISION_1,
+                    &mut descriptor,
+                    None,
+                )
+                .context("Build status pipe DACL")?;
+            }
+            *DESCRIPTOR.get_or_init(|| descriptor.0 as usize)
+        }
+    };
+
+    let mut attributes = SECURITY_ATTRIBUTES {
+        nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: descriptor as *mut c_void,
+        bInheritHandle: FALSE,
+    };
+    let server = unsafe {
+        ServerOptions::new()
+            .first_pipe_instance(first)
+            .create_with_security_attributes_raw(
+                PIPE_NAME,
+                &mut attributes as *mut SECURITY_ATTRIBUTES as *mut c_void,
+            )?
+    };
+
+    Ok(server)
+}
+
+/// Prints the status of the running client.
+pub fn print() -> Result<()> {
+    #[cfg(not(target_os = "windows"))]
+    let stream = std::os::unix::net::UnixStream::connect(crate::instance::socket_path(SOCKET_NAME));
+    #[cfg(target_os = "windows")]
+    let stream = std::fs::File::open(PIPE_NAME);
+
+    let mut stream = stream.context("No client is running")?;
+    let mut status = String::new();
+    stream.read_to_string(&mut status)?;
+
+    // Built without a console of our own, so print to the one we were started from
+    #[cfg(target_os = "windows")]
+    unsafe {
+        use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+    print!("{}", status);
+
+    Ok(())
+}
@@ -0,0 +1,170 @@
+//! Whether input from the server goes to this machine, shown in the tray.
+//!
+//! The tray tooltip also shows transfers in progress, see [`crate::progress`], when the
+//! connection to the server is degraded, and text an input method on the server composes.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use quinn::Connection;
+use rkvm_protocol::{LinkMonitor, LinkQuality, QualityThresholds};
+use serde::Deserialize;
+use tao::event_loop::EventLoopProxy;
+use tokio::sync::watch;
+
+use crate::Config;
+
+/// Round trip time and loss where the connection counts as degraded, and where as poor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct QualityConfig {
+    pub degraded_rtt_ms: u64,
+    pub degraded_loss_percent: u64,
+    pub poor_rtt_ms: u64,
+    pub poor_loss_percent: u64,
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        let thresholds = QualityThresholds::default();
+        Self {
+            degraded_rtt_ms: thresholds.degraded_rtt.as_millis() as u64,
+            degraded_loss_percent: thresholds.degraded_loss_percent,
+            poor_rtt_ms: thresholds.poor_rtt.as_millis() as u64,
+            poor_loss_percent: thresholds.poor_loss_percent,
+        }
+    }
+}
+
+impl QualityConfig {
+    fn thresholds(&self) -> QualityThresholds {
+        QualityThresholds {
+            degraded_rtt: Duration::from_millis(self.degraded_rtt_ms),
+            degraded_loss_percent: self.degraded_loss_percent,
+            poor_rtt: Duration::from_millis(self.poor_rtt_ms),
+            poor_loss_percent: self.poor_loss_percent,
+        }
+    }
+}
+
+static TARGETED: AtomicBool = AtomicBool::new(false);
+
+/// How well the connection to the server carries input, as last measured
+static QUALITY: Mutex<LinkQuality> = Mutex::new(LinkQuality::Good);
+
+/// Text an input method on the server is composing, until it commits it
+static PREEDIT: Mutex<String> = Mutex::new(String::new());
+
+/// Wakes up the tray whenever its tooltip changes
+static PROXY: Mutex<Option<EventLoopProxy<()>>> = Mutex::new(None);
+
+pub fn set_proxy(proxy: EventLoopProxy<()>) {
+    *PROXY.lock().unwrap() = Some(proxy);
+}
+
+/// Whether input goes to this machine.
+pub fn is_targeted() -> bool {
+    TARGETED.load(Ordering::Relaxed)
+}
+
+/// Notes whether input goes to this machine, as the server told us or on disconnects.
+pub fn set(active: bool) {
+    if TARGETED.swap(active, Ordering::Relaxed) == active {
+        return;
+    }
+
+    if active {
+        log::info!("Input goes to this machine");
+    } else {
+        log::info!("Input stopped going to this machine");
+        // Committed elsewhere, if at all
+        PREEDIT.lock().unwrap().clear();
+    }
+
+    refresh_tray();
+}
+
+/// Shows `text` as what the server's input method is composing, or nothing if empty.
+pub fn set_preedit(text: String) {
+    let before = std::mem::replace(&mut *PREEDIT.lock().unwrap(), text);
+    if before != *PREEDIT.lock().unwrap() {
+        refresh_tray();
+    }
+}
+
+pub fn quality() -> LinkQuality {
+    *QUALITY.lock().unwrap()
+}
+
+/// Measures `connection` every second until it closes, showing when it is degraded.
+pub async fn follow_quality(connection: Connection, config: watch::Receiver<Config>) {
+    let mut monitor = LinkMonitor::default();
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        let quality = tokio::select! {
+            _ = interval.tick() => {
+                let path = connection.stats().path;
+                let thresholds = config.borrow().link_quality.thresholds();
+                monitor.sample(&thresholds, path.rtt, path.sent_packets, path.lost_packets)
+            }
+            _ = connection.closed() => LinkQuality::Good,
+        };
+
+        let before = std::mem::replace(&mut *QUALITY.lock().unwrap(), quality);
+        if before != quality {
+            log::info!("Connection to the server is {:?}", quality);
+            refresh_tray();
+        }
+
+        if connection.close_reason().is_some() {
+            return;
+        }
+    }
+}
+
+/// Has the tray show the current tooltip.
+pub fn refresh_tray() {
+    if let Some(proxy) = &*PROXY.lock().unwrap() {
+        let _ = proxy.send_event(());
+    }
+}
+
+/// Tray tooltip for whether input goes to this machine, and any transfer in progress.
+pub fn tooltip() -> String {
+    let mut tooltip = if is_targeted() {
+        "RKVM Client - receiving input".to_owned()
+    } else {
+        "RKVM Client".to_owned()
+    };
+
+    match quality() {
+        LinkQuality::Good => {}
+        LinkQuality::Degraded => tooltip.push_str("\nConnection degraded"),
+        LinkQuality::Poor => tooltip.push_str("\nConnection poor"),
+    }
+
+    let preedit = PREEDIT.lock().unwrap();
+    if !preedit.is_empty() {
+        tooltip.push_str("\nComposing: ");
+        tooltip.push_str(&preedit);
+    }
+    drop(preedit);
+
+    #[cfg(not(target_os = "windows"))]
+    if let Some(offer) = crate::offer::describe() {
+        tooltip.push('\n');
+        tooltip.push_str(&offer);
+    }
+
+    if let Some(progress) = crate::progress::describe() {
+        tooltip.push('\n');
+        tooltip.push_str(&progress);
+    }
+    tooltip
+}
@@ -1,12 +1,86 @@
+use std::{
+    fmt,
+    io::Read,
+    time::{Duration, Instant},
+};
+
+use bincode::Options;
+use hmac::{Hmac, Mac};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305},
+    rand::{SecureRandom, SystemRandom},
+};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+pub use uuid::Uuid;
+
+/// Version of the wire format, bumped whenever it changes, so mismatched ends turn each
+/// other away instead of misreading packets.
+///
+/// 1: monitors with their DPI scale in [`ClientHello::screens`] and on screens streams.
+/// 2: [`Event::KeyboardLayout`] without a layout, once the server can't tell it anymore.
+/// 3: [`Packet::time`], stamped as packets are sent out.
+/// 4: [`ServerHello::Accepted`] only with a clipboard key if the server seals clipboards.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// TLS exporter label used to derive the per-session value that the client authenticates.
+pub const AUTH_EXPORTER_LABEL: &[u8] = b"EXPORTER-rkvm-psk-auth";
+
+/// Length of the exporter value and of the resulting proof.
+pub const AUTH_PROOF_LEN: usize = 32;
+
+/// Name prefix of every virtual input device rkvm creates, so servers can skip them.
+pub const VIRTUAL_DEVICE_NAME_PREFIX: &str = "rkvm virtual";
+
+/// Vendor id set on every virtual input device rkvm creates.
+pub const VIRTUAL_DEVICE_VENDOR: u16 = 0x524b;
+
+/// Length of the key of a [`ClipboardSeal`].
+pub const CLIPBOARD_KEY_LEN: usize = 32;
+
+/// Length of the nonce each [`Event::SealedClipboard`] is sealed with.
+pub const CLIPBOARD_NONCE_LEN: usize = 12;
 
 #[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum MouseButton {
     Left,
     Middle,
     Right,
 }
 
+/// Gamepad buttons, named after where they are on an Xbox controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum GamepadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftBumper,
+    RightBumper,
+    Back,
+    Start,
+    Guide,
+    LeftThumb,
+    RightThumb,
+    DpadUp,
+    DpadDown,
+    DpadLeft,
+    DpadRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum GamepadAxis {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+    LeftTrigger,
+    RightTrigger,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EventKind {
     Mouse,
@@ -14,8 +88,13 @@ pub enum EventKind {
     Misc,
 }
 
+/// An event with clipboard text of type `S` and clipboard bytes of type `B`, owned as
+/// [`Event`] or borrowed from what it was decoded from as [`EventRef`].
+///
+/// Both encode alike, so either can decode what the other encoded.
 #[derive(Debug, Deserialize, Serialize)]
-pub enum Event {
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum GenericEvent<S, B> {
     /// In pixels
     MouseMotion {
         dx: i32,
@@ -30,45 +109,329 @@ pub enum Event {
         button: MouseButton,
         pressed: bool,
     },
+    /// Windows scan code without the `0xe0` prefix, which `extended` stands for
     Keyboard {
         key: u16,
         pressed: bool,
+        extended: bool,
     },
     TextClipboard {
-        content: String,
+        content: S,
     },
     HtmlClipboard {
-        html: String,
-        plain: String,
+        html: S,
+        plain: S,
     },
     ImageClipboard {
-        png: Vec<u8>,
+        png: B,
+    },
+    /// Undecoded 8-bit RGBA, for clients that would rather not decode PNG
+    RawImageClipboard {
+        width: u32,
+        height: u32,
+        /// Bytes per row, at least `width * 4`
+        stride: u32,
+        rgba: B,
+    },
+    /// Clipboard contents too large to send unasked, see [`ClipboardFetch`]
+    ClipboardOffer {
+        id: u64,
+        /// MIME type of the contents
+        kind: String,
+        size: u64,
+    },
+    /// A drag held on the server crossed over to the client.
+    ///
+    /// The regular mouse events move it from here on, and releasing the button drops it.
+    DragEnter {
+        id: u64,
+        data: DragData,
+    },
+    /// Input went back to the server before the drag was dropped
+    DragCancel,
+    /// Puts the cursor where the server expects it, in pixels from the top left
+    MouseAbsolute {
+        x: i32,
+        y: i32,
+    },
+    /// Input moved elsewhere, so move the cursor out of the way until more input arrives
+    Park,
+    /// Text an input method on the server is composing, replacing the previous one.
+    ///
+    /// Empty once composing is cancelled or done.
+    Preedit {
+        text: String,
+    },
+    /// Text an input method on the server finished composing, to be typed as is
+    Commit {
+        text: String,
+    },
+    /// `pad` numbers the server's gamepads from 0, reusing numbers of removed ones
+    GamepadButton {
+        pad: u8,
+        button: GamepadButton,
+        pressed: bool,
+    },
+    /// Sticks span all of `i16`, positive to the right and down. Triggers go from 0 to
+    /// `i16::MAX`
+    GamepadAxis {
+        pad: u8,
+        axis: GamepadAxis,
+        value: i16,
+    },
+    /// The gamepad was unplugged, or stopped being forwarded. Sent after its axes on their
+    /// stream, so none of them brings it back
+    GamepadRemoved {
+        pad: u8,
+    },
+    /// Mouse motion now comes as raw deltas in datagrams, to be injected without delay
+    GameMode {
+        enabled: bool,
+    },
+    /// How things stand on the server, sent first to every client that connects
+    State {
+        grabbed: bool,
+        /// Whether input goes to the client getting this
+        active: bool,
+        /// Name of the client input goes to
+        active_client: Option<String>,
+        /// Windows scan codes of the modifiers held down, `0xe0` prefix included
+        modifiers: Vec<u16>,
+        caps_lock: bool,
+        num_lock: bool,
+        scroll_lock: bool,
+    },
+    /// Input started going to the client getting this, or stopped
+    Target {
+        active: bool,
+    },
+    /// A tablet pad ring was turned to `position`, in degrees counterclockwise from the top.
+    /// `None` once the finger left it
+    PadRing {
+        ring: u32,
+        position: Option<f64>,
     },
+    /// A tablet pad strip was touched at `position`, from 0 at the top or left to 1.
+    /// `None` once the finger left it
+    PadStrip {
+        strip: u32,
+        position: Option<f64>,
+    },
+    /// Tablet pad buttons are numbered from 0
+    PadButton {
+        button: u32,
+        pressed: bool,
+    },
+    /// The server is going to sleep, and wants clients to as well
+    Suspend,
+    /// Asks the client for its clipboard, sent back on an [`UpstreamKind::Clipboard`] stream
+    ClipboardRequest,
+    /// Clipboard contents sealed with the session's [`ClipboardSeal`], opened only to put
+    /// them on the clipboard
+    SealedClipboard {
+        nonce: [u8; CLIPBOARD_NONCE_LEN],
+        sealed: B,
+    },
+    /// The server's keyboard now types in another layout, named like XKB does, `de` or
+    /// `us(dvorak)`, or in one it can't tell
+    KeyboardLayout {
+        xkb: Option<String>,
+    },
+    /// Someone at the server wants the attention of whoever sits at the client, see
+    /// [`UpstreamKind::Attention`] for the other way
+    Attention,
+}
+
+/// An event as it is built and sent.
+pub type Event = GenericEvent<String, Vec<u8>>;
+
+/// An event borrowing its clipboard contents from the buffer it was decoded from, so
+/// large ones aren't copied before they're used.
+pub type EventRef<'a> = GenericEvent<&'a str, &'a [u8]>;
+
+/// What a drag carries.
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum DragData {
+    Text(String),
+    /// URIs that aren't local files, like links dragged out of a browser
+    Uris(Vec<String>),
+    /// Files and directories, fetched with [`DragFetch`] once dropped
+    Files(Vec<FileHeader>),
 }
 
 impl Event {
+    /// A key event for the Windows scan code `code`, prefix included.
+    pub fn key(code: u16, pressed: bool) -> Self {
+        Event::Keyboard {
+            key: code & 0xff,
+            pressed,
+            extended: code >> 8 == 0xe0,
+        }
+    }
+}
+
+impl<S, B> GenericEvent<S, B> {
     pub fn is_high_freq(&self) -> bool {
-        matches!(self, Event::MouseMotion { .. } | Event::MouseWheel { .. })
+        matches!(
+            self,
+            Self::MouseMotion { .. } | Self::MouseWheel { .. } | Self::GamepadAxis { .. }
+        )
     }
 
     pub fn kind(&self) -> EventKind {
         match self {
-            Event::MouseMotion { .. } | Event::MouseWheel { .. } | Event::MouseButton { .. } => {
-                EventKind::Mouse
-            }
-            Event::Keyboard { .. } => EventKind::Keyboard,
+            Self::MouseMotion { .. }
+            | Self::MouseWheel { .. }
+            | Self::MouseButton { .. }
+            | Self::MouseAbsolute { .. }
+            | Self::Park
+            | Self::GamepadAxis { .. }
+            | Self::GamepadRemoved { .. }
+            | Self::PadRing { .. }
+            | Self::PadStrip { .. } => EventKind::Mouse,
+            Self::Keyboard { .. }
+            | Self::Preedit { .. }
+            | Self::Commit { .. }
+            | Self::GamepadButton { .. }
+            | Self::PadButton { .. } => EventKind::Keyboard,
             _ => EventKind::Misc,
         }
     }
+
+    /// Whether this carries clipboard contents, sealed or not.
+    pub fn is_clipboard(&self) -> bool {
+        matches!(
+            self,
+            Self::TextClipboard { .. }
+                | Self::HtmlClipboard { .. }
+                | Self::ImageClipboard { .. }
+                | Self::RawImageClipboard { .. }
+                | Self::SealedClipboard { .. }
+        )
+    }
+}
+
+/// Formats a duration the way status and stats show it, like `42s`, `3m 5s` or `2h 10m`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// Puts the `0xe0` prefix back on the scan code of a key event, if it's extended.
+pub fn win_scan_code(key: u16, extended: bool) -> u16 {
+    if extended {
+        0xe000 | key
+    } else {
+        key
+    }
+}
+
+/// Line endings clipboard text is converted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[serde(rename_all = "snake_case")]
+pub enum LineEndings {
+    /// Whatever the text came with
+    Keep,
+    Lf,
+    Crlf,
+    /// CRLF on Windows, LF elsewhere
+    #[default]
+    Native,
+}
+
+/// How clipboard text is cleaned up on its way between machines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[serde(default)]
+pub struct TextNormalization {
+    pub line_endings: LineEndings,
+    /// Drop the NUL characters some programs copy along at the end
+    pub strip_trailing_nul: bool,
+    /// Drop a byte order mark at the start
+    pub strip_bom: bool,
+}
+
+impl Default for TextNormalization {
+    fn default() -> Self {
+        Self {
+            line_endings: LineEndings::Native,
+            strip_trailing_nul: true,
+            strip_bom: true,
+        }
+    }
+}
+
+impl TextNormalization {
+    pub fn apply(&self, text: &str) -> String {
+        let mut text = text;
+        if self.strip_bom {
+            text = text.strip_prefix('\u{feff}').unwrap_or(text);
+        }
+        if self.strip_trailing_nul {
+            text = text.trim_end_matches('\0');
+        }
+
+        let crlf = match self.line_endings {
+            LineEndings::Keep => return text.to_owned(),
+            LineEndings::Lf => false,
+            LineEndings::Crlf => true,
+            LineEndings::Native => cfg!(target_os = "windows"),
+        };
+
+        // Lone CRs are old Mac line endings, and are converted as well
+        let lf = text.replace("\r\n", "\n").replace('\r', "\n");
+        if crlf {
+            lf.replace('\n', "\r\n")
+        } else {
+            lf
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Packet {
     pub id: u64,
+    /// Counts the packets of one stream of a connection from 1, so clients can tell what
+    /// went missing. 0 for packets outside of these counts, like resent ones
+    pub seq: u64,
     pub event: Event,
+    /// Microseconds on the server's clock when it was sent out, 0 if not stamped.
+    /// Only differences between packets of one connection mean anything
+    pub time: u64,
 }
 
 impl Packet {
+    /// A packet to be stamped when it is sent out.
+    pub fn new(id: u64, event: Event) -> Self {
+        Self {
+            id,
+            seq: 0,
+            event,
+            time: 0,
+        }
+    }
+
+    /// Sets `seq` of a packet encoded with [`Packet::to_vec`], without decoding it.
+    ///
+    /// It always follows the 8 bytes of `id`, so encodings can be shared between
+    /// connections until the last moment.
+    pub fn set_seq(encoded: &mut [u8], seq: u64) {
+        encoded[8..16].copy_from_slice(&seq.to_le_bytes());
+    }
+
+    /// Splits a packet encoded with [`Packet::to_vec`] around its `seq`, so it can be written
+    /// out with another one in between without being copied.
+    pub fn around_seq(encoded: &[u8]) -> (&[u8], &[u8]) {
+        (&encoded[..8], &encoded[16..])
+    }
+
     pub fn to_vec(&self) -> Vec<u8> {
         bincode::serialize(self).unwrap()
     }
@@ -76,4 +439,963 @@ impl Packet {
     pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
         bincode::deserialize(slice)
     }
+
+    /// Reads a packet encoded like [`Packet::to_vec`] from a peer that may be hostile.
+    ///
+    /// Decoding fails instead of reading or allocating more than `limit` bytes.
+    pub fn from_reader<R: Read>(reader: R, limit: u64) -> bincode::Result<Self> {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(limit)
+            .deserialize_from(reader)
+    }
+}
+
+/// A [`Packet`] decoded without copying its clipboard contents out of the buffer.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PacketRef<'a> {
+    pub id: u64,
+    pub seq: u64,
+    #[serde(borrow)]
+    pub event: EventRef<'a>,
+    pub time: u64,
+}
+
+impl<'a> PacketRef<'a> {
+    /// Decodes a packet encoded with [`Packet::to_vec`], borrowing from `slice`.
+    pub fn from_slice(slice: &'a [u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+/// Encodings a client accepts for clipboard images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum ImageFormat {
+    /// [`Event::ImageClipboard`]
+    Png,
+    /// [`Event::RawImageClipboard`]
+    Rgba,
+}
+
+/// First message on the control stream, sent by the client right after connecting.
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ClientHello {
+    /// [`PROTOCOL_VERSION`] of the client, first so it can be read whatever follows, see
+    /// [`ClientHello::version_of`].
+    pub version: u32,
+    /// HMAC-SHA256 over the session's exporter value, keyed with the pre-shared key.
+    ///
+    /// The exporter value is unique to each TLS session, so a proof captured from one
+    /// connection is useless against any later one.
+    pub auth: Option<[u8; AUTH_PROOF_LEN]>,
+    /// Accepted clipboard image encodings, most preferred first.
+    pub image_formats: Vec<ImageFormat>,
+    /// Monitors at the time of connecting, updated on a [`UpstreamKind::Screens`] stream.
+    pub screens: ScreenLayout,
+    /// Friendly name to show for the client, its hostname unless configured.
+    pub name: String,
+    /// Identifies the client across connections, even when its address changes.
+    pub id: Uuid,
+    /// Keyboard layout the client types in, if it can tell.
+    pub layout: Option<String>,
+    /// Keep-alive and idle timeout the client is configured with.
+    pub timeouts: Timeouts,
+    /// Asks for every event on one stream, in the order sent, instead of a stream for each
+    /// kind with mouse motion ahead of the rest.
+    pub single_stream: bool,
+}
+
+impl ClientHello {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+
+    /// The [`ClientHello::version`] of an encoded hello, which may be one this end can't
+    /// decode otherwise.
+    pub fn version_of(slice: &[u8]) -> Option<u32> {
+        let version = slice.get(..4)?.try_into().ok()?;
+        Some(u32::from_le_bytes(version))
+    }
+}
+
+/// Reply to [`ClientHello`]. Input streams are only opened after `Accepted`.
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum ServerHello {
+    Accepted {
+        /// Keyboard layout the server's keys are meant for, if the client should switch to it
+        layout: Option<String>,
+        /// Key of the server's [`ClipboardSeal`], if it seals clipboard contents
+        clipboard_key: Option<[u8; CLIPBOARD_KEY_LEN]>,
+        /// What both ends settled on, see [`Timeouts::negotiate`]
+        timeouts: Timeouts,
+        /// Whether events come on one stream, as [`ClientHello::single_stream`] asked
+        single_stream: bool,
+        /// XKB layout the server's keyboard types in, if it can tell, see
+        /// [`Event::KeyboardLayout`]
+        xkb_layout: Option<String>,
+    },
+    Rejected,
+    /// The client speaks another [`PROTOCOL_VERSION`] than the server's `version`
+    Incompatible {
+        version: u32,
+    },
+}
+
+impl ServerHello {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+/// How often an end makes sure the connection is kept open while nothing else is sent, and
+/// how long it waits before giving up on a silent one. An `idle_ms` of 0 is no idle timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Timeouts {
+    pub keep_alive_ms: u64,
+    pub idle_ms: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            keep_alive_ms: 5000,
+            idle_ms: 10000,
+        }
+    }
+}
+
+impl Timeouts {
+    /// From `keep_alive_secs` and `idle_timeout_secs` as configured. Keep-alives must come
+    /// more often than the idle timeout, or a quiet connection closes between them.
+    pub fn from_secs(keep_alive_secs: u64, idle_secs: u64) -> Result<Timeouts, &'static str> {
+        let keep_alive_ms = keep_alive_secs
+            .max(1)
+            .checked_mul(1000)
+            .ok_or("keep_alive_secs is too large")?;
+        let idle_ms = idle_secs
+            .checked_mul(1000)
+            .ok_or("idle_timeout_secs is too large")?;
+        if idle_ms != 0 && keep_alive_ms >= idle_ms {
+            return Err("keep_alive_secs must be shorter than idle_timeout_secs");
+        }
+
+        Ok(Timeouts {
+            keep_alive_ms,
+            idle_ms,
+        })
+    }
+
+    /// The shorter of each, which is what a connection between the two ends gets: QUIC
+    /// closes it after the shorter idle timeout, and each end sends keep-alives on its own.
+    /// An end without an idle timeout takes the other's.
+    pub fn negotiate(&self, other: &Timeouts) -> Timeouts {
+        Timeouts {
+            keep_alive_ms: self.keep_alive_ms.min(other.keep_alive_ms),
+            idle_ms: match (self.idle_ms, other.idle_ms) {
+                (0, idle) | (idle, 0) => idle,
+                (ours, theirs) => ours.min(theirs),
+            },
+        }
+    }
+
+    pub fn keep_alive(&self) -> Duration {
+        Duration::from_millis(self.keep_alive_ms)
+    }
+
+    /// `None` if there is no idle timeout.
+    pub fn idle(&self) -> Option<Duration> {
+        (self.idle_ms != 0).then(|| Duration::from_millis(self.idle_ms))
+    }
+}
+
+impl fmt::Display for Timeouts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "keep-alive every {} ms, ", self.keep_alive_ms)?;
+        match self.idle_ms {
+            0 => write!(f, "no idle timeout"),
+            idle => write!(f, "idle timeout {} ms", idle),
+        }
+    }
+}
+
+/// Periodic report sent by the client on its activity stream.
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ActivityReport {
+    /// Time since someone last used the client's own keyboard or mouse, if known.
+    ///
+    /// Input injected by rkvm itself doesn't count as local activity.
+    pub idle_ms: Option<u64>,
+}
+
+impl ActivityReport {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+/// How the client wants pointer input, sent on a [`UpstreamKind::PointerMode`] stream
+/// whenever it changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct PointerMode {
+    /// A program on the client locked the pointer, so it must never be moved to a position
+    /// or held at the edges of the screen.
+    pub relative_only: bool,
+}
+
+impl PointerMode {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum LogLevel {
+    Warn,
+    Error,
+}
+
+/// A warning or error the client logged, sent on a [`UpstreamKind::Logs`] stream for the
+/// server to write to its own log.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct LogRecord {
+    pub level: LogLevel,
+    /// Module it was logged from
+    pub target: String,
+    pub message: String,
+}
+
+impl LogRecord {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+/// Input the client couldn't inject, sent on an [`UpstreamKind::InjectError`] stream once
+/// it starts failing, and not again until it worked in between.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct InjectError {
+    /// The system turned it away, as Windows does while a UAC prompt is in front
+    pub refused: bool,
+    pub message: String,
+}
+
+impl InjectError {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+/// One round of a clock sync on an [`UpstreamKind::TimeSync`] stream. The client sends its
+/// clock, and the server answers with it and its own, both in microseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct TimeProbe {
+    pub client_us: u64,
+    /// 0 until the server answered
+    pub server_us: u64,
+}
+
+impl TimeProbe {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+/// How often a stream that failed on a live connection was opened again lately, so one that
+/// keeps failing is given up on.
+#[derive(Debug, Default)]
+pub struct Reopens {
+    count: u32,
+    last: Option<Instant>,
+}
+
+impl Reopens {
+    /// Reopens allowed within [`Reopens::WINDOW`] of each other
+    pub const MAX: u32 = 5;
+    /// How long after the last reopen they are forgotten
+    pub const WINDOW: Duration = Duration::from_secs(60);
+
+    /// Counts a reopen now, returning whether it is allowed.
+    pub fn allow(&mut self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    /// [`Reopens::allow`] with the time given.
+    pub fn allow_at(&mut self, now: Instant) -> bool {
+        if self
+            .last
+            .is_some_and(|last| now.saturating_duration_since(last) > Self::WINDOW)
+        {
+            self.count = 0;
+        }
+        if self.count >= Self::MAX {
+            return false;
+        }
+
+        self.count += 1;
+        self.last = Some(now);
+        true
+    }
+}
+
+/// Most probes a [`ClockSync`] keeps, the oldest making way for new ones
+const CLOCK_SAMPLES: usize = 64;
+
+/// Estimates how far the server's clock is ahead of ours from answered [`TimeProbe`]s.
+///
+/// Each probe is taken to have been answered halfway through its round trip. The median
+/// of the offsets of the latest ones is used, so a few held up one way only don't throw it
+/// off.
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    offsets: Vec<i64>,
+}
+
+impl ClockSync {
+    /// Adds the answer to a probe, which arrived at `received_us` on our clock.
+    pub fn add(&mut self, probe: TimeProbe, received_us: u64) {
+        if received_us < probe.client_us {
+            return;
+        }
+
+        if self.offsets.len() == CLOCK_SAMPLES {
+            self.offsets.remove(0);
+        }
+        let midpoint = probe.client_us + (received_us - probe.client_us) / 2;
+        self.offsets.push(probe.server_us as i64 - midpoint as i64);
+    }
+
+    /// Microseconds to subtract from the server's clock to get ours, once a probe was added.
+    pub fn offset(&self) -> Option<i64> {
+        let mut offsets = self.offsets.clone();
+        offsets.sort_unstable();
+
+        let mid = offsets.len() / 2;
+        match offsets.len() {
+            0 => None,
+            n if n % 2 == 0 => Some((offsets[mid - 1] + offsets[mid]) / 2),
+            _ => Some(offsets[mid]),
+        }
+    }
+}
+
+/// One of the client's monitors.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Monitor {
+    /// Position on the client's desktop, in pixels
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    /// DPI relative to 96, so 1.5 for 144 DPI
+    pub scale: f32,
+    pub primary: bool,
+}
+
+impl Monitor {
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x
+            && y >= self.y
+            && i64::from(x) - i64::from(self.x) < i64::from(self.width)
+            && i64::from(y) - i64::from(self.y) < i64::from(self.height)
+    }
+}
+
+/// The client's monitors, empty if it couldn't list them.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ScreenLayout {
+    pub monitors: Vec<Monitor>,
+}
+
+impl ScreenLayout {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+/// First message on every stream the client opens, telling the server what it carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum UpstreamKind {
+    /// [`ActivityReport`]s
+    Activity,
+    /// [`Packet`]s captured for reverse control
+    Input,
+    /// A file transfer on a bidirectional stream, see [`FileManifest`]
+    Files,
+    /// A [`ClipboardFetch`] on a bidirectional stream, answered with the offered [`Packet`]
+    ClipboardFetch,
+    /// A [`DragFetch`] on a bidirectional stream, answered with the raw file contents
+    DragFetch,
+    /// A [`ScreenLayout`] whenever the client's monitors change
+    Screens,
+    /// A [`PointerMode`] whenever it changes
+    PointerMode,
+    /// An [`Ack`] for every misc packet handled
+    Acks,
+    /// The clipboard as a [`Packet`], when asked with [`Event::ClipboardRequest`]
+    Clipboard,
+    /// A [`LogRecord`] for every warning and error logged, if the client relays them
+    Logs,
+    /// [`TimeProbe`]s on a bidirectional stream, each answered in turn
+    TimeSync,
+    /// Asks for the attention of whoever sits at the server, carrying nothing else
+    Attention,
+    /// An [`InjectError`]
+    InjectError,
+}
+
+impl UpstreamKind {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+/// First byte on every stream the server opens, telling the client what it carries.
+///
+/// Streams may be opened at any point of a connection. Clients ignore tags they don't know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum StreamTag {
+    /// [`Packet`]s of [`EventKind::Mouse`]
+    Mouse = 0,
+    /// [`Packet`]s of [`EventKind::Keyboard`]
+    Keyboard = 1,
+    /// [`Packet`]s of [`EventKind::Misc`]
+    Misc = 2,
+    /// [`Packet`]s of every kind, see [`ClientHello::single_stream`]
+    Events = 3,
+    /// [`Event::Target`] whenever input starts or stops going to the client
+    Control = 4,
+    /// A misc [`Packet`] sent once more as it went unacked
+    Resend = 5,
+}
+
+impl StreamTag {
+    /// The tag of the stream for events of `kind`, every kind if `None`.
+    pub fn events(kind: Option<EventKind>) -> Self {
+        match kind {
+            Some(EventKind::Mouse) => Self::Mouse,
+            Some(EventKind::Keyboard) => Self::Keyboard,
+            Some(EventKind::Misc) => Self::Misc,
+            None => Self::Events,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        [
+            Self::Mouse,
+            Self::Keyboard,
+            Self::Misc,
+            Self::Events,
+            Self::Control,
+            Self::Resend,
+        ]
+        .into_iter()
+        .find(|tag| *tag as u8 == byte)
+    }
+}
+
+/// Confirms a [`Packet`] of [`EventKind::Misc`] was handled, for ids other than 0.
+///
+/// Packets without one in time are sent once more on a fresh stream, so the same id may
+/// arrive twice.
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Ack {
+    pub id: u64,
+}
+
+impl Ack {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+/// Accepts an [`Event::ClipboardOffer`].
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ClipboardFetch {
+    pub id: u64,
+}
+
+impl ClipboardFetch {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+/// Asks for the contents of a file in [`DragData::Files`].
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct DragFetch {
+    /// Of the [`Event::DragEnter`]
+    pub id: u64,
+    /// Into the file list
+    pub index: u32,
+}
+
+impl DragFetch {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+/// Paces bulk transfers to stay under a bandwidth cap, so they don't hold up input.
+#[derive(Debug)]
+pub struct Throttle {
+    /// Bytes per second, unlimited if `None`
+    rate: Option<u64>,
+    start: Instant,
+    sent: u64,
+}
+
+impl Throttle {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate: rate.filter(|&r| r > 0),
+            start: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Accounts for `len` more bytes, returning how long to wait before moving them.
+    pub fn delay(&mut self, len: usize) -> Duration {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return Duration::ZERO,
+        };
+
+        self.sent += len as u64;
+        let due = Duration::from_secs_f64(self.sent as f64 / rate as f64);
+        due.saturating_sub(self.start.elapsed())
+    }
+}
+
+/// File contents are sent in chunks of this size, each followed by its [`chunk_hash`].
+///
+/// Resumed transfers restart at a chunk boundary.
+pub const FILE_CHUNK_LEN: usize = 1024 * 1024;
+
+pub fn chunk_hash(chunk: &[u8]) -> [u8; 32] {
+    Sha256::digest(chunk).into()
+}
+
+/// Opens a files stream, listing everything that follows.
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct FileManifest {
+    /// Chosen by the sender and kept across reconnects, so the receiver finds what it
+    /// already has
+    pub id: u64,
+    /// Parent directories always come before their entries
+    pub entries: Vec<FileHeader>,
+    /// Sum of all file sizes
+    pub total_size: u64,
+}
+
+impl FileManifest {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+/// Sent by the receiver of a [`FileManifest`].
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum FileResponse {
+    /// Bytes of each entry already received, which the sender skips.
+    ///
+    /// The contents of every file then follow in manifest order, starting at its offset.
+    Resume { offsets: Vec<u64> },
+    /// Every file was received and verified
+    Complete,
+    /// The transfer is larger than the receiver accepts
+    Declined,
+}
+
+impl FileResponse {
+    pub fn to_vec(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_slice(slice: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(slice)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum FileKind {
+    /// Followed by `size` bytes of contents
+    File,
+    /// Has no contents, its entries follow as separate headers
+    Directory,
+}
+
+/// Describes one entry of a [`FileManifest`].
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct FileHeader {
+    /// Path relative to the transfer root, separated by `/`
+    pub path: String,
+    pub kind: FileKind,
+    pub size: u64,
+    /// Last modification, in milliseconds since the Unix epoch
+    pub modified: Option<u64>,
+}
+
+/// How far along a file transfer is, reported by both ends.
+#[derive(Debug, Clone)]
+pub struct TransferProgress {
+    /// Entry being transferred
+    pub path: String,
+    /// Bytes of the entry done so far
+    pub done: u64,
+    pub size: u64,
+    /// Bytes of the whole transfer done so far
+    pub total_done: u64,
+    pub total_size: u64,
+}
+
+impl TransferProgress {
+    /// Whether the current entry is complete.
+    pub fn file_done(&self) -> bool {
+        self.done == self.size
+    }
+}
+
+fn auth_mac(psk: &[u8], exporter: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(psk).expect("HMAC accepts keys of any length");
+    mac.update(exporter);
+    mac
+}
+
+/// Computes the proof of knowledge of `psk` bound to the session identified by `exporter`.
+pub fn auth_proof(psk: &[u8], exporter: &[u8]) -> [u8; AUTH_PROOF_LEN] {
+    auth_mac(psk, exporter).finalize().into_bytes().into()
+}
+
+/// Checks a proof produced by [`auth_proof`] in constant time.
+pub fn verify_auth_proof(psk: &[u8], exporter: &[u8], proof: &[u8]) -> bool {
+    auth_mac(psk, exporter).verify_slice(proof).is_ok()
+}
+
+/// How well a connection carries input, judged from its round trip time and packet loss.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LinkQuality {
+    Good,
+    Degraded,
+    Poor,
+}
+
+/// Where a connection stops being [`LinkQuality::Good`], and where it becomes
+/// [`LinkQuality::Poor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualityThresholds {
+    pub degraded_rtt: Duration,
+    pub degraded_loss_percent: u64,
+    pub poor_rtt: Duration,
+    pub poor_loss_percent: u64,
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        Self {
+            degraded_rtt: Duration::from_millis(150),
+            degraded_loss_percent: 2,
+            poor_rtt: Duration::from_millis(500),
+            poor_loss_percent: 10,
+        }
+    }
+}
+
+/// Packets sent since the last measurement before loss is measured again, so a quiet
+/// connection losing one of a handful doesn't look broken.
+const MIN_LOSS_SAMPLE: u64 = 50;
+
+/// Follows the quality of a connection from the totals it keeps.
+#[derive(Debug, Default)]
+pub struct LinkMonitor {
+    /// Totals when loss was last measured
+    sent: u64,
+    lost: u64,
+    loss_percent: u64,
+}
+
+impl LinkMonitor {
+    /// Quality now, given the round trip time and the packets sent and lost so far.
+    pub fn sample(
+        &mut self,
+        thresholds: &QualityThresholds,
+        rtt: Duration,
+        sent: u64,
+        lost: u64,
+    ) -> LinkQuality {
+        let sent_since = sent.saturating_sub(self.sent);
+        if sent_since >= MIN_LOSS_SAMPLE {
+            self.loss_percent = lost.saturating_sub(self.lost) * 100 / sent_since;
+            self.sent = sent;
+            self.lost = lost;
+        }
+
+        let loss = self.loss_percent;
+        if rtt >= thresholds.poor_rtt || loss >= thresholds.poor_loss_percent {
+            LinkQuality::Poor
+        } else if rtt >= thresholds.degraded_rtt || loss >= thresholds.degraded_loss_percent {
+            LinkQuality::Degraded
+        } else {
+            LinkQuality::Good
+        }
+    }
+}
+
+/// Seals clipboard events with ChaCha20-Poly1305, on top of TLS, so their contents aren't
+/// in the clear in buffers and logs on the way.
+///
+/// The server makes up a key whenever it starts and hands it to clients as they connect.
+#[derive(Debug)]
+pub struct ClipboardSeal {
+    key: LessSafeKey,
+}
+
+impl ClipboardSeal {
+    pub fn new(key: &[u8; CLIPBOARD_KEY_LEN]) -> Self {
+        let key = UnboundKey::new(&CHACHA20_POLY1305, key).expect("Key has the right length");
+        Self {
+            key: LessSafeKey::new(key),
+        }
+    }
+
+    /// A fresh random key.
+    pub fn generate_key() -> [u8; CLIPBOARD_KEY_LEN] {
+        let mut key = [0; CLIPBOARD_KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut key)
+            .expect("System random number generator failed");
+        key
+    }
+
+    /// `event` as an [`Event::SealedClipboard`].
+    pub fn seal(&self, event: &Event) -> Event {
+        let (nonce, sealed) = self.seal_bytes(bincode::serialize(event).unwrap());
+        Event::SealedClipboard { nonce, sealed }
+    }
+
+    /// The event sealed by [`seal`](Self::seal), unless it was sealed with another key or
+    /// tampered with.
+    pub fn open(&self, nonce: [u8; CLIPBOARD_NONCE_LEN], sealed: Vec<u8>) -> Option<Event> {
+        let opened = self.open_bytes(nonce, sealed)?;
+        bincode::deserialize(&opened).ok()
+    }
+
+    /// `data` sealed under a fresh nonce, returned with it.
+    pub fn seal_bytes(&self, mut data: Vec<u8>) -> ([u8; CLIPBOARD_NONCE_LEN], Vec<u8>) {
+        let mut nonce = [0; CLIPBOARD_NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("System random number generator failed");
+
+        let unique = Nonce::assume_unique_for_key(nonce);
+        self.key
+            .seal_in_place_append_tag(unique, Aad::empty(), &mut data)
+            .expect("Data fits in a sealed message");
+        (nonce, data)
+    }
+
+    /// The data sealed by [`seal_bytes`](Self::seal_bytes), unless it was sealed with
+    /// another key or tampered with.
+    pub fn open_bytes(
+        &self,
+        nonce: [u8; CLIPBOARD_NONCE_LEN],
+        mut sealed: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        let nonce = Nonce::assume_unique_for_key(nonce);
+        let len = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut sealed)
+            .ok()?
+            .len();
+        sealed.truncate(len);
+        Some(sealed)
+    }
+}
+
+/// SHA-256 digest of a DER certificate, the raw form of its fingerprint.
+pub fn cert_digest(der: &[u8]) -> [u8; 32] {
+    Sha256::digest(der).into()
+}
+
+/// Formats the SHA-256 fingerprint of a DER certificate as colon-separated uppercase hex.
+pub fn cert_fingerprint(der: &[u8]) -> String {
+    format_fingerprint(&cert_digest(der))
+}
+
+/// Formats a raw fingerprint the same way as [`cert_fingerprint`].
+pub fn format_fingerprint(fingerprint: &[u8; 32]) -> String {
+    fingerprint
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Parses a fingerprint as printed by [`cert_fingerprint`]. Colons and case are optional.
+pub fn parse_fingerprint(s: &str) -> Option<[u8; 32]> {
+    let hex = s.trim().replace(':', "");
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(out)
+}
+
+/// Checks whether `der` matches a fingerprint parsed by [`parse_fingerprint`].
+pub fn fingerprint_matches(der: &[u8], fingerprint: &[u8; 32]) -> bool {
+    &cert_digest(der) == fingerprint
+}
+
+/// Everything a client needs to connect to and trust a server.
+///
+/// Encoded as `rkvm://<address>:<port>?fp=<fingerprint>[&psk=<hex>]` so that it fits in a
+/// QR code or can be pasted as a single line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pairing {
+    pub address: String,
+    pub port: u16,
+    pub fingerprint: [u8; 32],
+    pub psk: Option<String>,
+}
+
+impl Pairing {
+    const SCHEME: &'static str = "rkvm://";
+
+    pub fn to_uri(&self) -> String {
+        let host = if self.address.contains(':') {
+            format!("[{}]", self.address)
+        } else {
+            self.address.clone()
+        };
+
+        let fingerprint = format_fingerprint(&self.fingerprint).replace(':', "");
+        let mut uri = format!("{}{}:{}?fp={}", Self::SCHEME, host, self.port, fingerprint);
+
+        if let Some(psk) = &self.psk {
+            let psk_hex = psk
+                .bytes()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            uri.push_str("&psk=");
+            uri.push_str(&psk_hex);
+        }
+
+        uri
+    }
+
+    pub fn from_uri(uri: &str) -> Option<Self> {
+        let rest = uri.trim().strip_prefix(Self::SCHEME)?;
+        let (authority, query) = rest.split_once('?')?;
+
+        let (host, port) = authority.rsplit_once(':')?;
+        let address = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let port = port.parse().ok()?;
+
+        let mut fingerprint = None;
+        let mut psk = None;
+        for pair in query.split('&') {
+            match pair.split_once('=')? {
+                ("fp", value) => fingerprint = Some(parse_fingerprint(value)?),
+                ("psk", value) => {
+                    if value.len() % 2 != 0 || !value.is_ascii() {
+                        return None;
+                    }
+                    let bytes = (0..value.len())
+                        .step_by(2)
+                        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+                        .collect::<Option<Vec<_>>>()?;
+                    psk = Some(String::from_utf8(bytes).ok()?);
+                }
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            address,
+            port,
+            fingerprint: fingerprint?,
+            psk,
+        })
+    }
 }
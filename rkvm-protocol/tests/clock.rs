@@ -0,0 +1,60 @@
+use rkvm_protocol::{ClockSync, TimeProbe};
+
+fn answered(client_us: u64, server_us: u64) -> TimeProbe {
+    TimeProbe {
+        client_us,
+        server_us,
+    }
+}
+
+#[test]
+fn offset_is_taken_halfway_through_the_round_trip() {
+    let mut sync = ClockSync::default();
+    assert_eq!(sync.offset(), None);
+
+    // Sent at 1000, answered at 50500 on the server, back at 2000
+    sync.add(answered(1000, 50500), 2000);
+    assert_eq!(sync.offset(), Some(49000));
+}
+
+#[test]
+fn probes_held_up_one_way_are_outvoted() {
+    let mut sync = ClockSync::default();
+    for start in [0, 10_000, 20_000, 30_000] {
+        sync.add(answered(start, start + 1_000_500), start + 1000);
+    }
+    // Held up 40 ms on the way back
+    sync.add(answered(40_000, 1_040_500), 81_000);
+
+    assert_eq!(sync.offset(), Some(1_000_000));
+}
+
+#[test]
+fn behind_servers_have_negative_offsets() {
+    let mut sync = ClockSync::default();
+    sync.add(answered(5_000_000, 1_000_500), 5_001_000);
+    sync.add(answered(5_010_000, 1_010_500), 5_011_000);
+
+    assert_eq!(sync.offset(), Some(-4_000_000));
+}
+
+#[test]
+fn answers_from_before_the_probe_are_ignored() {
+    let mut sync = ClockSync::default();
+    sync.add(answered(2000, 500), 1000);
+
+    assert_eq!(sync.offset(), None);
+}
+
+#[test]
+fn only_the_latest_probes_count() {
+    let mut sync = ClockSync::default();
+    for start in (0..200).map(|i| i * 10_000) {
+        sync.add(answered(start, start + 500), start + 1000);
+    }
+    for start in (200..400).map(|i| i * 10_000) {
+        sync.add(answered(start, start + 1_000_500), start + 1000);
+    }
+
+    assert_eq!(sync.offset(), Some(1_000_000));
+}
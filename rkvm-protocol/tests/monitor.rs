@@ -0,0 +1,35 @@
+//! Hit testing of monitors, whose geometry comes from the client.
+
+use rkvm_protocol::Monitor;
+
+fn monitor(x: i32, y: i32, width: u32, height: u32) -> Monitor {
+    Monitor {
+        x,
+        y,
+        width,
+        height,
+        scale: 1.0,
+        primary: false,
+    }
+}
+
+#[test]
+fn contains_its_pixels() {
+    let m = monitor(-1920, 0, 1920, 1080);
+    assert!(m.contains(-1920, 0));
+    assert!(m.contains(-1, 1079));
+    assert!(!m.contains(0, 0));
+    assert!(!m.contains(-1921, 0));
+    assert!(!m.contains(-1, 1080));
+}
+
+#[test]
+fn far_apart_coordinates_do_not_overflow() {
+    let m = monitor(i32::MIN, i32::MIN, 1920, 1080);
+    assert!(m.contains(i32::MIN, i32::MIN));
+    assert!(!m.contains(i32::MAX, i32::MAX));
+
+    let m = monitor(i32::MAX, i32::MAX, u32::MAX, u32::MAX);
+    assert!(m.contains(i32::MAX, i32::MAX));
+    assert!(!m.contains(i32::MIN, 0));
+}
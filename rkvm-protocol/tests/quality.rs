@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use rkvm_protocol::{LinkMonitor, LinkQuality, QualityThresholds};
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+#[test]
+fn slow_round_trips_degrade_the_link() {
+    let thresholds = QualityThresholds::default();
+    let mut monitor = LinkMonitor::default();
+
+    assert_eq!(monitor.sample(&thresholds, ms(20), 0, 0), LinkQuality::Good);
+    assert_eq!(
+        monitor.sample(&thresholds, ms(200), 0, 0),
+        LinkQuality::Degraded
+    );
+    assert_eq!(
+        monitor.sample(&thresholds, ms(800), 0, 0),
+        LinkQuality::Poor
+    );
+}
+
+#[test]
+fn loss_is_measured_over_enough_packets() {
+    let thresholds = QualityThresholds::default();
+    let mut monitor = LinkMonitor::default();
+
+    // One of a few lost is too little to go by
+    assert_eq!(
+        monitor.sample(&thresholds, ms(20), 10, 1),
+        LinkQuality::Good
+    );
+    assert_eq!(
+        monitor.sample(&thresholds, ms(20), 100, 5),
+        LinkQuality::Degraded
+    );
+    // Kept until enough were sent again
+    assert_eq!(
+        monitor.sample(&thresholds, ms(20), 110, 5),
+        LinkQuality::Degraded
+    );
+    assert_eq!(
+        monitor.sample(&thresholds, ms(20), 300, 45),
+        LinkQuality::Poor
+    );
+    assert_eq!(
+        monitor.sample(&thresholds, ms(20), 500, 45),
+        LinkQuality::Good
+    );
+}
@@ -0,0 +1,38 @@
+use std::time::{Duration, Instant};
+
+use rkvm_protocol::Reopens;
+
+#[test]
+fn reopens_run_out() {
+    let mut reopens = Reopens::default();
+    let now = Instant::now();
+
+    for _ in 0..Reopens::MAX {
+        assert!(reopens.allow_at(now));
+    }
+    assert!(!reopens.allow_at(now));
+}
+
+#[test]
+fn reopens_are_forgotten() {
+    let mut reopens = Reopens::default();
+    let now = Instant::now();
+
+    for _ in 0..Reopens::MAX {
+        assert!(reopens.allow_at(now));
+    }
+    let later = now + Reopens::WINDOW + Duration::from_secs(1);
+    assert!(reopens.allow_at(later));
+}
+
+#[test]
+fn reopens_close_together_add_up() {
+    let mut reopens = Reopens::default();
+    let mut now = Instant::now();
+
+    for _ in 0..Reopens::MAX {
+        assert!(reopens.allow_at(now));
+        now += Reopens::WINDOW / 2;
+    }
+    assert!(!reopens.allow_at(now));
+}
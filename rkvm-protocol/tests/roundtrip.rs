@@ -0,0 +1,438 @@
+//! Round trips of generated packets, and decoding of broken ones as a hostile peer sends them.
+
+use std::time::Duration;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rkvm_protocol::{
+    format_duration, win_scan_code, ClientHello, DragData, Event, EventKind, EventRef, FileHeader,
+    FileKind, GamepadAxis, GamepadButton, MouseButton, Packet, PacketRef, ScreenLayout, StreamTag,
+    Timeouts, Uuid, PROTOCOL_VERSION,
+};
+
+const CASES: usize = 1000;
+
+/// Number of [`Event`] variants, which [`variant`] keeps honest.
+const VARIANTS: usize = 29;
+
+/// Index of the variant of `event`, so no variant goes untested.
+fn variant(event: &Event) -> usize {
+    match event {
+        Event::MouseMotion { .. } => 0,
+        Event::MouseWheel { .. } => 1,
+        Event::MouseButton { .. } => 2,
+        Event::Keyboard { .. } => 3,
+        Event::TextClipboard { .. } => 4,
+        Event::HtmlClipboard { .. } => 5,
+        Event::ImageClipboard { .. } => 6,
+        Event::RawImageClipboard { .. } => 7,
+        Event::ClipboardOffer { .. } => 8,
+        Event::DragEnter { .. } => 9,
+        Event::DragCancel => 10,
+        Event::MouseAbsolute { .. } => 11,
+        Event::Park => 12,
+        Event::Preedit { .. } => 13,
+        Event::Commit { .. } => 14,
+        Event::GamepadButton { .. } => 15,
+        Event::GamepadAxis { .. } => 16,
+        Event::GamepadRemoved { .. } => 17,
+        Event::GameMode { .. } => 18,
+        Event::State { .. } => 19,
+        Event::Target { .. } => 20,
+        Event::PadRing { .. } => 21,
+        Event::PadStrip { .. } => 22,
+        Event::PadButton { .. } => 23,
+        Event::Suspend => 24,
+        Event::ClipboardRequest => 25,
+        Event::SealedClipboard { .. } => 26,
+        Event::KeyboardLayout { .. } => 27,
+        Event::Attention => 28,
+    }
+}
+
+/// Mostly short, sometimes up to `max` long, as clipboard contents are.
+fn len(rng: &mut StdRng, max: usize) -> usize {
+    if rng.gen_ratio(1, 10) {
+        rng.gen_range(0..=max)
+    } else {
+        rng.gen_range(0..=16)
+    }
+}
+
+fn string(rng: &mut StdRng, max: usize) -> String {
+    let len = len(rng, max);
+    (0..len).map(|_| rng.gen::<char>()).collect()
+}
+
+fn bytes(rng: &mut StdRng, max: usize) -> Vec<u8> {
+    let len = len(rng, max);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+fn drag_data(rng: &mut StdRng) -> DragData {
+    match rng.gen_range(0..3) {
+        0 => DragData::Text(string(rng, 4096)),
+        1 => DragData::Uris((0..len(rng, 8)).map(|_| string(rng, 256)).collect()),
+        _ => DragData::Files(
+            (0..len(rng, 8))
+                .map(|_| FileHeader {
+                    path: string(rng, 256),
+                    kind: if rng.gen() {
+                        FileKind::File
+                    } else {
+                        FileKind::Directory
+                    },
+                    size: rng.gen(),
+                    modified: rng.gen::<bool>().then(|| rng.gen()),
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// An event of the given variant, with random contents.
+fn event(rng: &mut StdRng, variant: usize) -> Event {
+    const BUTTONS: [GamepadButton; 3] = [
+        GamepadButton::South,
+        GamepadButton::Guide,
+        GamepadButton::DpadRight,
+    ];
+    const AXES: [GamepadAxis; 3] = [
+        GamepadAxis::LeftX,
+        GamepadAxis::RightY,
+        GamepadAxis::RightTrigger,
+    ];
+
+    match variant {
+        0 => Event::MouseMotion {
+            dx: rng.gen(),
+            dy: rng.gen(),
+        },
+        1 => Event::MouseWheel {
+            dx: rng.gen(),
+            dy: rng.gen(),
+        },
+        2 => Event::MouseButton {
+            button: match rng.gen_range(0..3) {
+                0 => MouseButton::Left,
+                1 => MouseButton::Middle,
+                _ => MouseButton::Right,
+            },
+            pressed: rng.gen(),
+        },
+        3 => Event::key(rng.gen(), rng.gen()),
+        4 => Event::TextClipboard {
+            content: string(rng, 64 * 1024),
+        },
+        5 => Event::HtmlClipboard {
+            html: string(rng, 64 * 1024),
+            plain: string(rng, 64 * 1024),
+        },
+        6 => Event::ImageClipboard {
+            png: bytes(rng, 256 * 1024),
+        },
+        7 => {
+            let (width, height) = (rng.gen_range(0..64), rng.gen_range(0..64));
+            Event::RawImageClipboard {
+                width,
+                height,
+                stride: width * 4,
+                rgba: (0..width * height * 4).map(|_| rng.gen()).collect(),
+            }
+        }
+        8 => Event::ClipboardOffer {
+            id: rng.gen(),
+            kind: string(rng, 64),
+            size: rng.gen(),
+        },
+        9 => Event::DragEnter {
+            id: rng.gen(),
+            data: drag_data(rng),
+        },
+        10 => Event::DragCancel,
+        11 => Event::MouseAbsolute {
+            x: rng.gen(),
+            y: rng.gen(),
+        },
+        12 => Event::Park,
+        13 => Event::Preedit {
+            text: string(rng, 256),
+        },
+        14 => Event::Commit {
+            text: string(rng, 256),
+        },
+        15 => Event::GamepadButton {
+            pad: rng.gen(),
+            button: BUTTONS[rng.gen_range(0..BUTTONS.len())],
+            pressed: rng.gen(),
+        },
+        16 => Event::GamepadAxis {
+            pad: rng.gen(),
+            axis: AXES[rng.gen_range(0..AXES.len())],
+            value: rng.gen(),
+        },
+        17 => Event::GamepadRemoved { pad: rng.gen() },
+        18 => Event::GameMode { enabled: rng.gen() },
+        19 => Event::State {
+            grabbed: rng.gen(),
+            active: rng.gen(),
+            active_client: rng.gen::<bool>().then(|| string(rng, 64)),
+            modifiers: (0..rng.gen_range(0..8)).map(|_| rng.gen()).collect(),
+            caps_lock: rng.gen(),
+            num_lock: rng.gen(),
+            scroll_lock: rng.gen(),
+        },
+        20 => Event::Target { active: rng.gen() },
+        21 => Event::PadRing {
+            ring: rng.gen(),
+            position: rng.gen::<bool>().then(|| rng.gen_range(0.0..360.0)),
+        },
+        22 => Event::PadStrip {
+            strip: rng.gen(),
+            position: rng.gen::<bool>().then(|| rng.gen()),
+        },
+        23 => Event::PadButton {
+            button: rng.gen(),
+            pressed: rng.gen(),
+        },
+        24 => Event::Suspend,
+        25 => Event::ClipboardRequest,
+        26 => Event::SealedClipboard {
+            nonce: rng.gen(),
+            sealed: bytes(rng, 64 * 1024),
+        },
+        27 => Event::KeyboardLayout {
+            xkb: rng.gen::<bool>().then(|| string(rng, 16)),
+        },
+        28 => Event::Attention,
+        _ => unreachable!(),
+    }
+}
+
+fn packets(seed: u64) -> impl Iterator<Item = Packet> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..CASES).map(move |i| {
+        let event = event(&mut rng, i % VARIANTS);
+        assert_eq!(variant(&event), i % VARIANTS);
+
+        let mut packet = Packet::new(rng.gen(), event);
+        packet.seq = rng.gen();
+        packet.time = rng.gen();
+        packet
+    })
+}
+
+#[test]
+fn every_variant_round_trips() {
+    for packet in packets(1) {
+        let data = packet.to_vec();
+
+        let decoded = Packet::from_slice(&data).unwrap();
+        assert_eq!(decoded.to_vec(), data);
+
+        let read = Packet::from_reader(data.as_slice(), data.len() as u64).unwrap();
+        assert_eq!(read.to_vec(), data);
+
+        let borrowed = PacketRef::from_slice(&data).unwrap();
+        assert_eq!(bincode::serialize(&borrowed).unwrap(), data);
+    }
+}
+
+#[test]
+fn extended_keys_round_trip() {
+    // Enter, numpad Enter, Up and the left Windows key
+    for code in [0x1c, 0xe01c, 0xe048, 0xe05b] {
+        let data = Packet::new(0, Event::key(code, true)).to_vec();
+        match Packet::from_slice(&data).unwrap().event {
+            Event::Keyboard {
+                key,
+                pressed: true,
+                extended,
+            } => assert_eq!(win_scan_code(key, extended), code),
+            event => panic!("Decoded {:?}", event),
+        }
+    }
+}
+
+#[test]
+fn borrowed_clipboard_is_not_copied() {
+    let event = Event::TextClipboard {
+        content: "clipboard ".repeat(1024),
+    };
+    let data = Packet::new(0, event).to_vec();
+
+    let content = match PacketRef::from_slice(&data).unwrap().event {
+        EventRef::TextClipboard { content } => content,
+        event => panic!("Decoded {:?}", event),
+    };
+    assert!(data.as_ptr_range().contains(&content.as_ptr()));
+}
+
+#[test]
+fn seq_is_set_in_place() {
+    for mut packet in packets(5) {
+        let mut data = packet.to_vec();
+
+        let seq = packet.seq.wrapping_add(1);
+        Packet::set_seq(&mut data, seq);
+        packet.seq = seq;
+        assert_eq!(data, packet.to_vec());
+    }
+}
+
+#[test]
+fn seq_splits_off() {
+    for mut packet in packets(5) {
+        let data = packet.to_vec();
+
+        let seq = packet.seq.wrapping_add(1);
+        let (before, after) = Packet::around_seq(&data);
+        let written = [before, &seq.to_le_bytes(), after].concat();
+        packet.seq = seq;
+        assert_eq!(written, packet.to_vec());
+    }
+}
+
+#[test]
+fn large_clipboard_round_trips() {
+    let content = "clipboard ".repeat(1024 * 1024);
+    let png = vec![0x89; 16 * 1024 * 1024];
+
+    for event in [
+        Event::TextClipboard { content },
+        Event::ImageClipboard { png },
+    ] {
+        let data = Packet::new(0, event).to_vec();
+        let read = Packet::from_reader(data.as_slice(), data.len() as u64).unwrap();
+        assert_eq!(read.to_vec(), data);
+    }
+}
+
+#[test]
+fn limit_is_enforced() {
+    let event = Event::ImageClipboard {
+        png: vec![0; 1024 * 1024],
+    };
+    let data = Packet::new(0, event).to_vec();
+
+    assert!(Packet::from_reader(data.as_slice(), 64 * 1024).is_err());
+}
+
+#[test]
+fn truncated_packets_fail() {
+    let mut rng = StdRng::seed_from_u64(2);
+
+    for packet in packets(3) {
+        let data = packet.to_vec();
+        let cut = rng.gen_range(0..data.len());
+
+        assert!(Packet::from_slice(&data[..cut]).is_err());
+        assert!(PacketRef::from_slice(&data[..cut]).is_err());
+        assert!(Packet::from_reader(&data[..cut], data.len() as u64).is_err());
+    }
+}
+
+#[test]
+fn garbage_does_not_panic() {
+    let mut rng = StdRng::seed_from_u64(4);
+
+    for packet in packets(5) {
+        // Valid packets with a few bytes flipped get furthest into decoding
+        let mut data = packet.to_vec();
+        for _ in 0..rng.gen_range(1..4) {
+            let i = rng.gen_range(0..data.len());
+            data[i] = rng.gen();
+        }
+        let _ = Packet::from_slice(&data);
+        let _ = PacketRef::from_slice(&data);
+        let _ = Packet::from_reader(data.as_slice(), 1024 * 1024);
+
+        let garbage: Vec<u8> = (0..rng.gen_range(0..64)).map(|_| rng.gen()).collect();
+        let _ = Packet::from_slice(&garbage);
+        let _ = Packet::from_reader(garbage.as_slice(), 1024 * 1024);
+    }
+}
+
+#[test]
+fn stream_tags_round_trip() {
+    for byte in 0..=u8::MAX {
+        if let Some(tag) = StreamTag::from_byte(byte) {
+            assert_eq!(tag as u8, byte);
+        }
+    }
+    for kind in [EventKind::Mouse, EventKind::Keyboard, EventKind::Misc] {
+        let tag = StreamTag::events(Some(kind));
+        assert_eq!(StreamTag::from_byte(tag as u8), Some(tag));
+    }
+    assert_eq!(StreamTag::from_byte(StreamTag::Resend as u8 + 1), None);
+}
+
+#[test]
+fn hello_version_reads_first() {
+    let hello = ClientHello {
+        version: PROTOCOL_VERSION,
+        auth: None,
+        image_formats: Vec::new(),
+        screens: ScreenLayout::default(),
+        name: "client".to_owned(),
+        id: Uuid::nil(),
+        layout: None,
+        timeouts: Timeouts::default(),
+        single_stream: false,
+    }
+    .to_vec();
+    assert_eq!(ClientHello::version_of(&hello), Some(PROTOCOL_VERSION));
+    assert_eq!(
+        ClientHello::from_slice(&hello).unwrap().version,
+        PROTOCOL_VERSION
+    );
+    assert_eq!(ClientHello::version_of(&hello[..3]), None);
+}
+
+/// Packets made up from random bytes, the way a fuzzer makes them up.
+#[cfg(feature = "arbitrary")]
+#[test]
+fn arbitrary_packets_round_trip() {
+    use arbitrary::{Arbitrary, Unstructured};
+
+    let mut rng = StdRng::seed_from_u64(8);
+    let mut seen = [false; VARIANTS];
+    for _ in 0..CASES * 10 {
+        let raw: Vec<u8> = (0..rng.gen_range(0..256)).map(|_| rng.gen()).collect();
+        let packet = match Packet::arbitrary(&mut Unstructured::new(&raw)) {
+            Ok(packet) => packet,
+            Err(_) => continue,
+        };
+        seen[variant(&packet.event)] = true;
+
+        let data = packet.to_vec();
+        assert_eq!(Packet::from_slice(&data).unwrap().to_vec(), data);
+        let borrowed = PacketRef::from_slice(&data).unwrap();
+        assert_eq!(bincode::serialize(&borrowed).unwrap(), data);
+    }
+    assert!(seen.iter().all(|&seen| seen));
+}
+
+#[test]
+fn durations_are_formatted() {
+    assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+    assert_eq!(format_duration(Duration::from_secs(185)), "3m 5s");
+    assert_eq!(format_duration(Duration::from_secs(7830)), "2h 10m");
+}
+
+#[test]
+fn timeouts_are_checked() {
+    let timeouts = Timeouts::from_secs(5, 10).unwrap();
+    assert_eq!((timeouts.keep_alive_ms, timeouts.idle_ms), (5000, 10000));
+    assert!(Timeouts::from_secs(10, 10).is_err());
+    assert!(Timeouts::from_secs(5, u64::MAX).is_err());
+    assert_eq!(Timeouts::from_secs(30, 0).unwrap().idle(), None);
+}
+
+#[test]
+fn no_idle_timeout_takes_the_other() {
+    let ours = Timeouts::from_secs(5, 0).unwrap();
+    let theirs = Timeouts::from_secs(2, 10).unwrap();
+    assert_eq!(ours.negotiate(&theirs), theirs);
+    assert_eq!(theirs.negotiate(&ours), theirs);
+    assert_eq!(ours.negotiate(&ours).idle_ms, 0);
+}
@@ -0,0 +1,57 @@
+use rkvm_protocol::{ClipboardSeal, Event};
+
+fn text(event: Option<Event>) -> Option<String> {
+    match event? {
+        Event::TextClipboard { content } => Some(content),
+        _ => None,
+    }
+}
+
+fn sealed(event: Event) -> ([u8; 12], Vec<u8>) {
+    match event {
+        Event::SealedClipboard { nonce, sealed } => (nonce, sealed),
+        event => panic!("Not sealed: {:?}", event),
+    }
+}
+
+#[test]
+fn sealed_clipboard_opens_with_the_same_key() {
+    let seal = ClipboardSeal::new(&ClipboardSeal::generate_key());
+    let content = "secret".to_owned();
+
+    let (nonce, sealed) = sealed(seal.seal(&Event::TextClipboard {
+        content: content.clone(),
+    }));
+    assert!(!sealed
+        .windows(content.len())
+        .any(|w| w == content.as_bytes()));
+    assert_eq!(text(seal.open(nonce, sealed)), Some(content));
+}
+
+#[test]
+fn sealed_clipboard_is_refused_with_another_key_or_changed() {
+    let seal = ClipboardSeal::new(&ClipboardSeal::generate_key());
+    let other = ClipboardSeal::new(&ClipboardSeal::generate_key());
+    let event = Event::TextClipboard {
+        content: "secret".to_owned(),
+    };
+
+    let (nonce, sealed) = sealed(seal.seal(&event));
+    assert!(other.open(nonce, sealed.clone()).is_none());
+
+    let mut changed = sealed;
+    changed[0] ^= 1;
+    assert!(seal.open(nonce, changed).is_none());
+}
+
+#[test]
+fn sealed_bytes_open_only_to_the_same_key() {
+    let seal = ClipboardSeal::new(&ClipboardSeal::generate_key());
+    let other = ClipboardSeal::new(&ClipboardSeal::generate_key());
+    let data = b"clipboard history".to_vec();
+
+    let (nonce, sealed) = seal.seal_bytes(data.clone());
+    assert_ne!(sealed[..data.len()], data[..]);
+    assert!(other.open_bytes(nonce, sealed.clone()).is_none());
+    assert_eq!(seal.open_bytes(nonce, sealed), Some(data));
+}
@@ -0,0 +1,33 @@
+use rkvm_protocol::{LineEndings, TextNormalization};
+
+fn normalize(line_endings: LineEndings, text: &str) -> String {
+    TextNormalization {
+        line_endings,
+        ..Default::default()
+    }
+    .apply(text)
+}
+
+#[test]
+fn line_endings_are_converted() {
+    let text = "one\r\ntwo\nthree\rfour";
+    assert_eq!(normalize(LineEndings::Lf, text), "one\ntwo\nthree\nfour");
+    assert_eq!(
+        normalize(LineEndings::Crlf, text),
+        "one\r\ntwo\r\nthree\r\nfour"
+    );
+    assert_eq!(normalize(LineEndings::Keep, text), text);
+}
+
+#[test]
+fn bom_and_trailing_nuls_are_stripped() {
+    let text = "\u{feff}text\n\0\0";
+    assert_eq!(normalize(LineEndings::Keep, text), "text\n");
+
+    let kept = TextNormalization {
+        line_endings: LineEndings::Keep,
+        strip_trailing_nul: false,
+        strip_bom: false,
+    };
+    assert_eq!(kept.apply(text), text);
+}
@@ -0,0 +1,74 @@
+//! Per-event latency and throughput of the way out, from filters over the wire to a client,
+//! as a baseline for performance work.
+//!
+//! Run with `cargo bench -p rkvm-server-core`. Numbers vary between machines, so compare
+//! runs on the same one.
+
+#[path = "../tests/harness/mod.rs"]
+mod harness;
+
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use harness::{loopback, synthetic_events};
+use rkvm_server_core::{Event, EventFilter, Verdict};
+
+/// Lets everything through, for what a loaded filter costs.
+struct Pass;
+
+impl EventFilter for Pass {
+    fn name(&self) -> &str {
+        "pass"
+    }
+
+    fn filter(&mut self, event: Event) -> Verdict {
+        Verdict::Keep(event)
+    }
+}
+
+fn filters(count: usize) -> Vec<Box<dyn EventFilter>> {
+    (0..count)
+        .map(|_| Box::new(Pass) as Box<dyn EventFilter>)
+        .collect()
+}
+
+fn throughput(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let count = 10_000;
+
+    let mut group = c.benchmark_group("throughput");
+    group.throughput(Throughput::Elements(count as u64));
+    group.sample_size(10);
+    for filter_count in [0, 4] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{} filters", filter_count)),
+            &filter_count,
+            |b, &filter_count| {
+                b.to_async(&runtime)
+                    .iter(|| loopback(synthetic_events(count), filters(filter_count), None))
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Mean time from capture to the client per event, paced like a 1000 Hz mouse so it isn't
+/// queueing behind a burst.
+fn latency(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("latency");
+    group.sample_size(10);
+    group.bench_function("paced", |b| {
+        b.iter_custom(|iters| {
+            let events = synthetic_events(iters as usize);
+            let pace = Some(Duration::from_millis(1));
+            let sink = runtime.block_on(loopback(events, filters(0), pace));
+            sink.received.iter().map(|r| r.latency).sum()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, throughput, latency);
+criterion_main!(benches);
@@ -0,0 +1,34 @@
+//! A filter swapping the left and right mouse buttons, for left-handed use on the clients.
+//!
+//! Built with `cargo build --example swap_buttons`, then listed in the server config as
+//! `filters = ["target/debug/examples/libswap_buttons.so"]`.
+
+use rkvm_server_core::{
+    export_filter,
+    rkvm_protocol::{Event, MouseButton},
+    EventFilter, Verdict,
+};
+
+struct SwapButtons;
+
+impl EventFilter for SwapButtons {
+    fn name(&self) -> &str {
+        "swap_buttons"
+    }
+
+    fn filter(&mut self, event: Event) -> Verdict {
+        let (button, pressed) = match event {
+            Event::MouseButton { button, pressed } => (button, pressed),
+            event => return Verdict::Keep(event),
+        };
+
+        let button = match button {
+            MouseButton::Left => MouseButton::Right,
+            MouseButton::Right => MouseButton::Left,
+            button => button,
+        };
+        Verdict::Keep(Event::MouseButton { button, pressed })
+    }
+}
+
+export_filter!(SwapButtons);
@@ -0,0 +1,166 @@
+//! How the server calls a filter in another library: through `extern "C"` functions, with
+//! events encoded by bincode, and panics caught on the side of the filter.
+
+use std::{
+    ffi::c_void,
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+};
+
+use crate::{Event, EventFilter, Verdict};
+
+/// Bytes allocated by the filter's library, and freed by it.
+#[repr(C)]
+pub struct RawBuf {
+    pub ptr: *mut u8,
+    pub len: usize,
+    pub cap: usize,
+}
+
+impl RawBuf {
+    fn new(bytes: Vec<u8>) -> Self {
+        let mut bytes = std::mem::ManuallyDrop::new(bytes);
+        Self {
+            ptr: bytes.as_mut_ptr(),
+            len: bytes.len(),
+            cap: bytes.capacity(),
+        }
+    }
+
+    fn empty() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+/// A filter as exported by [`export_filter!`](crate::export_filter).
+#[repr(C)]
+pub struct RawFilter {
+    pub filter: *mut c_void,
+    /// What [`EventFilter::name`] returned, in UTF-8
+    pub name: RawBuf,
+    /// Runs the filter on an encoded event, putting the encoded events it made into the last
+    /// argument. Returns false if the filter panicked, after which it mustn't be run again.
+    pub run: unsafe extern "C" fn(*mut c_void, *const u8, usize, *mut RawBuf) -> bool,
+    pub free: unsafe extern "C" fn(RawBuf),
+    pub drop: unsafe extern "C" fn(*mut c_void),
+}
+
+impl RawFilter {
+    /// Writes the filter made by `create` to `out`. Returns false if that panicked.
+    ///
+    /// # Safety
+    ///
+    /// `out` must be valid for writes.
+    pub unsafe fn create<F, C>(create: C, out: *mut RawFilter) -> bool
+    where
+        F: EventFilter + 'static,
+        C: FnOnce() -> F,
+    {
+        let created = panic::catch_unwind(AssertUnwindSafe(|| {
+            let filter = create();
+            let name = filter.name().as_bytes().to_vec();
+            (Box::new(filter), name)
+        }));
+        let Ok((filter, name)) = created else {
+            return false;
+        };
+
+        ptr::write(
+            out,
+            RawFilter {
+                filter: Box::into_raw(filter).cast(),
+                name: RawBuf::new(name),
+                run: run::<F>,
+                free,
+                drop: drop_filter::<F>,
+            },
+        );
+        true
+    }
+}
+
+unsafe extern "C" fn run<F: EventFilter>(
+    filter: *mut c_void,
+    event: *const u8,
+    len: usize,
+    out: *mut RawBuf,
+) -> bool {
+    let filter = &mut *filter.cast::<F>();
+    let event = slice::from_raw_parts(event, len);
+    let encoded = panic::catch_unwind(AssertUnwindSafe(|| {
+        let events = match filter.filter(bincode::deserialize(event).ok()?) {
+            Verdict::Keep(event) => vec![event],
+            Verdict::Drop => Vec::new(),
+            Verdict::Replace(events) => events,
+        };
+        bincode::serialize(&events).ok()
+    }));
+
+    match encoded {
+        Ok(Some(encoded)) => {
+            ptr::write(out, RawBuf::new(encoded));
+            true
+        }
+        _ => false,
+    }
+}
+
+unsafe extern "C" fn free(buf: RawBuf) {
+    drop(Vec::from_raw_parts(buf.ptr, buf.len, buf.cap));
+}
+
+unsafe extern "C" fn drop_filter<F>(filter: *mut c_void) {
+    let filter = Box::from_raw(filter.cast::<F>());
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(filter)));
+}
+
+/// A filter from another library, called through its [`RawFilter`].
+pub struct LoadedFilter {
+    raw: RawFilter,
+    name: String,
+}
+
+// Filters are Send, and only ever called from one thread at a time
+unsafe impl Send for LoadedFilter {}
+
+impl LoadedFilter {
+    /// # Safety
+    ///
+    /// `raw` must have been made by [`RawFilter::create`], with this version of the crate, in
+    /// a library that stays loaded until this is dropped.
+    pub unsafe fn new(raw: RawFilter) -> Self {
+        let name = slice::from_raw_parts(raw.name.ptr, raw.name.len);
+        let name = String::from_utf8_lossy(name).into_owned();
+        Self { raw, name }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// What the filter makes of `event`, or `None` if it panicked, and mustn't be run again.
+    pub fn filter(&mut self, event: &Event) -> Option<Vec<Event>> {
+        let event = bincode::serialize(event).ok()?;
+        let mut out = RawBuf::empty();
+
+        unsafe {
+            if !(self.raw.run)(self.raw.filter, event.as_ptr(), event.len(), &mut out) {
+                return None;
+            }
+
+            let events = bincode::deserialize(slice::from_raw_parts(out.ptr, out.len)).ok();
+            (self.raw.free)(out);
+            events
+        }
+    }
+}
+
+impl Drop for LoadedFilter {
+    fn drop(&mut self) {
+        unsafe {
+            (self.raw.drop)(self.raw.filter);
+            let name = std::mem::replace(&mut self.raw.name, RawBuf::empty());
+            (self.raw.free)(name);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! What the server shares with filters, which see events on their way to clients and may
+//! change or drop them.
+//!
+//! A filter is a shared library exporting itself with [`export_filter!`], listed in the
+//! `filters` option of the server config. Rust has no stable ABI, so the server calls it
+//! through a C one, with events encoded the way they are sent, and only the version of
+//! this crate has to match.
+
+pub mod abi;
+
+pub use abi::LoadedFilter;
+pub use rkvm_protocol;
+pub use rkvm_protocol::Event;
+
+/// Bumped whenever [`EventFilter`], [`Event`] or the [`abi`] change, so out of date filters
+/// aren't loaded.
+pub const API_VERSION: u32 = 2;
+
+/// What becomes of an event a filter saw.
+#[derive(Debug)]
+pub enum Verdict {
+    /// Sent on, changed or not
+    Keep(Event),
+    Drop,
+    /// Sent on as these events instead, in order
+    Replace(Vec<Event>),
+}
+
+impl Verdict {
+    /// The events sent on.
+    pub fn into_events(self) -> Vec<Event> {
+        match self {
+            Verdict::Keep(event) => vec![event],
+            Verdict::Drop => Vec::new(),
+            Verdict::Replace(events) => events,
+        }
+    }
+}
+
+/// Sees every event the server sends to clients, input and clipboard alike, in order.
+///
+/// Filters run one after another in the task sending events out, holding up every client,
+/// so they should be quick.
+pub trait EventFilter: Send {
+    /// Shown in the server log
+    fn name(&self) -> &str;
+
+    fn filter(&mut self, event: Event) -> Verdict;
+}
+
+/// Runs `event` through `filters` one after another, like the server does, each seeing
+/// what the ones before made of it.
+///
+/// `run` gives what a filter makes of an event, or `None` if it failed. A failed filter
+/// loses that event, is taken out of `filters` and returned, and the rest of the events
+/// pass it by.
+pub fn chain<F>(
+    filters: &mut Vec<F>,
+    event: Event,
+    mut run: impl FnMut(&mut F, Event) -> Option<Vec<Event>>,
+) -> (Vec<Event>, Vec<F>) {
+    let mut events = vec![event];
+    let mut failed = Vec::new();
+    let mut i = 0;
+    while i < filters.len() {
+        let mut next = Vec::with_capacity(events.len());
+        let mut events_left = events.into_iter();
+        let mut ok = true;
+        for event in events_left.by_ref() {
+            match run(&mut filters[i], event) {
+                Some(events) => next.extend(events),
+                None => {
+                    ok = false;
+                    break;
+                }
+            }
+        }
+
+        next.extend(events_left);
+        events = next;
+
+        if ok {
+            i += 1;
+        } else {
+            failed.push(filters.remove(i));
+        }
+    }
+
+    (events, failed)
+}
+
+/// Exports the filter made by `$create` from a `cdylib` crate, for the server to load.
+#[macro_export]
+macro_rules! export_filter {
+    ($create:expr) => {
+        #[no_mangle]
+        pub static RKVM_FILTER_API: u32 = $crate::API_VERSION;
+
+        /// # Safety
+        ///
+        /// `out` must be valid for writes.
+        #[no_mangle]
+        pub unsafe extern "C" fn rkvm_filter_create(out: *mut $crate::abi::RawFilter) -> bool {
+            $crate::abi::RawFilter::create(|| $create, out)
+        }
+    };
+}
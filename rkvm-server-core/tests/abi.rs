@@ -0,0 +1,76 @@
+use std::mem::MaybeUninit;
+
+use rkvm_server_core::{abi::RawFilter, rkvm_protocol::Event, EventFilter, LoadedFilter, Verdict};
+
+/// Doubles scrolling and panics on keys.
+struct Doubler;
+
+impl EventFilter for Doubler {
+    fn name(&self) -> &str {
+        "doubler"
+    }
+
+    fn filter(&mut self, event: Event) -> Verdict {
+        match event {
+            Event::MouseWheel { dx, dy } => Verdict::Replace(vec![
+                Event::MouseWheel { dx, dy },
+                Event::MouseWheel { dx, dy },
+            ]),
+            Event::Keyboard { .. } => panic!("keys"),
+            event => Verdict::Keep(event),
+        }
+    }
+}
+
+fn load() -> LoadedFilter {
+    let mut raw = MaybeUninit::uninit();
+    assert!(unsafe { RawFilter::create(|| Doubler, raw.as_mut_ptr()) });
+    unsafe { LoadedFilter::new(raw.assume_init()) }
+}
+
+#[test]
+fn events_cross_the_abi() {
+    let mut filter = load();
+    assert_eq!(filter.name(), "doubler");
+
+    let wheel = Event::MouseWheel { dx: 0, dy: -1 };
+    let events = filter.filter(&wheel).unwrap();
+    assert_eq!(events.len(), 2);
+    assert!(events
+        .iter()
+        .all(|event| matches!(event, Event::MouseWheel { dx: 0, dy: -1 })));
+
+    let motion = Event::MouseMotion { dx: 3, dy: 4 };
+    let events = filter.filter(&motion).unwrap();
+    assert!(matches!(events[..], [Event::MouseMotion { dx: 3, dy: 4 }]));
+}
+
+#[test]
+fn panics_stay_in_the_filter() {
+    let mut filter = load();
+    assert!(filter.filter(&Event::key(0x1e, true)).is_none());
+}
+
+#[test]
+fn panics_while_created_are_reported() {
+    let mut raw = MaybeUninit::uninit();
+    let created =
+        unsafe { RawFilter::create(|| -> Doubler { panic!("create") }, raw.as_mut_ptr()) };
+    assert!(!created);
+}
+
+#[test]
+fn chain_drops_a_filter_that_panicked() {
+    let mut filters = vec![load(), load()];
+    let run = |filter: &mut LoadedFilter, event| filter.filter(&event);
+
+    let (events, failed) =
+        rkvm_server_core::chain(&mut filters, Event::MouseWheel { dx: 0, dy: -1 }, run);
+    assert_eq!(events.len(), 4);
+    assert!(failed.is_empty());
+
+    let (events, failed) = rkvm_server_core::chain(&mut filters, Event::key(0x1e, true), run);
+    assert!(events.is_empty());
+    assert_eq!(failed.len(), 1);
+    assert_eq!(filters.len(), 1);
+}
@@ -0,0 +1,139 @@
+//! Events run through filters and sent over a loopback QUIC connection, framed the way the
+//! server frames them, into a client that records instead of injecting them.
+
+// Shared by tests and benchmarks, which each use only part of it
+#![allow(dead_code)]
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use quinn::{ClientConfig, Endpoint, ServerConfig};
+use rkvm_server_core::{
+    rkvm_protocol::{Event, MouseButton, Packet},
+    EventFilter,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+/// A mix of events like a busy user produces: mostly motion, some keys, scrolling and clicks.
+pub fn synthetic_events(count: usize) -> Vec<Event> {
+    (0..count)
+        .map(|i| match i % 16 {
+            0 => Event::key(0x1e, true),
+            1 => Event::key(0x1e, false),
+            2 => Event::MouseWheel { dx: 0, dy: -1 },
+            3 => Event::MouseButton {
+                button: MouseButton::Left,
+                pressed: i % 32 == 3,
+            },
+            _ => Event::MouseMotion {
+                dx: (i % 7) as i32 - 3,
+                dy: (i % 5) as i32 - 2,
+            },
+        })
+        .collect()
+}
+
+/// A packet as it reached the client.
+pub struct Received {
+    pub packet: Packet,
+    /// From being stamped by the sender to being decoded
+    pub latency: Duration,
+}
+
+/// Stands in for the client's input injection, keeping everything it is given.
+#[derive(Default)]
+pub struct MockSink {
+    pub received: Vec<Received>,
+}
+
+impl MockSink {
+    fn inject(&mut self, packet: Packet, latency: Duration) {
+        self.received.push(Received { packet, latency });
+    }
+}
+
+/// What `filters` make of `event`, one after another like the server runs them.
+pub fn filter(filters: &mut Vec<Box<dyn EventFilter>>, event: Event) -> Vec<Event> {
+    let (events, _) = rkvm_server_core::chain(filters, event, |filter, event| {
+        Some(filter.filter(event).into_events())
+    });
+    events
+}
+
+fn endpoints() -> (Endpoint, Endpoint) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let der = rustls::Certificate(cert.serialize_der().unwrap());
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+
+    let server_config = ServerConfig::with_single_cert(vec![der.clone()], key).unwrap();
+    let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let server = Endpoint::server(server_config, any).unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&der).unwrap();
+    let mut client = Endpoint::client(any).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+
+    (server, client)
+}
+
+/// Sends what `filters` make of `events` to a loopback client, waiting `pace` between them,
+/// and returns what it got.
+pub async fn loopback(
+    events: Vec<Event>,
+    mut filters: Vec<Box<dyn EventFilter>>,
+    pace: Option<Duration>,
+) -> MockSink {
+    let (server, client) = endpoints();
+    let addr = server.local_addr().unwrap();
+    let start = Instant::now();
+
+    let sender = tokio::spawn(async move {
+        let conn = server.accept().await.unwrap().await.unwrap();
+        let mut stream = BufWriter::new(conn.open_uni().await.unwrap());
+
+        let mut id = 0;
+        for event in events {
+            // Stamped when captured, so the filters count towards latency
+            let time = start.elapsed().as_micros() as u64;
+            for event in filter(&mut filters, event) {
+                let mut packet = Packet::new(id, event);
+                packet.time = time;
+                id += 1;
+
+                let data = packet.to_vec();
+                stream.write_u32(data.len() as u32).await.unwrap();
+                stream.write_all(&data).await.unwrap();
+            }
+            stream.flush().await.unwrap();
+
+            if let Some(pace) = pace {
+                tokio::time::sleep(pace).await;
+            }
+        }
+
+        stream.shutdown().await.unwrap();
+        // Keeps the connection open until the client read everything
+        conn.closed().await;
+    });
+
+    let conn = client.connect(addr, "localhost").unwrap().await.unwrap();
+    let mut stream = BufReader::new(conn.accept_uni().await.unwrap());
+    let mut sink = MockSink::default();
+    let mut buf = Vec::new();
+
+    while let Ok(len) = stream.read_u32().await {
+        buf.resize(len as usize, 0);
+        stream.read_exact(&mut buf).await.unwrap();
+
+        let packet = Packet::from_slice(&buf).unwrap();
+        let latency = start.elapsed() - Duration::from_micros(packet.time);
+        sink.inject(packet, latency);
+    }
+
+    conn.close(0u32.into(), b"Done");
+    sender.await.unwrap();
+    sink
+}
@@ -0,0 +1,92 @@
+mod harness;
+
+use harness::{loopback, synthetic_events};
+use rkvm_server_core::{
+    rkvm_protocol::{Event, MouseButton},
+    EventFilter, Verdict,
+};
+
+/// Swaps the mouse buttons, drops keys and doubles scrolling.
+struct Rewrite;
+
+impl EventFilter for Rewrite {
+    fn name(&self) -> &str {
+        "rewrite"
+    }
+
+    fn filter(&mut self, event: Event) -> Verdict {
+        match event {
+            Event::MouseButton {
+                button: MouseButton::Left,
+                pressed,
+            } => Verdict::Keep(Event::MouseButton {
+                button: MouseButton::Right,
+                pressed,
+            }),
+            Event::Keyboard { .. } => Verdict::Drop,
+            Event::MouseWheel { dx, dy } => Verdict::Replace(vec![
+                Event::MouseWheel { dx, dy },
+                Event::MouseWheel { dx, dy },
+            ]),
+            event => Verdict::Keep(event),
+        }
+    }
+}
+
+#[tokio::test]
+async fn every_event_arrives_in_order() {
+    let sink = loopback(synthetic_events(2000), Vec::new(), None).await;
+
+    let expected = synthetic_events(2000);
+    assert_eq!(sink.received.len(), expected.len());
+    for (i, (received, event)) in sink.received.iter().zip(&expected).enumerate() {
+        assert_eq!(received.packet.id, i as u64);
+        assert_eq!(
+            format!("{:?}", received.packet.event),
+            format!("{:?}", event)
+        );
+    }
+}
+
+#[tokio::test]
+async fn filters_change_what_arrives() {
+    let sink = loopback(synthetic_events(2000), vec![Box::new(Rewrite)], None).await;
+
+    let mut filters: Vec<Box<dyn EventFilter>> = vec![Box::new(Rewrite)];
+    let expected: Vec<_> = synthetic_events(2000)
+        .into_iter()
+        .flat_map(|event| harness::filter(&mut filters, event))
+        .collect();
+    assert_eq!(sink.received.len(), expected.len());
+    for (received, event) in sink.received.iter().zip(&expected) {
+        assert_eq!(
+            format!("{:?}", received.packet.event),
+            format!("{:?}", event)
+        );
+    }
+
+    let wheels = |events: &[Event]| {
+        events
+            .iter()
+            .filter(|e| matches!(e, Event::MouseWheel { .. }))
+            .count()
+    };
+    assert!(!expected.iter().any(|e| matches!(e, Event::Keyboard { .. })));
+    assert_eq!(wheels(&expected), 2 * wheels(&synthetic_events(2000)));
+}
+
+#[tokio::test]
+async fn paced_events_are_stamped() {
+    let sink = loopback(
+        synthetic_events(50),
+        Vec::new(),
+        Some(std::time::Duration::from_millis(1)),
+    )
+    .await;
+
+    assert_eq!(sink.received.len(), 50);
+    assert!(sink
+        .received
+        .windows(2)
+        .all(|w| w[0].packet.time < w[1].packet.time));
+}
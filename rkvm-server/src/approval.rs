@@ -0,0 +1,221 @@
+//! Holding clients never seen before until someone here lets them in, with
+//! `approve_new_clients`.
+//!
+//! A client is known once it has a slot, which it gets on being let in. Until then its
+//! hello goes unanswered, so it gets neither input nor the clipboard key. A notification
+//! asks whether to let it in, and the control socket takes `pending`, `approve <client>`
+//! and `reject <client>` too. Clients nobody answers for within `approval_timeout_secs`
+//! are turned away.
+//!
+//! What is let in is the client's certificate, so a client has to present one and another
+//! machine claiming its stable id is asked about again. Turned away certificates are not
+//! asked about again until the server restarts. Since anyone who can reach the port can
+//! ask, each address gets one prompt a minute and only a few clients wait at a time;
+//! the rest are turned away without one.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rkvm_protocol::{ClientHello, Uuid};
+use tokio::sync::oneshot;
+
+use crate::{
+    audit::{self, AuditEvent},
+    config::Config,
+    known, notify,
+};
+
+struct Pending {
+    /// Connection stable id
+    id: usize,
+    name: String,
+    uuid: Uuid,
+    addr: SocketAddr,
+    fingerprint: String,
+    decide: Option<oneshot::Sender<bool>>,
+}
+
+impl Pending {
+    /// Whether `key` is the name, stable id, IP address or fingerprint of this client.
+    fn is_known_by(&self, key: &str) -> bool {
+        self.name == key
+            || self.uuid.to_string() == key
+            || self.addr.ip().to_string() == key
+            || self.fingerprint == key
+    }
+}
+
+/// How long an address waits between prompts
+const PROMPT_INTERVAL: Duration = Duration::from_secs(60);
+/// How many clients wait to be approved at most
+const MAX_PENDING: usize = 4;
+
+static PENDING: Mutex<Vec<Pending>> = Mutex::new(Vec::new());
+/// Addresses by when they were last prompted for
+static PROMPTED: Mutex<Vec<(IpAddr, Instant)>> = Mutex::new(Vec::new());
+/// Fingerprints of the certificates turned away
+static REJECTED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Forgets a client once it is decided on or gone, however waiting for it ended.
+struct Entry(usize);
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        PENDING.lock().unwrap().retain(|p| p.id != self.0);
+    }
+}
+
+/// Whether the client with stable id `uuid` presenting the certificate with `fingerprint`
+/// has to be let in first.
+pub fn needed(config: &Config, uuid: Uuid, fingerprint: Option<&str>) -> bool {
+    config.approve_new_clients && !fingerprint.is_some_and(|fp| known::is_approved(uuid, fp))
+}
+
+/// Whether a prompt may be shown for a client from `ip`, counting it as shown if so.
+fn may_prompt(ip: IpAddr, fingerprint: &str) -> bool {
+    let pending = PENDING.lock().unwrap();
+    if pending.len() >= MAX_PENDING || pending.iter().any(|p| p.fingerprint == fingerprint) {
+        return false;
+    }
+
+    let now = Instant::now();
+    let mut prompted = PROMPTED.lock().unwrap();
+    prompted.retain(|(_, at)| now.duration_since(*at) < PROMPT_INTERVAL);
+    if prompted.iter().any(|(prompted, _)| *prompted == ip) {
+        return false;
+    }
+    prompted.push((ip, now));
+    true
+}
+
+/// Holds the client on connection `id` until it is let in or turned away, returning
+/// whether it was let in.
+pub async fn wait(
+    id: usize,
+    hello: &ClientHello,
+    addr: SocketAddr,
+    fingerprint: Option<&str>,
+    timeout: Duration,
+) -> bool {
+    if fingerprint.is_some_and(|fp| REJECTED.lock().unwrap().iter().any(|r| r == fp)) {
+        log::debug!("Client {} was turned away before", hello.name);
+        return false;
+    }
+    let Some(fingerprint) = fingerprint else {
+        log::warn!(
+            "Client {} presents no certificate to be approved by, turning it away",
+            hello.name
+        );
+        return false;
+    };
+    if !may_prompt(addr.ip(), fingerprint) {
+        log::debug!(
+            "Turning away client {} from {} without asking, too many asked for",
+            hello.name,
+            addr.ip()
+        );
+        return false;
+    }
+
+    let (decide, decided) = oneshot::channel();
+    PENDING.lock().unwrap().push(Pending {
+        id,
+        name: hello.name.clone(),
+        uuid: hello.id,
+        addr,
+        fingerprint: fingerprint.to_owned(),
+        decide: Some(decide),
+    });
+    let _entry = Entry(id);
+    log::info!(
+        "New client {} with id {} and certificate {} waits to be approved",
+        hello.name,
+        hello.id,
+        fingerprint
+    );
+
+    let summary = format!("{} wants to connect", hello.name);
+    let body = format!(
+        "A client never seen before connects from {}. Let it in to stream input to it.",
+        addr.ip()
+    );
+    let prompt = tokio::spawn(async move {
+        let actions = [("approve", "Let in"), ("reject", "Turn away")];
+        match notify::ask(&summary, &body, &actions, timeout).await {
+            Ok(Some(key)) => decide_id(id, key == "approve"),
+            Ok(None) => {}
+            Err(e) => log::debug!("Failed to show notification: {}", e),
+        }
+    });
+
+    let approved = match tokio::time::timeout(timeout, decided).await {
+        Ok(Ok(true)) => true,
+        Ok(Ok(false)) => {
+            REJECTED.lock().unwrap().push(fingerprint.to_owned());
+            false
+        }
+        Ok(Err(_)) => false,
+        Err(_) => {
+            log::warn!(
+                "Client {} wasn't approved within {:?}, turning it away",
+                hello.name,
+                timeout
+            );
+            false
+        }
+    };
+    prompt.abort();
+
+    if approved {
+        log::info!("Client {} was approved", hello.name);
+    } else {
+        log::warn!("Client {} was turned away", hello.name);
+    }
+    audit::record(AuditEvent::Approval {
+        client: &hello.name,
+        id: hello.id.to_string(),
+        approved,
+    });
+    approved
+}
+
+fn decide_id(id: usize, approved: bool) {
+    let mut pending = PENDING.lock().unwrap();
+    if let Some(decide) = pending
+        .iter_mut()
+        .find(|p| p.id == id)
+        .and_then(|p| p.decide.take())
+    {
+        let _ = decide.send(approved);
+    }
+}
+
+/// Lets in or turns away the waiting client known by `key`, its name, stable id, IP
+/// address or fingerprint, returning whether one was waiting.
+pub fn decide(key: &str, approved: bool) -> bool {
+    let mut pending = PENDING.lock().unwrap();
+    match pending
+        .iter_mut()
+        .find(|p| p.decide.is_some() && p.is_known_by(key))
+        .and_then(|p| p.decide.take())
+    {
+        Some(decide) => {
+            let _ = decide.send(approved);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The clients waiting to be approved, by name, stable id, address and fingerprint.
+pub fn pending() -> Vec<String> {
+    let pending = PENDING.lock().unwrap();
+    pending
+        .iter()
+        .filter(|p| p.decide.is_some())
+        .map(|p| format!("{} {} {} {}", p.name, p.uuid, p.addr.ip(), p.fingerprint))
+        .collect()
+}
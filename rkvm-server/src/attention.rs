@@ -0,0 +1,63 @@
+//! Getting the attention of whoever sits at the other machine, with a chime or a flash of
+//! its screen border.
+//!
+//! Each way goes at most once every few seconds, so a held key or a busy button doesn't
+//! turn into an alarm.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use rkvm_protocol::{Event, Packet};
+use tokio::sync::mpsc::Sender;
+
+use crate::{clients, config, notify, sound};
+
+const MIN_INTERVAL: Duration = Duration::from_secs(3);
+
+/// When the active client was last asked for attention
+static SENT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// When a client last asked for ours
+static RECEIVED: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Whether the last time in `last` is long enough ago, noting now as the last if so.
+fn allowed(last: &Mutex<Option<Instant>>) -> bool {
+    let mut last = last.lock().unwrap();
+    if last.is_some_and(|at| at.elapsed() < MIN_INTERVAL) {
+        return false;
+    }
+    *last = Some(Instant::now());
+    true
+}
+
+/// Asks for the attention of whoever sits at the active client.
+pub fn request(event_tx: &Sender<Packet>) -> Result<()> {
+    if clients::active().is_none() {
+        anyhow::bail!("no client is connected");
+    }
+    if !allowed(&SENT) {
+        anyhow::bail!("asked too recently");
+    }
+    // Never waits, as it is asked for from the input thread too
+    event_tx
+        .try_send(Packet::new(0, Event::Attention))
+        .map_err(|_| anyhow::anyhow!("too much input queued"))
+}
+
+/// Gets the attention of whoever sits here, as `client` asked.
+pub async fn received(client: &str) {
+    if !allowed(&RECEIVED) {
+        log::debug!("Ignoring another request for attention from {}", client);
+        return;
+    }
+    log::info!("{} asks for attention", client);
+
+    sound::play(&config::current().sounds, sound::Cue::Attention);
+    let summary = format!("{} asks for attention", client);
+    if let Err(e) = notify::notify(&summary, "Someone at the client wants you").await {
+        log::debug!("Failed to show notification: {}", e);
+    }
+}
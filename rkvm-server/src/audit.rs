@@ -0,0 +1,113 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+static AUDIT_LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Something that happened to forwarded input. Clipboard content is never recorded.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent<'a> {
+    Connected {
+        client: &'a str,
+        id: String,
+        addr: String,
+    },
+    Disconnected {
+        client: &'a str,
+    },
+    /// A client never seen before, let in or turned away
+    Approval {
+        client: &'a str,
+        id: String,
+        approved: bool,
+    },
+    Grab {
+        client: Option<String>,
+    },
+    Ungrab,
+    Switch {
+        client: &'a str,
+    },
+    Clipboard {
+        kind: &'a str,
+        size: usize,
+        sha256: String,
+        client: Option<String>,
+    },
+    Files {
+        client: &'a str,
+        count: usize,
+        size: u64,
+    },
+    /// A client's clipboard, taken when asked for
+    PulledClipboard {
+        client: &'a str,
+        kind: &'a str,
+        size: usize,
+        sha256: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    /// Milliseconds since the Unix epoch
+    time: u128,
+    #[serde(flatten)]
+    event: AuditEvent<'a>,
+}
+
+/// Opens the audit log for appending. Until this is called, [`record`] does nothing.
+pub fn init(path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Open audit log {:?}", path))?;
+
+    let _ = AUDIT_LOG.set(Mutex::new(file));
+
+    Ok(())
+}
+
+pub fn record(event: AuditEvent) {
+    let log = if let Some(log) = AUDIT_LOG.get() {
+        log
+    } else {
+        return;
+    };
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+
+    let mut line = match serde_json::to_string(&Entry { time, event }) {
+        Ok(line) => line,
+        Err(e) => {
+            log::error!("Failed to serialize audit entry: {}", e);
+            return;
+        }
+    };
+    line.push('\n');
+
+    let mut file = log.lock().unwrap();
+    if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+        log::error!("Failed to write audit log: {}", e);
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
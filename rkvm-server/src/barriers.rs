@@ -0,0 +1,180 @@
+//! Switching clients by pushing the cursor against an edge of the screen, under X11.
+//!
+//! XFixes pointer barriers line the configured edges, and XInput reports every push into
+//! one. Once enough motion went into an edge within a second, the main loop grabs and
+//! switches to the client of that edge.
+//!
+//! Pushes count while another application holds the pointer too, as the source of a drag
+//! does. The grab then hands the drag on to the client, see [`Controller::grab`].
+//!
+//! [`Controller::grab`]: crate::controller::Controller::grab
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xfixes::{BarrierDirections, ConnectionExt as _},
+        xinput::{self, ConnectionExt as _, Fp3232},
+        Event,
+    },
+};
+
+use crate::{
+    config::{self, EdgeSwitchConfig},
+    grab,
+};
+
+/// Pushes are added up over this long.
+const PUSH_WINDOW: Duration = Duration::from_secs(1);
+
+/// Client an edge was pushed towards, left for the main loop
+static SWITCH: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy)]
+enum Edge {
+    Left,
+    Right,
+    Top,
+    Bottom,
+}
+
+const EDGES: [Edge; 4] = [Edge::Left, Edge::Right, Edge::Top, Edge::Bottom];
+
+fn client(config: &EdgeSwitchConfig, edge: Edge) -> Option<&String> {
+    match edge {
+        Edge::Left => config.left.as_ref(),
+        Edge::Right => config.right.as_ref(),
+        Edge::Top => config.top.as_ref(),
+        Edge::Bottom => config.bottom.as_ref(),
+    }
+}
+
+fn to_f64(value: Fp3232) -> f64 {
+    value.integral as f64 + value.frac as f64 / (1u64 << 32) as f64
+}
+
+/// Sets up barriers on the edges configured at startup, if any.
+pub fn start() {
+    let config = config::current().edge_switch.clone();
+    if EDGES.iter().all(|edge| client(&config, *edge).is_none()) {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        if let Err(e) = run(&config) {
+            log::warn!("Not switching clients at screen edges: {:#}", e);
+        }
+    });
+}
+
+/// Takes the client to switch to, if an edge was pushed since the last call.
+pub fn take_switch() -> Option<String> {
+    SWITCH.lock().unwrap().take()
+}
+
+fn run(configured: &EdgeSwitchConfig) -> Result<()> {
+    let (conn, screen) = x11rb::connect(None).context("Connect to X11")?;
+    let screen = &conn.setup().roots[screen];
+    let (root, width, height) = (screen.root, screen.width_in_pixels, screen.height_in_pixels);
+
+    conn.xfixes_query_version(5, 0)?
+        .reply()
+        .context("Query XFixes")?;
+    let version = conn
+        .xinput_xi_query_version(2, 3)?
+        .reply()
+        .context("Query XInput")?;
+    if (version.major_version, version.minor_version) < (2, 3) {
+        anyhow::bail!(
+            "Barrier events need XInput 2.3, the X server has {}.{}",
+            version.major_version,
+            version.minor_version
+        );
+    }
+
+    let mut barriers = Vec::new();
+    for edge in EDGES {
+        if client(configured, edge).is_none() {
+            continue;
+        }
+
+        // Only motion away from the edge passes
+        let (x1, y1, x2, y2, away) = match edge {
+            Edge::Left => (0, 0, 0, height, BarrierDirections::POSITIVE_X),
+            Edge::Right => (width, 0, width, height, BarrierDirections::NEGATIVE_X),
+            Edge::Top => (0, 0, width, 0, BarrierDirections::POSITIVE_Y),
+            Edge::Bottom => (0, height, width, height, BarrierDirections::NEGATIVE_Y),
+        };
+        let barrier = conn.generate_id()?;
+        conn.xfixes_create_pointer_barrier(barrier, root, x1, y1, x2, y2, u8::from(away), &[])?;
+        barriers.push((barrier, edge));
+    }
+
+    let mask = xinput::XIEventMask::BARRIER_HIT | xinput::XIEventMask::BARRIER_LEAVE;
+    conn.xinput_xi_select_events(
+        root,
+        &[xinput::EventMask {
+            deviceid: xinput::Device::ALL_MASTER.into(),
+            mask: vec![mask.into()],
+        }],
+    )?;
+    conn.flush()?;
+    log::info!("Switching clients at screen edges");
+
+    // When the current push started, and how far into the edge it went
+    let mut push: Option<(Instant, f64)> = None;
+    loop {
+        let hit = match conn.wait_for_event()? {
+            Event::XinputBarrierHit(hit) => hit,
+            Event::XinputBarrierLeave(_) => {
+                push = None;
+                continue;
+            }
+            _ => continue,
+        };
+        let edge = match barriers.iter().find(|(barrier, _)| *barrier == hit.barrier) {
+            Some((_, edge)) => *edge,
+            None => continue,
+        };
+
+        let distance = match edge {
+            Edge::Left => -to_f64(hit.dx),
+            Edge::Right => to_f64(hit.dx),
+            Edge::Top => -to_f64(hit.dy),
+            Edge::Bottom => to_f64(hit.dy),
+        }
+        .max(0.0);
+        if push.is_none() && hit.flags & u32::from(xinput::BarrierFlags::DEVICE_IS_GRABBED) != 0 {
+            log::debug!("Pushed against the {:?} edge mid-drag", edge);
+        }
+
+        let (started, pushed) = match push {
+            Some((started, pushed)) if started.elapsed() < PUSH_WINDOW => {
+                (started, pushed + distance)
+            }
+            _ => (Instant::now(), distance),
+        };
+        push = Some((started, pushed));
+
+        let config = config::current();
+        if pushed < config.edge_switch.pressure as f64 || grab::is_grabbed() {
+            continue;
+        }
+        push = None;
+
+        // The config may have changed since the barriers were set up
+        if let Some(client) = client(&config.edge_switch, edge) {
+            log::info!(
+                "Pushed against the {:?} edge, switching to {}",
+                edge,
+                client
+            );
+            *SWITCH.lock().unwrap() = Some(client.clone());
+        }
+    }
+}
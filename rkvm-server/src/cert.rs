@@ -0,0 +1,48 @@
+use anyhow::Result;
+use clap::Subcommand;
+
+use crate::{config::Config, identity::Identity};
+
+#[derive(Subcommand, Debug)]
+pub enum CertCommand {
+    /// Generate the server identity if it doesn't exist yet
+    Generate,
+    /// Print the SHA-256 fingerprint to put in the client's `fingerprint` option
+    ShowFingerprint,
+    /// Replace the server identity with a fresh one, keeping the old files as `*.old`
+    Rotate,
+}
+
+pub fn run(config: &Config, command: CertCommand) -> Result<()> {
+    match command {
+        CertCommand::Generate => {
+            if Identity::load(&config.state_dir)?.is_some() {
+                anyhow::bail!(
+                    "An identity already exists in {:?}, use `cert rotate` to replace it",
+                    config.state_dir
+                );
+            }
+
+            let identity = Identity::generate()?;
+            identity.save(&config.state_dir)?;
+            println!("{}", identity.fingerprint());
+        }
+        CertCommand::ShowFingerprint => match Identity::load(&config.state_dir)? {
+            Some(identity) => println!("{}", identity.fingerprint()),
+            None => anyhow::bail!("No identity in {:?}", config.state_dir),
+        },
+        CertCommand::Rotate => {
+            if let Some(old) = Identity::load(&config.state_dir)? {
+                old.backup(&config.state_dir)?;
+                log::info!("Previous fingerprint: {}", old.fingerprint());
+            }
+
+            let identity = Identity::generate()?;
+            identity.save(&config.state_dir)?;
+            println!("{}", identity.fingerprint());
+            log::warn!("Clients pinning the previous fingerprint must be updated");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,179 @@
+//! A listing of the configured bindings and connected clients, shown as a notification for
+//! anyone who forgot which key does what. Clients are numbered by their slots.
+//!
+//! Made from the config in use, so it follows reloads.
+
+use keycode::KeyMappingId;
+
+use crate::{
+    clients,
+    config::{Config, GestureAction, GrabMode, RuleAction, RuleTrigger},
+    notify,
+};
+
+fn gesture(action: GestureAction) -> &'static str {
+    match action {
+        GestureAction::ToggleGrab => "toggle the grab",
+        GestureAction::NextClient => "next client",
+        GestureAction::PushClipboard => "push the clipboard",
+        GestureAction::PullClipboard => "pull the clipboard",
+        GestureAction::SwapClipboards => "swap clipboards",
+        GestureAction::ToggleClipboardSync => "toggle clipboard sync",
+        GestureAction::ToggleGameMode => "toggle game mode",
+        GestureAction::TypeSelection => "type the selection",
+        GestureAction::Attention => "get attention",
+        GestureAction::CheatSheet => "show this list",
+        GestureAction::SwitchBack => "switch back",
+        GestureAction::ReturnLocal => "return here",
+    }
+}
+
+fn rule_action(action: &RuleAction) -> String {
+    match action {
+        RuleAction::SwitchTo { client } => format!("switch to {}", client),
+        RuleAction::SwitchToSlot { slot } => format!("switch to client {}", slot),
+        RuleAction::PushClipboard => "push the clipboard".to_owned(),
+        RuleAction::PullClipboard => "pull the clipboard".to_owned(),
+        RuleAction::SwapClipboards => "swap clipboards".to_owned(),
+        RuleAction::TypeSelection => "type the selection".to_owned(),
+        RuleAction::Attention => "get attention".to_owned(),
+        RuleAction::CheatSheet => "show this list".to_owned(),
+        RuleAction::SwitchBack => "switch back".to_owned(),
+        RuleAction::ReturnLocal => "return here".to_owned(),
+        RuleAction::RunCommand { command } => format!("run {}", command),
+    }
+}
+
+fn combo(keys: &[KeyMappingId]) -> String {
+    let keys: Vec<_> = keys.iter().map(|key| format!("{:?}", key)).collect();
+    keys.join("+")
+}
+
+/// The listing for `config`, one binding or client per line.
+pub fn text(config: &Config) -> String {
+    let hotkey = &config.hotkey;
+    let key = hotkey.key;
+    let mut lines = Vec::new();
+
+    match hotkey.mode {
+        GrabMode::Toggle => {
+            let gestures = [
+                ("tap", hotkey.tap),
+                ("double tap", hotkey.double_tap),
+                ("long press", hotkey.long_press),
+            ];
+            for (name, action) in gestures {
+                if let Some(action) = action {
+                    lines.push(format!("{:?} {}: {}", key, name, gesture(action)));
+                }
+            }
+        }
+        GrabMode::Hold => lines.push(format!("Hold {:?}: grab", key)),
+        GrabMode::Hybrid => lines.push(format!(
+            "{:?} tap: toggle the grab, hold: grab until release",
+            key
+        )),
+    }
+
+    let keys = [
+        (hotkey.push_clipboard_key, GestureAction::PushClipboard),
+        (hotkey.pull_clipboard_key, GestureAction::PullClipboard),
+        (hotkey.swap_clipboards_key, GestureAction::SwapClipboards),
+        (
+            hotkey.toggle_clipboard_sync_key,
+            GestureAction::ToggleClipboardSync,
+        ),
+        (hotkey.toggle_game_mode_key, GestureAction::ToggleGameMode),
+        (hotkey.type_selection_key, GestureAction::TypeSelection),
+        (hotkey.attention_key, GestureAction::Attention),
+        (hotkey.cheat_sheet_key, GestureAction::CheatSheet),
+        (hotkey.switch_back_key, GestureAction::SwitchBack),
+        (hotkey.return_local_key, GestureAction::ReturnLocal),
+    ];
+    for (key, action) in keys {
+        if let Some(key) = key {
+            lines.push(format!("{:?}: {}", key, gesture(action)));
+        }
+    }
+
+    for rule in &config.rules {
+        if let RuleTrigger::Key { keys, double_tap } = &rule.trigger {
+            let actions: Vec<_> = rule.actions.iter().map(rule_action).collect();
+            let twice = if *double_tap { " twice" } else { "" };
+            lines.push(format!("{}{}: {}", combo(keys), twice, actions.join(", ")));
+        }
+    }
+
+    let clients = clients::list();
+    if clients.is_empty() {
+        lines.push("No clients connected".to_owned());
+    }
+    for (slot, name, active) in clients {
+        let active = if active { " (active)" } else { "" };
+        lines.push(format!("Client {}: {}{}", slot, name, active));
+    }
+
+    lines.join("\n")
+}
+
+/// Logs the listing for `config` and shows it as a notification.
+pub async fn show(config: &Config) {
+    let text = text(config);
+    log::info!("Bindings and clients:\n{}", text);
+
+    if let Err(e) = notify::notify("rkvm bindings", &text).await {
+        log::debug!("Failed to show notification: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::HotkeyConfig;
+
+    fn with_hotkey(hotkey: HotkeyConfig) -> Config {
+        Config {
+            hotkey,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(
+            text(&Config::default()),
+            "ControlRight tap: toggle the grab\nNo clients connected"
+        );
+    }
+
+    #[test]
+    fn names_the_configured_key() {
+        let config = with_hotkey(HotkeyConfig {
+            key: KeyMappingId::F13,
+            double_tap: Some(GestureAction::NextClient),
+            ..Default::default()
+        });
+        assert!(
+            text(&config).starts_with("F13 tap: toggle the grab\nF13 double tap: next client\n")
+        );
+
+        let config = with_hotkey(HotkeyConfig {
+            key: KeyMappingId::AltRight,
+            mode: GrabMode::Hold,
+            ..Default::default()
+        });
+        assert!(text(&config).starts_with("Hold AltRight: grab\n"));
+    }
+
+    #[test]
+    fn lists_bound_keys() {
+        let config = with_hotkey(HotkeyConfig {
+            cheat_sheet_key: Some(KeyMappingId::F17),
+            return_local_key: Some(KeyMappingId::F19),
+            ..Default::default()
+        });
+        let text = text(&config);
+        assert!(text.contains("\nF17: show this list\nF19: return here\n"));
+    }
+}
@@ -0,0 +1,470 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use quinn::Connection;
+use rkvm_protocol::{ClientHello, ImageFormat, LinkQuality, ScreenLayout, Uuid};
+use tokio::sync::watch;
+
+lazy_static::lazy_static! {
+    static ref CLIENTS: Mutex<Clients> = Mutex::new(Clients::default());
+
+    /// Bumped whenever input may have started or stopped going to a client
+    static ref TARGET_GENERATION: watch::Sender<u64> = watch::channel(0).0;
+}
+
+/// Bumped whenever the active client reports new monitors.
+static SCREENS_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Activity reports older than this are considered stale.
+const ACTIVITY_STALE: Duration = Duration::from_secs(5);
+
+struct ClientState {
+    /// Connection stable id
+    id: usize,
+    addr: SocketAddr,
+    conn: Connection,
+    /// Friendly name from the client's hello
+    name: String,
+    /// Stable id from the client's hello, the same across connections
+    uuid: Uuid,
+    /// Kept across connections and restarts, see [`crate::known`]
+    slot: usize,
+    /// Last reported local idle time and when it was received
+    activity: Option<(Instant, Duration)>,
+    /// Clipboard image encodings from the client's hello
+    image_formats: Vec<ImageFormat>,
+    screens: ScreenLayout,
+    /// Whether a program on the client locked the pointer
+    relative_only: bool,
+    quality: LinkQuality,
+}
+
+impl ClientState {
+    /// Whether `key` is the name, stable id or IP address of this client.
+    fn is_known_by(&self, key: &str) -> bool {
+        self.name == key || self.uuid.to_string() == key || self.addr.ip().to_string() == key
+    }
+
+    fn is_busy(&self, threshold: Duration) -> bool {
+        match self.activity {
+            Some((received, idle)) => received.elapsed() < ACTIVITY_STALE && idle < threshold,
+            None => false,
+        }
+    }
+}
+
+/// Where input goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Local,
+    Client(usize),
+}
+
+/// Authenticated clients in connection order, and which one input is routed to.
+struct Clients {
+    clients: Vec<ClientState>,
+    active: Option<usize>,
+    /// Whether input stays here, ungrabbed
+    local: bool,
+    /// Where input went before it went where it goes now
+    previous: Option<Target>,
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Self {
+            clients: Vec::new(),
+            active: None,
+            local: true,
+            previous: None,
+        }
+    }
+}
+
+impl Clients {
+    /// The client known by `key`, its name, stable id or IP address. A name or address
+    /// several clients share finds none, as only the stable id tells them apart.
+    fn find(&self, key: &str) -> Option<&ClientState> {
+        if let Some(client) = self.clients.iter().find(|c| c.uuid.to_string() == key) {
+            return Some(client);
+        }
+
+        let mut matching = self.clients.iter().filter(|c| c.is_known_by(key));
+        let client = matching.next()?;
+        // The same client may briefly be connected twice while it reconnects
+        if matching.any(|c| c.uuid != client.uuid) {
+            log::warn!("Several clients go by {}, use a stable id", key);
+            return None;
+        }
+        Some(client)
+    }
+
+    /// Routes input to `id`, remembering where it went before.
+    fn activate(&mut self, id: usize) {
+        self.retarget(|clients| clients.active = Some(id));
+        target_changed();
+    }
+
+    fn target(&self) -> Target {
+        match self.active {
+            Some(id) if !self.local => Target::Client(id),
+            _ => Target::Local,
+        }
+    }
+
+    /// Changes where input goes with `change`, remembering where it went before.
+    fn retarget(&mut self, change: impl FnOnce(&mut Self)) {
+        let before = self.target();
+        change(self);
+        if self.target() != before {
+            self.previous = Some(before);
+        }
+    }
+
+    /// Where input went before, if elsewhere than now.
+    fn back(&self) -> Option<Target> {
+        self.previous.filter(|&previous| previous != self.target())
+    }
+
+    fn active(&self) -> Option<&ClientState> {
+        let active = self.active?;
+        self.clients.iter().find(|c| c.id == active)
+    }
+}
+
+/// Wakes up everything waiting in [`subscribe_target`].
+pub fn target_changed() {
+    TARGET_GENERATION.send_modify(|generation| *generation += 1);
+}
+
+/// Changes whenever grabbing or switching may have changed where input goes.
+pub fn subscribe_target() -> watch::Receiver<u64> {
+    TARGET_GENERATION.subscribe()
+}
+
+pub fn register(conn: &Connection, hello: ClientHello) {
+    let (id, addr) = (conn.stable_id(), conn.remote_address());
+    let keys = [
+        hello.name.clone(),
+        hello.id.to_string(),
+        addr.ip().to_string(),
+    ];
+
+    let fingerprint = crate::identity::peer_fingerprint(conn);
+    let slot = crate::known::assign(hello.id, &hello.name, fingerprint.as_deref());
+    let mut clients = CLIENTS.lock().unwrap();
+    clients.clients.push(ClientState {
+        id,
+        addr,
+        conn: conn.clone(),
+        name: hello.name,
+        uuid: hello.id,
+        slot,
+        activity: None,
+        image_formats: hello.image_formats,
+        screens: hello.screens,
+        relative_only: false,
+        quality: LinkQuality::Good,
+    });
+
+    if clients.active.is_none() {
+        clients.activate(id);
+    }
+    drop(clients);
+
+    crate::rules::connected(&keys);
+}
+
+pub fn unregister(id: usize) {
+    let mut clients = CLIENTS.lock().unwrap();
+    clients.clients.retain(|c| c.id != id);
+
+    if clients.previous == Some(Target::Client(id)) {
+        clients.previous = None;
+    }
+    if clients.active == Some(id) {
+        clients.active = clients.clients.first().map(|c| c.id);
+        target_changed();
+    }
+}
+
+pub fn set_activity(id: usize, idle: Option<Duration>) {
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some(client) = clients.clients.iter_mut().find(|c| c.id == id) {
+        client.activity = idle.map(|idle| (Instant::now(), idle));
+    }
+}
+
+pub fn set_screens(id: usize, screens: ScreenLayout) {
+    let mut clients = CLIENTS.lock().unwrap();
+    let active = clients.active == Some(id);
+    if let Some(client) = clients.clients.iter_mut().find(|c| c.id == id) {
+        client.screens = screens;
+        // Others are tracked from scratch when input moves to them anyway
+        if active {
+            SCREENS_GENERATION.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn set_relative_only(id: usize, relative_only: bool) {
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some(client) = clients.clients.iter_mut().find(|c| c.id == id) {
+        client.relative_only = relative_only;
+    }
+}
+
+pub fn set_quality(id: usize, quality: LinkQuality) {
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some(client) = clients.clients.iter_mut().find(|c| c.id == id) {
+        client.quality = quality;
+    }
+}
+
+/// How well the connection with stable id `id` carries input, as last measured.
+pub fn quality(id: usize) -> LinkQuality {
+    let clients = CLIENTS.lock().unwrap();
+    let client = clients.clients.iter().find(|c| c.id == id);
+    client.map_or(LinkQuality::Good, |c| c.quality)
+}
+
+/// How well the connection of the active client carries input.
+pub fn active_quality() -> LinkQuality {
+    let clients = CLIENTS.lock().unwrap();
+    clients.active().map_or(LinkQuality::Good, |c| c.quality)
+}
+
+/// Whether the active client's pointer must only ever be moved by relative motion.
+pub fn active_relative_only() -> bool {
+    let clients = CLIENTS.lock().unwrap();
+    clients.active().is_some_and(|c| c.relative_only)
+}
+
+/// Changes whenever the active client's monitors do, so trackers know to start over.
+pub fn screens_generation() -> u64 {
+    SCREENS_GENERATION.load(Ordering::Relaxed)
+}
+
+/// Monitors the active client reported.
+pub fn active_screens() -> ScreenLayout {
+    let clients = CLIENTS.lock().unwrap();
+    clients
+        .active()
+        .map(|c| c.screens.clone())
+        .unwrap_or_default()
+}
+
+/// Whether input should be routed to the connection with stable id `id`.
+pub fn is_active(id: usize) -> bool {
+    CLIENTS.lock().unwrap().active == Some(id)
+}
+
+/// Whether input goes to the connection with stable id `id` right now, grabbed.
+pub fn is_target(id: usize) -> bool {
+    CLIENTS.lock().unwrap().target() == Target::Client(id)
+}
+
+/// Name of the client currently receiving input.
+pub fn active() -> Option<String> {
+    let clients = CLIENTS.lock().unwrap();
+    clients.active().map(|c| c.name.clone())
+}
+
+/// Stable id of the client currently receiving input.
+pub fn active_uuid() -> Option<Uuid> {
+    let clients = CLIENTS.lock().unwrap();
+    clients.active().map(|c| c.uuid)
+}
+
+/// Stable id of the client on the connection with stable id `id`.
+pub fn uuid(id: usize) -> Option<Uuid> {
+    let clients = CLIENTS.lock().unwrap();
+    clients.clients.iter().find(|c| c.id == id).map(|c| c.uuid)
+}
+
+/// Ways the config can refer to the client currently receiving input, most specific
+/// first: its name, its stable id and its IP address.
+pub fn active_keys() -> Vec<String> {
+    let clients = CLIENTS.lock().unwrap();
+    match clients.active() {
+        Some(c) => vec![c.name.clone(), c.uuid.to_string(), c.addr.ip().to_string()],
+        None => Vec::new(),
+    }
+}
+
+/// Clipboard image encodings the active client accepts, most preferred first.
+pub fn active_image_formats() -> Vec<ImageFormat> {
+    let clients = CLIENTS.lock().unwrap();
+    clients
+        .active()
+        .map(|c| c.image_formats.clone())
+        .unwrap_or_default()
+}
+
+/// Whether the active client's own keyboard or mouse was used within `threshold`.
+pub fn active_is_busy(threshold: Duration) -> bool {
+    let clients = CLIENTS.lock().unwrap();
+    clients.active().is_some_and(|c| c.is_busy(threshold))
+}
+
+/// Slots and names of the connected clients by slot, and whether each is active.
+pub fn list() -> Vec<(usize, String, bool)> {
+    let clients = CLIENTS.lock().unwrap();
+    let mut list: Vec<_> = clients
+        .clients
+        .iter()
+        .map(|c| (c.slot, c.name.clone(), Some(c.id) == clients.active))
+        .collect();
+    list.sort();
+    list
+}
+
+/// Where the active client is in connection order, starting from 0.
+pub fn active_position() -> Option<usize> {
+    let clients = CLIENTS.lock().unwrap();
+    clients
+        .clients
+        .iter()
+        .position(|c| Some(c.id) == clients.active)
+}
+
+/// Routes input to the client that connected after the active one, wrapping around.
+///
+/// Returns the name of the newly active client.
+pub fn switch_next() -> Option<String> {
+    let mut clients = CLIENTS.lock().unwrap();
+
+    let position = clients
+        .clients
+        .iter()
+        .position(|c| Some(c.id) == clients.active);
+    let next = match position {
+        Some(i) => (i + 1) % clients.clients.len(),
+        None => 0,
+    };
+
+    let next = clients.clients.get(next)?;
+    let (id, name) = (next.id, next.name.clone());
+    clients.activate(id);
+
+    Some(name)
+}
+
+/// Whether the client known by `key`, its name, stable id or IP address, is connected.
+pub fn is_connected(key: &str) -> bool {
+    let clients = CLIENTS.lock().unwrap();
+    clients.clients.iter().any(|c| c.is_known_by(key))
+}
+
+/// Routes input to the client known by `key`, its name, stable id or IP address.
+///
+/// Returns its name, or `None` if no such client is connected.
+pub fn switch_to(key: &str) -> Option<String> {
+    let mut clients = CLIENTS.lock().unwrap();
+
+    let client = clients.find(key)?;
+    let (id, name) = (client.id, client.name.clone());
+    clients.activate(id);
+
+    Some(name)
+}
+
+/// Closes the connection of the client known by `key`, its name, stable id or IP address.
+///
+/// Returns its name, or `None` if no such client is connected.
+pub fn disconnect(key: &str) -> Option<String> {
+    let clients = CLIENTS.lock().unwrap();
+
+    let client = clients.find(key)?;
+    client
+        .conn
+        .close(3u32.into(), b"Disconnected by the server");
+    Some(client.name.clone())
+}
+
+/// Notes whether input stays here, ungrabbed, or goes to the active client.
+pub fn set_local(local: bool) {
+    CLIENTS
+        .lock()
+        .unwrap()
+        .retarget(|clients| clients.local = local);
+}
+
+/// Routes input back to the client it went to before, like Alt+Tab.
+///
+/// Returns its name, or `None` if input was here before, or the client disconnected.
+pub fn switch_back() -> Option<String> {
+    let mut clients = CLIENTS.lock().unwrap();
+
+    let previous = match clients.back()? {
+        Target::Client(id) => id,
+        Target::Local => return None,
+    };
+    let client = clients.clients.iter().find(|c| c.id == previous)?;
+    let name = client.name.clone();
+    clients.activate(previous);
+
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grab(clients: &mut Clients, id: usize) {
+        clients.activate(id);
+        clients.retarget(|clients| clients.local = false);
+    }
+
+    fn ungrab(clients: &mut Clients) {
+        clients.retarget(|clients| clients.local = true);
+    }
+
+    #[test]
+    fn back_to_the_client_before() {
+        let mut clients = Clients::default();
+        grab(&mut clients, 1);
+        clients.activate(2);
+
+        assert_eq!(clients.back(), Some(Target::Client(1)));
+        clients.activate(1);
+        assert_eq!(clients.back(), Some(Target::Client(2)));
+    }
+
+    #[test]
+    fn back_to_local_after_ungrab() {
+        let mut clients = Clients::default();
+        grab(&mut clients, 1);
+        clients.activate(2);
+        ungrab(&mut clients);
+        grab(&mut clients, 2);
+
+        assert_eq!(clients.back(), Some(Target::Local));
+    }
+
+    #[test]
+    fn back_to_the_client_ungrabbed_from() {
+        let mut clients = Clients::default();
+        grab(&mut clients, 1);
+        ungrab(&mut clients);
+
+        assert_eq!(clients.back(), Some(Target::Client(1)));
+    }
+
+    #[test]
+    fn switching_while_local_is_no_target() {
+        let mut clients = Clients::default();
+        clients.activate(1);
+        clients.activate(2);
+
+        assert_eq!(clients.back(), None);
+        grab(&mut clients, 2);
+        assert_eq!(clients.back(), Some(Target::Local));
+    }
+}
@@ -0,0 +1,710 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use image::ImageFormat as SourceFormat;
+use rkvm_protocol::{ClipboardSeal, DragData, Event, ImageFormat, Packet, Uuid, CLIPBOARD_KEY_LEN};
+use tokio::{
+    runtime::Handle,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
+};
+
+use crate::{
+    audit, clients, config,
+    dnd::{self, Dragged},
+    grab,
+    history::{self, Direction},
+    wayland, xclip, ClipboardMode, ClipboardType,
+};
+
+/// Requests arriving within this window are served by a single fetch.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Clients that don't send their clipboard within this long after being asked declined,
+/// as they do with the clipboard off, and what they send later isn't taken
+const PULL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest selection typed out, as typing goes a key at a time
+const MAX_TYPED_LEN: usize = 4096;
+
+/// Image targets we take from the clipboard, in order of preference.
+pub const IMAGE_TYPES: [&str; 3] = ["image/png", "image/bmp", "image/jpeg"];
+
+lazy_static::lazy_static! {
+    /// Made up whenever the server starts, and handed to clients as they connect
+    static ref KEY: [u8; CLIPBOARD_KEY_LEN] = ClipboardSeal::generate_key();
+    static ref SEAL: ClipboardSeal = ClipboardSeal::new(&KEY);
+    /// `encrypt_clipboard` as the server started with it, as clients only get the key
+    /// when they connect
+    static ref SEALING: bool = config::current().encrypt_clipboard;
+}
+
+/// Key clipboard contents are sealed with, if `encrypt_clipboard` is on.
+pub fn key() -> Option<[u8; CLIPBOARD_KEY_LEN]> {
+    SEALING.then_some(*KEY)
+}
+
+#[derive(Debug)]
+enum Request {
+    /// Send the clipboard to the active client if it changed since the last push
+    Push,
+    /// Put files on our own clipboard
+    SetFiles(Vec<PathBuf>),
+    /// Ask the active client for its clipboard, sending ours to it as well if swapping
+    Pull { swap: bool },
+    /// Put the clipboard a client sent when asked on ours
+    SetPulled { client: String, event: Event },
+    /// Hand out the packet behind an offer the client accepted
+    Fetch {
+        id: u64,
+        reply: oneshot::Sender<Option<Arc<Vec<u8>>>>,
+    },
+    /// Repeat the last offer, for a client that connected since
+    LastOffer {
+        reply: oneshot::Sender<Option<Event>>,
+    },
+    /// Continue a drag held on this machine on the active client
+    ForwardDrag,
+    /// Drop the forwarded drag on the floor
+    CancelDrag,
+    /// Type the primary selection on the active client
+    TypeSelection,
+    /// Put contents kept in the history back on our clipboard and send them on
+    Restore { kind: String, data: Vec<u8> },
+    /// Find the file behind an entry of a forwarded drag
+    DragFile {
+        id: u64,
+        index: u32,
+        reply: oneshot::Sender<Option<PathBuf>>,
+    },
+}
+
+/// Clipboard contents offered to the client.
+struct Offer {
+    id: u64,
+    /// MIME type of the contents
+    kind: String,
+    size: u64,
+    /// Encoded packet with the contents
+    packet: Arc<Vec<u8>>,
+}
+
+/// A drag forwarded to the client.
+struct Drag {
+    id: u64,
+    /// For each entry of the drag, where to read it if it's a file
+    files: Vec<Option<PathBuf>>,
+    /// Not dropped or cancelled yet, as far as we know
+    active: bool,
+}
+
+/// Cheap to clone handle to the clipboard worker.
+#[derive(Debug, Clone)]
+pub struct ClipboardHandle {
+    tx: Sender<Request>,
+    /// Whether [`sync`](Self::sync) pushes
+    sync: Arc<AtomicBool>,
+}
+
+impl ClipboardHandle {
+    /// Spawns the worker on `runtime`.
+    ///
+    /// Contents larger than `clipboard_offer_size` bytes are only offered, and sent once
+    /// fetched.
+    pub fn start(runtime: &Handle, mode: ClipboardMode, event_tx: Sender<Packet>) -> Self {
+        let (tx, rx) = mpsc::channel(8);
+
+        let worker = Worker {
+            mode,
+            event_tx,
+            pushed: HashMap::new(),
+            offer: None,
+            next_offer_id: 0,
+            drag: None,
+            pulling: None,
+        };
+        runtime.spawn(worker.run(rx));
+
+        let sync = Arc::new(AtomicBool::new(config::current().clipboard_sync));
+        Self { tx, sync }
+    }
+
+    /// Asks the worker to push the clipboard. Never blocks.
+    pub fn push(&self) {
+        // A full queue means a push is already pending, which covers this one
+        let _ = self.tx.try_send(Request::Push);
+    }
+
+    /// Pushes the clipboard unless automatic sync was turned off, or paused in game mode.
+    pub fn sync(&self) {
+        if self.sync.load(Ordering::Relaxed) && !crate::controller::game_mode() {
+            self.push();
+        }
+    }
+
+    /// Turns automatic sync on or off.
+    pub fn set_sync(&self, enabled: bool) {
+        self.sync.store(enabled, Ordering::Relaxed);
+        log::info!(
+            "Clipboard sync turned {}",
+            if enabled { "on" } else { "off" }
+        );
+    }
+
+    /// Flips automatic sync and returns whether it is now on.
+    pub fn toggle_sync(&self) -> bool {
+        let enabled = !self.sync.load(Ordering::Relaxed);
+        self.set_sync(enabled);
+        enabled
+    }
+
+    /// Asks the active client for its clipboard, to put it on ours, whether sync is on or not.
+    pub fn pull(&self) {
+        let _ = self.tx.try_send(Request::Pull { swap: false });
+    }
+
+    /// Sends the clipboard to the active client and puts its clipboard on ours.
+    pub fn swap(&self) {
+        let _ = self.tx.try_send(Request::Pull { swap: true });
+    }
+
+    /// Puts the clipboard `client` sent on ours, if it was asked for.
+    pub async fn set_pulled(&self, client: &str, event: Event) {
+        let client = client.to_owned();
+        let _ = self.tx.send(Request::SetPulled { client, event }).await;
+    }
+
+    /// Offers `paths` to local applications as copied files.
+    pub async fn set_files(&self, paths: Vec<PathBuf>) {
+        let _ = self.tx.send(Request::SetFiles(paths)).await;
+    }
+
+    /// Reads what is being dragged here, if anything, and sends it to the active client.
+    pub fn forward_drag(&self) {
+        let _ = self.tx.try_send(Request::ForwardDrag);
+    }
+
+    /// Types the primary selection on the active client, for where pasting doesn't work.
+    pub fn type_selection(&self) {
+        let _ = self.tx.try_send(Request::TypeSelection);
+    }
+
+    /// Puts `data` of MIME type `kind` back on the clipboard, sending it to the active
+    /// client as well.
+    pub async fn restore(&self, kind: String, data: Vec<u8>) {
+        let _ = self.tx.send(Request::Restore { kind, data }).await;
+    }
+
+    /// Cancels the drag forwarded to the client, if it hasn't been dropped yet.
+    pub fn cancel_drag(&self) {
+        let _ = self.tx.try_send(Request::CancelDrag);
+    }
+
+    /// Returns the file behind entry `index` of the drag forwarded as `id`.
+    pub async fn drag_file(&self, id: u64, index: u32) -> Option<PathBuf> {
+        let (reply, rx) = oneshot::channel();
+        let request = Request::DragFile { id, index, reply };
+        self.tx.send(request).await.ok()?;
+        rx.await.ok()?
+    }
+
+    /// Returns the encoded packet offered as `id`, unless a newer clipboard replaced it.
+    pub async fn fetch(&self, id: u64) -> Option<Arc<Vec<u8>>> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(Request::Fetch { id, reply }).await.ok()?;
+        rx.await.ok()?
+    }
+
+    /// Returns the offer of the current clipboard contents, if they were too large to send.
+    pub async fn last_offer(&self) -> Option<Event> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(Request::LastOffer { reply }).await.ok()?;
+        rx.await.ok()?
+    }
+}
+
+/// What was last pushed to a client.
+#[derive(Default)]
+struct Pushed {
+    /// X11 selection timestamp
+    timestamp: Option<u64>,
+    /// Hash of the content, for backends without timestamps
+    hash: Option<u64>,
+}
+
+/// Owns all clipboard state, so fetches never overlap.
+struct Worker {
+    mode: ClipboardMode,
+    event_tx: Sender<Packet>,
+    /// What was last pushed to each client, by its stable id
+    pushed: HashMap<Option<Uuid>, Pushed>,
+    offer: Option<Offer>,
+    /// Never reused, so a late fetch can't get newer contents than it was offered
+    next_offer_id: u64,
+    drag: Option<Drag>,
+    /// When the client was asked for its clipboard, if it hasn't sent it yet
+    pulling: Option<Instant>,
+}
+
+impl Worker {
+    async fn run(mut self, mut rx: Receiver<Request>) {
+        while let Some(request) = rx.recv().await {
+            match request {
+                Request::Push => {
+                    tokio::time::sleep(DEBOUNCE).await;
+
+                    // Serve later requests in order, but only push once
+                    let mut deferred = Vec::new();
+                    while let Ok(request) = rx.try_recv() {
+                        if !matches!(request, Request::Push) {
+                            deferred.push(request);
+                        }
+                    }
+
+                    if let Err(e) = self.push().await {
+                        log::error!("Failed to send clipboard: {}", e);
+                    }
+
+                    for request in deferred {
+                        self.handle(request).await;
+                    }
+                }
+                request => self.handle(request).await,
+            }
+        }
+    }
+
+    async fn handle(&mut self, request: Request) {
+        match request {
+            Request::Push => {
+                if let Err(e) = self.push().await {
+                    log::error!("Failed to send clipboard: {}", e);
+                }
+            }
+            Request::SetFiles(paths) => {
+                // How clients answer a pull while they have files copied
+                self.pulling = None;
+                if let Err(e) = self.set_files(&paths).await {
+                    log::error!("Failed to put files on the clipboard: {}", e);
+                }
+            }
+            Request::Pull { swap } => {
+                self.pulling = Some(Instant::now());
+                // Asked first, so the client reads its clipboard before ours replaces it
+                let event = Event::ClipboardRequest;
+                let _ = self.event_tx.send(Packet::new(0, event)).await;
+
+                if swap {
+                    // Sent even if the client had it already, as its own is on the way here
+                    self.pushed.remove(&clients::active_uuid());
+                    if let Err(e) = self.push().await {
+                        log::error!("Failed to send clipboard: {}", e);
+                    }
+                }
+            }
+            Request::SetPulled { client, event } => {
+                let asked = self.pulling.take();
+                if asked.is_none_or(|asked| asked.elapsed() >= PULL_TIMEOUT) {
+                    log::warn!("Ignoring clipboard {} sent without being asked", client);
+                    return;
+                }
+                if let Err(e) = self.set_pulled(&client, event).await {
+                    log::error!("Failed to put the clipboard of {} on ours: {}", client, e);
+                }
+            }
+            Request::Fetch { id, reply } => {
+                let packet = match &self.offer {
+                    Some(offer) if offer.id == id => Some(offer.packet.clone()),
+                    _ => None,
+                };
+                let _ = reply.send(packet);
+            }
+            Request::LastOffer { reply } => {
+                let event = self.offer.as_ref().map(|offer| Event::ClipboardOffer {
+                    id: offer.id,
+                    kind: offer.kind.clone(),
+                    size: offer.size,
+                });
+                let _ = reply.send(event);
+            }
+            Request::ForwardDrag => {
+                if let Err(e) = self.forward_drag().await {
+                    log::error!("Failed to forward drag: {}", e);
+                }
+            }
+            Request::CancelDrag => {
+                if let Some(drag) = self.drag.as_mut().filter(|d| d.active) {
+                    drag.active = false;
+                    let event = Event::DragCancel;
+                    let _ = self.event_tx.send(Packet::new(0, event)).await;
+                }
+            }
+            Request::TypeSelection => {
+                if let Err(e) = self.type_selection().await {
+                    log::error!("Failed to type the selection: {}", e);
+                }
+            }
+            Request::Restore { kind, data } => {
+                if let Err(e) = self.set_local(&kind, &data).await {
+                    log::error!("Failed to put back {} on the clipboard: {}", kind, e);
+                    return;
+                }
+                log::info!("Put back {} bytes of {} on the clipboard", data.len(), kind);
+
+                // Sent even if the client had it last, it may have been overwritten there
+                self.pushed.remove(&clients::active_uuid());
+                if let Err(e) = self.push().await {
+                    log::error!("Failed to send clipboard: {}", e);
+                }
+            }
+            Request::DragFile { id, index, reply } => {
+                let file = self
+                    .drag
+                    .as_ref()
+                    .filter(|d| d.id == id)
+                    .and_then(|d| d.files.get(index as usize).cloned().flatten());
+                let _ = reply.send(file);
+            }
+        }
+    }
+
+    async fn forward_drag(&mut self) -> Result<()> {
+        if let ClipboardMode::Wayland = self.mode {
+            log::debug!("Drags can't be read on Wayland");
+            return Ok(());
+        }
+
+        let dragged = match tokio::task::spawn_blocking(dnd::read_drag).await?? {
+            Some(dragged) => dragged,
+            None => return Ok(()),
+        };
+
+        let (data, files) = match dragged {
+            Dragged::Text(text) => (DragData::Text(text), Vec::new()),
+            Dragged::Uris(uris) => (DragData::Uris(uris), Vec::new()),
+            Dragged::Paths(paths) => {
+                let (headers, files) =
+                    tokio::task::spawn_blocking(move || dnd::list(&paths)).await??;
+                (DragData::Files(headers), files)
+            }
+        };
+
+        // Files of older drags may still be being pasted, but only the last one is kept
+        let id = self.drag.as_ref().map_or(0, |d| d.id + 1);
+        self.drag = Some(Drag {
+            id,
+            files,
+            active: true,
+        });
+
+        log::info!("Forwarding drag to the client");
+        let event = Event::DragEnter { id, data };
+        let _ = self.event_tx.send(Packet::new(0, event)).await;
+
+        Ok(())
+    }
+
+    async fn set_files(&mut self, paths: &[PathBuf]) -> Result<()> {
+        let uri_list = paths
+            .iter()
+            .map(|p| format!("{}\r\n", file_uri(p)))
+            .collect::<String>();
+
+        match self.mode {
+            ClipboardMode::X11 => {
+                xclip::set_xclip_clipboard("text/uri-list", uri_list.as_bytes()).await?;
+            }
+            ClipboardMode::Wayland => {
+                wayland::set_wayland_clipboard("text/uri-list", uri_list.as_bytes()).await?;
+            }
+        }
+
+        log::info!("Put {} files on the clipboard", paths.len());
+        Ok(())
+    }
+
+    async fn set_pulled(&mut self, client: &str, event: Event) -> Result<()> {
+        let event = match event {
+            Event::SealedClipboard { nonce, sealed } => SEAL
+                .open(nonce, sealed)
+                .ok_or_else(|| anyhow::anyhow!("Client sealed its clipboard with another key"))?,
+            _ if *SEALING => anyhow::bail!("Client sent its clipboard unsealed"),
+            event => event,
+        };
+
+        let (kind, data) = match event {
+            // Like text sent out, as it is pasted here
+            Event::TextClipboard { content } => {
+                let content = config::current().clipboard_text.apply(&content);
+                ("text/plain", content.into_bytes())
+            }
+            Event::HtmlClipboard { html, .. } => ("text/html", html.into_bytes()),
+            Event::ImageClipboard { png } => ("image/png", png),
+            Event::RawImageClipboard {
+                width,
+                height,
+                stride,
+                rgba,
+            } => {
+                let png =
+                    tokio::task::spawn_blocking(move || encode_png(width, height, stride, rgba));
+                ("image/png", png.await??)
+            }
+            _ => anyhow::bail!("Client sent something other than its clipboard"),
+        };
+
+        let sha256 = audit::sha256_hex(&data);
+        history::record(Direction::Pulled, Some(client), kind, &data, &sha256);
+        audit::record(audit::AuditEvent::PulledClipboard {
+            client,
+            kind,
+            size: data.len(),
+            sha256,
+        });
+
+        self.set_local(kind, &data).await?;
+
+        log::info!(
+            "Put {} bytes of {} from {} on the clipboard",
+            data.len(),
+            kind,
+            client
+        );
+        Ok(())
+    }
+
+    /// Puts `data` of MIME type `kind` on our clipboard.
+    async fn set_local(&self, kind: &str, data: &[u8]) -> Result<()> {
+        let target = match (&self.mode, kind) {
+            (ClipboardMode::X11, "text/plain") => "UTF8_STRING",
+            (ClipboardMode::Wayland, "text/plain") => "text/plain;charset=utf-8",
+            _ => kind,
+        };
+        match self.mode {
+            ClipboardMode::X11 => xclip::set_xclip_clipboard(target, data).await?,
+            ClipboardMode::Wayland => wayland::set_wayland_clipboard(target, data).await?,
+        }
+        Ok(())
+    }
+
+    async fn type_selection(&mut self) -> Result<()> {
+        // Typed where input goes, so there is nowhere to type it
+        if !grab::is_grabbed() {
+            log::info!("Not typing the selection, input isn't grabbed");
+            return Ok(());
+        }
+
+        // Enough for the characters typed to be whole, however long they are in UTF-8
+        let limit = (MAX_TYPED_LEN + 1) * 4;
+        let text = match self.mode {
+            ClipboardMode::X11 => xclip::get_xclip_primary(limit).await?,
+            ClipboardMode::Wayland => wayland::get_wayland_primary(limit).await?,
+        };
+        let mut text = String::from_utf8_lossy(&text).into_owned();
+        // Typed as Enter, which would run a selected command line
+        text.truncate(text.trim_end_matches(['\r', '\n']).len());
+        if text.is_empty() {
+            log::info!("Nothing selected to type");
+            return Ok(());
+        }
+
+        if let Some((end, _)) = text.char_indices().nth(MAX_TYPED_LEN) {
+            log::warn!(
+                "Typing only the first {} characters of the selection",
+                MAX_TYPED_LEN
+            );
+            text.truncate(end);
+        }
+        log::info!(
+            "Typing {} characters of the selection",
+            text.chars().count()
+        );
+
+        let event = Event::Commit { text };
+        let _ = self.event_tx.send(Packet::new(0, event)).await;
+        Ok(())
+    }
+
+    async fn push(&mut self) -> Result<()> {
+        let pushed = self.pushed.entry(clients::active_uuid()).or_default();
+        let content = match self.mode {
+            ClipboardMode::X11 => {
+                let timestamp = xclip::get_xclip_timestamp().await?;
+                if timestamp.is_some() && timestamp == pushed.timestamp {
+                    return Ok(());
+                }
+
+                let content = if let Some(c) = xclip::get_xclip_clipboard().await? {
+                    c
+                } else {
+                    return Ok(());
+                };
+
+                pushed.timestamp = timestamp;
+                content
+            }
+            ClipboardMode::Wayland => {
+                let content = if let Some(c) = wayland::get_wayland_clipboard().await? {
+                    c
+                } else {
+                    return Ok(());
+                };
+
+                let mut hasher = DefaultHasher::new();
+                content.hash(&mut hasher);
+                let hash = hasher.finish();
+
+                if pushed.hash == Some(hash) {
+                    return Ok(());
+                }
+                pushed.hash = Some(hash);
+
+                content
+            }
+        };
+
+        let event = match content {
+            ClipboardType::Image(image) => {
+                let formats = clients::active_image_formats();
+                tokio::task::spawn_blocking(move || encode_image(image, &formats)).await??
+            }
+            ClipboardType::Utf8Text(content) => {
+                let content = config::current().clipboard_text.apply(&content);
+                Event::TextClipboard { content }
+            }
+            ClipboardType::HtmlText { html, plain } => {
+                let plain = config::current().clipboard_text.apply(&plain);
+                Event::HtmlClipboard { html, plain }
+            }
+        };
+
+        let (kind, bytes) = match &event {
+            Event::ImageClipboard { png } => ("image/png", png.as_slice()),
+            Event::RawImageClipboard { rgba, .. } => ("image/x-rgba", rgba.as_slice()),
+            Event::TextClipboard { content } => ("text/plain", content.as_bytes()),
+            Event::HtmlClipboard { html, .. } => ("text/html", html.as_bytes()),
+            _ => unreachable!(),
+        };
+        let client = clients::active();
+        let sha256 = audit::sha256_hex(bytes);
+        history::record(Direction::Sent, client.as_deref(), kind, bytes, &sha256);
+        audit::record(audit::AuditEvent::Clipboard {
+            kind,
+            size: bytes.len(),
+            sha256,
+            client,
+        });
+
+        let size = bytes.len() as u64;
+        let kind = kind.to_owned();
+
+        // Sealed before it is packed, so only the client has it in the clear again
+        let event = if *SEALING { SEAL.seal(&event) } else { event };
+
+        let event = if size > config::current().clipboard_offer_size {
+            let id = self.next_offer_id;
+            self.next_offer_id += 1;
+            let packet = Packet::new(0, event).to_vec();
+            self.offer = Some(Offer {
+                id,
+                kind: kind.clone(),
+                size,
+                packet: Arc::new(packet),
+            });
+
+            log::info!("Offering {} bytes of {} to the client", size, kind);
+            Event::ClipboardOffer { id, kind, size }
+        } else {
+            // Replaced, so clients connecting later aren't offered stale contents
+            self.offer = None;
+            event
+        };
+
+        let _ = self.event_tx.send(Packet::new(0, event)).await;
+
+        Ok(())
+    }
+}
+
+/// Percent-encodes an absolute path into a `file://` URI.
+fn file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+
+    for &b in path.as_os_str().as_bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+
+    uri
+}
+
+/// Encodes raw RGBA rows, `stride` bytes apart, as PNG.
+fn encode_png(width: u32, height: u32, stride: u32, rgba: Vec<u8>) -> Result<Vec<u8>> {
+    let row = (width as usize).saturating_mul(4);
+    let size = (stride as usize).checked_mul(height as usize);
+    if row == 0 || (stride as usize) < row || size.is_none_or(|size| rgba.len() < size) {
+        anyhow::bail!("Malformed clipboard image: {}x{}", width, height);
+    }
+
+    let rows = rgba
+        .chunks_exact(stride as usize)
+        .take(height as usize)
+        .flat_map(|r| &r[..row])
+        .copied()
+        .collect();
+    let image = image::RgbaImage::from_raw(width, height, rows)
+        .ok_or_else(|| anyhow::anyhow!("Malformed clipboard image: {}x{}", width, height))?;
+
+    let mut png = Vec::new();
+    image.write_to(&mut std::io::Cursor::new(&mut png), SourceFormat::Png)?;
+    Ok(png)
+}
+
+/// Converts an image from the clipboard into the first of `formats` we can produce.
+///
+/// PNG is passed through untouched; anything else is decoded once here so the client
+/// doesn't have to.
+fn encode_image(image: Vec<u8>, formats: &[ImageFormat]) -> Result<Event> {
+    let source = image::guess_format(&image)?;
+    let format = formats.first().copied().unwrap_or(ImageFormat::Png);
+
+    if format == ImageFormat::Png && source == SourceFormat::Png {
+        return Ok(Event::ImageClipboard { png: image });
+    }
+
+    let decoded = image::load_from_memory_with_format(&image, source)?.into_rgba8();
+    let (width, height) = decoded.dimensions();
+
+    let event = match format {
+        ImageFormat::Png => {
+            let mut png = Vec::new();
+            decoded.write_to(&mut std::io::Cursor::new(&mut png), SourceFormat::Png)?;
+            Event::ImageClipboard { png }
+        }
+        ImageFormat::Rgba => Event::RawImageClipboard {
+            width,
+            height,
+            stride: width
+                .checked_mul(4)
+                .ok_or_else(|| anyhow::anyhow!("Clipboard image too wide: {}", width))?,
+            rgba: decoded.into_raw(),
+        },
+    };
+
+    Ok(event)
+}
@@ -0,0 +1,1111 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use keycode::KeyMappingId;
+use rkvm_protocol::{Monitor, QualityThresholds, TextNormalization, Timeouts};
+use serde::Deserialize;
+use tokio::sync::watch;
+
+/// The config in effect, replaced on reload.
+static CURRENT: OnceLock<watch::Sender<Arc<Config>>> = OnceLock::new();
+
+/// File the config was loaded from, if any.
+static PATH: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Bumped on every reload.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// What to do when grabbing input while someone is using a client's own keyboard or mouse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BusyClientPolicy {
+    Ignore,
+    Warn,
+    Refuse,
+}
+
+/// How the hotkey controls the grab.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrabMode {
+    /// Each tap toggles the grab
+    Toggle,
+    /// Input is grabbed only while the hotkey is held
+    Hold,
+    /// A tap toggles the grab, holding grabs only until release
+    Hybrid,
+}
+
+/// What lights up on this machine's keyboard while input is grabbed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrabIndicator {
+    ScrollLock,
+    CapsLock,
+    NumLock,
+    Backlight,
+}
+
+/// Something a hotkey gesture can be bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GestureAction {
+    ToggleGrab,
+    NextClient,
+    PushClipboard,
+    PullClipboard,
+    SwapClipboards,
+    ToggleClipboardSync,
+    ToggleGameMode,
+    TypeSelection,
+    Attention,
+    CheatSheet,
+    SwitchBack,
+    ReturnLocal,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HotkeyConfig {
+    /// Key controlling the grab
+    pub key: KeyMappingId,
+    /// How the hotkey controls the grab
+    pub mode: GrabMode,
+    /// Presses held at least this long are long presses (momentary in hybrid mode)
+    pub long_press_ms: u64,
+    /// Maximum time between releasing the first tap and pressing the second
+    pub double_tap_ms: u64,
+    /// Action for a single tap in toggle mode
+    pub tap: Option<GestureAction>,
+    /// Action for a double tap in toggle mode. Leave unset to avoid delaying single taps
+    pub double_tap: Option<GestureAction>,
+    /// Action for a long press in toggle mode. Unset, long presses count as taps
+    pub long_press: Option<GestureAction>,
+    /// Key that sends the clipboard to the active client, without changing the grab
+    pub push_clipboard_key: Option<KeyMappingId>,
+    /// Key that puts the active client's clipboard on this machine's
+    pub pull_clipboard_key: Option<KeyMappingId>,
+    /// Key that trades clipboards with the active client
+    pub swap_clipboards_key: Option<KeyMappingId>,
+    /// Key that turns sending the clipboard on every grab and switch on or off
+    pub toggle_clipboard_sync_key: Option<KeyMappingId>,
+    /// Key that turns game mode on or off
+    pub toggle_game_mode_key: Option<KeyMappingId>,
+    /// Key that types the selection here on the active client
+    pub type_selection_key: Option<KeyMappingId>,
+    /// Key that flashes the active client's screen border and chimes there
+    pub attention_key: Option<KeyMappingId>,
+    /// Key that shows the bindings and connected clients
+    pub cheat_sheet_key: Option<KeyMappingId>,
+    /// Key that goes back to the client input went to before, or to this machine
+    pub switch_back_key: Option<KeyMappingId>,
+    /// Key that ungrabs, keeping input on this machine
+    pub return_local_key: Option<KeyMappingId>,
+    /// Also send the hotkey to the client while grabbed, so it still works there
+    pub pass_through: bool,
+    /// Key combinations never sent to the client while grabbed, like compositor shortcuts
+    pub blocked_combos: Vec<Vec<KeyMappingId>>,
+    /// Key combinations typed on this machine instead of the client while grabbed
+    pub local_combos: Vec<Vec<KeyMappingId>>,
+}
+
+impl Default for HotkeyConfig {
+    fn default() -> Self {
+        Self {
+            key: KeyMappingId::ControlRight,
+            mode: GrabMode::Toggle,
+            long_press_ms: 300,
+            double_tap_ms: 300,
+            tap: Some(GestureAction::ToggleGrab),
+            double_tap: None,
+            long_press: None,
+            push_clipboard_key: None,
+            pull_clipboard_key: None,
+            swap_clipboards_key: None,
+            toggle_clipboard_sync_key: None,
+            toggle_game_mode_key: None,
+            type_selection_key: None,
+            attention_key: None,
+            cheat_sheet_key: None,
+            switch_back_key: None,
+            return_local_key: None,
+            pass_through: false,
+            blocked_combos: Vec::new(),
+            local_combos: Vec::new(),
+        }
+    }
+}
+
+/// What happens to events for a client that can't keep up with them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Skip the oldest events the client hasn't been sent yet
+    DropOldest,
+    /// Hold up the events of every client until it catches up
+    Block,
+    /// Log an error and disconnect the client
+    Fail,
+}
+
+/// Queue sizes for events on their way to clients, used from the start of the server.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChannelConfig {
+    /// Events read from input devices and waiting to be sent out
+    pub queue: usize,
+    /// Mouse and gamepad axis events waiting for each client
+    pub mouse_capacity: usize,
+    /// Keyboard and gamepad button events waiting for each client
+    pub keyboard_capacity: usize,
+    /// Clipboard and other events waiting for each client
+    pub misc_capacity: usize,
+    pub mouse_overflow: OverflowPolicy,
+    pub keyboard_overflow: OverflowPolicy,
+    pub misc_overflow: OverflowPolicy,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            queue: 128,
+            mouse_capacity: 120,
+            keyboard_capacity: 30,
+            misc_capacity: 30,
+            mouse_overflow: OverflowPolicy::DropOldest,
+            keyboard_overflow: OverflowPolicy::Fail,
+            misc_overflow: OverflowPolicy::Fail,
+        }
+    }
+}
+
+/// Which state changes play a tone, and through what.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct SoundConfig {
+    pub grab: bool,
+    pub ungrab: bool,
+    /// Beeps once for the first client in connection order, twice for the second and so on
+    pub switch: bool,
+    /// Chimes when a client asks for attention
+    pub attention: bool,
+    /// Command playing a WAV file from its standard input
+    pub player: String,
+}
+
+impl Default for SoundConfig {
+    fn default() -> Self {
+        Self {
+            grab: false,
+            ungrab: false,
+            switch: false,
+            attention: true,
+            player: "aplay -q".to_owned(),
+        }
+    }
+}
+
+/// What is kept of clipboards sent and pulled, to see what went where and to get one back.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ClipboardHistoryConfig {
+    /// Keep kinds, sizes and digests, sealed in the state dir
+    pub enabled: bool,
+    /// Also keep the contents, so they can be put back on the clipboard
+    pub content: bool,
+    /// Most items kept, dropping the oldest
+    pub max_items: usize,
+    /// Items older than this are dropped
+    pub max_age_hours: u64,
+}
+
+impl Default for ClipboardHistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            content: false,
+            max_items: 20,
+            max_age_hours: 24,
+        }
+    }
+}
+
+/// Clients switched to by pushing the cursor against an edge of the screen, under X11.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct EdgeSwitchConfig {
+    /// Clients by name, id or IP address
+    pub left: Option<String>,
+    pub right: Option<String>,
+    pub top: Option<String>,
+    pub bottom: Option<String>,
+    /// Pixels of motion pushed into an edge within a second that switch
+    pub pressure: u32,
+}
+
+impl Default for EdgeSwitchConfig {
+    fn default() -> Self {
+        Self {
+            left: None,
+            right: None,
+            top: None,
+            bottom: None,
+            pressure: 150,
+        }
+    }
+}
+
+/// What the lid and tablet-mode switches of a laptop server do.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct SwitchConfig {
+    /// Ungrab when the lid closes
+    pub lid_ungrab: bool,
+    /// Tell clients to suspend when the lid closes
+    pub lid_suspend_clients: bool,
+    /// Ungrab and stop grabbing while the server is a tablet
+    pub tablet_mode_ungrab: bool,
+}
+
+impl Default for SwitchConfig {
+    fn default() -> Self {
+        Self {
+            lid_ungrab: true,
+            lid_suspend_clients: false,
+            tablet_mode_ungrab: false,
+        }
+    }
+}
+
+/// Adapting to connections that lose packets or take long to answer.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct LinkQualityConfig {
+    /// Adapt at all
+    pub adaptive: bool,
+    /// Round trip time and loss where a connection counts as degraded
+    pub degraded_rtt_ms: u64,
+    pub degraded_loss_percent: u64,
+    /// Round trip time and loss where a connection counts as poor
+    pub poor_rtt_ms: u64,
+    pub poor_loss_percent: u64,
+    /// Mouse motion to a degraded client is merged into one move over this long
+    pub coalesce_ms: u64,
+}
+
+impl Default for LinkQualityConfig {
+    fn default() -> Self {
+        let thresholds = QualityThresholds::default();
+        Self {
+            adaptive: true,
+            degraded_rtt_ms: thresholds.degraded_rtt.as_millis() as u64,
+            degraded_loss_percent: thresholds.degraded_loss_percent,
+            poor_rtt_ms: thresholds.poor_rtt.as_millis() as u64,
+            poor_loss_percent: thresholds.poor_loss_percent,
+            coalesce_ms: 16,
+        }
+    }
+}
+
+impl LinkQualityConfig {
+    pub fn thresholds(&self) -> QualityThresholds {
+        QualityThresholds {
+            degraded_rtt: Duration::from_millis(self.degraded_rtt_ms),
+            degraded_loss_percent: self.degraded_loss_percent,
+            poor_rtt: Duration::from_millis(self.poor_rtt_ms),
+            poor_loss_percent: self.poor_loss_percent,
+        }
+    }
+}
+
+/// Scheduling of the thread reading input devices, applied at the start of the server.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct InputThreadConfig {
+    /// Core to keep it on, numbered from 0
+    pub cpu: Option<usize>,
+    /// Run it under `SCHED_FIFO`, which takes `CAP_SYS_NICE` or an `RLIMIT_RTPRIO`
+    pub realtime: bool,
+    /// From 1 to 99, higher going first
+    pub realtime_priority: i32,
+}
+
+impl Default for InputThreadConfig {
+    fn default() -> Self {
+        Self {
+            cpu: None,
+            realtime: false,
+            realtime_priority: 10,
+        }
+    }
+}
+
+/// When a rule runs.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "on", rename_all = "snake_case")]
+pub enum RuleTrigger {
+    /// Pressing the last of `keys` while holding the others, which the client then doesn't get
+    Key {
+        keys: Vec<KeyMappingId>,
+        /// Only the second of two presses within `hotkey.double_tap_ms`
+        #[serde(default)]
+        double_tap: bool,
+    },
+    /// Input moving to a client, any if `client` is unset
+    Switch {
+        client: Option<String>,
+    },
+    ClientConnect {
+        client: Option<String>,
+    },
+}
+
+/// What a rule does, in order.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "do", rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Grabs for the client by name, id or IP address
+    SwitchTo {
+        client: String,
+    },
+    /// Grabs for the client in a slot, see `known-clients.json` in the state dir
+    SwitchToSlot {
+        slot: usize,
+    },
+    PushClipboard,
+    PullClipboard,
+    SwapClipboards,
+    TypeSelection,
+    Attention,
+    CheatSheet,
+    /// Goes to the client input went to before the active one, or back here
+    SwitchBack,
+    /// Ungrabs, keeping input on this machine
+    ReturnLocal,
+    /// Runs `command` with `sh -c`, with the client in `RKVM_CLIENT` when there is one
+    RunCommand {
+        command: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Rule {
+    #[serde(flatten)]
+    pub trigger: RuleTrigger,
+    pub actions: Vec<RuleAction>,
+}
+
+/// A shortcut and the keys sent to the client for it instead.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ShortcutMapping {
+    pub from: Vec<KeyMappingId>,
+    pub to: Vec<KeyMappingId>,
+}
+
+/// Size of a client's screen, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ScreenSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ScreenSize {
+    /// A single unscaled monitor of this size.
+    pub fn monitor(self) -> Monitor {
+        Monitor {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+            scale: 1.0,
+            primary: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Address and port to listen on
+    pub bind: SocketAddr,
+    /// Directory holding the persistent server identity and other state
+    pub state_dir: PathBuf,
+    /// Pre-shared key clients must prove knowledge of before any input is streamed to them
+    pub psk: Option<String>,
+    /// What gets logged by module, unless `--log`, `--verbose` or `RUST_LOG` say otherwise
+    pub log: Option<String>,
+    /// Hold clients never seen before until they are approved here
+    pub approve_new_clients: bool,
+    /// Turn away clients waiting to be approved for longer
+    pub approval_timeout_secs: u64,
+    /// Show a notification when a client can't inject input
+    pub notify_inject_errors: bool,
+    /// Write the warnings and errors clients relay to our log
+    pub client_logs: bool,
+    /// Most seconds between keep-alives and before a silent connection is given up on, 0
+    /// for no idle timeout. Clients may ask for less. Used from the start of the server
+    pub keep_alive_secs: u64,
+    pub idle_timeout_secs: u64,
+    /// Append-only log of grabs, connected clients and clipboard transfer metadata
+    pub audit_log: Option<PathBuf>,
+    /// Action taken when grabbing while a client is in local use
+    pub busy_client_policy: BusyClientPolicy,
+    /// A client counts as in local use if its own input was used within this many seconds
+    pub busy_client_threshold_secs: u64,
+    pub hotkey: HotkeyConfig,
+    /// Keep the server from blanking the screen or suspending while input is grabbed
+    pub inhibit_idle: bool,
+    /// Let clients send their own keyboard and mouse input to this machine
+    pub allow_reverse_control: bool,
+    /// Directory files sent by clients are written to before being put on the clipboard
+    pub staging_dir: PathBuf,
+    /// File transfers from clients larger than this many bytes are declined
+    pub max_transfer_size: Option<u64>,
+    /// Clipboard contents larger than this many bytes are offered to the client first
+    pub clipboard_offer_size: u64,
+    /// Cap in bytes per second for clipboard and file transfers, so input stays responsive
+    pub bandwidth_limit: Option<u64>,
+    /// Screen sizes of clients by name, id or IP address, overriding the monitors they report
+    pub screens: HashMap<String, ScreenSize>,
+    /// Move the cursor of clients to where it should be this often.
+    /// It is centered whenever input moves to a client
+    pub cursor_correction_secs: Option<u64>,
+    /// Drop motion past the outer edges of the client's monitors, for clients whose cursor
+    /// doesn't stop there
+    pub clamp_cursor: bool,
+    /// Scale motion by the DPI scale of the client monitor under the cursor
+    pub scale_motion: bool,
+    /// Move the cursor into a corner on whichever machine isn't receiving input
+    pub park_cursor: bool,
+    /// Send the clipboard to the client on every grab and switch
+    pub clipboard_sync: bool,
+    /// Seal clipboard contents with a key made up at startup, on top of TLS, so they stay
+    /// out of buffers, logs and crash dumps until put on the clipboard. Read at startup
+    pub encrypt_clipboard: bool,
+    /// Unix socket accepting commands like `reload`, `control.sock` in `state_dir` by default
+    pub control_socket: Option<PathBuf>,
+    /// Ungrab when nothing was forwarded for this many minutes, never if 0
+    pub idle_ungrab_mins: u64,
+    /// Windows layout id the keyboard here is labelled for, which clients switch to
+    pub keyboard_layout: Option<String>,
+    /// Layouts for clients by name, id or IP address, overriding `keyboard_layout`
+    pub keyboard_layouts: HashMap<String, String>,
+    /// Shortcut translations by the name of the map they are in
+    pub shortcut_maps: HashMap<String, Vec<ShortcutMapping>>,
+    /// Shortcut map for every client without one of its own
+    pub shortcut_map: Option<String>,
+    /// Shortcut maps for clients by name, id or IP address, overriding `shortcut_map`
+    pub client_shortcut_maps: HashMap<String, String>,
+    /// Send gamepad input to the active client while grabbed
+    pub forward_gamepads: bool,
+    /// Read only these evdev devices instead of everything on the seat, without udev
+    pub devices: Vec<PathBuf>,
+    /// Times to try again, 50 ms apart, grabbing a device another program has grabbed
+    pub grab_retries: u32,
+    /// Shared libraries with event filters, applied in order. Only read at startup
+    pub filters: Vec<PathBuf>,
+    /// Light up while input is grabbed
+    pub grab_indicator: Option<GrabIndicator>,
+    pub channels: ChannelConfig,
+    pub sounds: SoundConfig,
+    /// How text from the clipboard here is cleaned up before it is sent
+    pub clipboard_text: TextNormalization,
+    pub clipboard_history: ClipboardHistoryConfig,
+    pub edge_switch: EdgeSwitchConfig,
+    pub switches: SwitchConfig,
+    pub link_quality: LinkQualityConfig,
+    pub input_thread: InputThreadConfig,
+    /// Actions run on keys, switches and clients connecting
+    pub rules: Vec<Rule>,
+    /// Rhai script called on keys, switches and clients connecting
+    pub script: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:12334".parse().unwrap(),
+            state_dir: PathBuf::from("/var/lib/rkvm-server"),
+            psk: None,
+            log: None,
+            approve_new_clients: false,
+            approval_timeout_secs: 60,
+            notify_inject_errors: true,
+            client_logs: true,
+            keep_alive_secs: 5,
+            idle_timeout_secs: 10,
+            audit_log: None,
+            busy_client_policy: BusyClientPolicy::Warn,
+            busy_client_threshold_secs: 5,
+            hotkey: HotkeyConfig::default(),
+            inhibit_idle: true,
+            allow_reverse_control: false,
+            staging_dir: std::env::temp_dir().join("rkvm-server"),
+            max_transfer_size: None,
+            clipboard_offer_size: 8 * 1024 * 1024,
+            bandwidth_limit: None,
+            screens: HashMap::new(),
+            cursor_correction_secs: None,
+            clamp_cursor: false,
+            scale_motion: false,
+            park_cursor: false,
+            clipboard_sync: true,
+            encrypt_clipboard: false,
+            control_socket: None,
+            idle_ungrab_mins: 30,
+            keyboard_layout: None,
+            keyboard_layouts: HashMap::new(),
+            shortcut_maps: HashMap::new(),
+            shortcut_map: None,
+            client_shortcut_maps: HashMap::new(),
+            forward_gamepads: false,
+            devices: Vec::new(),
+            grab_retries: 3,
+            filters: Vec::new(),
+            grab_indicator: None,
+            channels: ChannelConfig::default(),
+            sounds: SoundConfig::default(),
+            clipboard_text: TextNormalization::default(),
+            clipboard_history: ClipboardHistoryConfig::default(),
+            edge_switch: EdgeSwitchConfig::default(),
+            switches: SwitchConfig::default(),
+            link_quality: LinkQualityConfig::default(),
+            input_thread: InputThreadConfig::default(),
+            rules: Vec::new(),
+            script: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let config_string = std::fs::read_to_string(path)?;
+        let config: Self = toml::from_str(&config_string)?;
+        config.timeouts()?;
+
+        Ok(config)
+    }
+
+    pub fn timeouts(&self) -> Result<Timeouts> {
+        Timeouts::from_secs(self.keep_alive_secs, self.idle_timeout_secs)
+            .map_err(anyhow::Error::msg)
+    }
+
+    pub fn control_socket_path(&self) -> PathBuf {
+        self.control_socket
+            .clone()
+            .unwrap_or_else(|| self.state_dir.join("control.sock"))
+    }
+
+    /// Keyboard layout for the client known by any of `keys`.
+    pub fn keyboard_layout_for(&self, keys: &[String]) -> Option<&str> {
+        keys.iter()
+            .find_map(|key| self.keyboard_layouts.get(key))
+            .or(self.keyboard_layout.as_ref())
+            .map(String::as_str)
+    }
+
+    /// Shortcut translations for the client known by any of `keys`.
+    pub fn shortcut_map_for(&self, keys: &[String]) -> &[ShortcutMapping] {
+        keys.iter()
+            .find_map(|key| self.client_shortcut_maps.get(key))
+            .or(self.shortcut_map.as_ref())
+            .and_then(|name| self.shortcut_maps.get(name))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
+
+/// A config file with every option at its default, commented.
+pub fn sample() -> String {
+    let defaults = Config::default();
+
+    format!(
+        r#"# Address and port to listen on
+bind = "{bind}"
+
+# Directory holding the persistent server identity and other state
+state_dir = {state_dir:?}
+
+# Pre-shared key clients must prove knowledge of before any input is streamed to them
+# psk = "correct horse battery staple"
+
+# What gets logged, by module and level like "info,rkvm_server::grab=debug,quinn=warn".
+# --log, --verbose and RUST_LOG take precedence. Follows reloads, and
+# "rkvm-server log <filter>" changes it until the next one
+# log = "info"
+
+# Hold clients never seen before until they are approved, from a notification or with
+# "rkvm-server approve <client>", and turn them away if nobody does in time. Clients are
+# approved by the certificate they generate on first start, so clients without one are
+# turned away. Clients seen before are kept in known-clients.json in state_dir.
+# "rkvm-server revoke <client>" needs this, as clients pick their own ids and a revoked one
+# could come back under a new one
+approve_new_clients = {approve_new_clients}
+approval_timeout_secs = {approval_timeout_secs}
+
+# Show a notification when a client can't inject the input sent to it, as on Windows while
+# a UAC prompt is in front. It is logged either way
+notify_inject_errors = {notify_inject_errors}
+
+# Write the warnings and errors clients with relay_logs send to our log, tagged with their
+# name. Each client gets a few a second, the rest are counted and dropped
+client_logs = {client_logs}
+
+# Most seconds between keep-alives, and before a silent connection is given up on, 0 for
+# never. Keep-alives must come more often. Clients may be configured with less, which their
+# connections then use
+keep_alive_secs = {keep_alive_secs}
+idle_timeout_secs = {idle_timeout_secs}
+
+# Append-only log of grabs, connected clients and clipboard transfer metadata
+# audit_log = "/var/log/rkvm-server/audit.log"
+
+# Rhai script for what the rules below can't express, read again on every reload. It may
+# define on_key(key, pressed), returning true to keep the key from the client, on_switch
+# (client) and on_client_connect(client), which call switch_to(client), switch_to_slot
+# (slot), switch_back(), return_local(), push_clipboard(), pull_clipboard(),
+# swap_clipboards() and run_command(command) and keep state on "this", for example
+#
+#     fn on_key(key, pressed) {{
+#         if key != "F13" {{ return false; }}
+#         if pressed && this.last_f13 != () && now_ms() - this.last_f13 < 300 {{
+#             switch_to("laptop");
+#             run_command("ddcutil setvcp 60 0x0f");
+#         }}
+#         if pressed {{ this.last_f13 = now_ms(); }}
+#         true
+#     }}
+# script = "/etc/rkvm/server.rhai"
+
+# What to do when grabbing while someone is using the client's own keyboard or mouse:
+# "ignore", "warn" or "refuse"
+busy_client_policy = "warn"
+
+# A client counts as in local use if its own input was used within this many seconds
+busy_client_threshold_secs = {busy_client_threshold_secs}
+
+# Keep this machine from blanking the screen or suspending while input is grabbed
+inhibit_idle = {inhibit_idle}
+
+# Let clients send their own keyboard and mouse input to this machine
+allow_reverse_control = {allow_reverse_control}
+
+# Directory files sent by clients are written to before being put on the clipboard
+staging_dir = {staging_dir:?}
+
+# File transfers from clients larger than this many bytes are declined
+# max_transfer_size = 1073741824
+
+# Clipboard contents larger than this many bytes are offered to the client first
+clipboard_offer_size = {clipboard_offer_size}
+
+# Cap in bytes per second for clipboard and file transfers, so input stays responsive
+# bandwidth_limit = 10485760
+
+# Move the cursor of clients to where it should be this often
+# cursor_correction_secs = 5
+
+# Drop motion past the outer edges of the client's monitors, for clients whose cursor
+# doesn't stop there and would get out of step with where it should be
+clamp_cursor = {clamp_cursor}
+
+# Scale motion by the DPI scale of the client monitor under the cursor
+scale_motion = {scale_motion}
+
+# Move the cursor into a corner on whichever machine isn't receiving input
+park_cursor = {park_cursor}
+
+# Send the clipboard to the client on every grab and switch. Without it the clipboard is
+# only sent when asked for, with a hotkey or `push-clipboard` on the control socket
+clipboard_sync = {clipboard_sync}
+
+# Seal clipboard contents with a key made up whenever the server starts, on top of TLS, so
+# they aren't in the clear in buffers, logs or crash dumps on the way. They are only
+# opened to be put on a clipboard, and clients that send theirs unsealed are ignored. Read
+# at startup
+encrypt_clipboard = {encrypt_clipboard}
+
+# Unix socket accepting commands like `rkvm-server reload`
+# control_socket = {control_socket:?}
+
+# Ungrab when nothing was forwarded for this many minutes, so a forgotten grab doesn't
+# leave this machine's keyboard dead. 0 never ungrabs
+idle_ungrab_mins = {idle_ungrab_mins}
+
+# Windows layout id the keyboard here is labelled for, like "00000407" for German or
+# "0000040C" for French. Windows clients type in it, so AltGr and dead keys give the
+# characters on the keys whatever layout the client uses itself. Unset, clients set to
+# follow_server_layout go by the XKB layout detected here instead
+# keyboard_layout = "00000407"
+
+# Map from [shortcut_maps] translating shortcuts for every client, like "mac"
+# shortcut_map = "mac"
+
+# Send gamepad input to the active client while grabbed, keeping it from programs here.
+# Windows clients need ViGEmBus and ViGEmClient.dll, Linux clients access to /dev/uinput
+forward_gamepads = {forward_gamepads}
+
+# Read only these devices instead of asking udev for everything on the seat, for
+# containers without udev or seats shared some other way. Devices plugged in later
+# aren't noticed, and paths under /dev/input/by-id keep working across reboots
+# devices = ["/dev/input/by-id/usb-Logitech_USB_Receiver-event-kbd"]
+
+# Times to try again, 50 ms apart, grabbing a device another program has grabbed. Input
+# isn't grabbed at all if the keyboard last typed on can't be
+grab_retries = {grab_retries}
+
+# Shared libraries with filters that see every event on its way to clients and may change
+# or drop it, in the order listed. They must be built against the version of
+# rkvm-server-core the server was built with, and are only loaded when the server starts
+# filters = ["/usr/local/lib/rkvm/libswap_buttons.so"]
+
+# Light up while input is grabbed, to show which machine it goes to when nothing on screen
+# does: "scroll_lock", "caps_lock" or "num_lock" for that LED on every keyboard, or
+# "backlight" to turn keyboard backlights all the way up
+# grab_indicator = "scroll_lock"
+
+[hotkey]
+# Key controlling the grab, by name like "ControlRight" or "F13"
+key = "ControlRight"
+
+# How the key controls the grab: "toggle", "hold" or "hybrid"
+mode = "toggle"
+
+# Presses held at least this long are long presses
+long_press_ms = {long_press_ms}
+
+# Maximum time between releasing the first tap and pressing the second
+double_tap_ms = {double_tap_ms}
+
+# Actions for gestures in toggle mode: "toggle_grab", "next_client", "push_clipboard",
+# "pull_clipboard", "swap_clipboards", "toggle_clipboard_sync", "toggle_game_mode",
+# "type_selection", "attention", "cheat_sheet", "switch_back" or "return_local".
+# Leave double_tap unset to avoid delaying single taps. Long presses count as taps
+# unless long_press is set
+tap = "toggle_grab"
+# double_tap = "next_client"
+# long_press = "push_clipboard"
+
+# Keys of their own for clipboard actions, by name like "ScrollLock" or "Pause".
+# They are never forwarded while grabbed. Pulling takes the active client's clipboard,
+# and swapping trades clipboards with it, for when automatic sync is off
+# push_clipboard_key = "ScrollLock"
+# pull_clipboard_key = "F13"
+# swap_clipboards_key = "F14"
+# toggle_clipboard_sync_key = "Pause"
+
+# Key for game mode, which sends mouse motion raw and unbuffered in datagrams, without
+# keeping the cursor on the client's screens, and stops sending the clipboard on switches
+# toggle_game_mode_key = "F12"
+
+# Key typing what is selected here, the primary selection and not the clipboard, on the
+# active client key by key. For consoles, VMs and password fields that won't take a paste
+# type_selection_key = "F15"
+
+# Key getting the attention of whoever sits at the active client, flashing its screen
+# border and chiming there. Once every few seconds at most, either way
+# attention_key = "F16"
+
+# Key showing a notification listing these bindings and the connected clients by number
+# cheat_sheet_key = "F17"
+
+# Key going back and forth between the last two places input went, like Alt+Tab: the client
+# before the active one, or this machine if input came from here
+# switch_back_key = "F18"
+
+# Key always bringing input back to this machine
+# return_local_key = "F19"
+
+# Also send the hotkey to the client while grabbed, so it still works as a key there.
+# This machine gets it whenever input isn't grabbed, as devices are left alone then
+pass_through = {pass_through}
+
+# Key combinations, by key names, that are never sent to the client while grabbed
+# blocked_combos = [["MetaLeft", "UsL"]]
+
+# Key combinations typed on this machine instead of the client while grabbed. Keys held
+# before the last one of a combination already went to the client, and are released there
+# as usual
+# local_combos = [["ControlLeft", "AltLeft", "F1"], ["MetaLeft", "Tab"]]
+
+[channels]
+# Events read from input devices and waiting to be sent out. Capacities only change on a
+# restart, overflow policies on reload too
+queue = {queue}
+
+# Events waiting for each client, by kind. Raise them on slow links. Clients taking every
+# event on one stream get room for all three. Motion beyond its share is skipped for them,
+# unless mouse_overflow is "block", and otherwise they fall behind like on the misc stream
+mouse_capacity = {mouse_capacity}
+keyboard_capacity = {keyboard_capacity}
+misc_capacity = {misc_capacity}
+
+# What to do once a client falls that far behind: "drop_oldest" skips events it hasn't
+# been sent yet, "block" holds up every client until it catches up and "fail" disconnects it
+mouse_overflow = "drop_oldest"
+keyboard_overflow = "fail"
+misc_overflow = "fail"
+
+[sounds]
+# Tones for grabbing, ungrabbing and switching clients, and a chime for a client asking
+# for attention, played by a command reading WAV from its standard input, like "paplay" or
+# "pw-play -"
+grab = {sound_grab}
+ungrab = {sound_ungrab}
+switch = {sound_switch}
+attention = {sound_attention}
+player = "{player}"
+
+[clipboard_text]
+# Line endings of text sent to clients: "native" for LF, which clients convert to their own,
+# "crlf", "lf", or "keep" to leave them alone
+line_endings = "native"
+# Drop the NUL characters some programs copy along at the end
+strip_trailing_nul = true
+# Drop a byte order mark at the start
+strip_bom = true
+
+[clipboard_history]
+# Keep the kind, size and digest of clipboards sent to and pulled from clients, sealed in
+# clipboard-history in state_dir with a key kept in the system keyring, or next to it
+# without one. "rkvm-server history" lists them
+enabled = {history_enabled}
+# Also keep the contents, so "rkvm-server restore-clipboard <n>" can put one back on this
+# machine's clipboard and send it on. Anyone who can read the key can read them
+content = {history_content}
+# The oldest items are dropped beyond this many, or once older than this
+max_items = {history_max_items}
+max_age_hours = {history_max_age_hours}
+
+[edge_switch]
+# Clients by name, id or IP address to grab and switch to by pushing the cursor hard
+# against an edge of the screen, without the hotkey. Only under X11, and the edges are
+# set up when the server starts
+# left = "laptop"
+# right = "desktop"
+
+# Pixels of motion pushed into an edge within a second that switch
+pressure = {edge_pressure}
+
+[switches]
+# Ungrab when the lid of this machine closes
+lid_ungrab = {lid_ungrab}
+# Tell clients to suspend as well when the lid closes
+lid_suspend_clients = {lid_suspend_clients}
+# Ungrab, and don't grab again, while this machine is folded into a tablet
+tablet_mode_ungrab = {tablet_mode_ungrab}
+
+[link_quality]
+# Adapt to clients on slow or lossy connections: mouse motion to them is merged, clipboard
+# sync pauses while input is on one with a poor connection, and changes are shown
+adaptive = {adaptive}
+# Round trip time in milliseconds and packet loss in percent where a connection is
+# degraded, and where it is poor
+degraded_rtt_ms = {degraded_rtt_ms}
+degraded_loss_percent = {degraded_loss_percent}
+poor_rtt_ms = {poor_rtt_ms}
+poor_loss_percent = {poor_loss_percent}
+# Mouse motion to a client on a degraded connection is merged over this many milliseconds
+coalesce_ms = {coalesce_ms}
+
+[input_thread]
+# Keep the thread reading input devices on this core, numbered from 0, and run it ahead of
+# everything else with SCHED_FIFO, for less jitter on a busy machine. Realtime scheduling
+# takes CAP_SYS_NICE or an RLIMIT_RTPRIO, and is skipped with a warning without them
+# cpu = 2
+realtime = {realtime}
+realtime_priority = {realtime_priority}
+
+# Screen sizes of clients by name, id or IP address, overriding the monitors they report
+# [screens.laptop]
+# width = 1920
+# height = 1080
+
+# Keyboard layouts of clients by name, id or IP address, overriding keyboard_layout
+# [keyboard_layouts]
+# laptop = "00000809"
+
+# Shortcuts sent to clients as other keys while grabbed, in maps by name. The keys of a
+# shortcut the translation lacks are let go of on the client until it is released
+# [[shortcut_maps.mac]]
+# from = ["ControlLeft", "UsC"]
+# to = ["MetaLeft", "UsC"]
+#
+# [[shortcut_maps.mac]]
+# from = ["ControlLeft", "UsV"]
+# to = ["MetaLeft", "UsV"]
+
+# Shortcut maps of clients by name, id or IP address, overriding shortcut_map
+# [client_shortcut_maps]
+# macbook = "mac"
+
+# Rules running actions on a key combination ("key", the client doesn't get its last key),
+# on input moving to a client ("switch") or on a client connecting ("client_connect").
+# Actions are "switch_to" a client, "switch_to_slot", "push_clipboard", "pull_clipboard",
+# "swap_clipboards", "type_selection", "attention", "cheat_sheet", "switch_back",
+# "return_local" and "run_command", which gets the client in RKVM_CLIENT. Clients get
+# numbered slots the first time they connect, kept in known-clients.json in state_dir,
+# so "switch_to_slot" goes to the same machine however they connect
+# [[rules]]
+# on = "key"
+# keys = ["F13"]
+# double_tap = true
+# actions = [
+#     {{ do = "switch_to", client = "laptop" }},
+#     {{ do = "run_command", command = "ddcutil setvcp 60 0x0f" }},
+# ]
+#
+# [[rules]]
+# on = "key"
+# keys = ["ControlLeft", "AltLeft", "Digit1"]
+# actions = [{{ do = "switch_to_slot", slot = 1 }}]
+#
+# [[rules]]
+# on = "client_connect"
+# client = "laptop"
+# actions = [{{ do = "run_command", command = "notify-send \"$RKVM_CLIENT connected\"" }}]
+"#,
+        bind = defaults.bind,
+        state_dir = defaults.state_dir,
+        approve_new_clients = defaults.approve_new_clients,
+        approval_timeout_secs = defaults.approval_timeout_secs,
+        notify_inject_errors = defaults.notify_inject_errors,
+        client_logs = defaults.client_logs,
+        keep_alive_secs = defaults.keep_alive_secs,
+        idle_timeout_secs = defaults.idle_timeout_secs,
+        busy_client_threshold_secs = defaults.busy_client_threshold_secs,
+        inhibit_idle = defaults.inhibit_idle,
+        allow_reverse_control = defaults.allow_reverse_control,
+        staging_dir = defaults.staging_dir,
+        clipboard_offer_size = defaults.clipboard_offer_size,
+        clamp_cursor = defaults.clamp_cursor,
+        scale_motion = defaults.scale_motion,
+        park_cursor = defaults.park_cursor,
+        clipboard_sync = defaults.clipboard_sync,
+        encrypt_clipboard = defaults.encrypt_clipboard,
+        control_socket = defaults.control_socket_path(),
+        idle_ungrab_mins = defaults.idle_ungrab_mins,
+        forward_gamepads = defaults.forward_gamepads,
+        grab_retries = defaults.grab_retries,
+        long_press_ms = defaults.hotkey.long_press_ms,
+        double_tap_ms = defaults.hotkey.double_tap_ms,
+        pass_through = defaults.hotkey.pass_through,
+        queue = defaults.channels.queue,
+        mouse_capacity = defaults.channels.mouse_capacity,
+        keyboard_capacity = defaults.channels.keyboard_capacity,
+        misc_capacity = defaults.channels.misc_capacity,
+        sound_grab = defaults.sounds.grab,
+        sound_ungrab = defaults.sounds.ungrab,
+        sound_switch = defaults.sounds.switch,
+        sound_attention = defaults.sounds.attention,
+        history_enabled = defaults.clipboard_history.enabled,
+        history_content = defaults.clipboard_history.content,
+        history_max_items = defaults.clipboard_history.max_items,
+        history_max_age_hours = defaults.clipboard_history.max_age_hours,
+        player = defaults.sounds.player,
+        edge_pressure = defaults.edge_switch.pressure,
+        lid_ungrab = defaults.switches.lid_ungrab,
+        lid_suspend_clients = defaults.switches.lid_suspend_clients,
+        tablet_mode_ungrab = defaults.switches.tablet_mode_ungrab,
+        adaptive = defaults.link_quality.adaptive,
+        degraded_rtt_ms = defaults.link_quality.degraded_rtt_ms,
+        degraded_loss_percent = defaults.link_quality.degraded_loss_percent,
+        poor_rtt_ms = defaults.link_quality.poor_rtt_ms,
+        poor_loss_percent = defaults.link_quality.poor_loss_percent,
+        coalesce_ms = defaults.link_quality.coalesce_ms,
+        realtime = defaults.input_thread.realtime,
+        realtime_priority = defaults.input_thread.realtime_priority,
+    )
+}
+
+/// Makes `config`, loaded from `path`, the config in effect.
+pub fn init(path: Option<PathBuf>, config: Config) -> Arc<Config> {
+    let config = Arc::new(config);
+    let _ = PATH.set(path);
+    if CURRENT.set(watch::channel(config.clone()).0).is_err() {
+        panic!("Config initialized twice");
+    }
+
+    config
+}
+
+/// The config in effect. Read it again for every use that should follow reloads.
+pub fn current() -> Arc<Config> {
+    CURRENT
+        .get()
+        .expect("Config not initialized")
+        .borrow()
+        .clone()
+}
+
+/// Changes on every reload, for state derived from the config.
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+/// Sees every reload, for tasks that have to act on one.
+pub fn subscribe() -> watch::Receiver<Arc<Config>> {
+    CURRENT.get().expect("Config not initialized").subscribe()
+}
+
+/// Reads the config file again and puts it in effect.
+pub fn reload() -> Result<()> {
+    let path = PATH
+        .get()
+        .and_then(Option::as_ref)
+        .context("Not started with a config file")?;
+    let config = Config::load(path)?;
+
+    let old = current();
+    if config.state_dir != old.state_dir
+        || config.audit_log != old.audit_log
+        || config.control_socket != old.control_socket
+        || config.devices != old.devices
+    {
+        log::warn!(
+            "Changes to state_dir, audit_log, control_socket and devices apply after a restart"
+        );
+    }
+    crate::logging::apply(&config);
+    let capacities = |c: &ChannelConfig| {
+        (
+            c.queue,
+            c.mouse_capacity,
+            c.keyboard_capacity,
+            c.misc_capacity,
+        )
+    };
+    if capacities(&config.channels) != capacities(&old.channels) {
+        log::warn!("Changes to channel capacities apply after a restart");
+    }
+
+    CURRENT
+        .get()
+        .expect("Config not initialized")
+        .send_replace(Arc::new(config));
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+
+    log::info!("Reloaded config from {:?}", path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_has_the_defaults() {
+        let sample: Config = toml::from_str(&sample()).unwrap();
+        assert_eq!(format!("{:?}", sample), format!("{:?}", Config::default()));
+    }
+}
@@ -0,0 +1,269 @@
+//! Controlling the running server, through SIGHUP and a Unix socket.
+//!
+//! The socket takes one command per line and answers each with a line starting with `ok`
+//! or `error`. Commands are `reload`, `push-clipboard`, `pull-clipboard`, `swap-clipboards`,
+//! `toggle-clipboard-sync`, `type-selection`, `attention` and `cheat-sheet`, plus
+//! `preedit <text>` and `commit <text>` for an input method to compose text on the active
+//! client while grabbed. `pending` lists the clients waiting to be approved, as a JSON
+//! array of lines after the `ok`, and `approve <client>` and `reject <client>` decide on one.
+//! `known` lists the clients seen before the same way, and `revoke <client>` turns one and
+//! its certificate away from now on. That needs `approve_new_clients`, as clients pick their
+//! own ids and certificates and a revoked one would otherwise be back under new ones.
+//! `rename ["<client>", "<name>"]` shows and matches one by another name from its next
+//! connection, taking a JSON array as either name may have spaces, and
+//! `disconnect <client>` closes a connection. `history` lists the clipboard history the
+//! same way, newest first, and `restore-clipboard <n>` puts item `n` of it back.
+//! `log <filter>` changes what gets logged until the next reload.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use rkvm_protocol::{Event, Packet, Uuid};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt},
+    net::UnixListener,
+    signal::unix::{signal, SignalKind},
+    sync::mpsc::Sender,
+};
+
+use crate::{clients, clipboard::ClipboardHandle, config, grab, history, known};
+
+/// Reloads the config on every SIGHUP.
+pub async fn handle_signals() -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    while hangup.recv().await.is_some() {
+        if let Err(e) = config::reload() {
+            log::error!("Failed to reload config: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Stable id of the client composing started on, so its text doesn't end up on another
+static COMPOSING: Mutex<Option<Uuid>> = Mutex::new(None);
+
+/// Passes text from an input method on to the active client.
+async fn compose(event: Event, event_tx: &Sender<Packet>) -> String {
+    if !grab::is_grabbed() {
+        return "error: not grabbed".to_owned();
+    }
+    let active = match clients::active_uuid() {
+        Some(active) => active,
+        None => return "error: no client is connected".to_owned(),
+    };
+
+    {
+        let mut composing = COMPOSING.lock().unwrap();
+        if composing.is_some_and(|started| started != active) {
+            *composing = None;
+            return "error: switched to another client while composing".to_owned();
+        }
+        let done = match &event {
+            Event::Preedit { text } => text.is_empty(),
+            _ => true,
+        };
+        *composing = (!done).then_some(active);
+    }
+
+    match event_tx.send(Packet::new(0, event)).await {
+        Ok(_) => "ok".to_owned(),
+        Err(_) => "error: server is shutting down".to_owned(),
+    }
+}
+
+/// Answers with `lines` as a JSON array, which keeps them apart whatever they contain.
+fn list(lines: &[String]) -> String {
+    format!("ok {}", serde_json::to_string(lines).unwrap())
+}
+
+/// Runs a command and returns the line to answer with.
+async fn execute(
+    command: &str,
+    clipboard: Option<&ClipboardHandle>,
+    event_tx: &Sender<Packet>,
+) -> String {
+    let (name, text) = command.split_once(' ').unwrap_or((command, ""));
+    let text = text.to_owned();
+
+    match (name, clipboard) {
+        ("preedit", _) => compose(Event::Preedit { text }, event_tx).await,
+        ("commit", _) => compose(Event::Commit { text }, event_tx).await,
+        ("attention", _) => match crate::attention::request(event_tx) {
+            Ok(_) => "ok".to_owned(),
+            Err(e) => format!("error: {}", e),
+        },
+        ("cheat-sheet", _) => {
+            crate::cheatsheet::show(&config::current()).await;
+            "ok".to_owned()
+        }
+        ("pending", _) => list(&crate::approval::pending()),
+        ("approve" | "reject", _) if crate::approval::decide(&text, name == "approve") => {
+            "ok".to_owned()
+        }
+        ("approve" | "reject", _) => format!("error: no client {:?} is waiting", text),
+        ("known", _) => {
+            let known: Vec<_> = known::list()
+                .into_iter()
+                .map(|client| {
+                    let fingerprint = client.fingerprint.as_deref().unwrap_or("no certificate");
+                    let revoked = if client.revoked { " revoked" } else { "" };
+                    format!(
+                        "{} {} {} ({}){}",
+                        client.slot, client.uuid, client.name, fingerprint, revoked
+                    )
+                })
+                .collect();
+            list(&known)
+        }
+        ("revoke", _) if !config::current().approve_new_clients => {
+            "error: revoking only holds with approve_new_clients on".to_owned()
+        }
+        ("revoke", _) => match known::revoke(&text) {
+            Some(uuid) => {
+                clients::disconnect(&uuid);
+                "ok".to_owned()
+            }
+            None => format!("error: no client {:?} is known", text),
+        },
+        ("rename", _) => match serde_json::from_str::<(String, String)>(&text) {
+            Ok((client, name)) if !name.trim().is_empty() => {
+                match known::rename(&client, name.trim()) {
+                    Some(_) => "ok".to_owned(),
+                    None => format!("error: no client {:?} is known", client),
+                }
+            }
+            _ => "error: usage: rename [\"<client>\", \"<name>\"]".to_owned(),
+        },
+        ("disconnect", _) => match clients::disconnect(&text) {
+            Some(_) => "ok".to_owned(),
+            None => format!("error: client {:?} is not connected", text),
+        },
+        ("log", _) => match crate::logging::set(&text) {
+            Ok(()) => "ok".to_owned(),
+            Err(e) => format!("error: {:#}", e),
+        },
+        ("history", _) => {
+            // Opening the history reads and may write the whole file
+            let items = tokio::task::spawn_blocking(history::list).await;
+            match items.unwrap_or_else(|e| Err(e.into())) {
+                Ok(items) => list(&items),
+                Err(e) => format!("error: {:#}", e),
+            }
+        }
+        ("restore-clipboard", Some(clipboard)) => {
+            let item = match text.parse() {
+                Ok(item) => item,
+                Err(_) => return "error: usage: restore-clipboard <n>".to_owned(),
+            };
+            let content = tokio::task::spawn_blocking(move || history::content(item)).await;
+            match content.unwrap_or_else(|e| Err(e.into())) {
+                Ok(Some((kind, data))) => {
+                    clipboard.restore(kind, data).await;
+                    "ok".to_owned()
+                }
+                Ok(None) => format!("error: item {} isn't kept", item),
+                Err(e) => format!("error: {:#}", e),
+            }
+        }
+        ("reload", _) => match config::reload() {
+            Ok(_) => "ok".to_owned(),
+            Err(e) => format!("error: {:#}", e),
+        },
+        ("push-clipboard", Some(clipboard)) => {
+            clipboard.push();
+            "ok".to_owned()
+        }
+        ("pull-clipboard", Some(clipboard)) => {
+            clipboard.pull();
+            "ok".to_owned()
+        }
+        ("swap-clipboards", Some(clipboard)) => {
+            clipboard.swap();
+            "ok".to_owned()
+        }
+        ("toggle-clipboard-sync", Some(clipboard)) => {
+            let enabled = clipboard.toggle_sync();
+            format!("ok {}", if enabled { "on" } else { "off" })
+        }
+        ("type-selection", Some(clipboard)) => {
+            clipboard.type_selection();
+            "ok".to_owned()
+        }
+        (
+            "push-clipboard"
+            | "pull-clipboard"
+            | "swap-clipboards"
+            | "toggle-clipboard-sync"
+            | "type-selection"
+            | "restore-clipboard",
+            None,
+        ) => "error: clipboard is disabled, see --clipboard-mode".to_owned(),
+        _ => format!("error: unknown command {:?}", command),
+    }
+}
+
+/// Serves the control socket at `path`, which only the owner may use.
+pub async fn serve(
+    path: &Path,
+    clipboard: Option<ClipboardHandle>,
+    event_tx: Sender<Packet>,
+) -> Result<()> {
+    if UnixStream::connect(path).is_ok() {
+        anyhow::bail!("Another server is listening on {:?}", path);
+    }
+    // Left behind by a server that didn't shut down cleanly
+    let _ = std::fs::remove_file(path);
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let listener = UnixListener::bind(path).with_context(|| format!("Bind {:?}", path))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    log::info!("Listening for commands on {:?}", path);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        let clipboard = clipboard.clone();
+        let event_tx = event_tx.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = tokio::io::BufReader::new(reader).lines();
+
+            while let Ok(Some(line)) = lines.next_line().await {
+                // Only the line ending is trimmed, spaces may be part of composed text
+                let line = line.trim_end_matches('\r');
+                let reply = execute(line, clipboard.as_ref(), &event_tx).await;
+                if writer
+                    .write_all(format!("{}\n", reply).as_bytes())
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Sends `command` to the server listening on `path` and returns its answer.
+pub fn request(path: &Path, command: &str) -> Result<String> {
+    let mut stream = UnixStream::connect(path).with_context(|| format!("Connect to {:?}", path))?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+
+    let reply = reply.trim_end();
+    match reply.strip_prefix("error: ") {
+        Some(e) => anyhow::bail!("{}", e),
+        None => Ok(reply.to_owned()),
+    }
+}
@@ -0,0 +1,556 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use rkvm_protocol::{Event, Packet};
+use tokio::{runtime::Handle, sync::mpsc::Sender, task::JoinHandle};
+use zbus::zvariant::OwnedFd;
+
+use crate::{
+    audit::{self, AuditEvent},
+    clients,
+    clipboard::ClipboardHandle,
+    config::{BusyClientPolicy, Config},
+    cursor::VirtualCursor,
+    grab,
+    hotkey::HotkeyAction,
+    indicator, inhibit, known, notify,
+    park::CursorPark,
+    quality, restore, rules,
+    sound::{self, Cue},
+};
+
+const INHIBIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+static GAME_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Whether mouse motion goes out raw and in datagrams.
+pub fn game_mode() -> bool {
+    GAME_MODE.load(Ordering::Relaxed)
+}
+
+/// Owns the grab state and everything that happens when it changes.
+pub struct Controller {
+    config: Arc<Config>,
+    grabbed: bool,
+    runtime: Handle,
+    clipboard: Option<ClipboardHandle>,
+    /// Takes the idle inhibitor lock held while grabbed, and then holds it in its output
+    inhibitor: Option<JoinHandle<Option<OwnedFd>>>,
+    /// Whether the left button is held, so grabbing continues a drag
+    left_button_down: bool,
+    cursor: VirtualCursor,
+    /// Of the monitors the cursor was last reset with
+    screens_generation: u64,
+    /// Whether the active client had its pointer locked, as of the last motion
+    relative_only: bool,
+    event_tx: Sender<Packet>,
+    /// Only set if `park_cursor` is enabled
+    park: Option<CursorPark>,
+    /// When input was last forwarded, or grabbed if nothing was since
+    last_forwarded: Instant,
+    /// Whether the tablet-mode switch is on
+    tablet_mode: bool,
+}
+
+impl Controller {
+    pub fn new(
+        config: Arc<Config>,
+        runtime: Handle,
+        clipboard: Option<ClipboardHandle>,
+        event_tx: Sender<Packet>,
+    ) -> Self {
+        Self {
+            park: make_park(&config),
+            cursor: make_cursor(&config),
+            config,
+            grabbed: false,
+            runtime,
+            clipboard,
+            inhibitor: None,
+            left_button_down: false,
+            screens_generation: clients::screens_generation(),
+            relative_only: false,
+            event_tx,
+            last_forwarded: Instant::now(),
+            tablet_mode: false,
+        }
+    }
+
+    /// Switches to a reloaded config.
+    pub fn set_config(&mut self, config: Arc<Config>) {
+        if config.park_cursor != self.config.park_cursor {
+            if let (true, Some(park)) = (self.grabbed, &mut self.park) {
+                if let Err(e) = park.unpark() {
+                    log::warn!("Failed to unpark cursor: {}", e);
+                }
+            }
+
+            self.park = make_park(&config);
+            if let (true, Some(park)) = (self.grabbed, &mut self.park) {
+                if let Err(e) = park.park() {
+                    log::warn!("Failed to park cursor: {}", e);
+                }
+            }
+        }
+
+        if config.clipboard_sync != self.config.clipboard_sync {
+            if let Some(clipboard) = &self.clipboard {
+                clipboard.set_sync(config.clipboard_sync);
+            }
+        }
+
+        if config.grab_indicator != self.config.grab_indicator && self.grabbed {
+            indicator::show(self.config.grab_indicator, false);
+            indicator::show(config.grab_indicator, true);
+        }
+
+        let cursor_changed = config.scale_motion != self.config.scale_motion
+            || config.clamp_cursor != self.config.clamp_cursor
+            || config.cursor_correction_secs != self.config.cursor_correction_secs
+            || config.screens != self.config.screens;
+
+        self.config = config;
+        if cursor_changed {
+            self.cursor = make_cursor(&self.config);
+            self.reset_cursor();
+        }
+    }
+
+    pub fn is_grabbed(&self) -> bool {
+        self.grabbed
+    }
+
+    /// Notes that input was forwarded to the active client.
+    pub fn on_forwarded(&mut self) {
+        self.last_forwarded = Instant::now();
+    }
+
+    fn idle_limit(&self) -> Option<Duration> {
+        let mins = self.config.idle_ungrab_mins;
+        (self.grabbed && mins > 0).then(|| Duration::from_secs(mins.saturating_mul(60)))
+    }
+
+    /// Time left until [`expire_idle`](Self::expire_idle) ungrabs, if grabbed.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        let limit = self.idle_limit()?;
+        Some(limit.saturating_sub(self.last_forwarded.elapsed()))
+    }
+
+    /// Ungrabs if nothing was forwarded for `idle_ungrab_mins`.
+    pub fn expire_idle(&mut self) {
+        let limit = match self.idle_limit() {
+            Some(limit) if self.last_forwarded.elapsed() >= limit => limit,
+            _ => return,
+        };
+
+        let minutes = limit.as_secs() / 60;
+        log::warn!("Nothing was forwarded for {} minutes, ungrabbing", minutes);
+        self.ungrab();
+
+        let body = format!(
+            "Nothing was sent to the client for {} minutes, so input stays on this machine.",
+            minutes
+        );
+        self.notify("Input released", body);
+    }
+
+    /// Shows a desktop notification without waiting for it.
+    fn notify(&self, summary: &'static str, body: String) {
+        self.runtime.spawn(async move {
+            if let Err(e) = notify::notify(summary, &body).await {
+                log::debug!("Failed to show notification: {}", e);
+            }
+        });
+    }
+
+    /// Tracks the left button, whether grabbed or not.
+    pub fn on_left_button(&mut self, pressed: bool) {
+        self.left_button_down = pressed;
+    }
+
+    /// Cursor of the active client, reset whenever input moves to another one or the
+    /// active client's monitors change.
+    pub fn cursor(&mut self) -> &mut VirtualCursor {
+        if self.screens_generation != clients::screens_generation() {
+            self.reset_cursor();
+        }
+
+        &mut self.cursor
+    }
+
+    /// Starts tracking the cursor of the active client.
+    fn reset_cursor(&mut self) {
+        self.screens_generation = clients::screens_generation();
+
+        let configured = clients::active_keys()
+            .iter()
+            .find_map(|key| self.config.screens.get(key));
+        let monitors = match configured {
+            Some(screen) => vec![screen.monitor()],
+            None => clients::active_screens().monitors,
+        };
+        self.cursor.reset(monitors);
+    }
+
+    pub fn handle(&mut self, action: HotkeyAction) {
+        match action {
+            HotkeyAction::Toggle => self.toggle(),
+            HotkeyAction::Grab => self.grab(),
+            HotkeyAction::Ungrab => self.ungrab(),
+            HotkeyAction::NextClient => self.next_client(),
+            HotkeyAction::PushClipboard => self.push_clipboard(),
+            HotkeyAction::PullClipboard => self.pull_clipboard(),
+            HotkeyAction::SwapClipboards => self.swap_clipboards(),
+            HotkeyAction::ToggleClipboardSync => {
+                if let Some(clipboard) = &self.clipboard {
+                    clipboard.toggle_sync();
+                }
+            }
+            HotkeyAction::ToggleGameMode => self.toggle_game_mode(),
+            HotkeyAction::TypeSelection => self.type_selection(),
+            HotkeyAction::Attention => self.attention(),
+            HotkeyAction::CheatSheet => self.cheat_sheet(),
+            HotkeyAction::SwitchBack => self.switch_back(),
+        }
+    }
+
+    /// Whether motion should reach the client as is, never clamped or corrected.
+    pub fn raw_motion(&mut self) -> bool {
+        let relative_only = clients::active_relative_only();
+        // Motion wasn't tracked while the pointer was locked, so start over
+        if std::mem::replace(&mut self.relative_only, relative_only) && !relative_only {
+            self.reset_cursor();
+        }
+        game_mode() || relative_only
+    }
+
+    pub fn toggle_game_mode(&mut self) {
+        let enabled = !game_mode();
+        GAME_MODE.store(enabled, Ordering::Relaxed);
+        log::info!("Game mode {}", if enabled { "on" } else { "off" });
+
+        // Sent from the runtime, as the queue may be full of input for a stalled client
+        let event_tx = self.event_tx.clone();
+        self.runtime.spawn(async move {
+            let event = Event::GameMode { enabled };
+            let _ = event_tx.send(Packet::new(0, event)).await;
+        });
+
+        // Motion wasn't tracked meanwhile, so start over
+        if !enabled {
+            self.reset_cursor();
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        if self.grabbed {
+            self.ungrab();
+        } else {
+            self.grab();
+        }
+    }
+
+    /// Checks whether input may go to the active client given its local activity.
+    fn may_enter_active(&self) -> bool {
+        let threshold = Duration::from_secs(self.config.busy_client_threshold_secs);
+        if !clients::active_is_busy(threshold) {
+            return true;
+        }
+
+        let client = clients::active().unwrap_or_default();
+        match self.config.busy_client_policy {
+            BusyClientPolicy::Ignore => true,
+            BusyClientPolicy::Warn => {
+                log::warn!("Client {} is in local use", client);
+                true
+            }
+            BusyClientPolicy::Refuse => {
+                log::warn!("Not sending input, client {} is in local use", client);
+                false
+            }
+        }
+    }
+
+    pub fn grab(&mut self) {
+        if self.grabbed || !self.may_enter_active() {
+            return;
+        }
+        if self.tablet_mode && self.config.switches.tablet_mode_ungrab {
+            log::info!("Not grabbing in tablet mode");
+            return;
+        }
+
+        match grab::grab_devices(&self.runtime, true, self.config.grab_retries) {
+            Ok(failed) if failed.is_empty() => {}
+            Ok(failed) => {
+                let paths: Vec<_> = failed
+                    .iter()
+                    .map(|(path, e)| format!("{} ({})", path.display(), e))
+                    .collect();
+                log::warn!("Grabbed, except for {}", paths.join(", "));
+                self.notify(
+                    "Some devices weren't grabbed",
+                    format!("Their input stays on this machine: {}", paths.join(", ")),
+                );
+            }
+            Err(e) => {
+                log::error!("Not grabbing: {:#}", e);
+                self.notify("Input not grabbed", format!("{:#}", e));
+                return;
+            }
+        }
+        self.grabbed = true;
+        clients::set_local(false);
+        self.last_forwarded = Instant::now();
+        self.reset_cursor();
+
+        if let Some(park) = &mut self.park {
+            if let Err(e) = park.park() {
+                log::warn!("Failed to park cursor: {}", e);
+            }
+        }
+        log::info!("Grabbed all devices");
+        restore::record(true);
+        rules::switched();
+        indicator::show(self.config.grab_indicator, true);
+        sound::play(&self.config.sounds, Cue::Grab);
+        audit::record(AuditEvent::Grab {
+            client: clients::active(),
+        });
+
+        if self.config.inhibit_idle {
+            // Not waited for, as input would stall until logind answers
+            self.inhibitor = Some(self.runtime.spawn(async {
+                let inhibit = tokio::time::timeout(INHIBIT_TIMEOUT, inhibit::inhibit_idle());
+                match inhibit.await {
+                    Ok(Ok(fd)) => return Some(fd),
+                    Ok(Err(e)) => log::warn!("Failed to inhibit idle: {}", e),
+                    Err(_) => log::warn!("Failed to inhibit idle: logind did not respond"),
+                }
+                None
+            }));
+        }
+
+        if self.left_button_down && !game_mode() {
+            if let Some(clipboard) = &self.clipboard {
+                clipboard.forward_drag();
+            }
+        }
+
+        self.sync_clipboard();
+    }
+
+    pub fn ungrab(&mut self) {
+        if !self.grabbed {
+            return;
+        }
+
+        if let Err(e) = grab::grab_devices(&self.runtime, false, 0) {
+            log::error!("Failed to ungrab: {:#}", e);
+        }
+        self.grabbed = false;
+        clients::set_local(true);
+        log::info!("Ungrabbed all devices");
+        restore::record(false);
+        indicator::show(self.config.grab_indicator, false);
+        sound::play(&self.config.sounds, Cue::Ungrab);
+        audit::record(AuditEvent::Ungrab);
+
+        if let Some(clipboard) = &self.clipboard {
+            clipboard.cancel_drag();
+        }
+
+        if let Some(park) = &mut self.park {
+            if let Err(e) = park.unpark() {
+                log::warn!("Failed to unpark cursor: {}", e);
+            }
+        }
+        self.park_clients();
+
+        // Dropping the task's output closes the descriptor, releasing the inhibitor
+        if let Some(inhibitor) = self.inhibitor.take() {
+            inhibitor.abort();
+        }
+    }
+
+    /// Ungrabs and suspends clients as configured once the lid closes.
+    pub fn on_lid(&mut self, closed: bool) {
+        log::info!("Lid {}", if closed { "closed" } else { "opened" });
+        if !closed {
+            return;
+        }
+
+        if self.config.switches.lid_ungrab {
+            self.ungrab();
+        }
+        if self.config.switches.lid_suspend_clients {
+            log::info!("Telling clients to suspend");
+            // Sent from the runtime, as the queue may be full of input for a stalled client
+            let event_tx = self.event_tx.clone();
+            self.runtime.spawn(async move {
+                let _ = event_tx.send(Packet::new(0, Event::Suspend)).await;
+            });
+        }
+    }
+
+    /// Ungrabs as configured when the server becomes a tablet, and keeps from grabbing until
+    /// it stops being one.
+    pub fn set_tablet_mode(&mut self, on: bool) {
+        log::info!("Tablet mode {}", if on { "on" } else { "off" });
+        self.tablet_mode = on;
+        if on && self.config.switches.tablet_mode_ungrab {
+            self.ungrab();
+        }
+    }
+
+    /// Routes input to the next connected client, grabbing if needed.
+    pub fn next_client(&mut self) {
+        match clients::switch_next() {
+            Some(client) => self.switched(&client),
+            None => log::warn!("No clients connected"),
+        }
+    }
+
+    /// Routes input to the client known by `key`, grabbing if needed.
+    pub fn switch_to(&mut self, key: &str) {
+        match clients::switch_to(key) {
+            Some(client) => self.switched(&client),
+            None => log::warn!("Client {} is not connected", key),
+        }
+    }
+
+    /// Routes input to the client in `slot`, grabbing if needed.
+    pub fn switch_to_slot(&mut self, slot: usize) {
+        match known::client(slot) {
+            Some(uuid) if clients::is_connected(&uuid) => self.switch_to(&uuid),
+            Some(_) => log::warn!("Client {} is not connected", slot),
+            None => log::warn!("No client has slot {}", slot),
+        }
+    }
+
+    /// Goes back to where input went before, like Alt+Tab: the active client if it is here,
+    /// otherwise the client before it, or here if there was none.
+    pub fn switch_back(&mut self) {
+        if !self.grabbed {
+            if let Some(client) = clients::active() {
+                self.switched(&client);
+            }
+            return;
+        }
+
+        match clients::switch_back() {
+            Some(client) => self.switched(&client),
+            None => self.ungrab(),
+        }
+    }
+
+    fn switched(&mut self, client: &str) {
+        log::info!("Switched to {}", client);
+        audit::record(AuditEvent::Switch { client });
+        if let Some(position) = clients::active_position() {
+            sound::play(&self.config.sounds, Cue::Switch(position));
+        }
+
+        if !self.grabbed {
+            self.grab();
+        } else if !self.may_enter_active() {
+            self.ungrab();
+        } else {
+            self.park_clients();
+            self.reset_cursor();
+            self.sync_clipboard();
+            restore::record(true);
+            rules::switched();
+        }
+    }
+
+    /// Has every client that input doesn't go to park its cursor, see [`Event::Park`].
+    fn park_clients(&self) {
+        if self.park.is_some() {
+            // Sent from the runtime, as the queue may be full of input for a stalled client
+            let event_tx = self.event_tx.clone();
+            self.runtime.spawn(async move {
+                let _ = event_tx.send(Packet::new(0, Event::Park)).await;
+            });
+        }
+    }
+
+    pub fn push_clipboard(&self) {
+        if let Some(clipboard) = &self.clipboard {
+            clipboard.push();
+        }
+    }
+
+    /// Asks the active client for its clipboard, to put it on ours.
+    pub fn pull_clipboard(&self) {
+        if let Some(clipboard) = &self.clipboard {
+            clipboard.pull();
+        }
+    }
+
+    /// Trades clipboards with the active client.
+    pub fn swap_clipboards(&self) {
+        if let Some(clipboard) = &self.clipboard {
+            clipboard.swap();
+        }
+    }
+
+    /// Types the selection here on the active client.
+    pub fn type_selection(&self) {
+        if let Some(clipboard) = &self.clipboard {
+            clipboard.type_selection();
+        }
+    }
+
+    /// Asks for the attention of whoever sits at the active client.
+    pub fn attention(&self) {
+        if let Err(e) = crate::attention::request(&self.event_tx) {
+            log::info!("Not asking for attention: {}", e);
+        }
+    }
+
+    /// Shows the bindings and connected clients.
+    pub fn cheat_sheet(&self) {
+        let config = self.config.clone();
+        self.runtime
+            .spawn(async move { crate::cheatsheet::show(&config).await });
+    }
+
+    /// Pushes the clipboard as input moves, unless clipboard sync is off, in game mode or
+    /// over a poor connection.
+    fn sync_clipboard(&self) {
+        if quality::pauses_clipboard_sync() {
+            log::info!("Not sending the clipboard over a poor connection");
+            return;
+        }
+
+        if let Some(clipboard) = &self.clipboard {
+            clipboard.sync();
+        }
+    }
+}
+
+/// Only succeeds if `park_cursor` is enabled.
+fn make_park(config: &Config) -> Option<CursorPark> {
+    if !config.park_cursor {
+        return None;
+    }
+
+    CursorPark::new()
+        .map_err(|e| log::warn!("Cursor won't be parked: {}", e))
+        .ok()
+}
+
+fn make_cursor(config: &Config) -> VirtualCursor {
+    let correction_interval = config.cursor_correction_secs.map(Duration::from_secs);
+    VirtualCursor::new(
+        config.clamp_cursor,
+        config.scale_motion,
+        correction_interval,
+    )
+}
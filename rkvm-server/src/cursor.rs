@@ -0,0 +1,184 @@
+//! Where the cursor of the active client should be, from the motion sent to it.
+
+use std::time::{Duration, Instant};
+
+use rkvm_protocol::Monitor;
+
+/// Tracks the cursor of the active client by summing the motion forwarded to it.
+pub struct VirtualCursor {
+    /// Monitors of the active client, empty if unknown
+    monitors: Vec<Monitor>,
+    x: i32,
+    y: i32,
+    /// Drop motion past the outer edges of the monitors
+    clamp: bool,
+    /// Multiply motion by the scale of the monitor under the cursor
+    scale_motion: bool,
+    /// Fractions of a pixel left over from scaling
+    remainder: (f64, f64),
+    correction_interval: Option<Duration>,
+    /// `None` until the first correction on this client
+    last_correction: Option<Instant>,
+    /// Whether the position changed since the last correction
+    moved: bool,
+    /// Whether the client's cursor still has to be moved to where tracking started
+    reset: bool,
+}
+
+impl VirtualCursor {
+    pub fn new(clamp: bool, scale_motion: bool, correction_interval: Option<Duration>) -> Self {
+        Self {
+            monitors: Vec::new(),
+            x: 0,
+            y: 0,
+            clamp,
+            scale_motion,
+            remainder: (0.0, 0.0),
+            correction_interval,
+            last_correction: None,
+            moved: false,
+            reset: false,
+        }
+    }
+
+    /// Starts tracking a client with `monitors`, from the center of the primary one, where
+    /// the next [`VirtualCursor::correction`] moves its cursor.
+    pub fn reset(&mut self, monitors: Vec<Monitor>) {
+        let start = monitors
+            .iter()
+            .find(|m| m.primary)
+            .or_else(|| monitors.first());
+        // Geometry comes from the client, so none of it may overflow
+        if let Some(m) = start {
+            self.x = m.x.saturating_add_unsigned(m.width / 2);
+            self.y = m.y.saturating_add_unsigned(m.height / 2);
+        }
+
+        self.monitors = monitors;
+        self.remainder = (0.0, 0.0);
+        self.last_correction = None;
+        self.moved = true;
+        self.reset = true;
+    }
+
+    /// Moves by `dx`, `dy` and returns the motion to send on.
+    ///
+    /// The cursor stops at the outer edges, as the client's does. With `clamp`, motion past
+    /// them is dropped here too, so a client whose cursor doesn't stop there stays in step
+    /// with us. Without monitors all motion passes.
+    pub fn motion(&mut self, dx: i32, dy: i32) -> (i32, i32) {
+        let current = match self
+            .monitors
+            .iter()
+            .find(|m| m.contains(self.x, self.y))
+            .or_else(|| self.monitors.first())
+        {
+            Some(m) => m,
+            None => return (dx, dy),
+        };
+
+        let (dx, dy) = if self.scale_motion {
+            let fx = dx as f64 * current.scale as f64 + self.remainder.0;
+            let fy = dy as f64 * current.scale as f64 + self.remainder.1;
+            self.remainder = (fx.fract(), fy.fract());
+            (fx.trunc() as i32, fy.trunc() as i32)
+        } else {
+            (dx, dy)
+        };
+
+        let mut x = self.x.saturating_add(dx);
+        let mut y = self.y.saturating_add(dy);
+
+        // Crossing onto a neighbouring monitor is fine, anywhere else stops at the edge
+        if !self.monitors.iter().any(|m| m.contains(x, y)) {
+            let right = current
+                .x
+                .saturating_add_unsigned(current.width.saturating_sub(1));
+            let bottom = current
+                .y
+                .saturating_add_unsigned(current.height.saturating_sub(1));
+            x = x.clamp(current.x, right);
+            y = y.clamp(current.y, bottom);
+        }
+        let delta = (x.saturating_sub(self.x), y.saturating_sub(self.y));
+
+        if delta != (0, 0) {
+            self.x = x;
+            self.y = y;
+            self.moved = true;
+        }
+
+        if self.clamp {
+            delta
+        } else {
+            (dx, dy)
+        }
+    }
+
+    /// Returns the position to move the client's cursor to, if a correction is due.
+    ///
+    /// One is always due after a reset, so tracking starts where the client's cursor is.
+    pub fn correction(&mut self) -> Option<(i32, i32)> {
+        if self.monitors.is_empty() {
+            return None;
+        }
+        if std::mem::take(&mut self.reset) {
+            self.last_correction = Some(Instant::now());
+            self.moved = false;
+            return Some((self.x, self.y));
+        }
+
+        let interval = self.correction_interval?;
+        if !self.moved {
+            return None;
+        }
+
+        if self.last_correction.is_some_and(|t| t.elapsed() < interval) {
+            return None;
+        }
+
+        self.last_correction = Some(Instant::now());
+        self.moved = false;
+        Some((self.x, self.y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(x: i32, y: i32, width: u32, height: u32) -> Monitor {
+        Monitor {
+            x,
+            y,
+            width,
+            height,
+            scale: 1.0,
+            primary: false,
+        }
+    }
+
+    #[test]
+    fn stops_at_the_outer_edges() {
+        let mut cursor = VirtualCursor::new(true, false, None);
+        cursor.reset(vec![monitor(0, 0, 100, 100)]);
+
+        assert_eq!(cursor.motion(500, 0), (49, 0));
+        assert_eq!(cursor.motion(1, 0), (0, 0));
+        assert_eq!(cursor.motion(-20, 0), (-20, 0));
+    }
+
+    #[test]
+    fn hostile_layouts_do_not_overflow() {
+        let mut cursor = VirtualCursor::new(true, false, None);
+        cursor.reset(vec![
+            monitor(i32::MAX, i32::MAX, u32::MAX, u32::MAX),
+            monitor(i32::MIN, i32::MIN, u32::MAX, 0),
+        ]);
+        cursor.motion(i32::MAX, i32::MAX);
+        cursor.motion(i32::MIN, i32::MIN);
+
+        cursor.reset(vec![monitor(i32::MIN, 0, 0, 0)]);
+        cursor.motion(i32::MAX, i32::MIN);
+    }
+}
@@ -0,0 +1,151 @@
+//! Making sure clipboard and control events reach the clients they were sent to.
+//!
+//! Misc packets get ids of their own, which clients ack once they handled them. A packet
+//! without an ack in time is sent once more on a fresh stream, and reported if that fails
+//! too, instead of getting lost without a word. The time starts once the packet went out,
+//! as a large one may sit in the send buffer for a while after being written.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use quinn::Connection;
+use rkvm_protocol::{Event, StreamTag};
+use tokio::io::AsyncWriteExt;
+use tracing::Instrument;
+
+use crate::notify;
+
+/// How long a client has to ack a packet, each time it is sent
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to look whether a written packet went out
+const SENT_POLL: Duration = Duration::from_millis(20);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Connection and packet ids waiting for an ack
+static PENDING: Mutex<Vec<(usize, u64)>> = Mutex::new(Vec::new());
+
+/// An id for a misc packet, never 0.
+pub fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// What a misc event is, to report it by.
+pub fn describe(event: &Event) -> &'static str {
+    match event {
+        Event::TextClipboard { .. }
+        | Event::HtmlClipboard { .. }
+        | Event::ImageClipboard { .. }
+        | Event::RawImageClipboard { .. }
+        | Event::ClipboardOffer { .. }
+        | Event::ClipboardRequest
+        | Event::SealedClipboard { .. } => "Clipboard",
+        Event::DragEnter { .. } | Event::DragCancel => "Drag",
+        Event::GameMode { .. } => "Game mode switch",
+        Event::Suspend => "Suspend",
+        Event::KeyboardLayout { .. } => "Keyboard layout",
+        Event::Attention => "Attention request",
+        _ => "Event",
+    }
+}
+
+/// Bytes that went out on `conn` so far, to pass to [`sent`] for a packet about to be
+/// written.
+pub fn tx_bytes(conn: &Connection) -> u64 {
+    conn.stats().udp_tx.bytes
+}
+
+/// Waits for the client on `conn` to ack packet `id`, encoded as `data` and written once
+/// `tx_bytes` went out.
+pub fn sent(conn: Connection, id: u64, data: Arc<[u8]>, tx_bytes: u64, what: &'static str) {
+    let key = (conn.stable_id(), id);
+    PENDING.lock().unwrap().push(key);
+
+    tokio::spawn(
+        async move {
+            let result = deliver(&conn, key, &data, tx_bytes, what).await;
+            PENDING.lock().unwrap().retain(|&pending| pending != key);
+
+            if let Err(reason) = result {
+                log::error!("{} not delivered: {}", what, reason);
+                let summary = format!("{} not delivered", what);
+                if let Err(e) = notify::notify(&summary, reason).await {
+                    log::debug!("Failed to show notification: {}", e);
+                }
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+/// Notes packet `id` reached the client on connection `conn_id`.
+pub fn acked(conn_id: usize, id: u64) {
+    PENDING
+        .lock()
+        .unwrap()
+        .retain(|&pending| pending != (conn_id, id));
+}
+
+async fn deliver(
+    conn: &Connection,
+    key: (usize, u64),
+    data: &[u8],
+    tx_bytes: u64,
+    what: &str,
+) -> Result<(), &'static str> {
+    went_out(conn, tx_bytes.saturating_add(data.len() as u64)).await?;
+    if acked_in_time(conn, key).await? {
+        return Ok(());
+    }
+
+    log::warn!("{} {} not acked, sending it again", what, key.1);
+    let mut stream = crate::server::open_downstream(conn, StreamTag::Resend)
+        .await
+        .map_err(|_| "client disconnected")?;
+    let tx_bytes = self::tx_bytes(conn);
+    let written = async {
+        stream.write_u32(data.len() as u32).await?;
+        stream.write_all(data).await?;
+        stream.finish().await?;
+        anyhow::Ok(())
+    };
+    written.await.map_err(|_| "client disconnected")?;
+
+    went_out(conn, tx_bytes.saturating_add(data.len() as u64)).await?;
+    if acked_in_time(conn, key).await? {
+        return Ok(());
+    }
+    Err("client did not ack it")
+}
+
+/// Waits until `tx_bytes` went out on `conn`, which other traffic counts towards as well.
+async fn went_out(conn: &Connection, tx_bytes: u64) -> Result<(), &'static str> {
+    while self::tx_bytes(conn) < tx_bytes {
+        tokio::select! {
+            _ = tokio::time::sleep(SENT_POLL) => {}
+            _ = conn.closed() => return Err("client disconnected"),
+        }
+    }
+
+    Ok(())
+}
+
+async fn acked_in_time(conn: &Connection, key: (usize, u64)) -> Result<bool, &'static str> {
+    let closed = tokio::select! {
+        _ = tokio::time::sleep(ACK_TIMEOUT) => false,
+        _ = conn.closed() => true,
+    };
+
+    if !PENDING.lock().unwrap().contains(&key) {
+        Ok(true)
+    } else if closed {
+        Err("client disconnected")
+    } else {
+        Ok(false)
+    }
+}
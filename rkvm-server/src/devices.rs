@@ -0,0 +1,163 @@
+//! Opening input devices, and listing them along with what the server does with them for
+//! `rkvm-server devices`.
+
+use std::{
+    fs::{File, OpenOptions},
+    os::unix::{fs::OpenOptionsExt, io::OwnedFd},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use evdev::Device;
+use input::{
+    event::{DeviceEvent, EventTrait},
+    DeviceCapability, Libinput, LibinputInterface,
+};
+
+use crate::{config::Config, gamepad};
+
+/// Opens devices like the server does, without taking them for itself.
+struct Interface;
+
+impl LibinputInterface for Interface {
+    fn open_restricted(&mut self, path: &Path, flags: i32) -> Result<OwnedFd, i32> {
+        OpenOptions::new()
+            .custom_flags(flags)
+            .read(true)
+            .open(path)
+            .map(OwnedFd::from)
+            .map_err(|err| {
+                log::warn!("Failed to open {:?}: {}", path, err);
+                err.raw_os_error().unwrap_or_default()
+            })
+    }
+
+    fn close_restricted(&mut self, fd: OwnedFd) {
+        let _ = File::from(fd);
+    }
+}
+
+const CAPABILITIES: [(DeviceCapability, &str); 7] = [
+    (DeviceCapability::Keyboard, "keyboard"),
+    (DeviceCapability::Pointer, "pointer"),
+    (DeviceCapability::Touch, "touch"),
+    (DeviceCapability::TabletTool, "tablet tool"),
+    (DeviceCapability::TabletPad, "tablet pad"),
+    (DeviceCapability::Gesture, "gesture"),
+    (DeviceCapability::Switch, "switch"),
+];
+
+fn print(path: &Path, name: &str, vendor: u32, product: u32, capabilities: &str, status: &str) {
+    println!("{}", path.display());
+    println!("  name: {}", name);
+    println!("  id: {:04x}:{:04x}", vendor, product);
+    println!("  capabilities: {}", capabilities);
+    println!("  {}", status);
+}
+
+/// Opens the devices of `seat` through udev, or only those at `paths` if any.
+pub fn open<I: LibinputInterface + 'static>(
+    interface: I,
+    seat: &str,
+    paths: &[PathBuf],
+) -> Result<Libinput> {
+    if paths.is_empty() {
+        let mut libinput = Libinput::new_with_udev(interface);
+        libinput
+            .udev_assign_seat(seat)
+            .map_err(|_| anyhow::anyhow!("Failed to assign seat {}", seat))?;
+        return Ok(libinput);
+    }
+
+    let mut libinput = Libinput::new_from_path(interface);
+    for path in paths {
+        let added = path
+            .to_str()
+            .and_then(|path| libinput.path_add_device(path))
+            .is_some();
+        if !added {
+            log::warn!("Failed to add device {:?}", path);
+        }
+    }
+    Ok(libinput)
+}
+
+/// Prints every device libinput sees, then the gamepads it leaves alone.
+pub fn run(config: &Config, seat: &str) -> Result<()> {
+    let mut libinput = open(Interface, seat, &config.devices)?;
+    libinput.dispatch()?;
+
+    let mut seen = Vec::new();
+    for event in &mut libinput {
+        let device = match event {
+            input::Event::Device(DeviceEvent::Added(event)) => event.device(),
+            _ => continue,
+        };
+
+        let path = PathBuf::from("/dev/input").join(device.sysname());
+        let capabilities: Vec<_> = CAPABILITIES
+            .iter()
+            .filter(|(capability, _)| device.has_capability(*capability))
+            .map(|(_, name)| *name)
+            .collect();
+
+        let virtual_device = device.id_vendor() == rkvm_protocol::VIRTUAL_DEVICE_VENDOR as u32
+            || device
+                .name()
+                .starts_with(rkvm_protocol::VIRTUAL_DEVICE_NAME_PREFIX);
+        let forwarded = device.has_capability(DeviceCapability::Keyboard)
+            || device.has_capability(DeviceCapability::Pointer)
+            || device.has_capability(DeviceCapability::TabletPad);
+        let status = if virtual_device {
+            "skipped: created by rkvm"
+        } else if forwarded {
+            "captured: grabbed and forwarded to clients"
+        } else {
+            "skipped: only keyboards, pointers and tablet pads are forwarded"
+        };
+
+        print(
+            &path,
+            device.name(),
+            device.id_vendor(),
+            device.id_product(),
+            &capabilities.join(", "),
+            status,
+        );
+        seen.push(path);
+    }
+
+    // libinput leaves joysticks to others, the server reads them itself
+    let mut paths: Vec<_> = std::fs::read_dir("/dev/input")?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let name = path.file_name().and_then(|name| name.to_str());
+            name.is_some_and(|name| name.starts_with("event")) && !seen.contains(path)
+        })
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let device = match Device::open(&path) {
+            Ok(device) if gamepad::is_gamepad(&device) => device,
+            _ => continue,
+        };
+
+        let id = device.input_id();
+        let status = if config.forward_gamepads {
+            "captured: forwarded to clients as a gamepad while grabbed"
+        } else {
+            "skipped: forward_gamepads is off"
+        };
+        print(
+            &path,
+            device.name().unwrap_or("unknown"),
+            id.vendor().into(),
+            id.product().into(),
+            "gamepad",
+            status,
+        );
+    }
+
+    Ok(())
+}
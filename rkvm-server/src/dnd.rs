@@ -0,0 +1,236 @@
+//! Reading what is being dragged on this machine, so the drag can continue on a client.
+//!
+//! Only X11 is supported. The drag source owns `XdndSelection` for as long as the drag
+//! lasts and converts it for anyone who asks, not just the drop target.
+
+use std::{
+    ffi::OsString,
+    os::unix::ffi::OsStringExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use rkvm_protocol::{FileHeader, FileKind};
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xproto::{AtomEnum, ConnectionExt, CreateWindowAux, Window, WindowClass},
+        Event,
+    },
+    rust_connection::RustConnection,
+};
+
+/// Drag sources answer right away, unless something is badly wrong.
+const CONVERT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Contents of a drag on this machine.
+#[derive(Debug)]
+pub enum Dragged {
+    Text(String),
+    Uris(Vec<String>),
+    Paths(Vec<PathBuf>),
+}
+
+struct Requestor {
+    conn: RustConnection,
+    window: Window,
+    selection: u32,
+    property: u32,
+}
+
+impl Requestor {
+    fn new() -> Result<Self> {
+        let (conn, screen) = x11rb::connect(None).context("Connect to X11")?;
+        let root = conn.setup().roots[screen].root;
+
+        let window = conn.generate_id()?;
+        conn.create_window(
+            x11rb::COPY_DEPTH_FROM_PARENT,
+            window,
+            root,
+            0,
+            0,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_ONLY,
+            x11rb::COPY_FROM_PARENT,
+            &CreateWindowAux::new(),
+        )?;
+
+        let selection = conn.intern_atom(false, b"XdndSelection")?.reply()?.atom;
+        let property = conn.intern_atom(false, b"RKVM_DRAG")?.reply()?.atom;
+
+        Ok(Self {
+            conn,
+            window,
+            selection,
+            property,
+        })
+    }
+
+    fn has_owner(&self) -> Result<bool> {
+        let owner = self
+            .conn
+            .get_selection_owner(self.selection)?
+            .reply()?
+            .owner;
+        Ok(owner != x11rb::NONE)
+    }
+
+    /// Asks the drag source for its data as `target`, `None` if it doesn't offer it.
+    fn convert(&self, target: &[u8]) -> Result<Option<Vec<u8>>> {
+        let target = self.conn.intern_atom(false, target)?.reply()?.atom;
+        self.conn.convert_selection(
+            self.window,
+            self.selection,
+            target,
+            self.property,
+            x11rb::CURRENT_TIME,
+        )?;
+        self.conn.flush()?;
+
+        let deadline = Instant::now() + CONVERT_TIMEOUT;
+        loop {
+            while let Some(event) = self.conn.poll_for_event()? {
+                if let Event::SelectionNotify(event) = event {
+                    if event.property == x11rb::NONE {
+                        return Ok(None);
+                    }
+
+                    let reply = self
+                        .conn
+                        .get_property(true, self.window, self.property, AtomEnum::ANY, 0, u32::MAX)?
+                        .reply()?;
+                    return Ok(Some(reply.value));
+                }
+            }
+
+            if Instant::now() > deadline {
+                anyhow::bail!("Drag source did not answer");
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
+
+/// Decodes a `file://` URI into a local path.
+fn file_path(uri: &str) -> Option<PathBuf> {
+    let rest = uri.strip_prefix("file://")?;
+    // Skip the host, which is empty or `localhost` for local files
+    let path = &rest[rest.find('/')?..];
+
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut iter = path.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+
+    Some(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+/// Reads the drag in progress. Blocks for a moment if there is one.
+pub fn read_drag() -> Result<Option<Dragged>> {
+    let requestor = Requestor::new()?;
+    if !requestor.has_owner()? {
+        return Ok(None);
+    }
+
+    if let Some(list) = requestor.convert(b"text/uri-list")? {
+        let uris: Vec<String> = String::from_utf8_lossy(&list)
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(str::to_owned)
+            .collect();
+
+        let paths: Option<Vec<PathBuf>> = uris.iter().map(|u| file_path(u)).collect();
+        return Ok(Some(match paths {
+            Some(paths) if !paths.is_empty() => Dragged::Paths(paths),
+            _ => Dragged::Uris(uris),
+        }));
+    }
+
+    for target in [
+        &b"UTF8_STRING"[..],
+        b"text/plain;charset=utf-8",
+        b"text/plain",
+    ] {
+        if let Some(text) = requestor.convert(target)? {
+            return Ok(Some(Dragged::Text(
+                String::from_utf8_lossy(&text).into_owned(),
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+fn walk(
+    path: &Path,
+    relative: String,
+    headers: &mut Vec<FileHeader>,
+    files: &mut Vec<Option<PathBuf>>,
+) -> Result<()> {
+    // Links are skipped rather than followed, so they can't loop
+    let metadata = std::fs::symlink_metadata(path).with_context(|| format!("Stat {:?}", path))?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as u64);
+
+    if metadata.is_dir() {
+        headers.push(FileHeader {
+            path: relative.clone(),
+            kind: FileKind::Directory,
+            size: 0,
+            modified,
+        });
+        files.push(None);
+
+        for child in std::fs::read_dir(path).with_context(|| format!("List {:?}", path))? {
+            let child = child?;
+            let name = child.file_name().to_string_lossy().into_owned();
+            walk(
+                &child.path(),
+                format!("{}/{}", relative, name),
+                headers,
+                files,
+            )?;
+        }
+    } else if metadata.is_file() {
+        headers.push(FileHeader {
+            path: relative,
+            kind: FileKind::File,
+            size: metadata.len(),
+            modified,
+        });
+        files.push(Some(path.to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Lists dragged paths with directories expanded, along with where to read each file.
+pub fn list(paths: &[PathBuf]) -> Result<(Vec<FileHeader>, Vec<Option<PathBuf>>)> {
+    let mut headers = Vec::new();
+    let mut files = Vec::new();
+
+    for path in paths {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        walk(path, name, &mut headers, &mut files)?;
+    }
+
+    Ok((headers, files))
+}
@@ -0,0 +1,299 @@
+//! Receiving files copied on a client.
+//!
+//! Transfers are staged in a directory named after the client and the manifest it sent. A
+//! client that reconnects in the middle of one sends the same manifest again, and we tell
+//! it how much of each file we already have. Files found there were verified against the
+//! very same entries, so their lengths can be trusted.
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use quinn::{RecvStream, SendStream};
+use rkvm_protocol::{
+    chunk_hash, FileHeader, FileKind, FileManifest, FileResponse, Throttle, TransferProgress,
+    FILE_CHUNK_LEN,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::{
+    audit::{self, AuditEvent},
+    config::Config,
+};
+
+/// Manifests list every file, so they can get big.
+const MAX_MANIFEST_LEN: u32 = 16 * 1024 * 1024;
+
+/// Turns a path chosen by the client into a relative one that can't escape the transfer
+/// directory.
+fn sanitize(path: &str) -> Option<PathBuf> {
+    let mut clean = PathBuf::new();
+
+    // Clients may be Windows machines, which also separate with backslashes
+    for part in path.split(['/', '\\']) {
+        match part {
+            "" | "." => continue,
+            ".." => return None,
+            part => clean.push(part),
+        }
+    }
+
+    if clean.as_os_str().is_empty() {
+        None
+    } else {
+        Some(clean)
+    }
+}
+
+/// Where a file is written until all of it arrived.
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.file_name().unwrap());
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+fn set_modified(path: &Path, millis: u64) -> Result<()> {
+    let time = UNIX_EPOCH + Duration::from_millis(millis);
+    std::fs::File::open(path)?.set_modified(time)?;
+
+    Ok(())
+}
+
+/// How much of a file an earlier attempt left behind.
+enum Received {
+    Complete,
+    /// Verified bytes in the part file, always a whole number of chunks
+    Partial(u64),
+}
+
+async fn received(path: &Path, size: u64) -> Received {
+    if let Ok(metadata) = tokio::fs::metadata(path).await {
+        if metadata.is_file() && metadata.len() == size {
+            return Received::Complete;
+        }
+    }
+
+    match tokio::fs::metadata(part_path(path)).await {
+        Ok(metadata) => {
+            // A chunk cut short by the disconnect was never verified
+            let len = metadata.len().min(size);
+            Received::Partial(len - len % FILE_CHUNK_LEN as u64)
+        }
+        Err(_) => Received::Partial(0),
+    }
+}
+
+async fn read_frame(stream: &mut RecvStream, max_len: u32) -> Result<Vec<u8>> {
+    let len = stream.read_u32().await?;
+    if len > max_len {
+        anyhow::bail!("Frame too large: {} bytes", len);
+    }
+
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+
+    Ok(buf)
+}
+
+async fn write_frame(stream: &mut SendStream, frame: &[u8]) -> Result<()> {
+    stream.write_u32(frame.len() as u32).await?;
+    stream.write_all(frame).await?;
+
+    Ok(())
+}
+
+/// Receives the rest of a file from `report.done` on, checking every chunk against its hash.
+async fn receive_file(
+    stream: &mut RecvStream,
+    path: &Path,
+    report: &mut TransferProgress,
+    throttle: &mut Throttle,
+    progress: &mut impl FnMut(&TransferProgress),
+) -> Result<()> {
+    let part = part_path(path);
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        // Cut to what was verified below instead
+        .truncate(false)
+        .open(&part)
+        .await
+        .with_context(|| format!("Open {:?}", part))?;
+    file.set_len(report.done).await?;
+    file.seek(std::io::SeekFrom::Start(report.done)).await?;
+
+    progress(report);
+
+    let mut chunk = vec![0u8; FILE_CHUNK_LEN];
+    let mut hash = [0u8; 32];
+    while report.done < report.size {
+        let len = (report.size - report.done).min(FILE_CHUNK_LEN as u64) as usize;
+        // Reading slower makes flow control slow the sender down too
+        tokio::time::sleep(throttle.delay(len)).await;
+        stream.read_exact(&mut chunk[..len]).await?;
+        stream.read_exact(&mut hash).await?;
+
+        if chunk_hash(&chunk[..len]) != hash {
+            anyhow::bail!("Chunk at {} of {:?} is corrupt", report.done, report.path);
+        }
+
+        file.write_all(&chunk[..len]).await?;
+        report.done += len as u64;
+        report.total_done += len as u64;
+        progress(report);
+    }
+
+    file.flush().await?;
+    drop(file);
+    tokio::fs::rename(&part, path).await?;
+
+    Ok(())
+}
+
+/// Receives a transfer into a directory under the staging directory, recreating the
+/// directories the files were sent in. `owner` tells whose transfers may be resumed.
+///
+/// Returns the top-level entries of the transfer, ready to be put on the clipboard, or
+/// nothing if it was declined.
+pub async fn receive(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    config: &Config,
+    client: &str,
+    owner: &str,
+    mut progress: impl FnMut(&TransferProgress),
+) -> Result<Vec<PathBuf>> {
+    let frame = read_frame(&mut recv, MAX_MANIFEST_LEN).await?;
+    let manifest = FileManifest::from_slice(&frame)?;
+
+    // What is written is what the entries say, so the cap goes by that
+    let size = manifest
+        .entries
+        .iter()
+        .filter(|header| header.kind == FileKind::File)
+        .try_fold(0u64, |size, header| size.checked_add(header.size))
+        .context("Transfer size overflows")?;
+    if size != manifest.total_size {
+        anyhow::bail!(
+            "Manifest declares {} bytes, but its files add up to {}",
+            manifest.total_size,
+            size
+        );
+    }
+
+    if config.max_transfer_size.is_some_and(|max| size > max) {
+        log::warn!(
+            "Declining transfer of {} bytes, larger than max_transfer_size",
+            size
+        );
+        write_frame(&mut send, &FileResponse::Declined.to_vec()).await?;
+        send.finish().await?;
+        return Ok(Vec::new());
+    }
+
+    // Another manifest under the same id, from this client or another, is a new transfer
+    let key = audit::sha256_hex(&[owner.as_bytes(), b"\0", &frame].concat());
+    let dir = config.staging_dir.join(&key[..32]);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("Create staging directory {:?}", dir))?;
+
+    let mut entries: Vec<(&FileHeader, PathBuf)> = Vec::new();
+    let mut roots = Vec::new();
+    for header in &manifest.entries {
+        let relative = sanitize(&header.path)
+            .with_context(|| format!("Invalid file path {:?}", header.path))?;
+
+        let root = dir.join(relative.iter().next().unwrap());
+        if !roots.contains(&root) {
+            roots.push(root);
+        }
+
+        entries.push((header, dir.join(relative)));
+    }
+
+    let mut offsets = Vec::new();
+    let mut pending = Vec::new();
+    for (header, path) in &entries {
+        match header.kind {
+            FileKind::Directory => {
+                tokio::fs::create_dir_all(path)
+                    .await
+                    .with_context(|| format!("Create {:?}", path))?;
+                offsets.push(0);
+                pending.push(false);
+            }
+            FileKind::File => match received(path, header.size).await {
+                Received::Complete => {
+                    offsets.push(header.size);
+                    pending.push(false);
+                }
+                Received::Partial(len) => {
+                    offsets.push(len);
+                    pending.push(true);
+                }
+            },
+        }
+    }
+
+    let resumed: u64 = offsets.iter().sum();
+    if resumed > 0 {
+        log::info!("Resuming transfer with {} bytes already received", resumed);
+    }
+    let response = FileResponse::Resume {
+        offsets: offsets.clone(),
+    };
+    write_frame(&mut send, &response.to_vec()).await?;
+
+    let mut throttle = Throttle::new(config.bandwidth_limit);
+    let mut total_done = resumed;
+    for (((header, path), offset), pending) in entries.iter().zip(offsets).zip(pending) {
+        if !pending {
+            continue;
+        }
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let mut report = TransferProgress {
+            path: header.path.clone(),
+            done: offset,
+            size: header.size,
+            total_done,
+            total_size: manifest.total_size,
+        };
+        receive_file(&mut recv, path, &mut report, &mut throttle, &mut progress).await?;
+        total_done = report.total_done;
+
+        if let Some(modified) = header.modified {
+            set_modified(path, modified)?;
+        }
+        log::debug!("Received {:?} ({} bytes)", path, header.size);
+    }
+
+    // Directory times are applied last, since writing their entries changes them
+    for (header, path) in entries.iter().rev() {
+        if let (FileKind::Directory, Some(modified)) = (header.kind, header.modified) {
+            set_modified(path, modified)?;
+        }
+    }
+
+    write_frame(&mut send, &FileResponse::Complete.to_vec()).await?;
+    send.finish().await?;
+
+    let files = entries
+        .iter()
+        .filter(|(h, _)| h.kind == FileKind::File)
+        .count();
+    audit::record(AuditEvent::Files {
+        client,
+        count: files,
+        size: manifest.total_size,
+    });
+
+    Ok(roots)
+}
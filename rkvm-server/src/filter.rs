@@ -0,0 +1,194 @@
+//! Keeping key combinations meant for this machine away from the client while grabbed.
+//!
+//! A blocked combination is dropped, like the compositor shortcuts of the server that
+//! would do something unexpected on the client. A local one is typed here instead, with a
+//! virtual keyboard, since the grabbed devices no longer reach the compositor. In both
+//! cases only the last key of the combination is held back, the client already got the
+//! ones pressed before it.
+
+use keycode::{KeyMap, KeyMappingId};
+use rkvm_protocol::Event;
+
+use crate::{config::HotkeyConfig, uinput::VirtualInput};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    Drop,
+    Local,
+}
+
+pub struct KeyFilter {
+    blocked: Vec<Vec<KeyMappingId>>,
+    local: Vec<Vec<KeyMappingId>>,
+    /// Keys currently down, in the order they went down
+    held: Vec<KeyMappingId>,
+    /// Keys that completed a combination, with where their release goes and the other keys
+    /// of the combination pressed here
+    captured: Vec<(KeyMappingId, Route, Vec<KeyMappingId>)>,
+    /// Created the first time a local combination is typed
+    device: Option<VirtualInput>,
+}
+
+impl KeyFilter {
+    pub fn new(config: &HotkeyConfig) -> Self {
+        let mut filter = Self {
+            blocked: Vec::new(),
+            local: Vec::new(),
+            held: Vec::new(),
+            captured: Vec::new(),
+            device: None,
+        };
+        filter.set_config(config);
+        filter
+    }
+
+    /// Takes new combinations, leaving keys already held back as they are.
+    pub fn set_config(&mut self, config: &HotkeyConfig) {
+        let combos = |combos: &[Vec<KeyMappingId>]| -> Vec<_> {
+            combos.iter().filter(|c| !c.is_empty()).cloned().collect()
+        };
+        self.blocked = combos(&config.blocked_combos);
+        self.local = combos(&config.local_combos);
+    }
+
+    /// Whether a press or release of `key` may be sent to the client.
+    pub fn on_key(&mut self, key: KeyMappingId, pressed: bool, grabbed: bool) -> bool {
+        if !pressed {
+            self.held.retain(|k| *k != key);
+
+            let position = self.captured.iter().position(|(k, _, _)| *k == key);
+            let (_, route, others) = match position {
+                Some(position) => self.captured.remove(position),
+                None => return true,
+            };
+            if route == Route::Local {
+                self.type_locally(&[key], false);
+                self.type_locally(&others, false);
+            }
+            return false;
+        }
+
+        if !self.held.contains(&key) {
+            self.held.push(key);
+        }
+        if self.captured.iter().any(|(k, _, _)| *k == key) {
+            return false;
+        }
+        if !grabbed {
+            return true;
+        }
+
+        if self.matching(&self.blocked, key).is_some() {
+            log::info!("Key {:?} completes a blocked combination, not sent", key);
+            self.captured.push((key, Route::Drop, Vec::new()));
+            return false;
+        }
+
+        if let Some(combo) = self.matching(&self.local, key) {
+            log::info!("Typing {:?} here instead of on the client", combo);
+
+            let others: Vec<_> = combo.into_iter().filter(|k| *k != key).collect();
+            self.type_locally(&others, true);
+            self.type_locally(&[key], true);
+            self.captured.push((key, Route::Local, others));
+            return false;
+        }
+
+        true
+    }
+
+    /// The first of `combos` that pressing `key` completes, all of it being held.
+    fn matching(
+        &self,
+        combos: &[Vec<KeyMappingId>],
+        key: KeyMappingId,
+    ) -> Option<Vec<KeyMappingId>> {
+        combos
+            .iter()
+            .find(|combo| combo.contains(&key) && combo.iter().all(|k| self.held.contains(k)))
+            .cloned()
+    }
+
+    fn type_locally(&mut self, keys: &[KeyMappingId], pressed: bool) {
+        if keys.is_empty() {
+            return;
+        }
+
+        if self.device.is_none() {
+            match VirtualInput::new() {
+                Ok(device) => self.device = Some(device),
+                Err(e) => {
+                    log::error!("Failed to create virtual keyboard for local keys: {}", e);
+                    return;
+                }
+            }
+        }
+        let device = self.device.as_mut().unwrap();
+
+        for key in keys {
+            let event = Event::key(KeyMap::from(*key).win, pressed);
+            if let Err(e) = device.inject(&event) {
+                log::error!("Failed to type {:?} here: {}", key, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use KeyMappingId::{AltLeft, ControlLeft, MetaLeft, UsC, UsT};
+
+    fn blocking(combo: &[KeyMappingId]) -> KeyFilter {
+        KeyFilter::new(&HotkeyConfig {
+            blocked_combos: vec![combo.to_vec(), Vec::new()],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn blocked_combo_holds_back_its_last_key() {
+        let mut filter = blocking(&[ControlLeft, AltLeft, UsT]);
+
+        assert!(filter.on_key(ControlLeft, true, true));
+        assert!(filter.on_key(AltLeft, true, true));
+        assert!(!filter.on_key(UsT, true, true));
+        // Repeats and the release of the held back key stay here too
+        assert!(!filter.on_key(UsT, true, true));
+        assert!(!filter.on_key(UsT, false, true));
+        assert!(filter.on_key(AltLeft, false, true));
+        assert!(filter.on_key(ControlLeft, false, true));
+
+        assert!(filter.on_key(UsT, true, true));
+    }
+
+    #[test]
+    fn blocked_combo_needs_all_its_keys() {
+        let mut filter = blocking(&[ControlLeft, AltLeft, UsT]);
+
+        assert!(filter.on_key(ControlLeft, true, true));
+        assert!(filter.on_key(UsT, true, true));
+        assert!(filter.on_key(UsC, true, true));
+    }
+
+    #[test]
+    fn nothing_is_blocked_ungrabbed() {
+        let mut filter = blocking(&[MetaLeft, UsT]);
+
+        assert!(filter.on_key(MetaLeft, true, false));
+        assert!(filter.on_key(UsT, true, false));
+        assert!(filter.on_key(UsT, false, false));
+    }
+
+    #[test]
+    fn release_after_grab_goes_through() {
+        let mut filter = blocking(&[MetaLeft, UsT]);
+
+        filter.on_key(MetaLeft, true, true);
+        assert!(!filter.on_key(UsT, true, true));
+        // The grab ending doesn't let the release of the held back key out
+        assert!(!filter.on_key(UsT, false, false));
+        assert!(filter.on_key(MetaLeft, false, false));
+    }
+}
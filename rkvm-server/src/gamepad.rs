@@ -0,0 +1,321 @@
+//! Forwarding gamepads, read straight from evdev since libinput leaves them alone.
+
+use std::{
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::Result;
+use evdev::{AbsoluteAxisType, Device, InputEventKind, Key};
+use nix::poll::{PollFd, PollFlags};
+use rkvm_protocol::{Event, GamepadAxis, GamepadButton, Packet};
+use tokio::sync::mpsc::Sender;
+
+use crate::{config, grab};
+
+/// How often `/dev/input` is checked for new gamepads.
+const SCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a gamepad goes on being grabbed, or not, after input moved.
+const GRAB_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Gamepads being read, by device path. The index is the pad number sent to clients
+static PADS: Mutex<Vec<Option<PathBuf>>> = Mutex::new(Vec::new());
+
+/// Starts looking for gamepads, forwarding them while grabbed and `forward_gamepads` is on.
+pub fn start(event_tx: Sender<Packet>) {
+    std::thread::spawn(move || {
+        // Devices found not to be gamepads, so they aren't opened again on every scan
+        let mut ignored = Vec::new();
+
+        loop {
+            if config::current().forward_gamepads {
+                scan(&mut ignored, &event_tx);
+            }
+            std::thread::sleep(SCAN_INTERVAL);
+        }
+    });
+}
+
+fn scan(ignored: &mut Vec<PathBuf>, event_tx: &Sender<Packet>) {
+    let entries = match std::fs::read_dir("/dev/input") {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("Failed to look for gamepads: {}", e);
+            return;
+        }
+    };
+
+    let paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("event"))
+        })
+        .collect();
+    // Device nodes are reused, so forget about any that went away
+    ignored.retain(|path| paths.contains(path));
+
+    for path in paths {
+        if ignored.contains(&path) || PADS.lock().unwrap().contains(&Some(path.clone())) {
+            continue;
+        }
+
+        let device = match Device::open(&path) {
+            Ok(device) if is_gamepad(&device) => device,
+            // Not ours to read, or not a gamepad
+            _ => {
+                ignored.push(path);
+                continue;
+            }
+        };
+
+        let pad = add(&path);
+        log::info!(
+            "Gamepad {} added: {}",
+            pad,
+            device.name().unwrap_or("unknown")
+        );
+
+        let event_tx = event_tx.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = read(device, pad, &event_tx) {
+                log::info!("Gamepad {} removed: {}", pad, e);
+            }
+
+            remove(&path);
+            let event = Event::GamepadRemoved { pad };
+            let _ = event_tx.blocking_send(Packet::new(0, event));
+        });
+    }
+}
+
+/// Whether `device` is a gamepad, and not one of our own.
+pub fn is_gamepad(device: &Device) -> bool {
+    let has_buttons = device
+        .supported_keys()
+        .is_some_and(|keys| keys.contains(Key::BTN_SOUTH));
+    has_buttons && !grab::is_rkvm_device(device.as_raw_fd())
+}
+
+/// Takes the lowest free pad number for `path`.
+fn add(path: &Path) -> u8 {
+    let mut pads = PADS.lock().unwrap();
+    let pad = match pads.iter().position(Option::is_none) {
+        Some(pad) => pad,
+        None => {
+            pads.push(None);
+            pads.len() - 1
+        }
+    };
+
+    pads[pad] = Some(path.to_owned());
+    pad as u8
+}
+
+fn remove(path: &Path) {
+    let mut pads = PADS.lock().unwrap();
+    for pad in pads.iter_mut() {
+        if pad.as_deref() == Some(path) {
+            *pad = None;
+        }
+    }
+}
+
+fn button(key: Key) -> Option<GamepadButton> {
+    Some(match key {
+        Key::BTN_SOUTH => GamepadButton::South,
+        Key::BTN_EAST => GamepadButton::East,
+        Key::BTN_NORTH => GamepadButton::North,
+        Key::BTN_WEST => GamepadButton::West,
+        Key::BTN_TL => GamepadButton::LeftBumper,
+        Key::BTN_TR => GamepadButton::RightBumper,
+        Key::BTN_SELECT => GamepadButton::Back,
+        Key::BTN_START => GamepadButton::Start,
+        Key::BTN_MODE => GamepadButton::Guide,
+        Key::BTN_THUMBL => GamepadButton::LeftThumb,
+        Key::BTN_THUMBR => GamepadButton::RightThumb,
+        Key::BTN_DPAD_UP => GamepadButton::DpadUp,
+        Key::BTN_DPAD_DOWN => GamepadButton::DpadDown,
+        Key::BTN_DPAD_LEFT => GamepadButton::DpadLeft,
+        Key::BTN_DPAD_RIGHT => GamepadButton::DpadRight,
+        _ => return None,
+    })
+}
+
+fn axis(axis: AbsoluteAxisType) -> Option<GamepadAxis> {
+    Some(match axis {
+        AbsoluteAxisType::ABS_X => GamepadAxis::LeftX,
+        AbsoluteAxisType::ABS_Y => GamepadAxis::LeftY,
+        AbsoluteAxisType::ABS_RX => GamepadAxis::RightX,
+        AbsoluteAxisType::ABS_RY => GamepadAxis::RightY,
+        AbsoluteAxisType::ABS_Z | AbsoluteAxisType::ABS_BRAKE => GamepadAxis::LeftTrigger,
+        AbsoluteAxisType::ABS_RZ | AbsoluteAxisType::ABS_GAS => GamepadAxis::RightTrigger,
+        _ => return None,
+    })
+}
+
+/// Scales `value` from `min..=max` to the range of `axis` in the protocol.
+fn normalize(axis: GamepadAxis, value: i32, min: i32, max: i32) -> i16 {
+    let (low, high) = match axis {
+        GamepadAxis::LeftTrigger | GamepadAxis::RightTrigger => (0, i16::MAX as i64),
+        _ => (i16::MIN as i64, i16::MAX as i64),
+    };
+    if max <= min {
+        return 0;
+    }
+
+    let scaled = low + (value as i64 - min as i64) * (high - low) / (max as i64 - min as i64);
+    scaled.clamp(low, high) as i16
+}
+
+/// Turns a hat position into presses and releases of the two directions it moves between.
+fn hat(
+    value: i32,
+    previous: i32,
+    negative: GamepadButton,
+    positive: GamepadButton,
+) -> Vec<(GamepadButton, bool)> {
+    let mut changes = Vec::new();
+    for (direction, button) in [(-1, negative), (1, positive)] {
+        if (previous == direction) != (value == direction) {
+            changes.push((button, value == direction));
+        }
+    }
+    changes
+}
+
+/// Forwards the events of `device` until it goes away.
+fn read(mut device: Device, pad: u8, event_tx: &Sender<Packet>) -> Result<()> {
+    let ranges = device.get_abs_state()?;
+    let mut hats = (0, 0);
+    let mut grabbed = false;
+
+    loop {
+        // Woken up every now and then, so grabbing follows without waiting for input
+        let mut pollfds = [PollFd::new(device.as_raw_fd(), PollFlags::POLLIN)];
+        match nix::poll::poll(&mut pollfds, GRAB_INTERVAL.as_millis() as i32) {
+            Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+            Err(e) => return Err(e.into()),
+        }
+        let readable = pollfds[0]
+            .revents()
+            .is_some_and(|events| !events.is_empty());
+        let events: Vec<_> = if readable {
+            device.fetch_events()?.collect()
+        } else {
+            Vec::new()
+        };
+
+        let forward = grab::is_grabbed() && config::current().forward_gamepads;
+        if forward != grabbed {
+            if forward {
+                device.grab()?;
+            } else {
+                device.ungrab()?;
+                // Lets go of whatever was held on the client
+                let event = Event::GamepadRemoved { pad };
+                let _ = event_tx.blocking_send(Packet::new(0, event));
+            }
+            grabbed = forward;
+        }
+        if !forward {
+            continue;
+        }
+
+        let send = |event| {
+            let _ = event_tx.blocking_send(Packet::new(0, event));
+        };
+        let press = |button, pressed| Event::GamepadButton {
+            pad,
+            button,
+            pressed,
+        };
+
+        for event in events {
+            match event.kind() {
+                InputEventKind::Key(key) => {
+                    // Some gamepads only have digital triggers
+                    let trigger = match key {
+                        Key::BTN_TL2 => Some(GamepadAxis::LeftTrigger),
+                        Key::BTN_TR2 => Some(GamepadAxis::RightTrigger),
+                        _ => None,
+                    };
+
+                    if let Some(axis) = trigger {
+                        let value = if event.value() != 0 { i16::MAX } else { 0 };
+                        send(Event::GamepadAxis { pad, axis, value });
+                    } else if let Some(button) = button(key) {
+                        // Repeats have the value 2
+                        if event.value() != 2 {
+                            send(press(button, event.value() != 0));
+                        }
+                    }
+                }
+                InputEventKind::AbsAxis(AbsoluteAxisType::ABS_HAT0X) => {
+                    let left = GamepadButton::DpadLeft;
+                    let right = GamepadButton::DpadRight;
+                    for (button, pressed) in hat(event.value(), hats.0, left, right) {
+                        send(press(button, pressed));
+                    }
+                    hats.0 = event.value();
+                }
+                InputEventKind::AbsAxis(AbsoluteAxisType::ABS_HAT0Y) => {
+                    let up = GamepadButton::DpadUp;
+                    let down = GamepadButton::DpadDown;
+                    for (button, pressed) in hat(event.value(), hats.1, up, down) {
+                        send(press(button, pressed));
+                    }
+                    hats.1 = event.value();
+                }
+                InputEventKind::AbsAxis(code) => {
+                    if let Some(axis) = axis(code) {
+                        let range = &ranges[code.0 as usize];
+                        let value = normalize(axis, event.value(), range.minimum, range.maximum);
+                        send(Event::GamepadAxis { pad, axis, value });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_sticks() {
+        let stick = GamepadAxis::LeftX;
+        assert_eq!(normalize(stick, 0, 0, 255), i16::MIN);
+        assert_eq!(normalize(stick, 255, 0, 255), i16::MAX);
+        assert_eq!(normalize(stick, 0, -32768, 32767), 0);
+        assert_eq!(normalize(stick, 1000, -100, 100), i16::MAX);
+    }
+
+    #[test]
+    fn normalize_triggers() {
+        let trigger = GamepadAxis::RightTrigger;
+        assert_eq!(normalize(trigger, 0, 0, 1023), 0);
+        assert_eq!(normalize(trigger, 1023, 0, 1023), i16::MAX);
+        assert_eq!(normalize(trigger, -5, 0, 1023), 0);
+    }
+
+    #[test]
+    fn normalize_empty_range() {
+        assert_eq!(normalize(GamepadAxis::LeftY, 7, 5, 5), 0);
+    }
+
+    #[test]
+    fn hat_presses_and_releases() {
+        let (left, right) = (GamepadButton::DpadLeft, GamepadButton::DpadRight);
+        assert_eq!(hat(-1, 0, left, right), [(left, true)]);
+        assert_eq!(hat(0, -1, left, right), [(left, false)]);
+        assert_eq!(hat(1, -1, left, right), [(left, false), (right, true)]);
+        assert!(hat(1, 1, left, right).is_empty());
+    }
+}
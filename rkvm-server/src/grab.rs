@@ -1,79 +1,336 @@
+use std::fmt;
 use std::os::fd::RawFd;
 use std::path::Path;
-use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, path::PathBuf, sync::Mutex};
 
-use nix::{ioctl_write_int_bad, request_code_write};
-use threadpool::ThreadPool;
+use anyhow::Result;
+use nix::{errno::Errno, ioctl_read, ioctl_read_buf, ioctl_write_int_bad, request_code_write};
+use tokio::runtime::Handle;
 
 // https://github.com/torvalds/linux/blob/68e77ffbfd06ae3ef8f2abf1c3b971383c866983/include/uapi/linux/input.h#L186
 ioctl_write_int_bad!(eviocgrab, request_code_write!('E', 0x90, 4));
+ioctl_read!(eviocgid, b'E', 0x02, InputId);
+ioctl_read_buf!(eviocgname, b'E', 0x06, u8);
+ioctl_read_buf!(eviocgkey, b'E', 0x18, u8);
+ioctl_read_buf!(eviocgled, b'E', 0x19, u8);
+
+// From linux/input-event-codes.h
+const EV_SYN: u16 = 0x00;
+const EV_LED: u16 = 0x11;
+const SYN_REPORT: u16 = 0;
+const KEY_CNT: usize = 0x300;
+pub const LED_NUML: u16 = 0x00;
+pub const LED_CAPSL: u16 = 0x01;
+pub const LED_SCROLLL: u16 = 0x02;
+
+/// `struct input_id` from linux/input.h
+#[repr(C)]
+#[derive(Debug, Default)]
+struct InputId {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
 
 lazy_static::lazy_static! {
     static ref DEVICES: Mutex<HashMap<PathBuf, RawFd>> = Mutex::new(HashMap::new());
-    static ref THREAD_POOL: SyncThreadPool = SyncThreadPool::new(4);
+}
+
+/// How long to wait before trying again to grab a device another program has grabbed.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Devices whose ioctl takes longer than this, retries included, count as failed, and are let
+/// go of again should the grab go through late.
+const GRAB_TIMEOUT: Duration = Duration::from_secs(2);
+
+static GRABBED: AtomicBool = AtomicBool::new(false);
+
+const MAX_DEVICES: usize = 64;
+
+/// Devices that may be grabbed, -1 for none, kept apart from [`DEVICES`] so they can be let
+/// go of without a lock
+static FDS: [AtomicI32; MAX_DEVICES] = [const { AtomicI32::new(-1) }; MAX_DEVICES];
+
+/// Name in `/dev/input` of the keyboard last typed on
+static KEYBOARD: Mutex<Option<String>> = Mutex::new(None);
+
+/// Set in dry runs, which leave devices alone
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Makes grabbing only pretend to, so local input keeps working.
+pub fn set_dry_run() {
+    DRY_RUN.store(true, Ordering::Relaxed);
+}
+
+/// Whether input currently goes to the active client.
+pub fn is_grabbed() -> bool {
+    GRABBED.load(Ordering::Relaxed)
+}
+
+fn set_grabbed(grabbed: bool) {
+    GRABBED.store(grabbed, Ordering::Relaxed);
+    crate::clients::target_changed();
 }
 
 pub fn add_device(path: &Path, fd: RawFd) {
     let mut devices = DEVICES.lock().unwrap();
     devices.insert(path.to_owned(), fd);
+
+    match FDS.iter().find(|slot| slot.load(Ordering::Relaxed) < 0) {
+        Some(slot) => slot.store(fd, Ordering::Relaxed),
+        None => log::warn!(
+            "Too many devices, {} may stay grabbed on a crash",
+            path.display()
+        ),
+    }
 }
 
 pub fn remove_device(fd: RawFd) {
     let mut devices = DEVICES.lock().unwrap();
     devices.retain(|_, &mut v| v != fd);
+
+    if let Some(slot) = FDS.iter().find(|slot| slot.load(Ordering::Relaxed) == fd) {
+        slot.store(-1, Ordering::Relaxed);
+    }
 }
 
-pub fn grab_devices(grab: bool) {
-    let devices = DEVICES.lock().unwrap();
+/// Whether the evdev device behind `fd` is a virtual device created by rkvm itself.
+///
+/// Capturing those would feed injected input straight back to the clients.
+pub fn is_rkvm_device(fd: RawFd) -> bool {
+    let mut id = InputId::default();
+    if unsafe { eviocgid(fd, &mut id) }.is_ok() && id.vendor == rkvm_protocol::VIRTUAL_DEVICE_VENDOR
+    {
+        return true;
+    }
 
-    let (tx, rx) = channel();
+    let mut name = [0u8; 256];
+    match unsafe { eviocgname(fd, &mut name) } {
+        Ok(len) => {
+            let len = (len.max(0) as usize).min(name.len());
+            name[..len].starts_with(rkvm_protocol::VIRTUAL_DEVICE_NAME_PREFIX.as_bytes())
+        }
+        Err(_) => false,
+    }
+}
 
-    for (path, device) in devices.iter() {
-        let device = *device;
-        let path = path.clone();
+/// Turns the LED `led` on or off on every device that has it.
+pub fn set_led(led: u16, on: bool) {
+    let event = |type_, code, value| libc::input_event {
+        time: libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+        type_,
+        code,
+        value,
+    };
+    let events = [event(EV_LED, led, on.into()), event(EV_SYN, SYN_REPORT, 0)];
+    let bytes = unsafe {
+        std::slice::from_raw_parts(events.as_ptr().cast::<u8>(), std::mem::size_of_val(&events))
+    };
 
-        let tx = tx.clone();
-        THREAD_POOL.execute(move || {
-            match unsafe { eviocgrab(device, grab.into()) } {
-                Ok(_) => {}
-                Err(e) => {
-                    log::error!(
-                        "Failed to {} {}: {}",
-                        if grab { "grab" } else { "ungrab" },
-                        path.display(),
-                        e
-                    );
-                }
+    // Devices without LEDs ignore it
+    for (path, &device) in DEVICES.lock().unwrap().iter() {
+        if let Err(e) = nix::unistd::write(device, bytes) {
+            log::debug!("Failed to set LED of {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Evdev codes of the keys held down on any device.
+pub fn held_keys() -> Vec<u16> {
+    let mut held = Vec::new();
+
+    for &device in DEVICES.lock().unwrap().values() {
+        let mut bits = [0u8; KEY_CNT / 8];
+        if unsafe { eviocgkey(device, &mut bits) }.is_err() {
+            continue;
+        }
+
+        for code in 0..KEY_CNT as u16 {
+            let down = bits[code as usize / 8] & (1 << (code % 8)) != 0;
+            if down && !held.contains(&code) {
+                held.push(code);
             }
+        }
+    }
+
+    held
+}
+
+/// Whether the LED `led` is lit on any device.
+pub fn is_lit(led: u16) -> bool {
+    DEVICES.lock().unwrap().values().any(|&device| {
+        let mut bits = [0u8; 2];
+        let read = unsafe { eviocgled(device, &mut bits) }.is_ok();
+        read && bits[led as usize / 8] & (1 << (led % 8)) != 0
+    })
+}
+
+/// Notes the device of a key event, by its name in `/dev/input`.
+pub fn typed_on(sysname: &str) {
+    let mut keyboard = KEYBOARD.lock().unwrap();
+    if keyboard.as_deref() != Some(sysname) {
+        *keyboard = Some(sysname.to_owned());
+    }
+}
+
+/// Why a device wasn't grabbed or ungrabbed.
+#[derive(Debug, Clone, Copy)]
+pub enum GrabError {
+    Ioctl(Errno),
+    /// The ioctl didn't return within [`GRAB_TIMEOUT`]
+    TimedOut,
+}
+
+impl fmt::Display for GrabError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GrabError::Ioctl(e) => write!(f, "{}", e),
+            GrabError::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+/// Grabs or ungrabs every device at once, returning the result for each.
+async fn set_grab(grab: bool, retries: u32) -> Vec<(PathBuf, Result<(), GrabError>)> {
+    let devices: Vec<_> = DEVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(path, device)| (path.clone(), *device))
+        .collect();
+
+    let deadline = tokio::time::Instant::now() + GRAB_TIMEOUT;
+    let tasks: Vec<_> = devices
+        .into_iter()
+        .map(|(path, device)| {
+            let std_deadline = deadline.into_std();
+            let task = tokio::task::spawn_blocking(move || {
+                let mut attempt = 0;
+                loop {
+                    match unsafe { eviocgrab(device, grab.into()) } {
+                        // Another program has it, maybe only for a moment
+                        Err(Errno::EBUSY)
+                            if attempt < retries
+                                && Instant::now() + RETRY_INTERVAL < std_deadline =>
+                        {
+                            attempt += 1;
+                            std::thread::sleep(RETRY_INTERVAL);
+                        }
+                        // Too late, the caller was told it failed
+                        Ok(_) if grab && Instant::now() >= std_deadline => {
+                            let _ = unsafe { eviocgrab(device, 0) };
+                            break Err(Errno::ETIMEDOUT);
+                        }
+                        result => break result.map(drop),
+                    }
+                }
+            });
+            (path, task)
+        })
+        .collect();
 
-            let _ = tx.send(());
-        });
+    let mut results = Vec::new();
+    for (path, task) in tasks {
+        let result = match tokio::time::timeout_at(deadline, task).await {
+            Ok(Ok(result)) => result.map_err(GrabError::Ioctl),
+            Ok(Err(e)) => std::panic::resume_unwind(e.into_panic()),
+            Err(_) => Err(GrabError::TimedOut),
+        };
+
+        if let Err(e) = result {
+            log::error!(
+                "Failed to {} {}: {}",
+                if grab { "grab" } else { "ungrab" },
+                path.display(),
+                e
+            );
+        }
+        results.push((path, result));
     }
 
-    drop(tx);
-    for _ in rx.iter() {}
+    results
 }
 
-/// A Send + Sync thread pool.
-#[derive(Debug)]
-struct SyncThreadPool {
-    pool: Mutex<ThreadPool>,
+/// Grabs or ungrabs every device, retrying those another program has grabbed up to
+/// `retries` times. Returns the devices that failed.
+///
+/// Grabbing fails as a whole, letting go of every device again, if the keyboard last typed
+/// on can't be grabbed, since whatever is typed would go to both machines.
+pub fn grab_devices(
+    runtime: &Handle,
+    grab: bool,
+    retries: u32,
+) -> Result<Vec<(PathBuf, GrabError)>> {
+    if DRY_RUN.load(Ordering::Relaxed) {
+        log::info!("Dry run, leaving devices alone");
+        set_grabbed(grab);
+        return Ok(Vec::new());
+    }
+
+    let failed: Vec<_> = runtime
+        .block_on(set_grab(grab, retries))
+        .into_iter()
+        .filter_map(|(path, result)| result.err().map(|e| (path, e)))
+        .collect();
+
+    let keyboard = KEYBOARD.lock().unwrap().clone();
+    let keyboard_failed = failed.iter().find(|(path, _)| {
+        // Devices listed in the config may be symlinks
+        let path = path.canonicalize().unwrap_or_else(|_| path.clone());
+        let name = path.file_name().and_then(|name| name.to_str());
+        name.is_some() && name == keyboard.as_deref()
+    });
+    if let (true, Some((path, e))) = (grab, keyboard_failed) {
+        runtime.block_on(set_grab(false, 0));
+        set_grabbed(false);
+        anyhow::bail!("Failed to grab the keyboard {}: {}", path.display(), e);
+    }
+
+    set_grabbed(grab);
+    Ok(failed)
 }
 
-impl SyncThreadPool {
-    /// Create a new thread pool with the specified size.
-    fn new(num_threads: usize) -> Self {
-        Self {
-            pool: Mutex::new(ThreadPool::new(num_threads)),
+/// Lets go of every device at once, without waiting on anything that may be stuck.
+///
+/// Safe to call from a panic, even one that struck while the devices were locked, or midway
+/// through grabbing them, which is why even devices that may not be grabbed are let go of.
+pub fn release_all() {
+    // Errors can't be logged, as the logger may be what panicked
+    for fd in &FDS {
+        let fd = fd.load(Ordering::Relaxed);
+        if fd >= 0 {
+            let _ = unsafe { eviocgrab(fd, 0) };
         }
     }
+    GRABBED.store(false, Ordering::Relaxed);
+}
+
+/// Lets go of every device when dropped, see [`release_guard`].
+pub struct ReleaseGuard(());
+
+/// Lets go of every device when the guard is dropped, and on any panic from now on, so an
+/// early return or a bug never leaves the keyboard here dead.
+///
+/// A panic aborts the process, even in a task the runtime would survive, as input would
+/// otherwise go both here and to a client the controller still believes it goes to.
+pub fn release_guard() -> ReleaseGuard {
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        release_all();
+        hook(info);
+        std::process::abort();
+    }));
+    ReleaseGuard(())
+}
 
-    /// Execute a job on the thread pool.
-    fn execute(&self, job: impl FnOnce() + Send + 'static) {
-        self.pool
-            .lock()
-            .expect("could not lock thread pool mutex")
-            .execute(job)
+impl Drop for ReleaseGuard {
+    fn drop(&mut self) {
+        release_all();
     }
 }
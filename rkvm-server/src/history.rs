@@ -0,0 +1,473 @@
+//! What went over the clipboard, with `clipboard_history`, to see what went where and to
+//! get back a clipboard overwritten by mistake.
+//!
+//! Items are appended to `clipboard-history` in the state dir, each sealed with a key kept
+//! in the system keyring, or next to the file, readable only by its owner, if there is
+//! none. Their contents are only kept if asked for, and items beyond `max_items` or older
+//! than `max_age_hours` are dropped, from the file once enough of them were.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use rkvm_protocol::{ClipboardSeal, CLIPBOARD_KEY_LEN, CLIPBOARD_NONCE_LEN};
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// Items dropped from the history that may stay in the file until it is written again
+const MAX_STALE: usize = 32;
+
+/// Where the key is kept in the system keyring
+const KEYRING_SERVICE: &str = "rkvm-server";
+const KEYRING_USER: &str = "clipboard-history";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    /// Sent to the active client
+    Sent,
+    /// Pulled from a client onto ours
+    Pulled,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Item {
+    /// Milliseconds since the Unix epoch
+    time: u64,
+    direction: Direction,
+    client: Option<String>,
+    /// MIME type
+    kind: String,
+    size: usize,
+    sha256: String,
+    content: Option<Vec<u8>>,
+}
+
+struct History {
+    state_dir: Option<PathBuf>,
+    /// Set once the key and the items were read
+    seal: Option<ClipboardSeal>,
+    /// Oldest first
+    items: Vec<Item>,
+    /// Items in the file, those dropped since it was last written included
+    on_disk: usize,
+}
+
+static HISTORY: Mutex<History> = Mutex::new(History {
+    state_dir: None,
+    seal: None,
+    items: Vec::new(),
+    on_disk: 0,
+});
+
+fn now() -> u64 {
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    since.as_millis() as u64
+}
+
+fn format_age(millis: u64) -> String {
+    let age = Duration::from_millis(now().saturating_sub(millis));
+    format!("{} ago", rkvm_protocol::format_duration(age))
+}
+
+impl History {
+    /// Reads the key and the items, making up a key if there is none yet.
+    fn load(&mut self) -> Result<&ClipboardSeal> {
+        let state_dir = self
+            .state_dir
+            .as_deref()
+            .context("Clipboard history isn't set up")?;
+
+        if self.seal.is_none() {
+            let seal = ClipboardSeal::new(&load_key(state_dir)?);
+            let path = state_dir.join("clipboard-history");
+            self.items = match std::fs::read(&path) {
+                Ok(data) => read(&seal, &data).with_context(|| format!("Read {:?}", path))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e).with_context(|| format!("Read {:?}", path)),
+            };
+            self.on_disk = self.items.len();
+            self.seal = Some(seal);
+        }
+
+        Ok(self.seal.as_ref().unwrap())
+    }
+
+    /// Drops what the config doesn't want kept any more, returning whether any contents
+    /// were, which mustn't stay in the file.
+    fn prune(&mut self, config: &config::ClipboardHistoryConfig) -> bool {
+        let max_age = Duration::from_secs(config.max_age_hours.saturating_mul(3600));
+        let oldest = now().saturating_sub(max_age.as_millis() as u64);
+        self.items.retain(|item| item.time >= oldest);
+
+        let excess = self.items.len().saturating_sub(config.max_items);
+        self.items.drain(..excess);
+
+        let mut content = false;
+        if !config.content {
+            for item in &mut self.items {
+                content |= item.content.take().is_some();
+            }
+        }
+        content
+    }
+
+    /// Reads the items and drops those not to be kept any more, from the file too.
+    fn load_pruned(&mut self) -> Result<()> {
+        let config = config::current().clipboard_history.clone();
+        self.load()?;
+        if self.prune(&config) || self.on_disk != self.items.len() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Adds `item` to the end of the file.
+    fn append(&mut self, item: &Item) -> Result<()> {
+        let record = encode(self.load()?, item)?;
+        let path = self.state_dir.as_deref().unwrap().join("clipboard-history");
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("Write {:?}", path))?;
+        file.write_all(&record)
+            .with_context(|| format!("Write {:?}", path))?;
+        self.on_disk += 1;
+        Ok(())
+    }
+
+    /// Writes the file again with only the items kept.
+    fn save(&mut self) -> Result<()> {
+        self.load()?;
+        let seal = self.seal.as_ref().unwrap();
+        let mut data = Vec::new();
+        for item in &self.items {
+            data.extend(encode(seal, item)?);
+        }
+        let state_dir = self.state_dir.as_deref().unwrap();
+
+        // Renamed into place, so a crash midway leaves the previous items
+        let path = state_dir.join("clipboard-history");
+        let temp = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp)
+            .with_context(|| format!("Write {:?}", temp))?;
+        file.write_all(&data)
+            .with_context(|| format!("Write {:?}", temp))?;
+        std::fs::rename(&temp, &path).with_context(|| format!("Write {:?}", path))?;
+        self.on_disk = self.items.len();
+        Ok(())
+    }
+}
+
+/// The key in the system keyring, made up if there is none yet. Without a keyring, as for
+/// a server running as root without a session, it is kept in a file instead.
+fn load_key(state_dir: &Path) -> Result<[u8; CLIPBOARD_KEY_LEN]> {
+    let entry = match keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER) {
+        Ok(entry) => entry,
+        Err(e) => {
+            log::warn!(
+                "System keyring unavailable, keeping the history key in a file: {}",
+                e
+            );
+            return load_key_file(state_dir);
+        }
+    };
+
+    match entry.get_password() {
+        Ok(key) => return parse_key(&key).context("Keyring doesn't hold a history key"),
+        Err(keyring::Error::NoEntry) => {}
+        Err(e) => {
+            log::warn!(
+                "System keyring unavailable, keeping the history key in a file: {}",
+                e
+            );
+            return load_key_file(state_dir);
+        }
+    }
+
+    let key = ClipboardSeal::generate_key();
+    match entry.set_password(&format_key(&key)) {
+        Ok(()) => Ok(key),
+        Err(e) => {
+            log::warn!("Failed to store the history key in the keyring: {}", e);
+            load_key_file(state_dir)
+        }
+    }
+}
+
+fn load_key_file(state_dir: &Path) -> Result<[u8; CLIPBOARD_KEY_LEN]> {
+    let path = state_dir.join("clipboard-history.key");
+    match std::fs::read(&path) {
+        Ok(key) => {
+            return key
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("{:?} doesn't hold a key", path))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Read {:?}", path)),
+    }
+
+    std::fs::create_dir_all(state_dir)
+        .with_context(|| format!("Create state dir {:?}", state_dir))?;
+    let key = ClipboardSeal::generate_key();
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("Write {:?}", path))?;
+    file.write_all(&key)?;
+    Ok(key)
+}
+
+fn format_key(key: &[u8]) -> String {
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_key(key: &str) -> Option<[u8; CLIPBOARD_KEY_LEN]> {
+    if key.len() != CLIPBOARD_KEY_LEN * 2 || !key.is_ascii() {
+        return None;
+    }
+    let mut parsed = [0; CLIPBOARD_KEY_LEN];
+    for (i, byte) in parsed.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&key[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(parsed)
+}
+
+/// `item` sealed as a record of the file: its length, the nonce and the sealed item.
+fn encode(seal: &ClipboardSeal, item: &Item) -> Result<Vec<u8>> {
+    let (nonce, sealed) = seal.seal_bytes(bincode::serialize(item)?);
+    let len = u32::try_from(nonce.len() + sealed.len()).context("Item too large")?;
+    Ok([&len.to_be_bytes()[..], &nonce, &sealed].concat())
+}
+
+/// The items of the records in `data`.
+fn read(seal: &ClipboardSeal, mut data: &[u8]) -> Result<Vec<Item>> {
+    let mut items = Vec::new();
+    while data.len() >= 4 {
+        let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        let record = match data[4..].get(..len) {
+            Some(record) if len >= CLIPBOARD_NONCE_LEN => record,
+            // Cut short by a crash while appending
+            _ => break,
+        };
+        data = &data[4 + len..];
+
+        let nonce = record[..CLIPBOARD_NONCE_LEN].try_into().unwrap();
+        let opened = seal
+            .open_bytes(nonce, record[CLIPBOARD_NONCE_LEN..].to_vec())
+            .context("Sealed with another key or changed")?;
+        items.push(bincode::deserialize(&opened)?);
+    }
+    Ok(items)
+}
+
+/// Keeps history in `state_dir`, once `clipboard_history` is enabled, dropping what was
+/// kept there for too long already.
+pub fn init(state_dir: &Path) {
+    let mut history = HISTORY.lock().unwrap();
+    history.state_dir = Some(state_dir.to_owned());
+
+    // Not making up a key for a history never kept
+    if state_dir.join("clipboard-history").exists() {
+        if let Err(e) = history.load_pruned() {
+            log::warn!("Failed to prune clipboard history: {:#}", e);
+        }
+    }
+}
+
+/// Records clipboard `data` of `kind` that went `direction`, with `client`, and its digest
+/// `sha256` as given to the audit log.
+///
+/// The item is appended to the file off the async threads.
+pub fn record(direction: Direction, client: Option<&str>, kind: &str, data: &[u8], sha256: &str) {
+    let config = config::current().clipboard_history.clone();
+    if !config.enabled {
+        return;
+    }
+
+    // Raw images can't be put back on a clipboard as they are
+    let content = (config.content && kind != "image/x-rgba").then(|| data.to_vec());
+    let item = Item {
+        time: now(),
+        direction,
+        client: client.map(str::to_owned),
+        kind: kind.to_owned(),
+        size: data.len(),
+        sha256: sha256.to_owned(),
+        content,
+    };
+    tokio::task::spawn_blocking(move || record_now(&config, item));
+}
+
+fn record_now(config: &config::ClipboardHistoryConfig, item: Item) {
+    let mut history = HISTORY.lock().unwrap();
+    if history.state_dir.is_none() {
+        return;
+    }
+    if let Err(e) = history.append(&item) {
+        log::warn!("Failed to keep clipboard history: {:#}", e);
+        return;
+    }
+    history.items.push(item);
+
+    let content = history.prune(config);
+    if content || history.on_disk > history.items.len() + MAX_STALE {
+        if let Err(e) = history.save() {
+            log::warn!("Failed to prune clipboard history: {:#}", e);
+        }
+    }
+}
+
+/// The items kept, newest first and numbered from 1, one line each.
+pub fn list() -> Result<Vec<String>> {
+    let mut history = HISTORY.lock().unwrap();
+    history.load_pruned()?;
+
+    let lines = history
+        .items
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, item)| {
+            let direction = match item.direction {
+                Direction::Sent => "sent to",
+                Direction::Pulled => "pulled from",
+            };
+            let client = item.client.as_deref().unwrap_or("nobody");
+            let kept = if item.content.is_some() {
+                ""
+            } else {
+                " (not kept)"
+            };
+            format!(
+                "{} {} {} {}: {} bytes of {} sha256 {}{}",
+                i + 1,
+                format_age(item.time),
+                direction,
+                client,
+                item.size,
+                item.kind,
+                item.sha256,
+                kept
+            )
+        })
+        .collect();
+    Ok(lines)
+}
+
+/// Kind and contents of item `n`, counting from 1 for the newest, if they were kept.
+pub fn content(n: usize) -> Result<Option<(String, Vec<u8>)>> {
+    let mut history = HISTORY.lock().unwrap();
+    history.load_pruned()?;
+
+    let item = n
+        .checked_sub(1)
+        .and_then(|i| history.items.iter().rev().nth(i));
+    Ok(item.and_then(|item| Some((item.kind.clone(), item.content.clone()?))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(age_hours: u64, content: bool) -> Item {
+        Item {
+            time: now() - age_hours * 3600 * 1000,
+            direction: Direction::Sent,
+            client: None,
+            kind: "text/plain".to_owned(),
+            size: 5,
+            sha256: String::new(),
+            content: content.then(|| b"hello".to_vec()),
+        }
+    }
+
+    fn history(items: Vec<Item>) -> History {
+        History {
+            state_dir: None,
+            seal: None,
+            on_disk: items.len(),
+            items,
+        }
+    }
+
+    fn config(max_items: usize, content: bool) -> config::ClipboardHistoryConfig {
+        config::ClipboardHistoryConfig {
+            enabled: true,
+            content,
+            max_items,
+            max_age_hours: 24,
+        }
+    }
+
+    #[test]
+    fn prune_keeps_the_newest() {
+        let mut history = history(vec![item(3, false), item(2, false), item(1, false)]);
+        assert!(!history.prune(&config(2, false)));
+
+        let ages: Vec<_> = history.items.iter().map(|i| now() - i.time).collect();
+        assert_eq!(history.items.len(), 2);
+        assert!(ages[0] >= 2 * 3600 * 1000 && ages[1] < 2 * 3600 * 1000);
+    }
+
+    #[test]
+    fn prune_drops_old_items() {
+        let mut history = history(vec![item(48, false), item(25, false), item(1, false)]);
+        history.prune(&config(20, false));
+        assert_eq!(history.items.len(), 1);
+    }
+
+    #[test]
+    fn prune_drops_contents_no_longer_kept() {
+        let mut history = history(vec![item(1, true), item(1, false)]);
+        assert!(!history.prune(&config(20, true)));
+        assert!(history.items[0].content.is_some());
+
+        assert!(history.prune(&config(20, false)));
+        assert!(history.items.iter().all(|i| i.content.is_none()));
+        assert!(!history.prune(&config(20, false)));
+    }
+
+    #[test]
+    fn records_read_back_without_one_cut_short() {
+        let seal = ClipboardSeal::new(&ClipboardSeal::generate_key());
+        let mut data = [
+            encode(&seal, &item(2, true)).unwrap(),
+            encode(&seal, &item(1, false)).unwrap(),
+        ]
+        .concat();
+        let last = encode(&seal, &item(0, false)).unwrap();
+        data.extend(&last[..last.len() - 1]);
+
+        let items = read(&seal, &data).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].content.as_deref(), Some(&b"hello"[..]));
+
+        let other = ClipboardSeal::new(&ClipboardSeal::generate_key());
+        assert!(read(&other, &data).is_err());
+    }
+
+    #[test]
+    fn key_round_trips_as_text() {
+        let key = ClipboardSeal::generate_key();
+        assert_eq!(parse_key(&format_key(&key)), Some(key));
+        assert_eq!(parse_key("not a key"), None);
+    }
+}
@@ -0,0 +1,379 @@
+use std::time::{Duration, Instant};
+
+use keycode::KeyMappingId;
+
+use crate::config::{GestureAction, GrabMode, HotkeyConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotkeyAction {
+    Toggle,
+    Grab,
+    Ungrab,
+    NextClient,
+    PushClipboard,
+    PullClipboard,
+    SwapClipboards,
+    ToggleClipboardSync,
+    ToggleGameMode,
+    TypeSelection,
+    Attention,
+    CheatSheet,
+    SwitchBack,
+}
+
+impl From<GestureAction> for HotkeyAction {
+    fn from(action: GestureAction) -> Self {
+        match action {
+            GestureAction::ToggleGrab => HotkeyAction::Toggle,
+            GestureAction::NextClient => HotkeyAction::NextClient,
+            GestureAction::PushClipboard => HotkeyAction::PushClipboard,
+            GestureAction::PullClipboard => HotkeyAction::PullClipboard,
+            GestureAction::SwapClipboards => HotkeyAction::SwapClipboards,
+            GestureAction::ToggleClipboardSync => HotkeyAction::ToggleClipboardSync,
+            GestureAction::ToggleGameMode => HotkeyAction::ToggleGameMode,
+            GestureAction::TypeSelection => HotkeyAction::TypeSelection,
+            GestureAction::Attention => HotkeyAction::Attention,
+            GestureAction::CheatSheet => HotkeyAction::CheatSheet,
+            GestureAction::SwitchBack => HotkeyAction::SwitchBack,
+            GestureAction::ReturnLocal => HotkeyAction::Ungrab,
+        }
+    }
+}
+
+/// Turns presses and releases of the hotkey into actions according to the grab mode.
+///
+/// In toggle mode the key recognizes taps, double taps and long presses. The hold and
+/// hybrid modes use the key for grabbing only.
+pub struct Hotkey {
+    key: KeyMappingId,
+    mode: GrabMode,
+    long_press: Duration,
+    double_tap: Duration,
+    tap_action: Option<GestureAction>,
+    double_tap_action: Option<GestureAction>,
+    long_press_action: Option<GestureAction>,
+    /// When the key went down, and whether that press grabbed input
+    pressed: Option<(Instant, bool)>,
+    /// Whether the current press is the second tap of a double tap
+    second_tap: bool,
+    /// Release of a tap that may still become a double tap
+    pending_tap: Option<Instant>,
+    /// Keys bound directly to an action, besides the hotkey itself
+    shortcuts: Vec<(KeyMappingId, HotkeyAction)>,
+    pass_through: bool,
+}
+
+impl Hotkey {
+    pub fn new(config: &HotkeyConfig) -> Self {
+        Self {
+            key: config.key,
+            mode: config.mode,
+            long_press: Duration::from_millis(config.long_press_ms),
+            double_tap: Duration::from_millis(config.double_tap_ms),
+            tap_action: config.tap,
+            double_tap_action: config.double_tap,
+            long_press_action: config.long_press,
+            pressed: None,
+            second_tap: false,
+            pending_tap: None,
+            shortcuts: [
+                (config.push_clipboard_key, HotkeyAction::PushClipboard),
+                (config.pull_clipboard_key, HotkeyAction::PullClipboard),
+                (config.swap_clipboards_key, HotkeyAction::SwapClipboards),
+                (
+                    config.toggle_clipboard_sync_key,
+                    HotkeyAction::ToggleClipboardSync,
+                ),
+                (config.toggle_game_mode_key, HotkeyAction::ToggleGameMode),
+                (config.type_selection_key, HotkeyAction::TypeSelection),
+                (config.attention_key, HotkeyAction::Attention),
+                (config.cheat_sheet_key, HotkeyAction::CheatSheet),
+                (config.switch_back_key, HotkeyAction::SwitchBack),
+                (config.return_local_key, HotkeyAction::Ungrab),
+            ]
+            .into_iter()
+            .filter_map(|(key, action)| Some((key?, action)))
+            .collect(),
+            pass_through: config.pass_through,
+        }
+    }
+
+    /// The key controlling the grab.
+    pub fn key(&self) -> KeyMappingId {
+        self.key
+    }
+
+    /// Whether the hotkey is also sent to the client while grabbed, instead of kept from it.
+    pub fn passes_through(&self) -> bool {
+        self.pass_through
+    }
+
+    /// Looks up a key bound to an action of its own.
+    ///
+    /// Returns `None` for other keys, and the action, if any, for bound ones, which act
+    /// when pressed.
+    pub fn on_shortcut(&self, key: KeyMappingId, pressed: bool) -> Option<Option<HotkeyAction>> {
+        let (_, action) = self.shortcuts.iter().find(|(k, _)| *k == key)?;
+        Some(pressed.then_some(*action))
+    }
+
+    pub fn on_key(&mut self, pressed: bool, grabbed: bool) -> Option<HotkeyAction> {
+        self.on_key_at(pressed, grabbed, Instant::now())
+    }
+
+    fn on_key_at(&mut self, pressed: bool, grabbed: bool, now: Instant) -> Option<HotkeyAction> {
+        match (self.mode, pressed) {
+            (GrabMode::Toggle, _) => self.on_gesture_key(pressed, now),
+            (GrabMode::Hold, true) => Some(HotkeyAction::Grab),
+            (GrabMode::Hold, false) => Some(HotkeyAction::Ungrab),
+            (GrabMode::Hybrid, true) => {
+                self.pressed = Some((now, !grabbed));
+                (!grabbed).then_some(HotkeyAction::Grab)
+            }
+            (GrabMode::Hybrid, false) => {
+                let (pressed_at, grabbed_by_press) = self.pressed.take()?;
+                let held = now - pressed_at >= self.long_press;
+
+                match (grabbed_by_press, held) {
+                    // Momentary use ends with the release
+                    (true, true) => Some(HotkeyAction::Ungrab),
+                    // A tap that grabbed leaves the grab on
+                    (true, false) => None,
+                    // A tap while grabbed toggles off, holding keeps the grab
+                    (false, false) => Some(HotkeyAction::Ungrab),
+                    (false, true) => None,
+                }
+            }
+        }
+    }
+
+    fn on_gesture_key(&mut self, pressed: bool, now: Instant) -> Option<HotkeyAction> {
+        if pressed {
+            let pending = self.pending_tap.take();
+            self.second_tap = pending.is_some_and(|t| now - t <= self.double_tap);
+            self.pressed = Some((now, false));
+            return None;
+        }
+
+        let (pressed_at, _) = self.pressed.take()?;
+
+        // Without an action of its own, a long press is as good as a tap
+        if now - pressed_at >= self.long_press {
+            if let Some(action) = self.long_press_action {
+                return Some(action.into());
+            }
+        }
+
+        if self.second_tap {
+            return self.double_tap_action.map(Into::into);
+        }
+
+        if self.double_tap_action.is_none() {
+            return self.tap_action.map(Into::into);
+        }
+
+        // Wait to see whether a second tap follows
+        self.pending_tap = Some(now);
+        None
+    }
+
+    /// How long until [`Hotkey::expire`] may have an action, if anything is pending.
+    pub fn timeout(&self) -> Option<Duration> {
+        let pending = self.pending_tap?;
+        Some((pending + self.double_tap).saturating_duration_since(Instant::now()))
+    }
+
+    /// Resolves a pending single tap once the double-tap window has passed.
+    pub fn expire(&mut self) -> Option<HotkeyAction> {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&mut self, now: Instant) -> Option<HotkeyAction> {
+        let pending = self.pending_tap?;
+        if now - pending <= self.double_tap {
+            return None;
+        }
+
+        self.pending_tap = None;
+        self.tap_action.map(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn start(config: HotkeyConfig) -> (Hotkey, Instant) {
+        (Hotkey::new(&config), Instant::now())
+    }
+
+    fn gestures() -> HotkeyConfig {
+        HotkeyConfig {
+            tap: Some(GestureAction::ToggleGrab),
+            double_tap: Some(GestureAction::NextClient),
+            long_press: Some(GestureAction::PushClipboard),
+            ..Default::default()
+        }
+    }
+
+    fn mode(mode: GrabMode) -> HotkeyConfig {
+        HotkeyConfig {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tap_acts_on_release_without_double_tap() {
+        let (mut hotkey, t) = start(HotkeyConfig::default());
+
+        assert_eq!(hotkey.on_key_at(true, false, t), None);
+        assert_eq!(
+            hotkey.on_key_at(false, false, t + 50 * MS),
+            Some(HotkeyAction::Toggle)
+        );
+        assert_eq!(hotkey.timeout(), None);
+    }
+
+    #[test]
+    fn tap_waits_out_the_double_tap_window() {
+        let (mut hotkey, t) = start(gestures());
+
+        hotkey.on_key_at(true, false, t);
+        assert_eq!(hotkey.on_key_at(false, false, t + 50 * MS), None);
+        assert!(hotkey.timeout().is_some());
+
+        // Still within the window at its very end
+        assert_eq!(hotkey.expire_at(t + 350 * MS), None);
+        assert_eq!(hotkey.expire_at(t + 351 * MS), Some(HotkeyAction::Toggle));
+        assert_eq!(hotkey.expire_at(t + 400 * MS), None);
+    }
+
+    #[test]
+    fn double_tap() {
+        let (mut hotkey, t) = start(gestures());
+
+        hotkey.on_key_at(true, false, t);
+        hotkey.on_key_at(false, false, t + 50 * MS);
+        // Pressed again exactly when the window closes
+        assert_eq!(hotkey.on_key_at(true, false, t + 350 * MS), None);
+        assert_eq!(
+            hotkey.on_key_at(false, false, t + 400 * MS),
+            Some(HotkeyAction::NextClient)
+        );
+        assert_eq!(hotkey.expire_at(t + 1000 * MS), None);
+    }
+
+    #[test]
+    fn second_tap_too_late_is_a_tap_again() {
+        let (mut hotkey, t) = start(gestures());
+
+        hotkey.on_key_at(true, false, t);
+        hotkey.on_key_at(false, false, t + 50 * MS);
+        hotkey.on_key_at(true, false, t + 351 * MS);
+        assert_eq!(hotkey.on_key_at(false, false, t + 400 * MS), None);
+        assert_eq!(hotkey.expire_at(t + 701 * MS), Some(HotkeyAction::Toggle));
+    }
+
+    #[test]
+    fn long_press_starts_at_the_threshold() {
+        let (mut hotkey, t) = start(gestures());
+
+        hotkey.on_key_at(true, false, t);
+        assert_eq!(
+            hotkey.on_key_at(false, false, t + 300 * MS),
+            Some(HotkeyAction::PushClipboard)
+        );
+
+        // Just short of it is a tap, waiting for a second one
+        hotkey.on_key_at(true, false, t + 1000 * MS);
+        assert_eq!(hotkey.on_key_at(false, false, t + 1299 * MS), None);
+        assert_eq!(hotkey.expire_at(t + 1600 * MS), Some(HotkeyAction::Toggle));
+    }
+
+    #[test]
+    fn long_press_without_action_is_a_tap() {
+        let (mut hotkey, t) = start(HotkeyConfig::default());
+
+        hotkey.on_key_at(true, false, t);
+        assert_eq!(
+            hotkey.on_key_at(false, false, t + 500 * MS),
+            Some(HotkeyAction::Toggle)
+        );
+        assert_eq!(hotkey.timeout(), None);
+
+        let (mut hotkey, t) = start(HotkeyConfig {
+            long_press: None,
+            ..gestures()
+        });
+        hotkey.on_key_at(true, false, t);
+        assert_eq!(hotkey.on_key_at(false, false, t + 500 * MS), None);
+        assert_eq!(hotkey.expire_at(t + 851 * MS), Some(HotkeyAction::Toggle));
+    }
+
+    #[test]
+    fn release_without_press_does_nothing() {
+        let (mut hotkey, t) = start(gestures());
+        assert_eq!(hotkey.on_key_at(false, false, t), None);
+
+        let (mut hotkey, t) = start(mode(GrabMode::Hybrid));
+        assert_eq!(hotkey.on_key_at(false, true, t), None);
+    }
+
+    #[test]
+    fn hold_grabs_while_pressed() {
+        let (mut hotkey, t) = start(mode(GrabMode::Hold));
+
+        assert_eq!(hotkey.on_key_at(true, false, t), Some(HotkeyAction::Grab));
+        assert_eq!(
+            hotkey.on_key_at(false, true, t + 10 * MS),
+            Some(HotkeyAction::Ungrab)
+        );
+    }
+
+    #[test]
+    fn hybrid_tap_toggles() {
+        let (mut hotkey, t) = start(mode(GrabMode::Hybrid));
+
+        assert_eq!(hotkey.on_key_at(true, false, t), Some(HotkeyAction::Grab));
+        assert_eq!(hotkey.on_key_at(false, true, t + 299 * MS), None);
+
+        assert_eq!(hotkey.on_key_at(true, true, t + 1000 * MS), None);
+        assert_eq!(
+            hotkey.on_key_at(false, true, t + 1299 * MS),
+            Some(HotkeyAction::Ungrab)
+        );
+    }
+
+    #[test]
+    fn hybrid_hold_is_momentary() {
+        let (mut hotkey, t) = start(mode(GrabMode::Hybrid));
+
+        assert_eq!(hotkey.on_key_at(true, false, t), Some(HotkeyAction::Grab));
+        assert_eq!(
+            hotkey.on_key_at(false, true, t + 300 * MS),
+            Some(HotkeyAction::Ungrab)
+        );
+
+        // Holding while grabbed already leaves the grab on
+        assert_eq!(hotkey.on_key_at(true, true, t + 1000 * MS), None);
+        assert_eq!(hotkey.on_key_at(false, true, t + 1300 * MS), None);
+    }
+
+    #[test]
+    fn shortcuts_act_on_press() {
+        let hotkey = Hotkey::new(&HotkeyConfig {
+            push_clipboard_key: Some(KeyMappingId::F9),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            hotkey.on_shortcut(KeyMappingId::F9, true),
+            Some(Some(HotkeyAction::PushClipboard))
+        );
+        assert_eq!(hotkey.on_shortcut(KeyMappingId::F9, false), Some(None));
+        assert_eq!(hotkey.on_shortcut(KeyMappingId::F10, true), None);
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+const CERT_FILE: &str = "server.crt.der";
+const KEY_FILE: &str = "server.key.der";
+
+/// The server's TLS certificate and private key, kept across restarts so clients can pin it.
+pub struct Identity {
+    pub cert_der: Vec<u8>,
+    pub key_der: Vec<u8>,
+}
+
+impl Identity {
+    pub fn generate() -> Result<Self> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+
+        Ok(Self {
+            cert_der: cert.serialize_der()?,
+            key_der: cert.serialize_private_key_der(),
+        })
+    }
+
+    pub fn load(state_dir: &Path) -> Result<Option<Self>> {
+        let (cert_path, key_path) = paths(state_dir);
+        if !cert_path.exists() || !key_path.exists() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            cert_der: std::fs::read(&cert_path).with_context(|| format!("Read {:?}", cert_path))?,
+            key_der: std::fs::read(&key_path).with_context(|| format!("Read {:?}", key_path))?,
+        }))
+    }
+
+    pub fn save(&self, state_dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(state_dir)
+            .with_context(|| format!("Create state dir {:?}", state_dir))?;
+
+        // Both are written out before either is renamed into place, so a crash midway
+        // leaves the identity before
+        let (cert_path, key_path) = paths(state_dir);
+        let cert_temp = write_temp(&cert_path, &self.cert_der, 0o666)?;
+        let key_temp = write_temp(&key_path, &self.key_der, 0o600)?;
+        for (temp, path) in [(key_temp, key_path), (cert_temp, cert_path)] {
+            std::fs::rename(&temp, &path).with_context(|| format!("Write {:?}", path))?;
+        }
+
+        Ok(())
+    }
+
+    /// Renames the files of the identity currently on disk to `*.old`.
+    pub fn backup(&self, state_dir: &Path) -> Result<()> {
+        let (cert_path, key_path) = paths(state_dir);
+        for path in [cert_path, key_path] {
+            let mut backup = path.clone().into_os_string();
+            backup.push(".old");
+            std::fs::rename(&path, &backup).with_context(|| format!("Back up {:?}", path))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn load_or_generate(state_dir: &Path) -> Result<Self> {
+        if let Some(identity) = Self::load(state_dir)? {
+            return Ok(identity);
+        }
+
+        log::info!("Generating a new server identity in {:?}", state_dir);
+        let identity = Self::generate()?;
+        identity.save(state_dir)?;
+
+        Ok(identity)
+    }
+
+    /// SHA-256 fingerprint in the format accepted by the client's `fingerprint` option.
+    pub fn fingerprint(&self) -> String {
+        rkvm_protocol::cert_fingerprint(&self.cert_der)
+    }
+}
+
+/// Fingerprint of the certificate the client on `conn` signed its handshake with, if it
+/// presented one.
+pub fn peer_fingerprint(conn: &quinn::Connection) -> Option<String> {
+    let certs = conn
+        .peer_identity()?
+        .downcast::<Vec<rustls::Certificate>>()
+        .ok()?;
+    let cert = certs.first()?;
+    Some(rkvm_protocol::cert_fingerprint(&cert.0))
+}
+
+/// Asks clients for a certificate without requiring one or checking who issued it.
+///
+/// Clients sign with a self-signed certificate, which only proves they hold its key.
+/// Whether that key is let in is up to [`crate::approval`].
+pub struct AnyClientCert;
+
+impl rustls::server::ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn client_auth_root_subjects(&self) -> &[rustls::DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::server::ClientCertVerified, rustls::Error> {
+        Ok(rustls::server::ClientCertVerified::assertion())
+    }
+}
+
+fn paths(state_dir: &Path) -> (PathBuf, PathBuf) {
+    (state_dir.join(CERT_FILE), state_dir.join(KEY_FILE))
+}
+
+/// Writes `data` to a file next to `path` with `mode`, returning the file's path.
+fn write_temp(path: &Path, data: &[u8], mode: u32) -> Result<PathBuf> {
+    let temp = path.with_extension("der.tmp");
+    // Left by a crash, maybe with other permissions
+    let _ = std::fs::remove_file(&temp);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&temp)
+        .with_context(|| format!("Write {:?}", temp))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    Ok(temp)
+}
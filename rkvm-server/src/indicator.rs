@@ -0,0 +1,56 @@
+//! Showing on this machine's keyboard whether input goes to a client, for when nothing
+//! on screen does.
+
+use std::{fs, io, path::PathBuf, sync::Mutex};
+
+use crate::{
+    config::GrabIndicator,
+    grab::{self, LED_CAPSL, LED_NUML, LED_SCROLLL},
+};
+
+/// Keyboard backlights turned up, with the brightness they had before
+static SAVED_BRIGHTNESS: Mutex<Vec<(PathBuf, String)>> = Mutex::new(Vec::new());
+
+/// Lights `indicator` up while `grabbed`.
+pub fn show(indicator: Option<GrabIndicator>, grabbed: bool) {
+    let led = match indicator {
+        None => return,
+        Some(GrabIndicator::ScrollLock) => LED_SCROLLL,
+        Some(GrabIndicator::CapsLock) => LED_CAPSL,
+        Some(GrabIndicator::NumLock) => LED_NUML,
+        Some(GrabIndicator::Backlight) => {
+            if let Err(e) = backlight(grabbed) {
+                log::warn!("Failed to set keyboard backlight: {}", e);
+            }
+            return;
+        }
+    };
+
+    grab::set_led(led, grabbed);
+}
+
+/// Turns every keyboard backlight all the way up, or back to where it was.
+fn backlight(on: bool) -> io::Result<()> {
+    let mut saved = SAVED_BRIGHTNESS.lock().unwrap();
+
+    if !on {
+        for (path, brightness) in saved.drain(..) {
+            fs::write(path.join("brightness"), brightness)?;
+        }
+        return Ok(());
+    }
+
+    for entry in fs::read_dir("/sys/class/leds")? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str());
+        if !name.is_some_and(|name| name.ends_with("::kbd_backlight")) {
+            continue;
+        }
+
+        let brightness = fs::read_to_string(path.join("brightness"))?;
+        let max = fs::read_to_string(path.join("max_brightness"))?;
+        fs::write(path.join("brightness"), max.trim())?;
+        saved.push((path, brightness.trim().to_owned()));
+    }
+    Ok(())
+}
@@ -0,0 +1,25 @@
+use zbus::zvariant::OwnedFd;
+
+/// Takes a logind inhibitor lock that keeps the machine from idling or suspending.
+///
+/// The lock is held for as long as the returned descriptor stays open.
+pub async fn inhibit_idle() -> zbus::Result<OwnedFd> {
+    let conn = zbus::Connection::system().await?;
+
+    let reply = conn
+        .call_method(
+            Some("org.freedesktop.login1"),
+            "/org/freedesktop/login1",
+            Some("org.freedesktop.login1.Manager"),
+            "Inhibit",
+            &(
+                "idle:sleep",
+                "rkvm",
+                "Input is being forwarded to a client",
+                "block",
+            ),
+        )
+        .await?;
+
+    reply.body::<OwnedFd>()
+}
@@ -0,0 +1,332 @@
+//! Clients seen before, kept in the state dir across restarts: their numbered slots, the
+//! names they are shown by, the fingerprints of their certificates and whether they were
+//! revoked.
+//!
+//! A client seen for the first time gets the lowest free slot, starting from 1, so a key
+//! switching to slot 2 goes to the same machine whichever connects first. Slots are never
+//! given to another client. The stable id is only what a client claims to be, so it only
+//! counts as known while it presents the certificate it was let in with. A revoked client is
+//! turned away until it is removed from `known-clients.json`.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use rkvm_protocol::Uuid;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KnownClient {
+    slot: usize,
+    /// Name from the client's last hello, until it was renamed
+    name: String,
+    /// Name given here, shown and matched instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rename: Option<String>,
+    /// Fingerprint of the certificate it was last let in with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<String>,
+    #[serde(default)]
+    revoked: bool,
+}
+
+impl KnownClient {
+    fn name(&self) -> &str {
+        self.rename.as_deref().unwrap_or(&self.name)
+    }
+}
+
+struct Known {
+    /// Where clients are kept, unset until loaded so nothing is written before
+    path: Option<PathBuf>,
+    /// By stable id
+    clients: BTreeMap<String, KnownClient>,
+}
+
+impl Known {
+    /// Stable id of the client known by `key`, its stable id, name or fingerprint. A name or
+    /// fingerprint several clients share finds none, as only the stable id tells them apart.
+    fn find(&self, key: &str) -> Option<String> {
+        if self.clients.contains_key(key) {
+            return Some(key.to_owned());
+        }
+        let mut matching = self.clients.iter().filter(|(_, client)| {
+            client.name() == key || client.fingerprint.as_deref() == Some(key)
+        });
+        let (uuid, _) = matching.next()?;
+        if matching.next().is_some() {
+            log::warn!("Several known clients go by {}, use a stable id", key);
+            return None;
+        }
+        Some(uuid.clone())
+    }
+
+    /// See [`assign`].
+    fn assign(&mut self, uuid: Uuid, name: &str, fingerprint: Option<&str>) -> usize {
+        let key = uuid.to_string();
+        if let Some(client) = self.clients.get_mut(&key) {
+            let slot = client.slot;
+            let mut changed = false;
+            if client.rename.is_none() && client.name != name {
+                client.name = name.to_owned();
+                changed = true;
+            }
+            if fingerprint.is_some() && client.fingerprint.as_deref() != fingerprint {
+                client.fingerprint = fingerprint.map(str::to_owned);
+                changed = true;
+            }
+            if changed {
+                self.save();
+            }
+            return slot;
+        }
+
+        let slot = (1..)
+            .find(|slot| !self.clients.values().any(|c| c.slot == *slot))
+            .unwrap();
+        let client = KnownClient {
+            slot,
+            name: name.to_owned(),
+            rename: None,
+            fingerprint: fingerprint.map(str::to_owned),
+            revoked: false,
+        };
+        self.clients.insert(key, client);
+        log::info!("Client {} gets slot {}", uuid, slot);
+        self.save();
+        slot
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = write(path, &self.clients) {
+                log::warn!("Failed to keep known clients: {:#}", e);
+            }
+        }
+    }
+}
+
+static KNOWN: Mutex<Known> = Mutex::new(Known {
+    path: None,
+    clients: BTreeMap::new(),
+});
+
+/// Loads the clients kept in `state_dir`.
+pub fn init(state_dir: &Path) {
+    let path = state_dir.join("known-clients.json");
+    let mut clients = match std::fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            log::warn!("Ignoring known clients in {:?}: {}", path, e);
+            BTreeMap::new()
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => {
+            log::warn!("Failed to read known clients from {:?}: {}", path, e);
+            BTreeMap::new()
+        }
+    };
+
+    // Kept by earlier versions, which only knew slots
+    let slots = state_dir.join("slots.json");
+    let migrate = clients.is_empty() && slots.exists();
+    if migrate {
+        let slots: BTreeMap<String, usize> = std::fs::read(&slots)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        for (uuid, slot) in slots {
+            let client = KnownClient {
+                slot,
+                name: uuid.clone(),
+                rename: None,
+                fingerprint: None,
+                revoked: false,
+            };
+            clients.insert(uuid, client);
+        }
+    }
+
+    let known = Known {
+        path: Some(path),
+        clients,
+    };
+    if migrate {
+        known.save();
+    }
+    *KNOWN.lock().unwrap() = known;
+}
+
+/// The slot of the client with stable id `uuid`, now called `name` and presenting the
+/// certificate with `fingerprint`, giving it the lowest free one if it has none yet.
+pub fn assign(uuid: Uuid, name: &str, fingerprint: Option<&str>) -> usize {
+    KNOWN.lock().unwrap().assign(uuid, name, fingerprint)
+}
+
+/// Whether the client with stable id `uuid` was let in before with the certificate with
+/// `fingerprint`.
+///
+/// Clients kept before certificates were asked for have to be let in once more.
+pub fn is_approved(uuid: Uuid, fingerprint: &str) -> bool {
+    let known = KNOWN.lock().unwrap();
+    known
+        .clients
+        .get(&uuid.to_string())
+        .is_some_and(|c| c.fingerprint.as_deref() == Some(fingerprint))
+}
+
+/// Stable id of the client in `slot`, if any was given it.
+pub fn client(slot: usize) -> Option<String> {
+    let known = KNOWN.lock().unwrap();
+    known
+        .clients
+        .iter()
+        .find(|(_, client)| client.slot == slot)
+        .map(|(uuid, _)| uuid.clone())
+}
+
+/// Whether the client with stable id `uuid` or the certificate with `fingerprint` was
+/// revoked.
+///
+/// A revoked machine stays revoked under a new id as long as it keeps its certificate.
+pub fn is_revoked(uuid: Uuid, fingerprint: Option<&str>) -> bool {
+    let known = KNOWN.lock().unwrap();
+    let uuid = uuid.to_string();
+    known.clients.iter().any(|(id, c)| {
+        c.revoked
+            && (*id == uuid || fingerprint.is_some() && c.fingerprint.as_deref() == fingerprint)
+    })
+}
+
+/// The name the client with stable id `uuid` was given here, if it was renamed.
+pub fn rename_of(uuid: Uuid) -> Option<String> {
+    let known = KNOWN.lock().unwrap();
+    known.clients.get(&uuid.to_string())?.rename.clone()
+}
+
+/// A known client as listed.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Listed {
+    pub slot: usize,
+    pub uuid: String,
+    pub name: String,
+    pub fingerprint: Option<String>,
+    pub revoked: bool,
+}
+
+/// Known clients by slot.
+pub fn list() -> Vec<Listed> {
+    let known = KNOWN.lock().unwrap();
+    let mut list: Vec<_> = known
+        .clients
+        .iter()
+        .map(|(uuid, c)| Listed {
+            slot: c.slot,
+            uuid: uuid.clone(),
+            name: c.name().to_owned(),
+            fingerprint: c.fingerprint.clone(),
+            revoked: c.revoked,
+        })
+        .collect();
+    list.sort();
+    list
+}
+
+/// Turns away the client known by `key`, its stable id, name or fingerprint, from now on,
+/// and its certificate under any other id.
+///
+/// Returns its stable id, or `None` if no such client is known.
+pub fn revoke(key: &str) -> Option<String> {
+    let mut known = KNOWN.lock().unwrap();
+    let uuid = known.find(key)?;
+    known.clients.get_mut(&uuid)?.revoked = true;
+    known.save();
+    log::warn!("Revoked client {}", uuid);
+    Some(uuid)
+}
+
+/// Shows and matches the client known by `key`, its stable id, name or fingerprint, as
+/// `name`.
+///
+/// Returns its stable id, or `None` if no such client is known.
+pub fn rename(key: &str, name: &str) -> Option<String> {
+    let mut known = KNOWN.lock().unwrap();
+    let uuid = known.find(key)?;
+    known.clients.get_mut(&uuid)?.rename = Some(name.to_owned());
+    known.save();
+    log::info!("Renamed client {} to {}", uuid, name);
+    Some(uuid)
+}
+
+fn write(path: &Path, clients: &BTreeMap<String, KnownClient>) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("Create state dir {:?}", dir))?;
+    }
+
+    // Renamed into place, so a crash midway leaves the previous clients
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, serde_json::to_vec_pretty(clients)?)
+        .with_context(|| format!("Write {:?}", temp))?;
+    std::fs::rename(&temp, path).with_context(|| format!("Write {:?}", path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn known() -> Known {
+        Known {
+            path: None,
+            clients: BTreeMap::new(),
+        }
+    }
+
+    fn uuid(n: u128) -> Uuid {
+        Uuid::from_u128(n)
+    }
+
+    #[test]
+    fn new_clients_get_the_lowest_free_slot() {
+        let mut known = known();
+        assert_eq!(known.assign(uuid(1), "laptop", None), 1);
+        assert_eq!(known.assign(uuid(2), "htpc", None), 2);
+        assert_eq!(known.assign(uuid(3), "desktop", None), 3);
+    }
+
+    #[test]
+    fn slots_are_kept_on_reconnect() {
+        let mut known = known();
+        known.assign(uuid(1), "laptop", None);
+        known.assign(uuid(2), "htpc", None);
+
+        assert_eq!(known.assign(uuid(2), "htpc", None), 2);
+        assert_eq!(known.assign(uuid(1), "renamed", None), 1);
+    }
+
+    #[test]
+    fn gaps_are_filled_first() {
+        let mut known = known();
+        for n in 1..=3 {
+            known.assign(uuid(n), "client", None);
+        }
+        known.clients.remove(&uuid(2).to_string());
+
+        assert_eq!(known.assign(uuid(4), "new", None), 2);
+        assert_eq!(known.assign(uuid(5), "newer", None), 4);
+    }
+
+    #[test]
+    fn shared_names_find_no_one() {
+        let mut known = known();
+        known.assign(uuid(1), "laptop", None);
+        known.assign(uuid(2), "laptop", None);
+        known.assign(uuid(3), "htpc", None);
+
+        assert_eq!(known.find("laptop"), None);
+        assert_eq!(known.find("htpc"), Some(uuid(3).to_string()));
+        assert_eq!(known.find(&uuid(1).to_string()), Some(uuid(1).to_string()));
+    }
+}
@@ -0,0 +1,115 @@
+//! Which messages get logged, by module, like `rkvm_server::grab=debug,quinn=warn,info`.
+//!
+//! The filter comes from `--log`, then `--verbose` as `trace`, then `RUST_LOG`, then the
+//! `log` option, and is `info` otherwise. The config's only applies if none of the first
+//! three is set, and follows reloads. The control socket's `log <filter>` changes it
+//! until the next reload. An invalid `--log` or `RUST_LOG` is warned about and skipped.
+
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use anyhow::{Context, Result};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{
+    filter::Targets, layer::SubscriberExt, reload, util::SubscriberInitExt, Registry,
+};
+
+use crate::config::Config;
+
+static HANDLE: OnceLock<reload::Handle<Targets, Registry>> = OnceLock::new();
+
+/// Whether the filter came from the command line or the environment
+static FIXED: AtomicBool = AtomicBool::new(false);
+
+/// The fallback when nothing else sets a filter
+const DEFAULT: &str = "info";
+
+/// The filter in effect, as it was given
+static CURRENT: Mutex<String> = Mutex::new(String::new());
+
+fn parse(filter: &str) -> Result<Targets> {
+    Targets::from_str(filter).with_context(|| format!("Invalid log filter {:?}", filter))
+}
+
+/// Starts logging with the filter from `cli`, `verbose` or `RUST_LOG`, or the default if
+/// none is set or valid.
+pub fn init(cli: Option<&str>, verbose: bool) -> Result<()> {
+    let env = std::env::var("RUST_LOG").ok().filter(|f| !f.is_empty());
+    let fixed = cli
+        .or(verbose.then_some("trace"))
+        .map(str::to_owned)
+        .or(env);
+
+    // A filter we can't read shouldn't keep the server from starting, so it is left to the
+    // config or the default
+    let (filter, targets, invalid) = match fixed.as_deref().map(|f| (f, parse(f))) {
+        Some((filter, Ok(targets))) => (filter, targets, None),
+        Some((_, Err(e))) => (DEFAULT, parse(DEFAULT)?, Some(e)),
+        None => (DEFAULT, parse(DEFAULT)?, None),
+    };
+    FIXED.store(fixed.is_some() && invalid.is_none(), Ordering::Relaxed);
+    *CURRENT.lock().unwrap() = filter.to_owned();
+    let (layer, handle) = reload::Layer::new(targets);
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    let _ = HANDLE.set(handle);
+    follow_max_level();
+
+    if let Some(e) = invalid {
+        log::warn!("{:#}, logging {} instead", e, filter);
+    }
+    Ok(())
+}
+
+/// Logs with `filter` from now on.
+pub fn set(filter: &str) -> Result<()> {
+    let mut current = CURRENT.lock().unwrap();
+    if *current == filter {
+        return Ok(());
+    }
+
+    let targets = parse(filter)?;
+    HANDLE
+        .get()
+        .context("Logging isn't set up")?
+        .reload(targets)
+        .context("Change log filter")?;
+    follow_max_level();
+    *current = filter.to_owned();
+    drop(current);
+
+    log::info!("Logging {}", filter);
+    Ok(())
+}
+
+/// Logs with the filter of `config`, unless one was given on the command line or in the
+/// environment.
+pub fn apply(config: &Config) {
+    if FIXED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Err(e) = set(config.log.as_deref().unwrap_or(DEFAULT)) {
+        log::error!("{:#}", e);
+    }
+}
+
+/// Lets through the `log` records the new filter may want, or drops them early if not.
+fn follow_max_level() {
+    let level = match LevelFilter::current() {
+        LevelFilter::OFF => log::LevelFilter::Off,
+        LevelFilter::ERROR => log::LevelFilter::Error,
+        LevelFilter::WARN => log::LevelFilter::Warn,
+        LevelFilter::INFO => log::LevelFilter::Info,
+        LevelFilter::DEBUG => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    log::set_max_level(level);
+}
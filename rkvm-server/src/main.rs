@@ -1,34 +1,87 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
+use evdev::Key;
 use input::event::keyboard::KeyboardEventTrait;
 use input::event::pointer::{Axis, PointerScrollEvent};
-use input::event::tablet_pad::KeyState;
+use input::event::switch::{Switch, SwitchEvent, SwitchState};
+use input::event::tablet_pad::{ButtonState, KeyState, TabletPadEvent};
 use input::event::EventTrait;
-use input::{Libinput, LibinputInterface};
-use keycode::{KeyMap, KeyMappingId};
+use input::LibinputInterface;
+use keycode::KeyMap;
 use nix::poll::{PollFd, PollFlags};
 use rkvm_protocol::Packet;
 use std::fs::{File, OpenOptions};
-use std::hash::{Hash, Hasher};
+use std::io::Write;
 use std::os::fd::AsRawFd;
 use std::os::unix::{fs::OpenOptionsExt, io::OwnedFd};
-use std::path::Path;
-use std::sync::atomic::AtomicU64;
+use std::path::{Path, PathBuf};
+
+use clipboard::ClipboardHandle;
+use controller::Controller;
+use filter::KeyFilter;
+use hotkey::Hotkey;
+use plugins::Plugins;
+use restore::Restore;
+use rules::KeyRules;
+use shortcuts::ShortcutTranslator;
 
 use libc::{O_RDONLY, O_RDWR, O_WRONLY};
 
 #[derive(Debug, Hash)]
 pub enum ClipboardType {
-    PngImage(Vec<u8>),
+    /// Encoded in one of [`clipboard::IMAGE_TYPES`]
+    Image(Vec<u8>),
     Utf8Text(String),
-    HtmlText { html: String, plain: String },
+    HtmlText {
+        html: String,
+        plain: String,
+    },
 }
 
+mod approval;
+mod attention;
+mod audit;
+mod barriers;
+mod cert;
+mod cheatsheet;
+mod clients;
+mod clipboard;
+mod config;
+mod control;
+mod controller;
+mod cursor;
+mod delivery;
+mod devices;
+mod dnd;
+mod files;
+mod filter;
+mod gamepad;
 mod grab;
+mod history;
+mod hotkey;
+mod identity;
+mod indicator;
+mod inhibit;
+mod known;
+mod logging;
+mod notify;
+mod pair;
+mod park;
+mod plugins;
+mod quality;
+mod restore;
+mod rules;
+mod scheduling;
+mod script;
 mod server;
+mod session;
+mod shortcuts;
+mod sound;
+mod stats;
+mod uinput;
+mod wake;
 mod wayland;
 mod xclip;
-
-static CLIPBOARD_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+mod xkb;
 
 struct Interface;
 
@@ -48,6 +101,11 @@ impl LibinputInterface for Interface {
                 err.raw_os_error().unwrap_or_default()
             })?;
 
+        if grab::is_rkvm_device(fd.as_raw_fd()) {
+            log::info!("Ignoring rkvm virtual device {:?}", path);
+            return Err(libc::ENODEV);
+        }
+
         grab::add_device(path, fd.as_raw_fd());
 
         Ok(fd)
@@ -59,76 +117,6 @@ impl LibinputInterface for Interface {
     }
 }
 
-async fn get_clipboard_content(
-    event_tx: tokio::sync::mpsc::Sender<Packet>,
-    mode: ClipboardMode,
-) -> anyhow::Result<()> {
-    let content = match mode {
-        ClipboardMode::X11 => {
-            let timestamp = xclip::get_xclip_timestamp().await?;
-            if let Some(ts) = timestamp {
-                if CLIPBOARD_TIMESTAMP.load(std::sync::atomic::Ordering::Relaxed) == ts {
-                    return Ok(());
-                }
-                CLIPBOARD_TIMESTAMP.store(ts, std::sync::atomic::Ordering::Relaxed);
-            }
-
-            if let Some(c) = xclip::get_xclip_clipboard().await? {
-                c
-            } else {
-                return Ok(());
-            }
-        }
-        ClipboardMode::Wayland => {
-            let content = if let Some(c) = wayland::get_wayland_clipboard().await? {
-                c
-            } else {
-                return Ok(());
-            };
-
-            let mut hasher = std::collections::hash_map::DefaultHasher::new();
-            content.hash(&mut hasher);
-            let hash = hasher.finish();
-
-            if CLIPBOARD_TIMESTAMP.load(std::sync::atomic::Ordering::Relaxed) == hash {
-                return Ok(());
-            }
-            CLIPBOARD_TIMESTAMP.store(hash, std::sync::atomic::Ordering::Relaxed);
-
-            content
-        }
-    };
-
-    match content {
-        ClipboardType::PngImage(img) => {
-            let _ = event_tx
-                .send(Packet {
-                    id: 0,
-                    event: rkvm_protocol::Event::ImageClipboard { png: img },
-                })
-                .await;
-        }
-        ClipboardType::Utf8Text(text) => {
-            let _ = event_tx
-                .send(Packet {
-                    id: 0,
-                    event: rkvm_protocol::Event::TextClipboard { content: text },
-                })
-                .await;
-        }
-        ClipboardType::HtmlText { html, plain } => {
-            let _ = event_tx
-                .send(Packet {
-                    id: 0,
-                    event: rkvm_protocol::Event::HtmlClipboard { html, plain },
-                })
-                .await;
-        }
-    }
-
-    Ok(())
-}
-
 #[derive(Debug, Clone, Copy, ValueEnum)]
 enum ClipboardMode {
     X11,
@@ -138,41 +126,298 @@ enum ClipboardMode {
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Enable verbose logging
+    /// Enable verbose logging, short for `--log trace`
     #[arg(short, long)]
     verbose: bool,
 
+    /// What gets logged by module, like `info,rkvm_server::grab=debug,quinn=warn`, taking
+    /// precedence over RUST_LOG and the config
+    #[arg(long)]
+    log: Option<String>,
+
     #[arg(short, long)]
     clipboard_mode: Option<ClipboardMode>,
+
+    /// Path to configuration file
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// udev seat to read input devices from, unless `devices` are listed in the config
+    #[arg(long, default_value = "seat0")]
+    seat: String,
+
+    /// Log input and what would be sent for it, without grabbing devices or serving clients.
+    /// Motion is only logged with --verbose
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Grab again for the client input went to when the server last stopped, like after a
+    /// crash or upgrade
+    #[arg(long)]
+    restore: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print a pairing code (QR and URI) that clients can use to add this server
+    Pair {
+        /// Address clients should connect to
+        address: String,
+        /// Also save the QR code as a PNG image
+        #[arg(long)]
+        png: Option<PathBuf>,
+        /// Include the pre-shared key in the pairing code
+        #[arg(long)]
+        with_psk: bool,
+    },
+    /// Manage the persistent server identity
+    Cert {
+        #[command(subcommand)]
+        command: cert::CertCommand,
+    },
+    /// Make the running server read its config file again, like SIGHUP does
+    Reload,
+    /// List the clients waiting to be approved, see `approve_new_clients`
+    Pending,
+    /// Let in a client waiting to be approved
+    Approve {
+        /// Name, id, IP address or fingerprint of the client
+        client: String,
+    },
+    /// Turn away a client waiting to be approved
+    Reject {
+        /// Name, id, IP address or fingerprint of the client
+        client: String,
+    },
+    /// List the clients seen before, with their slots
+    Known,
+    /// Turn away a client seen before and its certificate from now on, disconnecting it.
+    /// Needs approve_new_clients, or it could come back under a new id
+    Revoke {
+        /// Name, id or fingerprint of the client
+        client: String,
+    },
+    /// Show and match a client seen before by another name, from its next connection
+    Rename {
+        /// Name, id or fingerprint of the client
+        client: String,
+        name: String,
+    },
+    /// Close the connection of a client
+    Disconnect {
+        /// Name, id or IP address of the client
+        client: String,
+    },
+    /// Change what the running server logs until the config is reloaded
+    Log {
+        /// By module and level, like `info,rkvm_server::grab=debug`
+        filter: String,
+    },
+    /// List the clipboard history, newest first, see `clipboard_history`
+    History,
+    /// Put an item of the clipboard history back on the clipboard and send it on
+    RestoreClipboard {
+        /// Number of the item, 1 for the newest
+        item: usize,
+    },
+    /// List the input devices read and whether they are captured
+    Devices,
+    /// Print a config file with every option at its default, explained
+    GenerateConfig {
+        /// Write it to this file instead, which must not exist yet
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    let mut logger_builder = tracing_subscriber::fmt::SubscriberBuilder::default();
-    if args.verbose {
-        logger_builder = logger_builder.with_max_level(tracing::Level::TRACE);
-    } else {
-        logger_builder = logger_builder.with_max_level(tracing::Level::INFO);
+    logging::init(args.log.as_deref(), args.verbose)?;
+
+    let config = match &args.config {
+        Some(path) => config::Config::load(path)?,
+        None => config::Config::default(),
+    };
+
+    if let Some(command) = args.command {
+        return match command {
+            Command::Pair {
+                address,
+                png,
+                with_psk,
+            } => pair::run(&config, address, png.as_deref(), with_psk),
+            Command::Cert { command } => cert::run(&config, command),
+            Command::Reload => {
+                control::request(&config.control_socket_path(), "reload")?;
+                println!("Reloaded config");
+                Ok(())
+            }
+            Command::Pending | Command::Known | Command::History => {
+                let command = match command {
+                    Command::Pending => "pending",
+                    Command::Known => "known",
+                    _ => "history",
+                };
+                let reply = control::request(&config.control_socket_path(), command)?;
+                let lines: Vec<String> = serde_json::from_str(reply.trim_start_matches("ok"))
+                    .map_err(|e| anyhow::anyhow!("Unexpected reply from the server: {}", e))?;
+                for line in lines {
+                    println!("{}", line);
+                }
+                Ok(())
+            }
+            Command::Approve { client } => {
+                let command = format!("approve {}", client);
+                control::request(&config.control_socket_path(), &command)?;
+                println!("Approved {}", client);
+                Ok(())
+            }
+            Command::Reject { client } => {
+                let command = format!("reject {}", client);
+                control::request(&config.control_socket_path(), &command)?;
+                println!("Rejected {}", client);
+                Ok(())
+            }
+            Command::Revoke { client } => {
+                let command = format!("revoke {}", client);
+                control::request(&config.control_socket_path(), &command)?;
+                println!("Revoked {}", client);
+                Ok(())
+            }
+            Command::Rename { client, name } => {
+                let command = format!("rename {}", serde_json::to_string(&(&client, &name))?);
+                control::request(&config.control_socket_path(), &command)?;
+                println!("Renamed {} to {}", client, name);
+                Ok(())
+            }
+            Command::Disconnect { client } => {
+                let command = format!("disconnect {}", client);
+                control::request(&config.control_socket_path(), &command)?;
+                println!("Disconnected {}", client);
+                Ok(())
+            }
+            Command::Log { filter } => {
+                control::request(&config.control_socket_path(), &format!("log {}", filter))?;
+                println!("Logging {}", filter);
+                Ok(())
+            }
+            Command::RestoreClipboard { item } => {
+                let command = format!("restore-clipboard {}", item);
+                control::request(&config.control_socket_path(), &command)?;
+                println!("Put item {} back on the clipboard", item);
+                Ok(())
+            }
+            Command::Devices => devices::run(&config, &args.seat),
+            Command::GenerateConfig { output } => match output {
+                Some(path) => {
+                    let mut file = OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(&path)?;
+                    file.write_all(config::sample().as_bytes())?;
+                    Ok(())
+                }
+                None => {
+                    print!("{}", config::sample());
+                    Ok(())
+                }
+            },
+        };
+    }
+
+    if let Some(path) = &config.audit_log {
+        audit::init(path)?;
     }
-    logger_builder.init();
 
-    let mut grabbed = false;
+    let identity = identity::Identity::load_or_generate(&config.state_dir)?;
+    log::info!("Server fingerprint: {}", identity.fingerprint());
+
+    let config = config::init(args.config, config);
+    logging::apply(&config);
 
-    let (event_tx, event_rx) = tokio::sync::mpsc::channel::<Packet>(128);
+    let queue = config.channels.queue.max(1);
+    let (event_tx, event_rx) = tokio::sync::mpsc::channel::<Packet>(queue);
 
     let tokio_rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()?;
-    tokio_rt.spawn(async move { server::sender(event_rx).await });
-    tokio_rt.spawn(async move {
-        if let Err(e) = server::server().await {
-            log::error!("Error running server: {}", e);
+    let dry_run = args.dry_run;
+    let plugins = Plugins::load(&config.filters);
+    if dry_run {
+        log::info!("Dry run, no devices are grabbed and no clients served");
+        grab::set_dry_run();
+        tokio_rt.spawn(async move { server::log_only(event_rx, plugins).await });
+    } else {
+        tokio_rt.spawn(async move { server::sender(event_rx, plugins).await });
+    }
+    let clipboard = args
+        .clipboard_mode
+        .map(|mode| ClipboardHandle::start(tokio_rt.handle(), mode, event_tx.clone()));
+
+    let server_clipboard = clipboard.clone();
+    if !dry_run {
+        tokio_rt.spawn(async move {
+            if let Err(e) = server::server(identity, server_clipboard).await {
+                log::error!("Error running server: {}", e);
+            }
+        });
+    }
+
+    tokio_rt.spawn(async {
+        if let Err(e) = control::handle_signals().await {
+            log::error!("Error handling signals: {}", e);
         }
     });
+    // Would take the socket of a server running alongside
+    if !dry_run {
+        let control_socket = config.control_socket_path();
+        let control_clipboard = clipboard.clone();
+        let control_event_tx = event_tx.clone();
+        tokio_rt.spawn(async move {
+            let serve = control::serve(&control_socket, control_clipboard, control_event_tx);
+            if let Err(e) = serve.await {
+                log::error!("Error serving control socket: {}", e);
+            }
+        });
 
-    let mut libinput = Libinput::new_with_udev(Interface);
-    libinput.udev_assign_seat("seat0").unwrap();
+        // Gamepads are grabbed by evdev directly
+        gamepad::start(event_tx.clone());
+        barriers::start();
+        xkb::start(event_tx.clone());
+    }
+
+    // A dry run's pretend grabs would overwrite the real ones
+    let mut restore = None;
+    if !dry_run {
+        if args.restore {
+            restore = Restore::load(&config.state_dir);
+        }
+        restore::init(&config.state_dir);
+    }
+    known::init(&config.state_dir);
+    history::init(&config.state_dir);
+
+    // Before anything can grab, so nothing returns or panics with the devices still grabbed
+    let _release = grab::release_guard();
+    let mut controller = Controller::new(
+        config.clone(),
+        tokio_rt.handle().clone(),
+        clipboard,
+        event_tx.clone(),
+    );
+    let mut hotkey = Hotkey::new(&config.hotkey);
+    let mut key_filter = KeyFilter::new(&config.hotkey);
+    let mut shortcuts = ShortcutTranslator::new(config.clone());
+    let mut key_rules = KeyRules::new(config.clone());
+    let mut config_generation = config::generation();
+
+    let mut libinput = devices::open(Interface, &args.seat, &config.devices)?;
+    // Only this thread reads input, the runtime's threads are already running
+    scheduling::apply(&config.input_thread);
 
     let mut packet_id = 0;
 
@@ -181,10 +426,79 @@ fn main() -> anyhow::Result<()> {
     let mut wheel_dx = 0;
     let mut wheel_dy = 0;
 
-    let pollfd = PollFd::new(libinput.as_raw_fd(), PollFlags::POLLIN);
+    session::start(tokio_rt.handle(), &args.seat);
+    // Whether to grab again once the session is back in front
+    let mut regrab = false;
+
+    let mut pollfds = [
+        PollFd::new(libinput.as_raw_fd(), PollFlags::POLLIN),
+        PollFd::new(
+            wake::fd().map_err(|e| anyhow::anyhow!("Create main loop waker: {}", e))?,
+            PollFlags::POLLIN,
+        ),
+    ];
 
     loop {
-        nix::poll::poll(&mut [pollfd], -1)?;
+        let timeout = [
+            hotkey.timeout(),
+            controller.idle_timeout(),
+            restore.as_ref().map(Restore::timeout),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .map(|t| (t.as_millis().min(i32::MAX as u128) as i32).saturating_add(1))
+        .unwrap_or(-1);
+        match nix::poll::poll(&mut pollfds, timeout) {
+            // SIGHUP may interrupt us, and is handled elsewhere
+            Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+            Err(e) => return Err(e.into()),
+        }
+        wake::drain();
+
+        if config_generation != config::generation() {
+            config_generation = config::generation();
+
+            let config = config::current();
+            hotkey = Hotkey::new(&config.hotkey);
+            key_filter.set_config(&config.hotkey);
+            shortcuts.set_config(config.clone());
+            key_rules.set_config(config.clone());
+            controller.set_config(config);
+        }
+
+        if let Some(action) = hotkey.expire() {
+            controller.handle(action);
+        }
+        controller.expire_idle();
+        if restore.as_ref().is_some_and(|r| r.attempt(&mut controller)) {
+            restore = None;
+        }
+        // Pushing against an edge moves the pointer, so this is seen without waiting
+        if let Some(client) = barriers::take_switch() {
+            controller.switch_to(&client);
+        }
+        rules::run_pending(&mut controller);
+
+        match session::take_change() {
+            Some(false) => {
+                regrab = controller.is_grabbed();
+                controller.ungrab();
+                // Input belongs to the session in front now
+                libinput.suspend();
+                log::info!("Let go of input devices while another session is active");
+            }
+            Some(true) => {
+                if libinput.resume().is_err() {
+                    log::error!("Failed to reopen input devices");
+                }
+                if std::mem::take(&mut regrab) {
+                    controller.grab();
+                }
+            }
+            None => {}
+        }
+
         libinput.dispatch()?;
 
         for event in &mut libinput {
@@ -205,10 +519,17 @@ fn main() -> anyhow::Result<()> {
                 }
                 input::Event::Keyboard(ev) => {
                     // let time = ev.time();
+                    grab::typed_on(ev.device().sysname());
+                    // Ungrabbed, keys only matter to the hotkey, which is always known.
+                    // Grabbed, every key is translated and sent
+                    let grabbed = controller.is_grabbed();
+
                     let key: u16 = match ev.key().try_into() {
                         Ok(key) => key,
                         Err(_) => {
-                            log::warn!("Unknown key that exceeds u16: {}", ev.key());
+                            if grabbed {
+                                log::warn!("Unknown key that exceeds u16: {}", ev.key());
+                            }
                             continue;
                         }
                     };
@@ -217,46 +538,87 @@ fn main() -> anyhow::Result<()> {
                     let keymap = match KeyMap::from_key_mapping(keycode::KeyMapping::Evdev(key)) {
                         Ok(keymap) => keymap,
                         Err(_) => {
-                            log::warn!("Unknown key: {}", key);
+                            if grabbed {
+                                log::warn!("Unknown key: {}", key);
+                            }
                             continue;
                         }
                     };
 
-                    if keymap.id == KeyMappingId::ControlRight {
-                        if state == KeyState::Released {
-                            if grabbed {
-                                grab::grab_devices(false);
-                                grabbed = false;
-                                log::info!("Ungrabbed all devices");
-                            } else {
-                                grab::grab_devices(true);
-                                grabbed = true;
-                                log::info!("Grabbed all devices");
-
-                                if let Some(mode) = args.clipboard_mode {
-                                    // Send clipboard to client
-                                    let event_tx = event_tx.clone();
-                                    tokio_rt.spawn(async move {
-                                        if let Err(e) = get_clipboard_content(event_tx, mode).await
-                                        {
-                                            log::error!("Failed to send clipboard: {}", e);
-                                        }
-                                    });
-                                }
-                            }
+                    let pressed = state == KeyState::Pressed;
+                    if dry_run {
+                        log::info!(
+                            "Key {} {}: {:?}, scan code {:#06x}",
+                            key,
+                            if pressed { "pressed" } else { "released" },
+                            keymap.id,
+                            keymap.win
+                        );
+                    }
+
+                    if let Some(action) = hotkey.on_shortcut(keymap.id, pressed) {
+                        if let Some(action) = action {
+                            controller.handle(action);
                         }
 
-                        // Ignore this key
+                        // Bound keys are ours
+                        if dry_run {
+                            log::info!("Key {:?} is bound to an action, not sent", keymap.id);
+                        }
                         continue;
                     }
 
-                    event_to_send = Some(rkvm_protocol::Event::Keyboard {
-                        key: keymap.win,
-                        pressed: state == KeyState::Pressed,
-                    });
+                    if keymap.id == hotkey.key() {
+                        // Sent before the hotkey acts, so the client gets the release of
+                        // every press it got even when this ungrabs
+                        if grabbed && hotkey.passes_through() {
+                            let event = rkvm_protocol::Event::key(keymap.win, pressed);
+                            let _ = event_tx.blocking_send(Packet::new(packet_id, event));
+                            packet_id = packet_id.wrapping_add(1);
+                        }
+
+                        if let Some(action) = hotkey.on_key(pressed, grabbed) {
+                            controller.handle(action);
+                        }
+                        continue;
+                    }
+
+                    if key_rules.on_key(keymap.id, pressed) {
+                        if dry_run {
+                            log::info!("Key {:?} belongs to a rule, not sent", keymap.id);
+                        }
+                        continue;
+                    }
+
+                    let forward = key_filter.on_key(keymap.id, pressed, grabbed);
+                    let mut events = shortcuts.translate(keymap.id, pressed, grabbed && forward);
+                    if !grabbed {
+                        if dry_run {
+                            log::info!("Not grabbed, key {:?} stays here", keymap.id);
+                        }
+                        continue;
+                    }
+                    if !forward {
+                        continue;
+                    }
+
+                    // A translated shortcut may take several, the last is sent as usual
+                    event_to_send = events.pop();
+                    for event in events {
+                        let _ = event_tx.blocking_send(Packet::new(packet_id, event));
+                        packet_id = packet_id.wrapping_add(1);
+                    }
                 }
                 input::Event::Pointer(ev) => {
-                    if !grabbed {
+                    if let input::event::PointerEvent::Button(ev) = &ev {
+                        if ev.button() == u32::from(Key::BTN_LEFT.code()) {
+                            controller.on_left_button(
+                                ev.button_state() == input::event::pointer::ButtonState::Pressed,
+                            );
+                        }
+                    }
+
+                    if !controller.is_grabbed() {
                         continue;
                     }
 
@@ -265,23 +627,52 @@ fn main() -> anyhow::Result<()> {
                             mouse_dx += ev.dx_unaccelerated();
                             mouse_dy += ev.dy_unaccelerated();
 
-                            if mouse_dx.abs() > 1.0 || mouse_dy.abs() > 1.0 {
+                            if controller::game_mode() {
+                                // Every report as is, the cursor belonging to the game.
+                                // Only the rounding error carries over
+                                let dx = mouse_dx.round() as i32;
+                                let dy = mouse_dy.round() as i32;
+
+                                mouse_dx -= dx as f64;
+                                mouse_dy -= dy as f64;
+
+                                if dx != 0 || dy != 0 {
+                                    event_to_send =
+                                        Some(rkvm_protocol::Event::MouseMotion { dx, dy });
+                                }
+                            } else if mouse_dx.abs() > 1.0 || mouse_dy.abs() > 1.0 {
                                 let dx = mouse_dx as i32;
                                 let dy = mouse_dy as i32;
 
                                 mouse_dx -= dx as f64;
                                 mouse_dy -= dy as f64;
 
-                                event_to_send = Some(rkvm_protocol::Event::MouseMotion { dx, dy });
+                                let (dx, dy) = if controller.raw_motion() {
+                                    (dx, dy)
+                                } else {
+                                    controller.cursor().motion(dx, dy)
+                                };
+                                if dx != 0 || dy != 0 {
+                                    event_to_send =
+                                        Some(rkvm_protocol::Event::MouseMotion { dx, dy });
+                                } else if dry_run {
+                                    log::debug!("Motion stopped at the edge of the client");
+                                }
+                            } else if dry_run {
+                                log::debug!(
+                                    "Motion held back until a pixel: {:.2}, {:.2}",
+                                    mouse_dx,
+                                    mouse_dy
+                                );
                             }
                         }
                         input::event::PointerEvent::Button(ev) => {
                             let pressed =
                                 ev.button_state() == input::event::pointer::ButtonState::Pressed;
-                            let button = match ev.button() {
-                                272 => rkvm_protocol::MouseButton::Left,
-                                273 => rkvm_protocol::MouseButton::Right,
-                                274 => rkvm_protocol::MouseButton::Middle,
+                            let button = match u16::try_from(ev.button()).map(Key::new) {
+                                Ok(Key::BTN_LEFT) => rkvm_protocol::MouseButton::Left,
+                                Ok(Key::BTN_RIGHT) => rkvm_protocol::MouseButton::Right,
+                                Ok(Key::BTN_MIDDLE) => rkvm_protocol::MouseButton::Middle,
                                 _ => continue,
                             };
                             event_to_send =
@@ -304,22 +695,68 @@ fn main() -> anyhow::Result<()> {
                                 wheel_dy -= dy * 120;
 
                                 event_to_send = Some(rkvm_protocol::Event::MouseWheel { dx, dy });
+                            } else if dry_run {
+                                log::info!(
+                                    "Scrolling held back until a notch: {}, {}",
+                                    wheel_dx,
+                                    wheel_dy
+                                );
                             }
                         }
                         _ => {}
                     }
                 }
+                input::Event::TabletPad(ev) => {
+                    if !controller.is_grabbed() {
+                        continue;
+                    }
+
+                    // libinput gives -1 once the finger lifts
+                    let position = |position: f64| (position >= 0.0).then_some(position);
+                    event_to_send = Some(match ev {
+                        TabletPadEvent::Ring(ev) => rkvm_protocol::Event::PadRing {
+                            ring: ev.number(),
+                            position: position(ev.position()),
+                        },
+                        TabletPadEvent::Strip(ev) => rkvm_protocol::Event::PadStrip {
+                            strip: ev.number(),
+                            position: position(ev.position()),
+                        },
+                        TabletPadEvent::Button(ev) => rkvm_protocol::Event::PadButton {
+                            button: ev.button_number(),
+                            pressed: ev.button_state() == ButtonState::Pressed,
+                        },
+                        _ => continue,
+                    });
+                }
+                input::Event::Switch(SwitchEvent::Toggle(ev)) => {
+                    let on = ev.switch_state() == SwitchState::On;
+                    match ev.switch() {
+                        Some(Switch::Lid) => controller.on_lid(on),
+                        Some(Switch::TabletMode) => controller.set_tablet_mode(on),
+                        _ => {}
+                    }
+                }
                 _ => {
                     println!("Got event: {:?}", event);
                 }
             }
 
-            if let (true, Some(event)) = (grabbed, event_to_send) {
-                let _ = event_tx.blocking_send(rkvm_protocol::Packet {
-                    id: packet_id,
-                    event,
-                });
+            if let (true, Some(event)) = (controller.is_grabbed(), event_to_send) {
+                let _ = event_tx.blocking_send(rkvm_protocol::Packet::new(packet_id, event));
                 packet_id = packet_id.wrapping_add(1);
+                controller.on_forwarded();
+
+                let correction = if controller.raw_motion() {
+                    None
+                } else {
+                    controller.cursor().correction()
+                };
+                if let Some((x, y)) = correction {
+                    let event = rkvm_protocol::Event::MouseAbsolute { x, y };
+                    let _ = event_tx.blocking_send(rkvm_protocol::Packet::new(packet_id, event));
+                    packet_id = packet_id.wrapping_add(1);
+                }
             }
         }
     }
@@ -0,0 +1,93 @@
+use std::{collections::HashMap, time::Duration};
+
+use futures_util::StreamExt;
+use zbus::{zvariant::Value, Proxy};
+
+const DESTINATION: &str = "org.freedesktop.Notifications";
+const PATH: &str = "/org/freedesktop/Notifications";
+
+/// Shows a desktop notification, if a notification daemon is on the session bus.
+pub async fn notify(summary: &str, body: &str) -> zbus::Result<()> {
+    let conn = zbus::Connection::session().await?;
+
+    let hints: HashMap<&str, Value> = HashMap::new();
+    conn.call_method(
+        Some(DESTINATION),
+        PATH,
+        Some(DESTINATION),
+        "Notify",
+        &(
+            "rkvm",
+            0u32,
+            "input-keyboard",
+            summary,
+            body,
+            Vec::<&str>::new(),
+            hints,
+            -1i32,
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Shows a desktop notification with buttons for `actions`, pairs of key and label, for
+/// `timeout` at most.
+///
+/// Returns the key of the one picked, or `None` if it closed without that.
+pub async fn ask(
+    summary: &str,
+    body: &str,
+    actions: &[(&str, &str)],
+    timeout: Duration,
+) -> zbus::Result<Option<String>> {
+    let conn = zbus::Connection::session().await?;
+    let proxy = Proxy::new(&conn, DESTINATION, PATH, DESTINATION).await?;
+
+    // Before showing it, so a quick answer isn't missed
+    let mut invoked = proxy.receive_signal("ActionInvoked").await?;
+    let mut closed = proxy.receive_signal("NotificationClosed").await?;
+
+    let actions: Vec<&str> = actions
+        .iter()
+        .flat_map(|&(key, label)| [key, label])
+        .collect();
+    // Critical, so it stays up until answered
+    let hints = HashMap::from([("urgency", Value::U8(2))]);
+    let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+    let reply = proxy
+        .call_method(
+            "Notify",
+            &(
+                "rkvm",
+                0u32,
+                "input-keyboard",
+                summary,
+                body,
+                actions,
+                hints,
+                timeout,
+            ),
+        )
+        .await?;
+    let id: u32 = reply.body()?;
+
+    loop {
+        tokio::select! {
+            Some(message) = invoked.next() => {
+                let (invoked, key): (u32, String) = message.body()?;
+                if invoked == id {
+                    return Ok(Some(key));
+                }
+            }
+            Some(message) = closed.next() => {
+                let (closed, _reason): (u32, u32) = message.body()?;
+                if closed == id {
+                    return Ok(None);
+                }
+            }
+            else => return Ok(None),
+        }
+    }
+}
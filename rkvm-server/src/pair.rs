@@ -0,0 +1,45 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use qrcode::QrCode;
+use rkvm_protocol::Pairing;
+
+use crate::{config::Config, identity::Identity};
+
+/// Prints a pairing code for `address` as a URI and a terminal QR code, optionally saving a PNG.
+pub fn run(config: &Config, address: String, png: Option<&Path>, with_psk: bool) -> Result<()> {
+    let identity = Identity::load_or_generate(&config.state_dir)?;
+
+    let psk = if with_psk {
+        Some(config.psk.clone().context("No psk configured")?)
+    } else {
+        None
+    };
+
+    let pairing = Pairing {
+        address,
+        port: config.bind.port(),
+        fingerprint: rkvm_protocol::cert_digest(&identity.cert_der),
+        psk,
+    };
+    let uri = pairing.to_uri();
+
+    let code = QrCode::new(uri.as_bytes())?;
+    let terminal = code
+        .render::<qrcode::render::unicode::Dense1x2>()
+        .quiet_zone(true)
+        .build();
+
+    println!("{}", terminal);
+    println!("{}", uri);
+
+    if let Some(path) = png {
+        code.render::<image::Luma<u8>>()
+            .build()
+            .save(path)
+            .with_context(|| format!("Save {:?}", path))?;
+        println!("Saved QR code to {:?}", path);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! Moving the local cursor out of the way while input goes to a client.
+//!
+//! On X11 the cursor is warped to the bottom right corner and back on ungrab. Elsewhere it
+//! is placed there with a virtual pointer. Where it was can't be read then, so on ungrab it
+//! comes back to the middle of the desktop.
+
+use anyhow::{Context, Result};
+use x11rb::{
+    connection::Connection,
+    protocol::xproto::{ConnectionExt, Window},
+    rust_connection::RustConnection,
+};
+
+use crate::uinput::VirtualPointer;
+
+enum Backend {
+    X11 {
+        conn: Box<RustConnection>,
+        root: Window,
+    },
+    Uinput {
+        pointer: VirtualPointer,
+        parked: bool,
+    },
+}
+
+pub struct CursorPark {
+    backend: Backend,
+    /// Root window position to return to, if parked under X11
+    saved: Option<(i16, i16)>,
+}
+
+impl CursorPark {
+    pub fn new() -> Result<Self> {
+        let backend = match x11rb::connect(None) {
+            Ok((conn, screen)) => {
+                let root = conn.setup().roots[screen].root;
+                Backend::X11 {
+                    conn: Box::new(conn),
+                    root,
+                }
+            }
+            Err(e) => {
+                log::debug!("Parking the cursor with a virtual pointer, no X11: {}", e);
+                Backend::Uinput {
+                    pointer: VirtualPointer::new().context("Create virtual pointer")?,
+                    parked: false,
+                }
+            }
+        };
+
+        Ok(Self {
+            backend,
+            saved: None,
+        })
+    }
+
+    pub fn park(&mut self) -> Result<()> {
+        match &mut self.backend {
+            Backend::X11 { conn, root } => {
+                if self.saved.is_some() {
+                    return Ok(());
+                }
+
+                let pointer = conn.query_pointer(*root)?.reply()?;
+                let screen = conn.get_geometry(*root)?.reply()?;
+
+                conn.warp_pointer(
+                    x11rb::NONE,
+                    *root,
+                    0,
+                    0,
+                    0,
+                    0,
+                    i16::try_from(screen.width.saturating_sub(1)).unwrap_or(i16::MAX),
+                    i16::try_from(screen.height.saturating_sub(1)).unwrap_or(i16::MAX),
+                )?;
+                conn.flush()?;
+
+                self.saved = Some((pointer.root_x, pointer.root_y));
+            }
+            Backend::Uinput { pointer, parked } => {
+                pointer.move_to(1.0, 1.0)?;
+                *parked = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn unpark(&mut self) -> Result<()> {
+        match &mut self.backend {
+            Backend::X11 { conn, root } => {
+                if let Some((x, y)) = self.saved.take() {
+                    conn.warp_pointer(x11rb::NONE, *root, 0, 0, 0, 0, x, y)?;
+                    conn.flush()?;
+                }
+            }
+            Backend::Uinput { pointer, parked } => {
+                if std::mem::take(parked) {
+                    pointer.move_to(0.5, 0.5)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+//! Event filters loaded from shared libraries, between capture and what is sent to clients.
+
+use std::{
+    mem::MaybeUninit,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use libloading::{Library, Symbol};
+use rkvm_protocol::Packet;
+use rkvm_server_core::{abi::RawFilter, LoadedFilter, API_VERSION};
+
+struct Plugin {
+    filter: LoadedFilter,
+    /// Dropped after the filter, whose code it holds
+    _library: Library,
+}
+
+/// The filters loaded, applied in order.
+pub struct Plugins {
+    loaded: Vec<Plugin>,
+}
+
+fn load(path: &Path) -> Result<Plugin> {
+    // Runs whatever the library runs on load, which the config asks for
+    let library = unsafe { Library::new(path) }.context("Load library")?;
+
+    let version =
+        unsafe { library.get::<*const u32>(b"RKVM_FILTER_API\0") }.context("Not an rkvm filter")?;
+    let version = unsafe { **version };
+    if version != API_VERSION {
+        anyhow::bail!(
+            "Built for filter API {}, the server has {}",
+            version,
+            API_VERSION
+        );
+    }
+
+    let create: Symbol<unsafe extern "C" fn(*mut RawFilter) -> bool> =
+        unsafe { library.get(b"rkvm_filter_create\0") }.context("Not an rkvm filter")?;
+    let mut raw = MaybeUninit::uninit();
+    if !unsafe { create(raw.as_mut_ptr()) } {
+        anyhow::bail!("Creating the filter panicked");
+    }
+    // Made by the same version of rkvm-server-core, as checked above
+    let filter = unsafe { LoadedFilter::new(raw.assume_init()) };
+
+    Ok(Plugin {
+        filter,
+        _library: library,
+    })
+}
+
+impl Plugins {
+    /// Loads the filters at `paths`, leaving out those that fail to.
+    pub fn load(paths: &[PathBuf]) -> Self {
+        let mut loaded = Vec::new();
+        for path in paths {
+            match load(path) {
+                Ok(plugin) => {
+                    log::info!("Loaded filter {} from {:?}", plugin.filter.name(), path);
+                    loaded.push(plugin);
+                }
+                Err(e) => log::error!("Failed to load filter {:?}: {:#}", path, e),
+            }
+        }
+
+        Self { loaded }
+    }
+
+    /// Puts what the filters make of `packet` into `out`.
+    pub fn apply(&mut self, packet: Packet, out: &mut Vec<Packet>) {
+        if self.loaded.is_empty() {
+            out.push(packet);
+            return;
+        }
+
+        let Packet {
+            id,
+            seq,
+            event,
+            time,
+        } = packet;
+        let (events, failed) = rkvm_server_core::chain(&mut self.loaded, event, |plugin, event| {
+            plugin.filter.filter(&event)
+        });
+        for plugin in failed {
+            log::error!("Filter {} panicked, unloading it", plugin.filter.name());
+        }
+
+        out.extend(events.into_iter().map(|event| Packet {
+            id,
+            seq,
+            event,
+            time,
+        }));
+    }
+}
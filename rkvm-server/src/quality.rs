@@ -0,0 +1,83 @@
+//! Adapting to clients on slow or lossy connections.
+//!
+//! Each connection is measured every second. Mouse motion to a client on a degraded one
+//! is merged into fewer moves, clipboard sync pauses while input is on one that is poor,
+//! and every change is logged and shown in a notification.
+
+use std::{sync::Arc, time::Duration};
+
+use quinn::Connection;
+use rkvm_protocol::{LinkMonitor, LinkQuality};
+
+use crate::{clients, config, notify, stats::ConnStats};
+
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Follows the quality of the connection of `client` until it closes, noting its round
+/// trips in `stats`.
+pub async fn monitor(id: usize, client: String, conn: Connection, stats: Arc<ConnStats>) {
+    let mut monitor = LinkMonitor::default();
+    let mut quality = LinkQuality::Good;
+    let mut interval = tokio::time::interval(INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = conn.closed() => return,
+        }
+        stats.note_rtt(conn.rtt());
+
+        let config = config::current();
+        let now = if config.link_quality.adaptive {
+            let path = conn.stats().path;
+            let thresholds = config.link_quality.thresholds();
+            monitor.sample(&thresholds, path.rtt, path.sent_packets, path.lost_packets)
+        } else {
+            LinkQuality::Good
+        };
+        if now == quality {
+            continue;
+        }
+
+        clients::set_quality(id, now);
+        let rtt = conn.rtt().as_millis();
+        if now > quality {
+            log::warn!(
+                "Connection to {} is {:?}, {} ms round trip",
+                client,
+                now,
+                rtt
+            );
+            let summary = format!("Connection to {} {}", client, describe(now));
+            let body = format!("{} ms round trip", rtt);
+            if let Err(e) = notify::notify(&summary, &body).await {
+                log::debug!("Failed to show notification: {}", e);
+            }
+        } else {
+            log::info!("Connection to {} is {:?} again", client, now);
+        }
+        quality = now;
+    }
+}
+
+fn describe(quality: LinkQuality) -> &'static str {
+    match quality {
+        LinkQuality::Good => "recovered",
+        LinkQuality::Degraded => "degraded",
+        LinkQuality::Poor => "poor, clipboard sync paused",
+    }
+}
+
+/// How long to merge mouse motion to the connection with stable id `id` over, if at all.
+pub fn coalesce_window(id: usize) -> Option<Duration> {
+    let config = &config::current().link_quality;
+    if !config.adaptive || config.coalesce_ms == 0 || clients::quality(id) == LinkQuality::Good {
+        return None;
+    }
+    Some(Duration::from_millis(config.coalesce_ms))
+}
+
+/// Whether clipboard sync should wait, as input is on a client with a poor connection.
+pub fn pauses_clipboard_sync() -> bool {
+    config::current().link_quality.adaptive && clients::active_quality() == LinkQuality::Poor
+}
@@ -0,0 +1,149 @@
+//! Remembering whether input is grabbed and for which client, so a server restarted with
+//! `--restore` picks up where the last one left off instead of keeping input here.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{clients, controller::Controller};
+
+/// How long to wait for the client to reconnect before giving up.
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to check whether it did meanwhile.
+const RESTORE_POLL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+struct GrabState {
+    grabbed: bool,
+    /// Stable id of the client input went to
+    client: Option<String>,
+}
+
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Last state written, so unchanged ones aren't written again
+static LAST: Mutex<Option<GrabState>> = Mutex::new(None);
+
+fn path(state_dir: &Path) -> PathBuf {
+    state_dir.join("grab-state.json")
+}
+
+/// Starts recording grab state in `state_dir`.
+pub fn init(state_dir: &Path) {
+    let _ = PATH.set(path(state_dir));
+}
+
+/// Records that input is `grabbed`, for the active client.
+pub fn record(grabbed: bool) {
+    let path = match PATH.get() {
+        Some(path) => path,
+        None => return,
+    };
+
+    let state = GrabState {
+        grabbed,
+        client: clients::active_uuid().map(|uuid| uuid.to_string()),
+    };
+    let mut last = LAST.lock().unwrap();
+    if last.as_ref() == Some(&state) {
+        return;
+    }
+
+    if let Err(e) = write(path, &state) {
+        log::warn!("Failed to record grab state: {:#}", e);
+    }
+    *last = Some(state);
+}
+
+fn write(path: &Path, state: &GrabState) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("Create state dir {:?}", dir))?;
+    }
+
+    // Renamed into place, so a crash midway leaves the previous state
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, serde_json::to_vec(state)?)
+        .with_context(|| format!("Write {:?}", temp))?;
+    std::fs::rename(&temp, path).with_context(|| format!("Write {:?}", path))?;
+    Ok(())
+}
+
+/// A grab of the last server to bring back once its client reconnects.
+pub struct Restore {
+    client: Option<String>,
+    deadline: Instant,
+}
+
+impl Restore {
+    /// The grab recorded in `state_dir`, if input was grabbed when the last server stopped.
+    pub fn load(state_dir: &Path) -> Option<Self> {
+        let path = path(state_dir);
+        let state: GrabState = match std::fs::read(&path) {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(state) => state,
+                Err(e) => {
+                    log::warn!("Ignoring grab state in {:?}: {}", path, e);
+                    return None;
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                log::warn!("Failed to read grab state from {:?}: {}", path, e);
+                return None;
+            }
+        };
+
+        if !state.grabbed {
+            log::info!("Input wasn't grabbed before the restart, nothing to restore");
+            return None;
+        }
+        match &state.client {
+            Some(client) => log::info!("Restoring the grab for {} once it reconnects", client),
+            None => log::info!("Restoring the grab"),
+        }
+
+        Some(Self {
+            client: state.client,
+            deadline: Instant::now() + RESTORE_TIMEOUT,
+        })
+    }
+
+    /// How long the main loop may wait before trying again.
+    pub fn timeout(&self) -> Duration {
+        self.deadline
+            .saturating_duration_since(Instant::now())
+            .min(RESTORE_POLL)
+    }
+
+    /// Grabs for the client if it is back, returning whether this is done with.
+    pub fn attempt(&self, controller: &mut Controller) -> bool {
+        let client = match &self.client {
+            Some(client) => client,
+            None => {
+                controller.grab();
+                return true;
+            }
+        };
+
+        if clients::is_connected(client) {
+            controller.switch_to(client);
+            return true;
+        }
+        if Instant::now() >= self.deadline {
+            log::warn!(
+                "Client {} didn't reconnect within {:?}, input stays here",
+                client,
+                RESTORE_TIMEOUT
+            );
+            return true;
+        }
+
+        false
+    }
+}
@@ -0,0 +1,213 @@
+//! Rules from the config running actions on keys, switches and clients connecting, and
+//! the callbacks of the script for the same.
+//!
+//! Key rules are checked on the input thread as keys go by. Clients connect on other
+//! threads, so every action waits in a queue the main loop runs, woken for it.
+
+use std::{
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use keycode::KeyMappingId;
+
+use crate::{
+    clients,
+    config::{self, Config, RuleAction, RuleTrigger},
+    controller::Controller,
+    script, wake,
+};
+
+/// Actions to run, with the client they are about
+static QUEUE: Mutex<Vec<(RuleAction, Option<String>)>> = Mutex::new(Vec::new());
+
+/// Set while running actions, so switching from a rule doesn't trigger switch rules
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Runs `actions` about `client` from the main loop.
+pub fn queue(actions: &[RuleAction], client: Option<String>) {
+    if actions.is_empty() {
+        return;
+    }
+
+    let mut queue = QUEUE.lock().unwrap();
+    queue.extend(actions.iter().map(|a| (a.clone(), client.clone())));
+    drop(queue);
+    wake::wake();
+}
+
+/// Whether a rule meant for `client`, any if unset, applies to one known by `keys`.
+fn applies(client: &Option<String>, keys: &[String]) -> bool {
+    match client {
+        Some(client) => keys.contains(client),
+        None => true,
+    }
+}
+
+/// Runs the switch rules for the client input just moved to.
+pub fn switched() {
+    if RUNNING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let keys = clients::active_keys();
+    if let Some(client) = keys.first() {
+        script::on_switch(client);
+    }
+    for rule in &config::current().rules {
+        if let RuleTrigger::Switch { client } = &rule.trigger {
+            if applies(client, &keys) {
+                queue(&rule.actions, keys.first().cloned());
+            }
+        }
+    }
+}
+
+/// Runs the connect rules for the client known by `keys`, its name first.
+pub fn connected(keys: &[String]) {
+    if let Some(client) = keys.first() {
+        script::on_client_connect(client);
+    }
+    for rule in &config::current().rules {
+        if let RuleTrigger::ClientConnect { client } = &rule.trigger {
+            if applies(client, keys) {
+                queue(&rule.actions, keys.first().cloned());
+            }
+        }
+    }
+}
+
+/// Runs the actions queued since the last call.
+pub fn run_pending(controller: &mut Controller) {
+    let actions = std::mem::take(&mut *QUEUE.lock().unwrap());
+    if actions.is_empty() {
+        return;
+    }
+
+    RUNNING.store(true, Ordering::Relaxed);
+    for (action, client) in actions {
+        log::info!("Running rule action {:?}", action);
+        match action {
+            RuleAction::SwitchTo { client } => controller.switch_to(&client),
+            RuleAction::SwitchToSlot { slot } => controller.switch_to_slot(slot),
+            RuleAction::PushClipboard => controller.push_clipboard(),
+            RuleAction::PullClipboard => controller.pull_clipboard(),
+            RuleAction::SwapClipboards => controller.swap_clipboards(),
+            RuleAction::TypeSelection => controller.type_selection(),
+            RuleAction::Attention => controller.attention(),
+            RuleAction::CheatSheet => controller.cheat_sheet(),
+            RuleAction::SwitchBack => controller.switch_back(),
+            RuleAction::ReturnLocal => controller.ungrab(),
+            RuleAction::RunCommand { command } => run_command(command, client),
+        }
+    }
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+fn run_command(command: String, client: Option<String>) {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(&command);
+    if let Some(client) = client {
+        cmd.env("RKVM_CLIENT", client);
+    }
+
+    match cmd.spawn() {
+        // Waited for elsewhere, so it doesn't linger as a zombie or hold up input
+        Ok(mut child) => {
+            std::thread::spawn(move || match child.wait() {
+                Ok(status) if !status.success() => {
+                    log::warn!("Rule command {:?} exited with {}", command, status)
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Failed to wait for rule command {:?}: {}", command, e),
+            });
+        }
+        Err(e) => log::error!("Failed to run rule command {:?}: {}", command, e),
+    }
+}
+
+/// Checks key presses against the key rules.
+pub struct KeyRules {
+    config: Arc<Config>,
+    /// Keys currently down, in the order they went down
+    held: Vec<KeyMappingId>,
+    /// Keys that completed a rule, whose release is kept from the client as well
+    captured: Vec<KeyMappingId>,
+    /// The double-tap rule tapped once, by index, and when
+    first_tap: Option<(usize, Instant)>,
+}
+
+impl KeyRules {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            config,
+            held: Vec::new(),
+            captured: Vec::new(),
+            first_tap: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: Arc<Config>) {
+        self.config = config;
+        self.first_tap = None;
+    }
+
+    /// Whether a press or release of `key` belongs to a rule, and not to the client.
+    pub fn on_key(&mut self, key: KeyMappingId, pressed: bool) -> bool {
+        if !pressed {
+            self.held.retain(|k| *k != key);
+            script::on_key(key, false);
+
+            let captured = self.captured.contains(&key);
+            self.captured.retain(|k| *k != key);
+            return captured;
+        }
+
+        if !self.held.contains(&key) {
+            self.held.push(key);
+        }
+        if self.captured.contains(&key) {
+            // Repeats of a key held down
+            return true;
+        }
+        if script::on_key(key, true) {
+            self.captured.push(key);
+            return true;
+        }
+
+        let (index, double_tap) = match self.completed(key) {
+            Some(completed) => completed,
+            None => return false,
+        };
+        let rule = &self.config.rules[index];
+        self.captured.push(key);
+
+        if double_tap {
+            let window = Duration::from_millis(self.config.hotkey.double_tap_ms);
+            let first = self.first_tap.take();
+            if !first.is_some_and(|(i, at)| i == index && at.elapsed() <= window) {
+                self.first_tap = Some((index, Instant::now()));
+                return true;
+            }
+        }
+
+        queue(&rule.actions, clients::active());
+        true
+    }
+
+    /// The key rule pressing `key` completes, by index, and whether it wants a double tap.
+    fn completed(&self, key: KeyMappingId) -> Option<(usize, bool)> {
+        for (i, rule) in self.config.rules.iter().enumerate() {
+            if let RuleTrigger::Key { keys, double_tap } = &rule.trigger {
+                if keys.contains(&key) && keys.iter().all(|k| self.held.contains(k)) {
+                    return Some((i, *double_tap));
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,45 @@
+//! Pinning the thread reading input devices to a core, and running it ahead of everything
+//! else, so capture keeps up on a busy machine.
+//!
+//! Both apply to the calling thread only. Realtime scheduling isn't passed on to threads
+//! and commands started from it, while the core it is pinned to is.
+
+use nix::{errno::Errno, sched::CpuSet, unistd::Pid};
+
+use crate::config::InputThreadConfig;
+
+/// Applies `config` to the calling thread, warning about what can't be.
+pub fn apply(config: &InputThreadConfig) {
+    if let Some(cpu) = config.cpu {
+        match pin(cpu) {
+            Ok(()) => log::info!("Reading input on core {}", cpu),
+            Err(e) => log::warn!("Failed to keep input on core {}: {}", cpu, e),
+        }
+    }
+
+    if config.realtime {
+        let priority = config.realtime_priority.clamp(1, 99);
+        match realtime(priority) {
+            Ok(()) => log::info!("Reading input with realtime priority {}", priority),
+            Err(Errno::EPERM) => log::warn!(
+                "Not allowed realtime scheduling, which takes CAP_SYS_NICE or an RLIMIT_RTPRIO"
+            ),
+            Err(e) => log::warn!("Failed to use realtime scheduling: {}", e),
+        }
+    }
+}
+
+fn pin(cpu: usize) -> nix::Result<()> {
+    let mut set = CpuSet::new();
+    set.set(cpu)?;
+    nix::sched::sched_setaffinity(Pid::from_raw(0), &set)
+}
+
+fn realtime(priority: i32) -> nix::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    // Threads and commands started from here go back to normal scheduling
+    let policy = libc::SCHED_FIFO | libc::SCHED_RESET_ON_FORK;
+    Errno::result(unsafe { libc::sched_setscheduler(0, policy, &param) }).map(drop)
+}
@@ -0,0 +1,222 @@
+//! A Rhai script from the `script` option, for switching and automation the rules can't
+//! express.
+//!
+//! The script may define `on_key(key, pressed)`, returning `true` to keep the key from the
+//! client, `on_switch(client)` and `on_client_connect(client)`. Keys are named like in the
+//! config. They can call `switch_to(client)`, `switch_to_slot(slot)`, `switch_back()`,
+//! `return_local()`, `push_clipboard()`, `pull_clipboard()`, `swap_clipboards()` and
+//! `run_command(command)`, which run like rule actions, and `now_ms()`. State kept between
+//! calls goes on `this`, which starts out as an empty map.
+//!
+//! The script is read again on every reload. Calls that run too long, by operations or by
+//! time, are cut short, so a loop in it can't hold up input.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use keycode::KeyMappingId;
+use rhai::{CallFnOptions, Dynamic, Engine, FuncArgs, Map, Scope, AST};
+
+use crate::{
+    config::{self, RuleAction},
+    rules,
+};
+
+/// Operations a single call may take before it is cut short
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// Time a single call may take before it is cut short, as `on_key` holds up the key
+const MAX_CALL_TIME: Duration = Duration::from_millis(5);
+
+struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    /// `this` of every call
+    state: Dynamic,
+    /// Actions the current call asked for
+    actions: Arc<Mutex<Vec<RuleAction>>>,
+    /// When the current call started
+    started: Arc<Mutex<Instant>>,
+}
+
+/// The script of a config
+struct Loaded {
+    generation: u64,
+    path: Option<PathBuf>,
+    /// `None` if there is none or it failed to load
+    script: Option<Script>,
+}
+
+static LOADED: Mutex<Option<Loaded>> = Mutex::new(None);
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+fn engine(actions: &Arc<Mutex<Vec<RuleAction>>>, started: &Arc<Mutex<Instant>>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    let started = started.clone();
+    engine.on_progress(move |_| {
+        let late = started.lock().unwrap().elapsed() > MAX_CALL_TIME;
+        late.then(|| Dynamic::from("took too long"))
+    });
+    engine.on_print(|text| log::info!("script: {}", text));
+    engine.on_debug(|text, _, _| log::debug!("script: {}", text));
+
+    let register = |engine: &mut Engine, name: &str, action: RuleAction| {
+        let actions = actions.clone();
+        engine.register_fn(name, move || actions.lock().unwrap().push(action.clone()));
+    };
+    register(&mut engine, "switch_back", RuleAction::SwitchBack);
+    register(&mut engine, "return_local", RuleAction::ReturnLocal);
+    register(&mut engine, "push_clipboard", RuleAction::PushClipboard);
+    register(&mut engine, "pull_clipboard", RuleAction::PullClipboard);
+    register(&mut engine, "swap_clipboards", RuleAction::SwapClipboards);
+
+    let switch_actions = actions.clone();
+    engine.register_fn("switch_to", move |client: &str| {
+        let client = client.to_owned();
+        switch_actions
+            .lock()
+            .unwrap()
+            .push(RuleAction::SwitchTo { client });
+    });
+    let slot_actions = actions.clone();
+    engine.register_fn("switch_to_slot", move |slot: i64| match slot.try_into() {
+        Ok(slot) => slot_actions
+            .lock()
+            .unwrap()
+            .push(RuleAction::SwitchToSlot { slot }),
+        Err(_) => log::warn!("script: no slot {}", slot),
+    });
+    let command_actions = actions.clone();
+    engine.register_fn("run_command", move |command: &str| {
+        let command = command.to_owned();
+        command_actions
+            .lock()
+            .unwrap()
+            .push(RuleAction::RunCommand { command });
+    });
+    engine.register_fn("now_ms", || {
+        START.get_or_init(Instant::now).elapsed().as_millis() as i64
+    });
+
+    engine
+}
+
+fn load(path: &Path) -> Result<Script> {
+    let actions = Arc::new(Mutex::new(Vec::new()));
+    let started = Arc::new(Mutex::new(Instant::now()));
+    let engine = engine(&actions, &started);
+    let ast = engine
+        .compile_file(path.to_owned())
+        .map_err(|e| anyhow::anyhow!("{}", e))
+        .with_context(|| format!("Load script {:?}", path))?;
+
+    // Runs what is outside of functions once, which may set up variables for them
+    let mut scope = Scope::new();
+    *started.lock().unwrap() = Instant::now();
+    engine
+        .run_ast_with_scope(&mut scope, &ast)
+        .map_err(|e| anyhow::anyhow!("{}", e))
+        .with_context(|| format!("Run script {:?}", path))?;
+
+    Ok(Script {
+        engine,
+        ast,
+        scope,
+        state: Dynamic::from_map(Map::new()),
+        actions,
+        started,
+    })
+}
+
+/// Calls `name` in the script of the current config, if it defines one taking `args`,
+/// queueing the actions it asks for as about `client`.
+fn call(name: &str, args: impl FuncArgs, arity: usize, client: Option<String>) -> Option<Dynamic> {
+    let config = config::current();
+    let generation = config::generation();
+
+    let mut loaded = LOADED.lock().unwrap();
+    let stale =
+        !matches!(&*loaded, Some(l) if l.generation == generation && l.path == config.script);
+    if stale {
+        let script = config.script.as_deref().and_then(|path| match load(path) {
+            Ok(script) => {
+                log::info!("Loaded script {:?}", path);
+                Some(script)
+            }
+            Err(e) => {
+                log::error!("{:#}", e);
+                None
+            }
+        });
+        *loaded = Some(Loaded {
+            generation,
+            path: config.script.clone(),
+            script,
+        });
+    }
+
+    let script = loaded.as_mut()?.script.as_mut()?;
+    if !script
+        .ast
+        .iter_functions()
+        .any(|f| f.name == name && f.params.len() == arity)
+    {
+        return None;
+    }
+
+    *script.started.lock().unwrap() = Instant::now();
+    let options = CallFnOptions::new()
+        .eval_ast(false)
+        .bind_this_ptr(&mut script.state);
+    let result = script.engine.call_fn_with_options::<Dynamic>(
+        options,
+        &mut script.scope,
+        &script.ast,
+        name,
+        args,
+    );
+    let actions = std::mem::take(&mut *script.actions.lock().unwrap());
+    rules::queue(&actions, client);
+
+    match result {
+        Ok(result) => Some(result),
+        Err(e) => {
+            log::warn!("Script {} failed: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Whether the script keeps a press or release of `key` from the client.
+pub fn on_key(key: KeyMappingId, pressed: bool) -> bool {
+    let key = format!("{:?}", key);
+    let result = call("on_key", (key, pressed), 2, crate::clients::active());
+    result.and_then(|r| r.as_bool().ok()).unwrap_or(false)
+}
+
+/// Tells the script input moved to `client`.
+pub fn on_switch(client: &str) {
+    call(
+        "on_switch",
+        (client.to_owned(),),
+        1,
+        Some(client.to_owned()),
+    );
+}
+
+/// Tells the script `client` connected.
+pub fn on_client_connect(client: &str) {
+    call(
+        "on_client_connect",
+        (client.to_owned(),),
+        1,
+        Some(client.to_owned()),
+    );
+}
@@ -1,28 +1,601 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
 
 use anyhow::{Context, Result};
-use quinn::{Connecting, Endpoint, SendStream};
-use rkvm_protocol::Packet;
-use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use keycode::{KeyMap, KeyMapping};
+use quinn::{Connecting, Connection, Endpoint, RecvStream, SendDatagramError, SendStream};
+use rkvm_protocol::{
+    Ack, ActivityReport, ClientHello, ClipboardFetch, DragFetch, EventKind, InjectError, LogLevel,
+    LogRecord, Packet, PointerMode, Reopens, ScreenLayout, ServerHello, StreamTag, Throttle,
+    TimeProbe, Timeouts, TransferProgress, UpstreamKind, FILE_CHUNK_LEN,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::{
+        broadcast::{
+            error::{RecvError, TryRecvError},
+            Receiver,
+        },
+        Notify,
+    },
+};
 use tracing::Instrument;
 
+use crate::{
+    audit::{self, AuditEvent},
+    clients,
+    clipboard::{self, ClipboardHandle},
+    config::{self, Config, GrabIndicator, OverflowPolicy},
+    delivery, files, grab,
+    identity::Identity,
+    known, notify,
+    plugins::Plugins,
+    quality,
+    stats::ConnStats,
+    uinput::VirtualInput,
+};
+
+/// Upper bound for control messages, which are all tiny.
+const MAX_CONTROL_LEN: u32 = 64 * 1024;
+
+/// Largest clipboard a client may send when asked for it
+const MAX_CLIPBOARD_LEN: u32 = 64 * 1024 * 1024;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most records a client relays that are written each [`CLIENT_LOGS_WINDOW`]
+const MAX_CLIENT_LOGS: u32 = 20;
+const CLIENT_LOGS_WINDOW: Duration = Duration::from_secs(10);
+
+/// Woken whenever a client took an event or went away, for blocked events to check for room
+static ROOM: Notify = Notify::const_new();
+
+/// Wakes blocked events when a client stops taking events, making room for them.
+struct WakeOnDrop;
+
+impl Drop for WakeOnDrop {
+    fn drop(&mut self) {
+        ROOM.notify_waiters();
+    }
+}
+
+/// What the endpoint was started with, as reloads don't change it
+static TIMEOUTS: OnceLock<Timeouts> = OnceLock::new();
+
+/// What packets are stamped against
+static CLOCK: OnceLock<Instant> = OnceLock::new();
+
+/// Microseconds on the clock packets are stamped with, also what clients sync to.
+fn clock_us() -> u64 {
+    CLOCK.get_or_init(Instant::now).elapsed().as_micros() as u64
+}
+
+/// Which clients an outgoing packet is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Audience {
+    /// Only the active client
+    Active,
+    Everyone,
+    /// Every client but the one input goes to, which is left as it is
+    Idle,
+}
+
+impl Audience {
+    /// Whether the connection with stable id `id` gets the packet.
+    fn includes(self, id: usize) -> bool {
+        match self {
+            Audience::Active => clients::is_active(id),
+            Audience::Everyone => true,
+            Audience::Idle => !clients::is_target(id),
+        }
+    }
+}
+
+/// An encoded packet on its way to the clients.
+#[derive(Clone)]
+struct Outgoing {
+    kind: EventKind,
+    audience: Audience,
+    data: Arc<[u8]>,
+    /// Packet id to wait for an ack of, and what the packet is
+    tracked: Option<(u64, &'static str)>,
+}
+
 lazy_static::lazy_static! {
-    static ref MOUSE_CHANNEL: tokio::sync::broadcast::Sender<Arc<[u8]>> = {
-        let (tx, _) = tokio::sync::broadcast::channel(120);
+    static ref MOUSE_CHANNEL: tokio::sync::broadcast::Sender<Outgoing> = {
+        let capacity = config::current().channels.mouse_capacity;
+        let (tx, _) = tokio::sync::broadcast::channel(capacity.max(1));
+        tx
+    };
+
+    static ref KEYBOARD_CHANNEL: tokio::sync::broadcast::Sender<Outgoing> = {
+        let capacity = config::current().channels.keyboard_capacity;
+        let (tx, _) = tokio::sync::broadcast::channel(capacity.max(1));
+        tx
+    };
+
+    static ref MISC_CHANNEL: tokio::sync::broadcast::Sender<Outgoing> = {
+        let capacity = config::current().channels.misc_capacity;
+        let (tx, _) = tokio::sync::broadcast::channel(capacity.max(1));
         tx
     };
 
-    static ref KEYBOARD_CHANNEL: tokio::sync::broadcast::Sender<Arc<[u8]>> = {
-        let (tx, _) = tokio::sync::broadcast::channel(30);
+    /// Events of every kind, for clients taking them on a single stream
+    static ref COMBINED_CHANNEL: tokio::sync::broadcast::Sender<Outgoing> = {
+        let channels = &config::current().channels;
+        let capacity =
+            channels.mouse_capacity + channels.keyboard_capacity + channels.misc_capacity;
+        let (tx, _) = tokio::sync::broadcast::channel(capacity.max(1));
         tx
     };
 
-    static ref MISC_CHANNEL: tokio::sync::broadcast::Sender<Arc<[u8]>> = {
-        let (tx, _) = tokio::sync::broadcast::channel(30);
+    /// Mouse motion in game mode
+    static ref DATAGRAM_CHANNEL: tokio::sync::broadcast::Sender<Outgoing> = {
+        let capacity = config::current().channels.mouse_capacity;
+        let (tx, _) = tokio::sync::broadcast::channel(capacity.max(1));
         tx
     };
 }
 
+/// The channel events of `kind` are sent to clients through.
+fn channel(kind: EventKind) -> &'static tokio::sync::broadcast::Sender<Outgoing> {
+    match kind {
+        EventKind::Mouse => &MOUSE_CHANNEL,
+        EventKind::Keyboard => &KEYBOARD_CHANNEL,
+        EventKind::Misc => &MISC_CHANNEL,
+    }
+}
+
+/// How much of the single stream's queue events of `kind` may take up, motion only its
+/// share so it never crowds out the rest.
+fn combined_capacity(kind: EventKind) -> usize {
+    let channels = &config::current().channels;
+    match kind {
+        EventKind::Mouse => channels.mouse_capacity,
+        _ => channels.mouse_capacity + channels.keyboard_capacity + channels.misc_capacity,
+    }
+}
+
+/// Priority of the stream for events of `kind`, motion going ahead of keys ahead of the rest.
+fn priority(kind: Option<EventKind>) -> i32 {
+    match kind {
+        Some(EventKind::Mouse) => 2,
+        Some(EventKind::Keyboard) => 1,
+        Some(EventKind::Misc) | None => 0,
+    }
+}
+
+/// What to do for clients falling behind on events of `kind`.
+fn overflow(kind: EventKind) -> (usize, OverflowPolicy) {
+    let channels = &config::current().channels;
+    match kind {
+        EventKind::Mouse => (channels.mouse_capacity, channels.mouse_overflow),
+        EventKind::Keyboard => (channels.keyboard_capacity, channels.keyboard_overflow),
+        EventKind::Misc => (channels.misc_capacity, channels.misc_overflow),
+    }
+}
+
+async fn activity_rx_task(id: usize, mut stream: RecvStream) -> Result<()> {
+    loop {
+        let report = read_packet(&mut stream, MAX_CONTROL_LEN).await?;
+        let report = ActivityReport::from_slice(&report)?;
+
+        clients::set_activity(id, report.idle_ms.map(Duration::from_millis));
+    }
+}
+
+async fn ack_rx_task(id: usize, mut stream: RecvStream) -> Result<()> {
+    loop {
+        let ack = read_packet(&mut stream, MAX_CONTROL_LEN).await?;
+        let ack = Ack::from_slice(&ack)?;
+
+        delivery::acked(id, ack.id);
+    }
+}
+
+async fn screens_rx_task(id: usize, mut stream: RecvStream) -> Result<()> {
+    loop {
+        let screens = read_packet(&mut stream, MAX_CONTROL_LEN).await?;
+        let screens = ScreenLayout::from_slice(&screens)?;
+
+        log::info!("Client monitors changed: {:?}", screens.monitors);
+        clients::set_screens(id, screens);
+    }
+}
+
+async fn pointer_rx_task(id: usize, mut stream: RecvStream) -> Result<()> {
+    loop {
+        let mode = read_packet(&mut stream, MAX_CONTROL_LEN).await?;
+        let mode = PointerMode::from_slice(&mode)?;
+
+        if mode.relative_only {
+            log::info!("Client locked its pointer, sending relative motion only");
+        } else {
+            log::info!("Client released its pointer");
+        }
+        clients::set_relative_only(id, mode.relative_only);
+    }
+}
+
+/// Writes the warnings and errors the client relays to our log, tagged with its name, at
+/// most [`MAX_CLIENT_LOGS`] each [`CLIENT_LOGS_WINDOW`].
+async fn logs_rx_task(client: &str, mut stream: RecvStream) -> Result<()> {
+    let mut window = Instant::now();
+    let (mut written, mut dropped) = (0, 0);
+
+    loop {
+        let record = read_packet(&mut stream, MAX_CONTROL_LEN).await?;
+        let record = LogRecord::from_slice(&record)?;
+        if !config::current().client_logs {
+            continue;
+        }
+
+        if window.elapsed() >= CLIENT_LOGS_WINDOW {
+            if dropped > 0 {
+                log::warn!("[{}] {} more records dropped", client, dropped);
+            }
+            window = Instant::now();
+            (written, dropped) = (0, 0);
+        }
+        if written == MAX_CLIENT_LOGS {
+            dropped += 1;
+            continue;
+        }
+        written += 1;
+
+        let level = match record.level {
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Error => log::Level::Error,
+        };
+        let (target, message) = (sanitize(&record.target), sanitize(&record.message));
+        log::log!(level, "[{}] {}: {}", client, target, message);
+    }
+}
+
+/// `text` from a client without the control characters that could forge log lines or
+/// terminal output.
+fn sanitize(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+/// Logs that `client` couldn't inject input, showing a notification too if configured.
+async fn inject_error_rx_task(client: &str, mut stream: RecvStream, notify: bool) -> Result<()> {
+    let error = read_packet(&mut stream, MAX_CONTROL_LEN).await?;
+    let error = InjectError::from_slice(&error)?;
+
+    let summary = if error.refused {
+        format!("{} refused input (UAC prompt active?)", client)
+    } else {
+        format!("{} failed to inject input", client)
+    };
+    log::warn!("{}: {}", summary, error.message);
+    if notify {
+        if let Err(e) = notify::notify(&summary, &error.message).await {
+            log::debug!("Failed to show notification: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Answers the client's [`TimeProbe`]s with our clock, until it has enough of them.
+async fn time_sync_task(mut reply: SendStream, mut stream: RecvStream) -> Result<()> {
+    loop {
+        let probe = read_packet(&mut stream, MAX_CONTROL_LEN).await?;
+        let mut probe = TimeProbe::from_slice(&probe)?;
+        probe.server_us = clock_us();
+        write_packet(&mut reply, &probe.to_vec()).await?;
+    }
+}
+
+/// Injects input the client captured from its own keyboard and mouse.
+async fn reverse_rx_task(mut stream: RecvStream) -> Result<()> {
+    let mut input = VirtualInput::new().context("Create virtual input device")?;
+    log::info!("Client took control of this machine");
+
+    loop {
+        let packet = read_packet(&mut stream, MAX_CONTROL_LEN).await?;
+        let packet = Packet::from_slice(&packet)?;
+
+        if packet.event.is_high_freq() {
+            log::trace!("Injecting event {}: {:?}", packet.id, packet.event);
+        } else {
+            log::debug!("Injecting event {}: {:?}", packet.id, packet.event);
+        }
+
+        input.inject(&packet.event)?;
+    }
+}
+
+/// Receives files the client copied and puts them on our clipboard.
+async fn files_rx_task(
+    id: usize,
+    send: SendStream,
+    recv: RecvStream,
+    config: &Config,
+    client: &str,
+    clipboard: Option<ClipboardHandle>,
+) -> Result<()> {
+    let progress = |p: &TransferProgress| {
+        if p.file_done() {
+            log::info!(
+                "Received {} ({} of {} bytes total)",
+                p.path,
+                p.total_done,
+                p.total_size
+            );
+        } else {
+            log::trace!("Receiving {}: {} of {} bytes", p.path, p.done, p.size);
+        }
+    };
+
+    let owner = match clients::uuid(id) {
+        Some(uuid) => uuid.to_string(),
+        None => format!("connection-{}", id),
+    };
+
+    let paths = files::receive(send, recv, config, client, &owner, progress).await?;
+    if paths.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(clipboard) = clipboard {
+        clipboard.set_files(paths).await;
+    }
+
+    Ok(())
+}
+
+/// Puts the clipboard the client was asked for on ours.
+async fn clipboard_rx_task(
+    id: usize,
+    client: &str,
+    mut stream: RecvStream,
+    clipboard: ClipboardHandle,
+) -> Result<()> {
+    let packet = read_packet(&mut stream, MAX_CLIPBOARD_LEN).await?;
+    let packet = Packet::from_slice(&packet)?;
+
+    if !clients::is_active(id) {
+        log::warn!("Ignoring clipboard of {}, which input isn't on", client);
+        return Ok(());
+    }
+    clipboard.set_pulled(client, packet.event).await;
+
+    Ok(())
+}
+
+/// Sends the clipboard contents behind an offer the client accepted.
+async fn fetch_task(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    clipboard: ClipboardHandle,
+    bandwidth_limit: Option<u64>,
+) -> Result<()> {
+    let fetch = read_packet(&mut recv, MAX_CONTROL_LEN).await?;
+    let fetch = ClipboardFetch::from_slice(&fetch)?;
+
+    let packet = match clipboard.fetch(fetch.id).await {
+        Some(packet) => packet,
+        None => {
+            log::warn!(
+                "Client fetched clipboard offer {}, which was replaced",
+                fetch.id
+            );
+            return Ok(());
+        }
+    };
+
+    // Below the input streams, so a large paste doesn't lag the cursor
+    send.set_priority(-1)?;
+    send.write_u32(packet.len() as u32).await?;
+
+    let mut throttle = Throttle::new(bandwidth_limit);
+    for chunk in packet.chunks(FILE_CHUNK_LEN) {
+        tokio::time::sleep(throttle.delay(chunk.len())).await;
+        send.write_all(chunk).await?;
+    }
+    send.finish().await?;
+
+    Ok(())
+}
+
+/// Sends a file of a forwarded drag, raw, as the client drops or pastes it.
+async fn drag_fetch_task(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    clipboard: ClipboardHandle,
+    bandwidth_limit: Option<u64>,
+) -> Result<()> {
+    let fetch = read_packet(&mut recv, MAX_CONTROL_LEN).await?;
+    let fetch = DragFetch::from_slice(&fetch)?;
+
+    let path = match clipboard.drag_file(fetch.id, fetch.index).await {
+        Some(path) => path,
+        None => {
+            log::warn!(
+                "Client fetched entry {} of drag {}, which is gone",
+                fetch.index,
+                fetch.id
+            );
+            return Ok(());
+        }
+    };
+    let mut file = tokio::fs::File::open(&path)
+        .await
+        .with_context(|| format!("Open {}", path.display()))?;
+
+    send.set_priority(-1)?;
+
+    let mut throttle = Throttle::new(bandwidth_limit);
+    let mut buf = vec![0u8; FILE_CHUNK_LEN];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        tokio::time::sleep(throttle.delay(n)).await;
+        send.write_all(&buf[..n]).await?;
+    }
+    send.finish().await?;
+
+    Ok(())
+}
+
+/// Accepts the streams a client opens towards us, each tagged with what it carries.
+///
+/// File transfers need replies, so they come on bidirectional streams, everything else
+/// on unidirectional ones. Every stream is dispatched on its own task, so one that is slow
+/// to say what it carries, or fails, holds up nothing else.
+async fn upstream_task(
+    conn: Connection,
+    client: String,
+    clipboard: Option<ClipboardHandle>,
+) -> Result<()> {
+    let id = conn.stable_id();
+
+    loop {
+        let (reply, stream) = tokio::select! {
+            stream = conn.accept_uni() => (None, stream.context("Accept upstream")?),
+            stream = conn.accept_bi() => {
+                let (send, recv) = stream.context("Accept upstream")?;
+                (Some(send), recv)
+            }
+        };
+
+        let client = client.clone();
+        let clipboard = clipboard.clone();
+        tokio::spawn(
+            async move {
+                if let Err(e) = dispatch_upstream(id, &client, reply, stream, clipboard).await {
+                    log::error!("Error reading upstream kind: {}", e);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// Reads what an upstream stream carries and hands it to the task for that.
+async fn dispatch_upstream(
+    id: usize,
+    client: &str,
+    reply: Option<SendStream>,
+    mut stream: RecvStream,
+    clipboard: Option<ClipboardHandle>,
+) -> Result<()> {
+    let kind = read_packet(&mut stream, MAX_CONTROL_LEN).await?;
+    let kind = match UpstreamKind::from_slice(&kind) {
+        Ok(kind) => kind,
+        Err(_) => {
+            // Sent by a newer client, which copes with us not knowing
+            log::warn!("Ignoring stream of an unknown kind");
+            return Ok(());
+        }
+    };
+    // Streams opened after a reload follow the new config
+    let config = config::current();
+
+    match (kind, reply) {
+        (UpstreamKind::Activity, None) => {
+            if let Err(e) = activity_rx_task(id, stream).await {
+                log::error!("Error handling activity rx: {}", e);
+            }
+        }
+        (UpstreamKind::Acks, None) => {
+            if let Err(e) = ack_rx_task(id, stream).await {
+                log::error!("Error handling ack rx: {}", e);
+            }
+        }
+        (UpstreamKind::Screens, None) => {
+            if let Err(e) = screens_rx_task(id, stream).await {
+                log::error!("Error handling screens rx: {}", e);
+            }
+        }
+        (UpstreamKind::PointerMode, None) => {
+            if let Err(e) = pointer_rx_task(id, stream).await {
+                log::error!("Error handling pointer mode rx: {}", e);
+            }
+        }
+        (UpstreamKind::Logs, None) => {
+            if let Err(e) = logs_rx_task(client, stream).await {
+                log::debug!("Stopped relaying client logs: {}", e);
+            }
+        }
+        (UpstreamKind::Attention, None) => crate::attention::received(client).await,
+        (UpstreamKind::InjectError, None) => {
+            if let Err(e) = inject_error_rx_task(client, stream, config.notify_inject_errors).await
+            {
+                log::error!("Error reading injection error: {}", e);
+            }
+        }
+        (UpstreamKind::TimeSync, Some(reply)) => {
+            if let Err(e) = time_sync_task(reply, stream).await {
+                log::debug!("Stopped answering clock sync: {}", e);
+            }
+        }
+        (UpstreamKind::Input, None) => {
+            if !config.allow_reverse_control {
+                log::warn!("Client tried to control this machine, but reverse control is disabled");
+                return Ok(());
+            }
+
+            if let Err(e) = reverse_rx_task(stream).await {
+                log::error!("Error handling input rx: {}", e);
+            }
+        }
+        (UpstreamKind::Files, Some(reply)) => {
+            if let Err(e) = files_rx_task(id, reply, stream, &config, client, clipboard).await {
+                log::error!("Error receiving files: {}", e);
+            }
+        }
+        (UpstreamKind::Clipboard, None) => {
+            let clipboard = match clipboard {
+                Some(clipboard) => clipboard,
+                None => return Ok(()),
+            };
+            if let Err(e) = clipboard_rx_task(id, client, stream, clipboard).await {
+                log::error!("Error receiving client clipboard: {}", e);
+            }
+        }
+        (UpstreamKind::ClipboardFetch, Some(reply)) => {
+            let clipboard = match clipboard {
+                Some(clipboard) => clipboard,
+                None => return Ok(()),
+            };
+            if let Err(e) = fetch_task(reply, stream, clipboard, config.bandwidth_limit).await {
+                log::error!("Error sending offered clipboard: {}", e);
+            }
+        }
+        (UpstreamKind::DragFetch, Some(reply)) => {
+            let clipboard = match clipboard {
+                Some(clipboard) => clipboard,
+                None => return Ok(()),
+            };
+            if let Err(e) = drag_fetch_task(reply, stream, clipboard, config.bandwidth_limit).await
+            {
+                log::error!("Error sending dragged file: {}", e);
+            }
+        }
+        (kind, _) => log::warn!("Ignoring {:?} stream opened in the wrong direction", kind),
+    }
+
+    Ok(())
+}
+
+/// Opens a stream to the client, tagged with what it carries.
+pub async fn open_downstream(conn: &Connection, tag: StreamTag) -> Result<SendStream> {
+    let mut stream = conn.open_uni().await?;
+    stream.write_u8(tag as u8).await?;
+
+    Ok(stream)
+}
+
 async fn write_packet<W: AsyncWrite + Unpin>(writer: &mut W, packet: &[u8]) -> Result<()> {
     writer.write_u32(packet.len() as u32).await?;
     writer.write_all(packet).await?;
@@ -31,91 +604,752 @@ async fn write_packet<W: AsyncWrite + Unpin>(writer: &mut W, packet: &[u8]) -> R
     Ok(())
 }
 
-pub async fn sender(mut rx: tokio::sync::mpsc::Receiver<Packet>) {
-    while let Some(packet) = rx.recv().await {
-        if packet.event.is_high_freq() {
-            log::trace!("Sending event {}: {:?}", packet.id, packet.event);
-        } else {
-            log::debug!("Sending event {}: {:?}", packet.id, packet.event);
+/// Writes a packet encoded with [`Packet::to_vec`] with `seq` in place of its own, so one
+/// encoding serves every client.
+async fn write_sequenced<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    packet: &[u8],
+    seq: u64,
+) -> Result<()> {
+    let (before, after) = Packet::around_seq(packet);
+    writer.write_u32(packet.len() as u32).await?;
+    writer.write_all(before).await?;
+    writer.write_u64_le(seq).await?;
+    writer.write_all(after).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R, max_len: u32) -> Result<Vec<u8>> {
+    let len = reader.read_u32().await?;
+    if len > max_len {
+        anyhow::bail!("Packet too large: {} bytes", len);
+    }
+
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf).await?;
+
+    Ok(buf)
+}
+
+/// Waits for the client's hello on the control stream and checks its pre-shared key proof,
+/// holding clients never seen before until they are approved.
+///
+/// Returns the hello, or `None` if the client must be turned away.
+async fn handshake(conn: &Connection, config: &Config) -> Result<Option<ClientHello>> {
+    let (mut control_tx, hello) = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let (control_tx, mut control_rx) = conn.accept_bi().await.context("Accept control")?;
+        let hello = read_packet(&mut control_rx, MAX_CONTROL_LEN).await?;
+        anyhow::Ok((control_tx, hello))
+    })
+    .await
+    .context("Handshake timed out")??;
+    let version = ClientHello::version_of(&hello);
+    if version != Some(rkvm_protocol::PROTOCOL_VERSION) {
+        log::warn!(
+            "Client speaks protocol version {:?}, not {}, turning it away",
+            version,
+            rkvm_protocol::PROTOCOL_VERSION
+        );
+        let reply = ServerHello::Incompatible {
+            version: rkvm_protocol::PROTOCOL_VERSION,
+        };
+        write_packet(&mut control_tx, &reply.to_vec()).await?;
+        return Ok(None);
+    }
+    let mut hello = ClientHello::from_slice(&hello)?;
+    if let Some(name) = known::rename_of(hello.id) {
+        hello.name = name;
+    }
+
+    let fingerprint = crate::identity::peer_fingerprint(conn);
+    let accepted = match (&config.psk, &hello.auth) {
+        // It would share slot, approval and revocation with every other one
+        _ if hello.id.is_nil() => {
+            log::warn!("Client {} sent no stable id", hello.name);
+            false
+        }
+        _ if known::is_revoked(hello.id, fingerprint.as_deref()) => {
+            log::warn!("Client {} with id {} was revoked", hello.name, hello.id);
+            false
         }
+        (None, _) => true,
+        (Some(_), None) => {
+            log::warn!("Client did not provide a pre-shared key");
+            false
+        }
+        (Some(psk), Some(proof)) => {
+            let mut exporter = [0u8; rkvm_protocol::AUTH_PROOF_LEN];
+            conn.export_keying_material(&mut exporter, rkvm_protocol::AUTH_EXPORTER_LABEL, b"")
+                .map_err(|_| anyhow::anyhow!("Failed to export keying material"))?;
 
-        match packet.event.kind() {
-            rkvm_protocol::EventKind::Mouse => {
-                let _ = MOUSE_CHANNEL.send(packet.to_vec().into());
+            let valid = rkvm_protocol::verify_auth_proof(psk.as_bytes(), &exporter, proof);
+            if !valid {
+                log::warn!("Client provided a wrong pre-shared key");
             }
-            rkvm_protocol::EventKind::Keyboard => {
-                let _ = KEYBOARD_CHANNEL.send(packet.to_vec().into());
+            valid
+        }
+    };
+
+    // Before the reply, which carries the clipboard key
+    let accepted = if accepted && crate::approval::needed(config, hello.id, fingerprint.as_deref())
+    {
+        let timeout = Duration::from_secs(config.approval_timeout_secs);
+        let approval = crate::approval::wait(
+            conn.stable_id(),
+            &hello,
+            conn.remote_address(),
+            fingerprint.as_deref(),
+            timeout,
+        );
+        tokio::select! {
+            approved = approval => approved,
+            _ = conn.closed() => false,
+        }
+    } else {
+        accepted
+    };
+
+    let reply = if accepted {
+        let keys = [
+            hello.name.clone(),
+            hello.id.to_string(),
+            conn.remote_address().ip().to_string(),
+        ];
+        let layout = config.keyboard_layout_for(&keys).map(str::to_owned);
+        if let (Some(theirs), Some(ours)) = (&hello.layout, &layout) {
+            if !theirs.eq_ignore_ascii_case(ours) {
+                log::info!(
+                    "Client types in keyboard layout {}, switching to {}",
+                    theirs,
+                    ours
+                );
             }
-            rkvm_protocol::EventKind::Misc => {
-                let _ = MISC_CHANNEL.send(packet.to_vec().into());
+        }
+
+        let ours = TIMEOUTS.get().copied().unwrap_or_default();
+        let timeouts = ours.negotiate(&hello.timeouts);
+        log::info!("Negotiated {}", timeouts);
+        if hello.single_stream {
+            log::info!("Sending all events on one stream");
+        }
+
+        ServerHello::Accepted {
+            layout,
+            clipboard_key: clipboard::key(),
+            timeouts,
+            single_stream: hello.single_stream,
+            xkb_layout: crate::xkb::current(),
+        }
+    } else {
+        ServerHello::Rejected
+    };
+    write_packet(&mut control_tx, &reply.to_vec()).await?;
+
+    Ok(accepted.then_some(hello))
+}
+
+/// Packets telling a client that just connected how things stand, to go before the rest on
+/// the stream for `kind`.
+///
+/// Modifier state goes on the keyboard stream, so it can't overtake the key releases.
+async fn state_bundle(
+    id: usize,
+    kind: Option<EventKind>,
+    clipboard: Option<&ClipboardHandle>,
+) -> Vec<Packet> {
+    let mut packets = Vec::new();
+    if matches!(kind, None | Some(EventKind::Keyboard)) {
+        packets.push(Packet::new(0, state(id)));
+    }
+
+    if let Some(offer) = match clipboard {
+        Some(clipboard) if matches!(kind, None | Some(EventKind::Misc)) => {
+            clipboard.last_offer().await
+        }
+        _ => None,
+    } {
+        packets.push(Packet::new(0, offer));
+    }
+
+    packets
+}
+
+fn state(id: usize) -> rkvm_protocol::Event {
+    let config = config::current();
+    // Lit by the grab indicator, so it says nothing about the lock
+    let lit = |led, indicator| grab::is_lit(led) && config.grab_indicator != Some(indicator);
+
+    let modifiers = grab::held_keys()
+        .into_iter()
+        .filter_map(|key| KeyMap::from_key_mapping(KeyMapping::Evdev(key)).ok())
+        // The hotkey, which clients only get while it passes through
+        .filter(|keymap| keymap.modifier.is_some() && keymap.id != config.hotkey.key)
+        .map(|keymap| keymap.win)
+        .collect();
+
+    rkvm_protocol::Event::State {
+        grabbed: grab::is_grabbed(),
+        active: clients::is_active(id),
+        active_client: clients::active(),
+        modifiers,
+        caps_lock: lit(grab::LED_CAPSL, GrabIndicator::CapsLock),
+        num_lock: lit(grab::LED_NUML, GrabIndicator::NumLock),
+        scroll_lock: lit(grab::LED_SCROLLL, GrabIndicator::ScrollLock),
+    }
+}
+
+pub async fn sender(mut rx: tokio::sync::mpsc::Receiver<Packet>, mut plugins: Plugins) {
+    let mut filtered = Vec::new();
+
+    while let Some(packet) = rx.recv().await {
+        plugins.apply(packet, &mut filtered);
+        for packet in filtered.drain(..) {
+            send(packet).await;
+        }
+    }
+}
+
+async fn send(mut packet: Packet) {
+    packet.time = clock_us();
+
+    let kind = packet.event.kind();
+    let tracked = if kind == EventKind::Misc {
+        packet.id = delivery::next_id();
+        Some((packet.id, delivery::describe(&packet.event)))
+    } else {
+        None
+    };
+
+    if packet.event.is_high_freq() {
+        log::trace!("Sending event {}: {:?}", packet.id, packet.event);
+    } else {
+        log::debug!("Sending event {}: {:?}", packet.id, packet.event);
+    }
+
+    let outgoing = Outgoing {
+        kind,
+        audience: match packet.event {
+            rkvm_protocol::Event::Park => Audience::Idle,
+            rkvm_protocol::Event::GameMode { .. }
+            | rkvm_protocol::Event::Suspend
+            | rkvm_protocol::Event::KeyboardLayout { .. } => Audience::Everyone,
+            _ => Audience::Active,
+        },
+        data: packet.to_vec().into(),
+        tracked,
+    };
+
+    let motion = matches!(packet.event, rkvm_protocol::Event::MouseMotion { .. });
+    if motion && crate::controller::game_mode() {
+        // Single stream clients keep it in order with everything else instead
+        if COMBINED_CHANNEL.len() < combined_capacity(kind) {
+            let _ = COMBINED_CHANNEL.send(outgoing.clone());
+        }
+        let _ = DATAGRAM_CHANNEL.send(outgoing);
+        return;
+    }
+
+    let channel = channel(kind);
+    // Waits for the slowest client to make room instead of letting it skip events
+    while let (capacity, OverflowPolicy::Block) = overflow(kind) {
+        // Listening before checking, so room made in between isn't missed
+        let room = ROOM.notified();
+        tokio::pin!(room);
+        room.as_mut().enable();
+
+        if channel.len() < capacity && COMBINED_CHANNEL.len() < combined_capacity(kind) {
+            break;
+        }
+        room.await;
+    }
+
+    // Motion past its share of the single stream is skipped there, instead of pushing out
+    // the keys and clipboard events queued with it
+    let skipped = kind == EventKind::Mouse && COMBINED_CHANNEL.len() >= combined_capacity(kind);
+    let combined = !skipped && COMBINED_CHANNEL.send(outgoing.clone()).is_ok();
+    if channel.send(outgoing).is_err() && !combined {
+        if let Some((_, what)) = tracked {
+            log::warn!("{} not delivered: no client connected", what);
+        }
+    }
+}
+
+/// Like [`sender`] without any clients, for dry runs.
+pub async fn log_only(mut rx: tokio::sync::mpsc::Receiver<Packet>, mut plugins: Plugins) {
+    let mut filtered = Vec::new();
+
+    while let Some(packet) = rx.recv().await {
+        plugins.apply(packet, &mut filtered);
+        for packet in filtered.drain(..) {
+            if packet.event.is_high_freq() {
+                log::debug!("Would send event {}: {:?}", packet.id, packet.event);
+            } else {
+                log::info!("Would send event {}: {:?}", packet.id, packet.event);
             }
         }
     }
 }
 
+/// Writes events of `kind` to `stream`, or of every kind if `None`.
 async fn tx_task(
-    conn: SendStream,
-    mut sub: tokio::sync::broadcast::Receiver<Arc<[u8]>>,
+    id: usize,
+    conn: Connection,
+    stream: SendStream,
+    kind: Option<EventKind>,
+    stats: Arc<ConnStats>,
+) -> Result<()> {
+    // Dropped after the subscription, once its events no longer take up room
+    let _wake = WakeOnDrop;
+    let mut sub = kind.map_or(&*COMBINED_CHANNEL, channel).subscribe();
+    // Motion never fills a single stream past its share, so skipping there loses more
+    let overflow_kind = kind.unwrap_or(EventKind::Misc);
+    let mut stream = BufWriter::new(stream);
+    let mut seq = 0;
+    let mut reopens = Reopens::default();
+    // Read while merging motion, to be sent next
+    let mut pending = None;
+
+    loop {
+        let received = match pending.take() {
+            Some(outgoing) => Ok(outgoing),
+            None => sub.recv().await,
+        };
+        ROOM.notify_waiters();
+        let outgoing = match received {
+            Ok(outgoing) => outgoing,
+            Err(RecvError::Lagged(skipped)) => {
+                // Counted here, as they never went out to look lost on the way
+                stats.lagged(skipped);
+                match overflow(overflow_kind).1 {
+                    OverflowPolicy::Fail => {
+                        conn.close(2u32.into(), b"Fell behind");
+                        anyhow::bail!("Fell {} events behind", skipped);
+                    }
+                    _ if overflow_kind == EventKind::Misc => {
+                        log::error!(
+                            "{} clipboard or control events not delivered: client fell behind",
+                            skipped
+                        );
+                        continue;
+                    }
+                    _ => {
+                        log::warn!(
+                            "Fell behind, skipped {} {:?} events",
+                            skipped,
+                            overflow_kind
+                        );
+                        continue;
+                    }
+                }
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let packet = outgoing.data;
+        if !outgoing.audience.includes(id) {
+            continue;
+        }
+
+        let mut data = packet.clone();
+        // Stops at the first packet that isn't motion, so a single stream stays in order
+        if matches!(kind, Some(EventKind::Mouse) | None) {
+            if let Some(window) = quality::coalesce_window(id) {
+                let (next, skipped) = merge_motion(id, &mut sub, &mut data, window).await;
+                pending = next;
+                stats.lagged(skipped);
+            }
+        }
+
+        seq += 1;
+
+        let tx_bytes = delivery::tx_bytes(&conn);
+        if let Err(e) = write_sequenced(&mut stream, &data, seq).await {
+            stream = reopen(id, &conn, kind, e, &mut reopens, &mut seq).await?;
+            write_sequenced(&mut stream, &data, seq).await?;
+        }
+        stats.sent(outgoing.kind);
+        // Resent without a seq, which would look out of order on a stream of its own
+        if let Some((packet_id, what)) = outgoing.tracked {
+            delivery::sent(conn.clone(), packet_id, packet, tx_bytes, what);
+        }
+    }
+
+    Ok(())
+}
+
+/// Opens a stream in place of the one for events of `kind` that failed with `error`, unless
+/// the connection is gone.
+///
+/// What was on the way got lost with it, so the state goes out again first, numbered from
+/// `seq` of the packet that failed, which is moved past them. Clipboard and control events
+/// are sent again once they go unacked.
+async fn reopen(
+    id: usize,
+    conn: &Connection,
+    kind: Option<EventKind>,
+    error: anyhow::Error,
+    reopens: &mut Reopens,
+    seq: &mut u64,
+) -> Result<BufWriter<SendStream>> {
+    if conn.close_reason().is_some() || !reopens.allow() {
+        return Err(error);
+    }
+
+    let name = match kind {
+        Some(EventKind::Mouse) => "mouse",
+        Some(EventKind::Keyboard) => "keyboard",
+        Some(EventKind::Misc) => "misc",
+        None => "events",
+    };
+    log::warn!("The {} stream failed, reopening it: {}", name, error);
+
+    let stream = open_downstream(conn, StreamTag::events(kind))
+        .await
+        .context("Reopen tx")?;
+    stream.set_priority(priority(kind))?;
+    let mut stream = BufWriter::new(stream);
+    for mut packet in state_bundle(id, kind, None).await {
+        packet.seq = *seq;
+        *seq += 1;
+        write_packet(&mut stream, &packet.to_vec()).await?;
+    }
+    Ok(stream)
+}
+
+/// Merges into `packet`, if it is mouse motion, the motion for `id` arriving within
+/// `window`.
+///
+/// Returns the first packet that couldn't be merged, and how many were skipped as the
+/// connection fell behind.
+async fn merge_motion(
+    id: usize,
+    sub: &mut Receiver<Outgoing>,
+    packet: &mut Arc<[u8]>,
+    window: Duration,
+) -> (Option<Outgoing>, u64) {
+    let mut merged = match Packet::from_slice(packet) {
+        Ok(merged) if matches!(merged.event, rkvm_protocol::Event::MouseMotion { .. }) => merged,
+        _ => return (None, 0),
+    };
+
+    tokio::time::sleep(window).await;
+
+    let mut next = None;
+    let mut skipped = 0;
+    let mut count = 0;
+    loop {
+        let outgoing = match sub.try_recv() {
+            Ok(outgoing) => outgoing,
+            Err(TryRecvError::Lagged(lagged)) => {
+                skipped += lagged;
+                continue;
+            }
+            Err(_) => break,
+        };
+        if !outgoing.audience.includes(id) {
+            continue;
+        }
+
+        let motion = match Packet::from_slice(&outgoing.data) {
+            Ok(packet) => match packet.event {
+                rkvm_protocol::Event::MouseMotion { dx, dy } => Some((dx, dy, packet.time)),
+                _ => None,
+            },
+            Err(_) => None,
+        };
+        let (dx, dy, time) = match motion {
+            Some(motion) => motion,
+            None => {
+                next = Some(outgoing);
+                break;
+            }
+        };
+
+        if let rkvm_protocol::Event::MouseMotion { dx: x, dy: y } = &mut merged.event {
+            *x = x.saturating_add(dx);
+            *y = y.saturating_add(dy);
+        }
+        // Stamped like the latest, so the client doesn't take it for stale
+        merged.time = time;
+        count += 1;
+    }
+
+    ROOM.notify_waiters();
+    if count > 0 {
+        log::trace!("Merged {} mouse motion packets", count + 1);
+        *packet = merged.to_vec().into();
+    }
+    (next, skipped)
+}
+
+/// Tells the client whenever input starts or stops going to it, on a stream of its own.
+///
+/// Clients only get events while input goes to them, so this can't share their streams.
+async fn target_tx_task(id: usize, mut stream: SendStream) -> Result<()> {
+    let mut changes = clients::subscribe_target();
+    let mut targeted = false;
+
+    loop {
+        changes.borrow_and_update();
+
+        let active = grab::is_grabbed() && clients::is_active(id);
+        if active != targeted {
+            let event = rkvm_protocol::Event::Target { active };
+            write_packet(&mut stream, &Packet::new(0, event).to_vec()).await?;
+            targeted = active;
+        }
+
+        if changes.changed().await.is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Like [`tx_task`], but unreliable and unordered, so a lost packet never holds up later ones.
+///
+/// Packets that can't go as datagrams, as the client turned them off or they are too large,
+/// go on a mouse stream of their own instead, outside of the sequence.
+async fn datagram_tx_task(
+    id: usize,
+    conn: Connection,
+    mut sub: tokio::sync::broadcast::Receiver<Outgoing>,
+    stats: Arc<ConnStats>,
 ) -> Result<()> {
-    let mut conn = BufWriter::new(conn);
+    let mut seq = 0;
+    let mut fallback = None;
+
+    loop {
+        let outgoing = match sub.recv().await {
+            Ok(outgoing) => outgoing,
+            // Would have been dropped on the way anyway
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+        if !outgoing.audience.includes(id) {
+            continue;
+        }
+
+        let mut data = outgoing.data.to_vec();
+        Packet::set_seq(&mut data, seq + 1);
+
+        match conn.send_datagram(data.into()) {
+            Ok(()) => {
+                seq += 1;
+                stats.sent_datagram();
+                continue;
+            }
+            Err(SendDatagramError::ConnectionLost(e)) => return Err(e.into()),
+            Err(e) => log::debug!("Sending motion on a stream, not as a datagram: {}", e),
+        }
 
-    while let Ok(packet) = sub.recv().await {
-        write_packet(&mut conn, &packet).await?;
+        let stream = match &mut fallback {
+            Some(stream) => stream,
+            None => {
+                let stream = open_downstream(&conn, StreamTag::Mouse)
+                    .await
+                    .context("Open datagram fallback tx")?;
+                stream.set_priority(priority(Some(EventKind::Mouse)))?;
+                fallback.insert(BufWriter::new(stream))
+            }
+        };
+        write_packet(stream, &outgoing.data).await?;
+        stats.sent(outgoing.kind);
     }
 
     Ok(())
 }
 
-async fn handle_conn(conn: Connecting) -> Result<()> {
+async fn handle_conn(conn: Connecting, clipboard: Option<ClipboardHandle>) -> Result<()> {
     let conn = conn.await?;
 
     let span = tracing::info_span!(
         "connection",
         remote = %conn.remote_address(),
         id = %conn.stable_id(),
+        client = tracing::field::Empty,
     );
     let _guard = span.enter();
 
     log::info!("New connection");
 
-    let mouse_tx = conn.open_uni().await.context("Open mouse tx")?;
-    mouse_tx.set_priority(2)?;
-    tokio::spawn(async move {
-        let sub = MOUSE_CHANNEL.subscribe();
+    let hello = if let Some(hello) = handshake(&conn, &config::current()).await? {
+        hello
+    } else {
+        conn.close(1u32.into(), b"Authentication failed");
+        return Ok(());
+    };
+    span.record("client", hello.name.as_str());
+
+    let id = conn.stable_id();
+    let start = Instant::now();
+    let stats = Arc::new(ConnStats::default());
+
+    if !hello.single_stream {
+        let datagram_conn = conn.clone();
+        let datagram_stats = stats.clone();
+        tokio::spawn(
+            async move {
+                let sub = DATAGRAM_CHANNEL.subscribe();
 
-        if let Err(e) = tx_task(mouse_tx, sub).await {
-            log::error!("Error handling mouse tx: {}", e);
+                if let Err(e) = datagram_tx_task(id, datagram_conn, sub, datagram_stats).await {
+                    log::error!("Error handling datagram tx: {}", e);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    if hello.single_stream {
+        let mut events_tx = open_downstream(&conn, StreamTag::Events)
+            .await
+            .context("Open events tx")?;
+        for packet in state_bundle(id, None, clipboard.as_ref()).await {
+            write_packet(&mut events_tx, &packet.to_vec()).await?;
         }
-    }.in_current_span());
+        let events_conn = conn.clone();
+        let events_stats = stats.clone();
+        tokio::spawn(
+            async move {
+                if let Err(e) = tx_task(id, events_conn, events_tx, None, events_stats).await {
+                    log::error!("Error handling events tx: {}", e);
+                }
+            }
+            .in_current_span(),
+        );
+    } else {
+        let mouse_tx = open_downstream(&conn, StreamTag::Mouse)
+            .await
+            .context("Open mouse tx")?;
+        mouse_tx.set_priority(priority(Some(EventKind::Mouse)))?;
+        let mouse_conn = conn.clone();
+        let mouse_stats = stats.clone();
+        tokio::spawn(
+            async move {
+                let kind = Some(EventKind::Mouse);
 
-    let keyboard_tx = conn.open_uni().await.context("Open keyboard tx")?;
-    keyboard_tx.set_priority(1)?;
-    tokio::spawn(async move {
-        let sub = KEYBOARD_CHANNEL.subscribe();
+                if let Err(e) = tx_task(id, mouse_conn, mouse_tx, kind, mouse_stats).await {
+                    log::error!("Error handling mouse tx: {}", e);
+                }
+            }
+            .in_current_span(),
+        );
 
-        if let Err(e) = tx_task(keyboard_tx, sub).await {
-            log::error!("Error handling keyboard tx: {}", e);
+        let mut keyboard_tx = open_downstream(&conn, StreamTag::Keyboard)
+            .await
+            .context("Open keyboard tx")?;
+        keyboard_tx.set_priority(priority(Some(EventKind::Keyboard)))?;
+        for packet in state_bundle(id, Some(EventKind::Keyboard), None).await {
+            write_packet(&mut keyboard_tx, &packet.to_vec()).await?;
         }
-    }.in_current_span());
+        let keyboard_conn = conn.clone();
+        let keyboard_stats = stats.clone();
+        tokio::spawn(
+            async move {
+                let kind = Some(EventKind::Keyboard);
 
-    let misc_tx = conn.open_uni().await.context("Open misc tx")?;
-    misc_tx.set_priority(0)?;
-    tokio::spawn(async move {
-        let sub = MISC_CHANNEL.subscribe();
+                if let Err(e) = tx_task(id, keyboard_conn, keyboard_tx, kind, keyboard_stats).await
+                {
+                    log::error!("Error handling keyboard tx: {}", e);
+                }
+            }
+            .in_current_span(),
+        );
 
-        if let Err(e) = tx_task(misc_tx, sub).await {
-            log::error!("Error handling misc tx: {}", e);
+        let mut misc_tx = open_downstream(&conn, StreamTag::Misc)
+            .await
+            .context("Open misc tx")?;
+        misc_tx.set_priority(priority(Some(EventKind::Misc)))?;
+        for packet in state_bundle(id, Some(EventKind::Misc), clipboard.as_ref()).await {
+            write_packet(&mut misc_tx, &packet.to_vec()).await?;
         }
-    }.in_current_span());
+        let misc_conn = conn.clone();
+        let misc_stats = stats.clone();
+        tokio::spawn(
+            async move {
+                let kind = Some(EventKind::Misc);
+
+                if let Err(e) = tx_task(id, misc_conn, misc_tx, kind, misc_stats).await {
+                    log::error!("Error handling misc tx: {}", e);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    let target_tx = open_downstream(&conn, StreamTag::Control)
+        .await
+        .context("Open target tx")?;
+    tokio::spawn(
+        async move {
+            if let Err(e) = target_tx_task(id, target_tx).await {
+                log::error!("Error handling target tx: {}", e);
+            }
+        }
+        .in_current_span(),
+    );
+
+    let client = hello.name.clone();
+    log::info!("Client {} authenticated with id {}", client, hello.id);
+    audit::record(AuditEvent::Connected {
+        client: &client,
+        id: hello.id.to_string(),
+        addr: conn.remote_address().to_string(),
+    });
+    clients::register(&conn, hello);
+
+    let monitor = quality::monitor(id, client.clone(), conn.clone(), stats.clone());
+    tokio::spawn(monitor.in_current_span());
+
+    let upstream_conn = conn.clone();
+    let upstream_client = client.clone();
+    tokio::spawn(
+        async move {
+            if let Err(e) = upstream_task(upstream_conn, upstream_client, clipboard).await {
+                log::error!("Error handling upstream: {}", e);
+            }
+        }
+        .in_current_span(),
+    );
 
     let reason = conn.closed().await;
     log::info!("Connection closed: {:?}", reason);
+    stats.log(&client, &conn, start);
+
+    clients::unregister(id);
+    audit::record(AuditEvent::Disconnected { client: &client });
 
     Ok(())
 }
 
-pub async fn server() -> Result<()> {
-    let (endpoint, _server_cert) = make_server_endpoint("0.0.0.0:12334".parse()?)?;
+/// Moves `endpoint` to a new socket whenever a reload changes the bind address.
+///
+/// Connections survive the move, since QUIC identifies them by connection ID.
+async fn rebind_task(endpoint: Endpoint) {
+    let mut configs = config::subscribe();
+    let mut bind = configs.borrow_and_update().bind;
+
+    while configs.changed().await.is_ok() {
+        let new_bind = configs.borrow_and_update().bind;
+        if new_bind == bind {
+            continue;
+        }
+
+        match std::net::UdpSocket::bind(new_bind).and_then(|socket| endpoint.rebind(socket)) {
+            Ok(_) => {
+                log::info!("Now listening on {}", new_bind);
+                bind = new_bind;
+            }
+            Err(e) => log::error!("Failed to listen on {}: {}", new_bind, e),
+        }
+    }
+}
+
+pub async fn server(identity: Identity, clipboard: Option<ClipboardHandle>) -> Result<()> {
+    let endpoint = make_server_endpoint(config::current().bind, &identity)?;
+    tokio::spawn(rebind_task(endpoint.clone()));
 
     loop {
         let conn = if let Some(conn) = endpoint.accept().await {
@@ -124,34 +1358,40 @@ pub async fn server() -> Result<()> {
             return Ok(());
         };
 
+        let clipboard = clipboard.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_conn(conn).await {
+            if let Err(e) = handle_conn(conn, clipboard).await {
                 log::error!("Error handling connection: {}", e);
             }
         });
     }
 }
 
-fn make_server_endpoint(bind_addr: SocketAddr) -> Result<(Endpoint, Vec<u8>)> {
-    let (server_config, server_cert) = configure_server()?;
+fn make_server_endpoint(bind_addr: SocketAddr, identity: &Identity) -> Result<Endpoint> {
+    let server_config = configure_server(identity)?;
     let endpoint = Endpoint::server(server_config, bind_addr)?;
-    Ok((endpoint, server_cert))
+    Ok(endpoint)
 }
 
-/// Returns default server configuration along with its certificate.
-fn configure_server() -> Result<(quinn::ServerConfig, Vec<u8>)> {
-    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-    let cert_der = cert.serialize_der().unwrap();
-    let priv_key = cert.serialize_private_key_der();
-    let priv_key = rustls::PrivateKey(priv_key);
-    let cert_chain = vec![rustls::Certificate(cert_der.clone())];
+/// Returns default server configuration using the persistent server identity.
+fn configure_server(identity: &Identity) -> Result<quinn::ServerConfig> {
+    let priv_key = rustls::PrivateKey(identity.key_der.clone());
+    let cert_chain = vec![rustls::Certificate(identity.cert_der.clone())];
 
-    let mut server_config = quinn::ServerConfig::with_single_cert(cert_chain, priv_key)?;
+    let crypto = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_client_cert_verifier(Arc::new(crate::identity::AnyClientCert))
+        .with_single_cert(cert_chain, priv_key)?;
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
     let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
-    transport_config.keep_alive_interval(Some(Duration::from_secs(5)));
-    transport_config.max_idle_timeout(Some(Duration::from_secs(10).try_into()?));
+    let timeouts = config::current().timeouts()?;
+    let timeouts = *TIMEOUTS.get_or_init(|| timeouts);
+    transport_config.keep_alive_interval(Some(timeouts.keep_alive()));
+    transport_config.max_idle_timeout(timeouts.idle().map(TryInto::try_into).transpose()?);
     // transport_config.max_concurrent_uni_streams(100u8.into());
     // transport_config.max_concurrent_bidi_streams(100u8.into());
 
-    Ok((server_config, cert_der))
+    Ok(server_config)
 }
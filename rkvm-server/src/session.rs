@@ -0,0 +1,167 @@
+//! Letting go of input while another session is in front, like after a VT switch.
+//!
+//! logind tells which session of the seat is active. Once another one than ours is, input
+//! is ungrabbed and libinput closes its devices, since they belong to whoever sits there
+//! now. Both come back when ours is again.
+//!
+//! Ours is the session the server runs in. Run as a system service it is in none, and
+//! takes the first user session in front, never the login screen or no session at all.
+
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use tokio::runtime::Handle;
+use zbus::{zvariant::OwnedObjectPath, Proxy};
+
+use crate::wake;
+
+/// Whether the session is back in front since the last check, or went away
+static CHANGE: Mutex<Option<bool>> = Mutex::new(None);
+
+/// Follows the active session of `seat`, without giving up if logind isn't there.
+pub fn start(runtime: &Handle, seat: &str) {
+    let seat = seat.to_owned();
+    runtime.spawn(async move {
+        let notify = |back| {
+            *CHANGE.lock().unwrap() = Some(back);
+            wake::wake();
+        };
+        if let Err(e) = watch(&seat, notify).await {
+            log::warn!("Not following session switches: {:#}", e);
+        }
+    });
+}
+
+/// Whether the session came back since the last call, if it changed.
+pub fn take_change() -> Option<bool> {
+    CHANGE.lock().unwrap().take()
+}
+
+/// Class of the session at `path`, like `user` or `greeter`.
+async fn class(conn: &zbus::Connection, path: &OwnedObjectPath) -> Result<String> {
+    let session = Proxy::new(
+        conn,
+        "org.freedesktop.login1",
+        path.clone(),
+        "org.freedesktop.login1.Session",
+    )
+    .await?;
+    Ok(session.get_property("Class").await?)
+}
+
+/// Whether `session` is one input may belong to, unlike the login screen or none at all.
+async fn is_user(conn: &zbus::Connection, (id, path): &(String, OwnedObjectPath)) -> bool {
+    if id.is_empty() {
+        return false;
+    }
+    match class(conn, path).await {
+        Ok(class) => class == "user",
+        Err(e) => {
+            log::warn!("Failed to get class of session {}: {}", id, e);
+            false
+        }
+    }
+}
+
+async fn watch(seat: &str, mut notify: impl FnMut(bool)) -> Result<()> {
+    let conn = zbus::Connection::system().await?;
+
+    let manager = Proxy::new(
+        &conn,
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1",
+        "org.freedesktop.login1.Manager",
+    )
+    .await?;
+    let reply = manager
+        .call_method("GetSeat", &(seat,))
+        .await
+        .with_context(|| format!("Find seat {}", seat))?;
+    let path: OwnedObjectPath = reply.body()?;
+
+    let seat = Proxy::new(
+        &conn,
+        "org.freedesktop.login1",
+        path,
+        "org.freedesktop.login1.Seat",
+    )
+    .await?;
+    let mut changes = seat
+        .receive_property_changed::<(String, OwnedObjectPath)>("ActiveSession")
+        .await;
+
+    // Started from a session, it is ours. Otherwise, as a system service, ours is the first
+    // user session seen in front, which isn't the login screen we may have started under
+    let own = match manager
+        .call_method("GetSessionByPID", &(std::process::id(),))
+        .await
+    {
+        Ok(reply) => {
+            let path: OwnedObjectPath = reply.body()?;
+            let session = Proxy::new(
+                &conn,
+                "org.freedesktop.login1",
+                path,
+                "org.freedesktop.login1.Session",
+            )
+            .await?;
+            Some(session.get_property::<String>("Id").await?)
+        }
+        Err(_) => None,
+    };
+    let active = seat
+        .get_property::<(String, OwnedObjectPath)>("ActiveSession")
+        .await
+        .context("Get active session")?;
+    let mut ours = match own {
+        Some(own) => Some(own),
+        None if is_user(&conn, &active).await => Some(active.0.clone()),
+        None => None,
+    };
+    match &ours {
+        Some(ours) => log::info!("Following switches away from session {}", ours),
+        None => log::info!("Following switches away from the next user session"),
+    }
+
+    let mut away = ours.as_ref().is_some_and(|ours| *ours != active.0) && !active.0.is_empty();
+    if away {
+        notify(false);
+    }
+    while let Some(change) = changes.next().await {
+        let session = match change.get().await {
+            Ok(session) => session,
+            Err(e) => {
+                log::warn!("Failed to get active session: {}", e);
+                continue;
+            }
+        };
+        // None is in front for a moment while switching
+        if session.0.is_empty() {
+            continue;
+        }
+
+        let ours = match &ours {
+            Some(ours) => ours,
+            None => {
+                if is_user(&conn, &session).await {
+                    log::info!("Following switches away from session {}", session.0);
+                    ours = Some(session.0);
+                }
+                continue;
+            }
+        };
+        if session.0 == *ours {
+            if std::mem::take(&mut away) {
+                log::info!("Session {} is active again", ours);
+                notify(true);
+            }
+        } else if !away {
+            log::info!("Session {} took over from {}", session.0, ours);
+            away = true;
+            notify(false);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,202 @@
+//! Translating shortcuts into what the active client's platform uses for them.
+//!
+//! Maps of translations are named in the config and picked per client, so a macOS client
+//! can get Cmd+C for Ctrl+C. The press completing a shortcut lets go of the keys the
+//! translation lacks, which the client already got, and presses the ones it adds. Its
+//! release undoes that for the keys still held here.
+
+use std::sync::Arc;
+
+use keycode::{KeyMap, KeyMappingId};
+use rkvm_protocol::Event;
+
+use crate::{
+    clients,
+    config::{Config, ShortcutMapping},
+};
+
+pub struct ShortcutTranslator {
+    config: Arc<Config>,
+    /// Keys down on the client, in the order they went down
+    held: Vec<KeyMappingId>,
+    /// Keys that completed a shortcut, with the translation sent for it
+    translated: Vec<(KeyMappingId, ShortcutMapping)>,
+}
+
+fn key(id: KeyMappingId, pressed: bool) -> Event {
+    Event::key(KeyMap::from(id).win, pressed)
+}
+
+impl ShortcutTranslator {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            config,
+            held: Vec::new(),
+            translated: Vec::new(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: Arc<Config>) {
+        self.config = config;
+    }
+
+    /// Events to send the client for a press or release of `id`.
+    ///
+    /// Keys that aren't forwarded are only kept track of, and give no events.
+    pub fn translate(&mut self, id: KeyMappingId, pressed: bool, forward: bool) -> Vec<Event> {
+        if !pressed {
+            self.held.retain(|k| *k != id);
+
+            let position = self.translated.iter().position(|(k, _)| *k == id);
+            let mapping = match position {
+                Some(position) => self.translated.remove(position).1,
+                None if forward => return vec![key(id, false)],
+                None => return Vec::new(),
+            };
+            if !forward {
+                return Vec::new();
+            }
+
+            let mut events = Vec::new();
+            if let Some((last, rest)) = mapping.to.split_last() {
+                events.push(key(*last, false));
+                events.extend(
+                    rest.iter()
+                        .rev()
+                        .filter(|k| !mapping.from.contains(k))
+                        .map(|k| key(*k, false)),
+                );
+            }
+            events.extend(
+                mapping
+                    .from
+                    .iter()
+                    .filter(|k| !mapping.to.contains(k) && self.held.contains(k))
+                    .map(|k| key(*k, true)),
+            );
+            return events;
+        }
+
+        if !forward {
+            return Vec::new();
+        }
+        if !self.held.contains(&id) {
+            self.held.push(id);
+        }
+
+        let mapping = match self.mapping(id) {
+            Some(mapping) => mapping,
+            None => return vec![key(id, true)],
+        };
+        log::debug!("Translating {:?} to {:?}", mapping.from, mapping.to);
+
+        let mut events: Vec<_> = mapping
+            .from
+            .iter()
+            .filter(|k| **k != id && !mapping.to.contains(k))
+            .map(|k| key(*k, false))
+            .collect();
+        if let Some((last, rest)) = mapping.to.split_last() {
+            events.extend(
+                rest.iter()
+                    .filter(|k| !mapping.from.contains(k))
+                    .map(|k| key(*k, true)),
+            );
+            events.push(key(*last, true));
+        }
+
+        self.translated.push((id, mapping));
+        events
+    }
+
+    /// The translation of the shortcut that pressing `id` completes for the active client.
+    fn mapping(&self, id: KeyMappingId) -> Option<ShortcutMapping> {
+        let maps = &self.config.shortcut_maps;
+        // Spares looking up the active client on every other key
+        if !maps.values().flatten().any(|m| m.from.contains(&id)) {
+            return None;
+        }
+
+        let keys = clients::active_keys();
+        self.config
+            .shortcut_map_for(&keys)
+            .iter()
+            .find(|m| m.from.contains(&id) && m.from.iter().all(|k| self.held.contains(k)))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use KeyMappingId::{ControlLeft, MetaLeft, UsC, UsV};
+
+    fn translator() -> ShortcutTranslator {
+        let mapping = ShortcutMapping {
+            from: vec![ControlLeft, UsC],
+            to: vec![MetaLeft, UsC],
+        };
+        let config = Config {
+            shortcut_maps: [("mac".to_owned(), vec![mapping])].into(),
+            shortcut_map: Some("mac".to_owned()),
+            ..Default::default()
+        };
+        ShortcutTranslator::new(Arc::new(config))
+    }
+
+    /// Windows scan code and press of each key event.
+    fn keys(events: Vec<Event>) -> Vec<(u16, bool)> {
+        events
+            .into_iter()
+            .map(|event| match event {
+                Event::Keyboard { key, pressed, .. } => (key, pressed),
+                event => panic!("Not a key: {:?}", event),
+            })
+            .collect()
+    }
+
+    fn k(id: KeyMappingId, pressed: bool) -> (u16, bool) {
+        (KeyMap::from(id).win & 0xff, pressed)
+    }
+
+    #[test]
+    fn shortcut_is_translated() {
+        let mut translator = translator();
+
+        let events = translator.translate(ControlLeft, true, true);
+        assert_eq!(keys(events), [k(ControlLeft, true)]);
+        let events = translator.translate(UsC, true, true);
+        assert_eq!(
+            keys(events),
+            [k(ControlLeft, false), k(MetaLeft, true), k(UsC, true)]
+        );
+
+        // Ctrl is pressed again while still held here
+        let events = translator.translate(UsC, false, true);
+        assert_eq!(
+            keys(events),
+            [k(UsC, false), k(MetaLeft, false), k(ControlLeft, true)]
+        );
+        let events = translator.translate(ControlLeft, false, true);
+        assert_eq!(keys(events), [k(ControlLeft, false)]);
+    }
+
+    #[test]
+    fn other_keys_are_sent_as_they_are() {
+        let mut translator = translator();
+
+        translator.translate(ControlLeft, true, true);
+        let events = translator.translate(UsV, true, true);
+        assert_eq!(keys(events), [k(UsV, true)]);
+    }
+
+    #[test]
+    fn keys_not_forwarded_give_nothing() {
+        let mut translator = translator();
+
+        assert!(translator.translate(ControlLeft, true, false).is_empty());
+        assert!(translator.translate(UsC, true, false).is_empty());
+        assert!(translator.translate(UsC, false, false).is_empty());
+    }
+}
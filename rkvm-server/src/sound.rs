@@ -0,0 +1,115 @@
+//! Tones for state changes, so they can be told apart without looking.
+//!
+//! They are generated as WAV and piped to a player command, one after another so a grab
+//! followed by a switch doesn't come out as a chord.
+
+use std::{
+    f32::consts::TAU,
+    io::Write,
+    process::{Command, Stdio},
+    sync::{
+        mpsc::{self, Sender},
+        Mutex, OnceLock,
+    },
+};
+
+use crate::config::SoundConfig;
+
+const SAMPLE_RATE: u32 = 22050;
+/// Length of a note, and of the gap between beeps, in milliseconds
+const NOTE_MS: u32 = 90;
+/// Switching to a client further down beeps no more than this
+const MAX_BEEPS: usize = 5;
+
+/// Player command and WAV to play
+type Sound = (String, Vec<u8>);
+
+/// Sounds waiting for the thread playing them
+static QUEUE: OnceLock<Mutex<Sender<Sound>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy)]
+pub enum Cue {
+    Grab,
+    Ungrab,
+    /// Switching to the client at this position in connection order
+    Switch(usize),
+    /// A client asks for attention
+    Attention,
+}
+
+/// Plays `cue`, if the config wants it, without waiting for it to finish.
+pub fn play(config: &SoundConfig, cue: Cue) {
+    // Frequencies of the notes, 0 for silence
+    let notes = match cue {
+        Cue::Grab if config.grab => vec![660.0, 880.0],
+        Cue::Ungrab if config.ungrab => vec![880.0, 660.0],
+        Cue::Switch(position) if config.switch => {
+            let beeps = (position + 1).min(MAX_BEEPS);
+            [990.0, 0.0].repeat(beeps)
+        }
+        Cue::Attention if config.attention => [660.0, 990.0, 1320.0, 0.0].repeat(2),
+        _ => return,
+    };
+
+    let queue = QUEUE.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<Sound>();
+        std::thread::spawn(move || {
+            for (player, wav) in rx {
+                if let Err(e) = run(&player, &wav) {
+                    log::warn!("Failed to play sound with {:?}: {}", player, e);
+                }
+            }
+        });
+        Mutex::new(tx)
+    });
+    let sound = (config.player.clone(), wav(&notes));
+    let _ = queue.lock().unwrap().send(sound);
+}
+
+fn run(player: &str, wav: &[u8]) -> std::io::Result<()> {
+    let mut args = player.split_whitespace();
+    let program = args.next().unwrap_or("aplay");
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    // Dropping stdin closes it, so the player knows the file ended
+    child.stdin.take().unwrap().write_all(wav)?;
+    child.wait()?;
+    Ok(())
+}
+
+/// 16-bit mono WAV of `notes`, each faded in and out so it doesn't click.
+fn wav(notes: &[f32]) -> Vec<u8> {
+    let note_len = SAMPLE_RATE * NOTE_MS / 1000;
+    let fade = note_len / 8;
+
+    let mut samples = Vec::new();
+    for &frequency in notes {
+        for i in 0..note_len {
+            let envelope = i.min(note_len - i).min(fade) as f32 / fade as f32;
+            let t = i as f32 / SAMPLE_RATE as f32;
+            let value = (TAU * frequency * t).sin() * envelope * 0.3;
+            samples.extend_from_slice(&((value * i16::MAX as f32) as i16).to_le_bytes());
+        }
+    }
+
+    let mut wav = Vec::with_capacity(44 + samples.len());
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + samples.len() as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, one channel
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+    wav.extend_from_slice(&samples);
+    wav
+}
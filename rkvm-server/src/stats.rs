@@ -0,0 +1,70 @@
+//! Counters of what went over a connection, logged once it closes to help tell why it did.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use quinn::Connection;
+use rkvm_protocol::{format_duration, EventKind};
+
+#[derive(Default)]
+pub struct ConnStats {
+    /// Packets written to the mouse, keyboard and misc streams
+    events: [AtomicU64; 3],
+    datagrams: AtomicU64,
+    /// Events that never made it out, as a stream fell behind its channel
+    lagged: AtomicU64,
+    max_rtt_micros: AtomicU64,
+}
+
+fn index(kind: EventKind) -> usize {
+    match kind {
+        EventKind::Mouse => 0,
+        EventKind::Keyboard => 1,
+        EventKind::Misc => 2,
+    }
+}
+
+impl ConnStats {
+    pub fn sent(&self, kind: EventKind) {
+        self.events[index(kind)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sent_datagram(&self) {
+        self.datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn lagged(&self, skipped: u64) {
+        self.lagged.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    pub fn note_rtt(&self, rtt: Duration) {
+        let micros = rtt.as_micros().min(u64::MAX as u128) as u64;
+        self.max_rtt_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Logs what went over `conn` since `start`, once it closed.
+    pub fn log(&self, client: &str, conn: &Connection, start: Instant) {
+        let stats = conn.stats();
+        self.note_rtt(stats.path.rtt);
+
+        let events = |kind| self.events[index(kind)].load(Ordering::Relaxed);
+        log::info!(
+            "Connection to {} lasted {}: sent {} mouse, {} keyboard and {} other events and {} \
+             datagrams, {} bytes in {} packets, {} packets lost, at most {} ms round trip, {} \
+             events dropped falling behind",
+            client,
+            format_duration(start.elapsed()),
+            events(EventKind::Mouse),
+            events(EventKind::Keyboard),
+            events(EventKind::Misc),
+            self.datagrams.load(Ordering::Relaxed),
+            stats.udp_tx.bytes,
+            stats.path.sent_packets,
+            stats.path.lost_packets,
+            self.max_rtt_micros.load(Ordering::Relaxed) / 1000,
+            self.lagged.load(Ordering::Relaxed),
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::Result;
+use evdev::{
+    uinput::{VirtualDevice, VirtualDeviceBuilder},
+    AbsInfo, AbsoluteAxisType, AttributeSet, BusType, EventType, InputEvent, InputId, Key,
+    RelativeAxisType, UinputAbsSetup,
+};
+use keycode::{KeyMap, KeyMapping};
+use rkvm_protocol::{Event, MouseButton};
+
+/// Highest evdev key code we advertise, covering every regular keyboard key.
+const MAX_KEY: u16 = 0x2ff;
+
+/// Resolution of [`VirtualPointer`] across the whole desktop.
+const ABS_MAX: i32 = 0xffff;
+
+/// A virtual keyboard and mouse injecting input received from a client.
+///
+/// The device is tagged as rkvm's own so the capture loop doesn't pick it up again.
+pub struct VirtualInput {
+    device: VirtualDevice,
+}
+
+impl VirtualInput {
+    pub fn new() -> Result<Self> {
+        let mut keys = AttributeSet::<Key>::new();
+        for code in 1..=MAX_KEY {
+            keys.insert(Key::new(code));
+        }
+
+        let mut axes = AttributeSet::<RelativeAxisType>::new();
+        axes.insert(RelativeAxisType::REL_X);
+        axes.insert(RelativeAxisType::REL_Y);
+        axes.insert(RelativeAxisType::REL_WHEEL);
+        axes.insert(RelativeAxisType::REL_HWHEEL);
+
+        let name = format!("{} input", rkvm_protocol::VIRTUAL_DEVICE_NAME_PREFIX);
+        let device = VirtualDeviceBuilder::new()?
+            .name(&name)
+            .input_id(InputId::new(
+                BusType::BUS_VIRTUAL,
+                rkvm_protocol::VIRTUAL_DEVICE_VENDOR,
+                1,
+                1,
+            ))
+            .with_keys(&keys)?
+            .with_relative_axes(&axes)?
+            .build()?;
+
+        Ok(Self { device })
+    }
+
+    pub fn inject(&mut self, event: &Event) -> Result<()> {
+        let events = match *event {
+            Event::MouseMotion { dx, dy } => vec![
+                InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, dx),
+                InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, dy),
+            ],
+            Event::MouseWheel { dx, dy } => vec![
+                InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_HWHEEL.0, dx),
+                // Positive wheel values scroll down in the protocol, but up in evdev
+                InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_WHEEL.0, -dy),
+            ],
+            Event::MouseButton {
+                ref button,
+                pressed,
+            } => {
+                let key = match button {
+                    MouseButton::Left => Key::BTN_LEFT,
+                    MouseButton::Middle => Key::BTN_MIDDLE,
+                    MouseButton::Right => Key::BTN_RIGHT,
+                };
+                vec![InputEvent::new(EventType::KEY, key.code(), pressed.into())]
+            }
+            Event::Keyboard {
+                key,
+                pressed,
+                extended,
+            } => {
+                let key = rkvm_protocol::win_scan_code(key, extended);
+                let keymap = match KeyMap::from_key_mapping(KeyMapping::Win(key)) {
+                    Ok(keymap) => keymap,
+                    Err(_) => {
+                        log::warn!("Unknown windows scan code: {}", key);
+                        return Ok(());
+                    }
+                };
+                vec![InputEvent::new(
+                    EventType::KEY,
+                    keymap.evdev,
+                    pressed.into(),
+                )]
+            }
+            _ => return Ok(()),
+        };
+
+        self.device.emit(&events)?;
+
+        Ok(())
+    }
+}
+
+/// A virtual pointer placing the cursor anywhere on the desktop, like a tablet, without
+/// knowing its size.
+///
+/// Tagged like [`VirtualInput`], so the capture loop doesn't pick it up either.
+pub struct VirtualPointer {
+    device: VirtualDevice,
+}
+
+impl VirtualPointer {
+    pub fn new() -> Result<Self> {
+        // Pointers need a button to be taken for one
+        let mut keys = AttributeSet::<Key>::new();
+        keys.insert(Key::BTN_LEFT);
+
+        let axis = |axis| UinputAbsSetup::new(axis, AbsInfo::new(0, 0, ABS_MAX, 0, 0, 0));
+        let name = format!("{} pointer", rkvm_protocol::VIRTUAL_DEVICE_NAME_PREFIX);
+        let device = VirtualDeviceBuilder::new()?
+            .name(&name)
+            .input_id(InputId::new(
+                BusType::BUS_VIRTUAL,
+                rkvm_protocol::VIRTUAL_DEVICE_VENDOR,
+                2,
+                1,
+            ))
+            .with_keys(&keys)?
+            .with_absolute_axis(&axis(AbsoluteAxisType::ABS_X))?
+            .with_absolute_axis(&axis(AbsoluteAxisType::ABS_Y))?
+            .build()?;
+
+        Ok(Self { device })
+    }
+
+    /// Moves the cursor to `x` and `y`, as fractions of the desktop from its top left.
+    pub fn move_to(&mut self, x: f64, y: f64) -> Result<()> {
+        let scale = |v: f64| (v.clamp(0.0, 1.0) * ABS_MAX as f64).round() as i32;
+        self.device.emit(&[
+            InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, scale(x)),
+            InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Y.0, scale(y)),
+        ])?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+//! Waking the main loop from other threads, which leave it work in their own state.
+
+use std::{
+    io::{self, Read, Write},
+    os::{fd::AsRawFd, unix::net::UnixStream},
+    sync::OnceLock,
+};
+
+/// The end the main loop polls, and the one written to wake it
+static PAIR: OnceLock<(UnixStream, UnixStream)> = OnceLock::new();
+
+fn pair() -> io::Result<&'static (UnixStream, UnixStream)> {
+    if let Some(pair) = PAIR.get() {
+        return Ok(pair);
+    }
+
+    let (polled, woken) = UnixStream::pair()?;
+    polled.set_nonblocking(true)?;
+    woken.set_nonblocking(true)?;
+    Ok(PAIR.get_or_init(|| (polled, woken)))
+}
+
+/// Polled by the main loop along with libinput.
+pub fn fd() -> io::Result<i32> {
+    Ok(pair()?.0.as_raw_fd())
+}
+
+/// Makes the main loop look at what changed.
+pub fn wake() {
+    // A full buffer already wakes it
+    if let Ok(pair) = pair() {
+        let _ = (&pair.1).write(&[0]);
+    }
+}
+
+/// Clears wakeups once the main loop is up.
+pub fn drain() {
+    let mut buf = [0; 64];
+    if let Ok(pair) = pair() {
+        while matches!((&pair.0).read(&mut buf), Ok(n) if n > 0) {}
+    }
+}
@@ -1,8 +1,33 @@
-use std::io::Read;
+use std::{io::Read, process::Stdio};
 
 use anyhow::Result;
+use tokio::io::AsyncWriteExt;
 
-use crate::ClipboardType;
+use crate::{clipboard::IMAGE_TYPES, ClipboardType};
+
+/// Takes ownership of the clipboard with `data` as `mime_type`.
+///
+/// wl-copy keeps serving the selection from the background after we return.
+pub async fn set_wayland_clipboard(mime_type: &str, data: &[u8]) -> Result<()> {
+    let mut child = tokio::process::Command::new("wl-copy")
+        .arg("--type")
+        .arg(mime_type)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(data).await?;
+    drop(stdin);
+
+    let status = child.wait().await?;
+    if !status.success() {
+        anyhow::bail!("wl-copy failed: {}", status);
+    }
+
+    Ok(())
+}
 
 pub async fn get_wayland_clipboard() -> Result<Option<ClipboardType>> {
     tokio::task::spawn_blocking(|| {
@@ -11,17 +36,19 @@ pub async fn get_wayland_clipboard() -> Result<Option<ClipboardType>> {
             wl_clipboard_rs::paste::Seat::Unspecified,
         )?;
 
-        if targets.contains("image/png") {
-            let (mut pipe, _) = wl_clipboard_rs::paste::get_contents(
-                wl_clipboard_rs::paste::ClipboardType::Regular,
-                wl_clipboard_rs::paste::Seat::Unspecified,
-                wl_clipboard_rs::paste::MimeType::Specific("image/png"),
-            )?;
+        for image_type in &IMAGE_TYPES {
+            if targets.contains(*image_type) {
+                let (mut pipe, _) = wl_clipboard_rs::paste::get_contents(
+                    wl_clipboard_rs::paste::ClipboardType::Regular,
+                    wl_clipboard_rs::paste::Seat::Unspecified,
+                    wl_clipboard_rs::paste::MimeType::Specific(image_type),
+                )?;
 
-            let mut image = Vec::new();
-            pipe.read_to_end(&mut image)?;
+                let mut image = Vec::new();
+                pipe.read_to_end(&mut image)?;
 
-            return Ok(Some(ClipboardType::PngImage(image)));
+                return Ok(Some(ClipboardType::Image(image)));
+            }
         }
 
         let html_text = if targets.contains("text/html") {
@@ -72,3 +99,21 @@ pub async fn get_wayland_clipboard() -> Result<Option<ClipboardType>> {
     })
     .await?
 }
+
+/// Reads up to `limit` bytes of the primary selection as text, what was last selected rather
+/// than copied.
+pub async fn get_wayland_primary(limit: usize) -> Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || {
+        let (pipe, _) = wl_clipboard_rs::paste::get_contents(
+            wl_clipboard_rs::paste::ClipboardType::Primary,
+            wl_clipboard_rs::paste::Seat::Unspecified,
+            wl_clipboard_rs::paste::MimeType::Text,
+        )?;
+
+        let mut text = Vec::new();
+        pipe.take(limit as u64).read_to_end(&mut text)?;
+
+        Ok(text)
+    })
+    .await?
+}
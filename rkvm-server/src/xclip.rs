@@ -1,6 +1,9 @@
+use std::process::Stdio;
+
 use anyhow::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::ClipboardType;
+use crate::{clipboard::IMAGE_TYPES, ClipboardType};
 
 async fn xclip_get(target: &str) -> Result<Vec<u8>> {
     let output = tokio::process::Command::new("xclip")
@@ -19,6 +22,33 @@ async fn xclip_get(target: &str) -> Result<Vec<u8>> {
     Ok(output.stdout)
 }
 
+/// Takes ownership of the clipboard with `data` as `target`.
+///
+/// xclip keeps serving the selection from the background after we return.
+pub async fn set_xclip_clipboard(target: &str, data: &[u8]) -> Result<()> {
+    let mut child = tokio::process::Command::new("xclip")
+        .arg("-selection")
+        .arg("clipboard")
+        .arg("-t")
+        .arg(target)
+        .arg("-i")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(data).await?;
+    drop(stdin);
+
+    let status = child.wait().await?;
+    if !status.success() {
+        anyhow::bail!("xclip failed: {}", status);
+    }
+
+    Ok(())
+}
+
 pub async fn get_xclip_timestamp() -> Result<Option<u64>> {
     let targets_str = String::from_utf8(xclip_get("TARGETS").await?)?;
     let targets = targets_str.split('\n').collect::<Vec<_>>();
@@ -37,9 +67,11 @@ pub async fn get_xclip_clipboard() -> Result<Option<ClipboardType>> {
     let targets_str = String::from_utf8(xclip_get("TARGETS").await?)?;
     let targets = targets_str.split('\n').collect::<Vec<_>>();
 
-    if targets.contains(&"image/png") {
-        let image = xclip_get("image/png").await?;
-        return Ok(Some(ClipboardType::PngImage(image)));
+    for image_type in &IMAGE_TYPES {
+        if targets.contains(image_type) {
+            let image = xclip_get(image_type).await?;
+            return Ok(Some(ClipboardType::Image(image)));
+        }
     }
 
     let html_text = if targets.contains(&"text/html") {
@@ -75,3 +107,32 @@ pub async fn get_xclip_clipboard() -> Result<Option<ClipboardType>> {
     Ok(None)
 }
 
+/// Reads up to `limit` bytes of the primary selection as text, what was last selected rather
+/// than copied.
+pub async fn get_xclip_primary(limit: usize) -> Result<Vec<u8>> {
+    let mut child = tokio::process::Command::new("xclip")
+        .arg("-selection")
+        .arg("primary")
+        .arg("-t")
+        .arg("UTF8_STRING")
+        .arg("-o")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let mut text = Vec::new();
+    let stdout = child.stdout.take().unwrap();
+    stdout.take(limit as u64).read_to_end(&mut text).await?;
+    // The rest isn't wanted
+    if text.len() == limit {
+        return Ok(text);
+    }
+
+    let status = child.wait().await?;
+    if !status.success() {
+        anyhow::bail!("xclip failed: {}", status);
+    }
+
+    Ok(text)
+}
@@ -0,0 +1,269 @@
+//! The XKB layout the keyboard here types in, told to clients so they can type alike.
+//!
+//! Under X11 the active group is followed through XKB state events, and named after the
+//! layout the keymap's symbols give it. Without a display the first layout configured with
+//! `localectl` is taken, once, as nothing tells of switches then.
+
+use std::{process::Command, sync::Mutex};
+
+use anyhow::{Context, Result};
+use rkvm_protocol::{Event, Packet};
+use tokio::sync::mpsc::Sender;
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xkb::{self, ConnectionExt as _, EventType, NameDetail, StatePart, ID},
+        xproto::ConnectionExt as _,
+        Event as XEvent,
+    },
+    rust_connection::RustConnection,
+};
+
+/// Parts of keymap symbols that add to a layout instead of being one
+const NOT_LAYOUTS: &[&str] = &[
+    "pc",
+    "inet",
+    "group",
+    "compose",
+    "level3",
+    "level5",
+    "lv3",
+    "lv5",
+    "ctrl",
+    "caps",
+    "capslock",
+    "altwin",
+    "terminate",
+    "keypad",
+    "kpdl",
+    "eurosign",
+    "rupeesign",
+    "shift",
+    "srvr_ctrl",
+    "nbsp",
+    "grp",
+    "grp_led",
+    "mod_led",
+    "japan",
+    "korean",
+    "numpad",
+    "apple",
+    "empty",
+];
+
+static CURRENT: Mutex<Option<String>> = Mutex::new(None);
+
+/// The layout last detected, like `de` or `us(dvorak)`.
+pub fn current() -> Option<String> {
+    CURRENT.lock().unwrap().clone()
+}
+
+/// Starts detecting the layout, telling clients whenever it changes.
+pub fn start(event_tx: Sender<Packet>) {
+    let conn = match std::env::var_os("DISPLAY") {
+        Some(_) => connect()
+            .map_err(|e| log::warn!("Not following keyboard layout switches: {:#}", e))
+            .ok(),
+        None => None,
+    };
+
+    // Clients that connect learn of it in the handshake
+    *CURRENT.lock().unwrap() = match &conn {
+        Some(conn) => active(conn).unwrap_or(None),
+        None => ask("localectl", &["status"], "X11 Layout:", "X11 Variant:"),
+    };
+    if let Some(xkb) = current() {
+        log::info!("Keyboard layout is {}", xkb);
+    }
+
+    if let Some(conn) = conn {
+        std::thread::spawn(move || {
+            if let Err(e) = watch(&conn, &event_tx) {
+                log::warn!("Stopped following keyboard layout switches: {:#}", e);
+                announce(None, &event_tx);
+            }
+        });
+    }
+}
+
+/// Connects to X11, asking for the events that tell of a switch.
+fn connect() -> Result<RustConnection> {
+    let (conn, _) = x11rb::connect(None).context("Connect to X11")?;
+    let version = conn.xkb_use_extension(1, 0)?.reply().context("Query XKB")?;
+    if !version.supported {
+        anyhow::bail!("The X server has no XKB");
+    }
+
+    let events = EventType::STATE_NOTIFY | EventType::NAMES_NOTIFY;
+    let details = xkb::SelectEventsAux::new()
+        .bitcase2(xkb::SelectEventsAuxBitcase2 {
+            affect_state: StatePart::GROUP_STATE.into(),
+            state_details: StatePart::GROUP_STATE.into(),
+        })
+        .bitcase6(xkb::SelectEventsAuxBitcase6 {
+            affect_names: NameDetail::SYMBOLS.into(),
+            names_details: NameDetail::SYMBOLS.into(),
+        });
+    conn.xkb_select_events(
+        ID::USE_CORE_KBD.into(),
+        0u16,
+        0u16,
+        events,
+        events,
+        &details,
+    )?;
+    conn.flush()?;
+
+    Ok(conn)
+}
+
+fn watch(conn: &RustConnection, event_tx: &Sender<Packet>) -> Result<()> {
+    loop {
+        match conn.wait_for_event()? {
+            XEvent::XkbStateNotify(_) | XEvent::XkbNamesNotify(_) => {}
+            _ => continue,
+        }
+
+        if !announce(active(conn)?, event_tx) {
+            return Ok(());
+        }
+    }
+}
+
+/// Tells clients of `detected` if it differs from the layout before. Returns false once
+/// there is no one left to tell.
+fn announce(detected: Option<String>, event_tx: &Sender<Packet>) -> bool {
+    let previous = std::mem::replace(&mut *CURRENT.lock().unwrap(), detected.clone());
+    if detected == previous {
+        return true;
+    }
+
+    match &detected {
+        Some(xkb) => log::info!("Keyboard layout switched to {}", xkb),
+        None => log::info!("Keyboard layout can't be told anymore"),
+    }
+    let event = Event::KeyboardLayout { xkb: detected };
+    event_tx.blocking_send(Packet::new(0, event)).is_ok()
+}
+
+/// The layout of the active group.
+fn active(conn: &RustConnection) -> Result<Option<String>> {
+    let state = conn.xkb_get_state(ID::USE_CORE_KBD.into())?.reply()?;
+    let names = conn
+        .xkb_get_names(ID::USE_CORE_KBD.into(), NameDetail::SYMBOLS)?
+        .reply()?;
+    let symbols = match names.value_list.symbols_name {
+        Some(atom) if atom != x11rb::NONE => conn.get_atom_name(atom)?.reply()?.name,
+        _ => return Ok(None),
+    };
+
+    let group = u8::from(state.group) as usize;
+    Ok(layouts(&String::from_utf8_lossy(&symbols))
+        .into_iter()
+        .nth(group))
+}
+
+/// The layout of each group in keymap symbols like `pc+us+de(nodeadkeys):2+inet(evdev)`.
+fn layouts(symbols: &str) -> Vec<String> {
+    let mut layouts = Vec::new();
+    for part in symbols.split('+') {
+        let (name, group) = match part.split_once(':') {
+            Some((name, group)) => (name, group.parse().unwrap_or(0)),
+            None => (part, 1),
+        };
+        let base = name.split('(').next().unwrap_or(name);
+        if base.is_empty() || NOT_LAYOUTS.contains(&base) {
+            continue;
+        }
+
+        // Listed by group, the first one without its number
+        if group == layouts.len() + 1 {
+            layouts.push(name.to_owned());
+        }
+    }
+
+    layouts
+}
+
+/// Runs `program` and reads the layout and variant from its output, see [`parse`].
+fn ask(program: &str, args: &[&str], layout: &str, variant: &str) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    parse(&String::from_utf8_lossy(&output.stdout), layout, variant)
+}
+
+/// Reads the first layout and variant from the lines of `output` starting with `layout` and
+/// `variant`.
+fn parse(output: &str, layout: &str, variant: &str) -> Option<String> {
+    let value = |key: &str| {
+        output
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(key))
+            .and_then(|value| value.trim().split(',').next())
+            .map(str::trim)
+            // What localectl says of what isn't set
+            .filter(|value| !value.is_empty() && *value != "n/a")
+    };
+    let layout = value(layout)?;
+    Some(match value(variant) {
+        Some(variant) => format!("{}({})", layout, variant),
+        None => layout.to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCALECTL: &str = "   System Locale: LANG=de_DE.UTF-8
+       VC Keymap: de-latin1
+      X11 Layout: de,us
+       X11 Model: pc105
+     X11 Variant: nodeadkeys,
+";
+
+    #[test]
+    fn layouts_by_group() {
+        assert_eq!(layouts("pc+us+inet(evdev)"), ["us"]);
+        assert_eq!(
+            layouts("pc+de(nodeadkeys)+us:2+ru:3+inet(evdev)+group(alt_shift_toggle)"),
+            ["de(nodeadkeys)", "us", "ru"]
+        );
+        assert!(layouts("pc+inet(evdev)").is_empty());
+    }
+
+    #[test]
+    fn layouts_skip_options() {
+        assert_eq!(
+            layouts("pc+gb+level3(ralt_switch)+compose(menu)+capslock(escape)"),
+            ["gb"]
+        );
+    }
+
+    #[test]
+    fn parse_takes_the_first_layout() {
+        assert_eq!(
+            parse(LOCALECTL, "X11 Layout:", "X11 Variant:").as_deref(),
+            Some("de(nodeadkeys)")
+        );
+    }
+
+    #[test]
+    fn parse_without_variant() {
+        let output = "X11 Layout: fr\nX11 Model: pc105\n";
+        assert_eq!(
+            parse(output, "X11 Layout:", "X11 Variant:").as_deref(),
+            Some("fr")
+        );
+    }
+
+    #[test]
+    fn parse_without_layout() {
+        let output = "   System Locale: LANG=C\n       VC Keymap: n/a\n      X11 Layout: n/a\n";
+        assert_eq!(parse(output, "X11 Layout:", "X11 Variant:"), None);
+        assert_eq!(parse("", "X11 Layout:", "X11 Variant:"), None);
+    }
+}
@@ -1,37 +1,77 @@
+use std::{
+    io::{Seek, SeekFrom},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
 use parking_lot::Mutex;
 use windows::{
     core::HRESULT,
     Win32::{
-        Foundation::{BOOL, E_FAIL, E_NOTIMPL, OLE_E_ADVISENOTSUPPORTED, S_FALSE, S_OK},
+        Foundation::{
+            BOOL, E_FAIL, E_INVALIDARG, E_NOTIMPL, E_POINTER, OLE_E_ADVISENOTSUPPORTED,
+            STG_E_INVALIDFUNCTION, STG_E_INVALIDPOINTER, STG_E_MEDIUMFULL, STG_E_READFAULT,
+            STG_E_SEEKERROR, S_FALSE, S_OK,
+        },
         System::Com::{
-            IAdviseSink, IDataObject, IDataObject_Impl, IEnumFORMATETC, IEnumSTATDATA,
-            ISequentialStream, ISequentialStream_Impl, IStream, IStream_Impl, DATADIR_GET,
-            FORMATETC, LOCKTYPE, STATFLAG, STATSTG, STGC, STGMEDIUM, STREAM_SEEK, STGTY_STREAM, STGM, STGM_READ,
+            IAdviseSink, IBindCtx, IDataObject, IDataObject_Impl, IEnumFORMATETC,
+            IEnumFORMATETC_Impl, IEnumSTATDATA, ISequentialStream, ISequentialStream_Impl, IStream,
+            IStream_Impl, DATADIR_GET, FORMATETC, LOCKTYPE, STATFLAG, STATSTG, STGC, STGMEDIUM,
+            STGM_READ, STGTY_STREAM, STREAM_SEEK, STREAM_SEEK_CUR, STREAM_SEEK_END,
+            STREAM_SEEK_SET,
         },
+        UI::Shell::{IDataObjectAsyncCapability, IDataObjectAsyncCapability_Impl},
     },
 };
 
-#[windows::core::implement(IDataObject)]
-struct ClipboardDataObject {}
+use crate::{registry::FormatRegistry, source::Source};
+
+/// Data object serving whatever its registry offers.
+///
+/// It supports asynchronous extraction, so Explorer pulls slow streams on a background
+/// thread with its own progress UI instead of blocking the shell.
+#[windows::core::implement(IDataObject, IDataObjectAsyncCapability)]
+pub struct ClipboardDataObject {
+    registry: FormatRegistry,
+    async_mode: AtomicBool,
+    in_operation: AtomicBool,
+}
+
+impl ClipboardDataObject {
+    pub fn new(registry: FormatRegistry) -> Self {
+        Self {
+            registry,
+            async_mode: AtomicBool::new(true),
+            in_operation: AtomicBool::new(false),
+        }
+    }
+}
 
 #[allow(non_snake_case)]
 impl IDataObject_Impl for ClipboardDataObject {
     fn GetData(&self, pformatetcin: *const FORMATETC) -> windows::core::Result<STGMEDIUM> {
-        let mut ret = STGMEDIUM::default();
-        self.GetDataHere(pformatetcin, &mut ret)?;
-        Ok(ret)
+        let wanted = unsafe { pformatetcin.as_ref() }.ok_or(E_POINTER)?;
+        self.registry.get(wanted)
     }
 
     fn GetDataHere(
         &self,
-        pformatetc: *const FORMATETC,
-        pmedium: *mut STGMEDIUM,
+        _pformatetc: *const FORMATETC,
+        _pmedium: *mut STGMEDIUM,
     ) -> windows::core::Result<()> {
-        todo!()
+        Err(E_NOTIMPL.into())
     }
 
     fn QueryGetData(&self, pformatetc: *const FORMATETC) -> HRESULT {
-        todo!()
+        let wanted = if let Some(w) = unsafe { pformatetc.as_ref() } {
+            w
+        } else {
+            return E_POINTER;
+        };
+
+        match self.registry.query(wanted) {
+            Ok(_) => S_OK,
+            Err(e) => e.code(),
+        }
     }
 
     fn GetCanonicalFormatEtc(
@@ -56,7 +96,7 @@ impl IDataObject_Impl for ClipboardDataObject {
             return Err(E_NOTIMPL.into());
         }
 
-        todo!()
+        Ok(FormatEnumerator::new(self.registry.formats()).into())
     }
 
     fn DAdvise(
@@ -77,13 +117,129 @@ impl IDataObject_Impl for ClipboardDataObject {
     }
 }
 
+#[allow(non_snake_case)]
+impl IDataObjectAsyncCapability_Impl for ClipboardDataObject {
+    fn SetAsyncMode(&self, fdoopasync: BOOL) -> windows::core::Result<()> {
+        self.async_mode
+            .store(fdoopasync.as_bool(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn GetAsyncMode(&self) -> windows::core::Result<BOOL> {
+        Ok(self.async_mode.load(Ordering::Relaxed).into())
+    }
+
+    fn StartOperation(&self, _pbcreserved: Option<&IBindCtx>) -> windows::core::Result<()> {
+        self.in_operation.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn InOperation(&self) -> windows::core::Result<BOOL> {
+        Ok(self.in_operation.load(Ordering::Relaxed).into())
+    }
+
+    fn EndOperation(
+        &self,
+        _hresult: HRESULT,
+        _pbcreserved: Option<&IBindCtx>,
+        _dweffects: u32,
+    ) -> windows::core::Result<()> {
+        self.in_operation.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[windows::core::implement(IEnumFORMATETC)]
+struct FormatEnumerator {
+    formats: Vec<FORMATETC>,
+    next: AtomicUsize,
+}
+
+impl FormatEnumerator {
+    fn new(formats: Vec<FORMATETC>) -> Self {
+        Self {
+            formats,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+impl IEnumFORMATETC_Impl for FormatEnumerator {
+    fn Next(
+        &self,
+        celt: u32,
+        rgelt: *mut FORMATETC,
+        pceltfetched: *mut u32,
+    ) -> windows::core::Result<()> {
+        if rgelt.is_null() || (pceltfetched.is_null() && celt != 1) {
+            return Err(E_INVALIDARG.into());
+        }
+
+        let start = self.next.load(Ordering::Relaxed).min(self.formats.len());
+        let fetched = &self.formats[start..][..(celt as usize).min(self.formats.len() - start)];
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(fetched.as_ptr(), rgelt, fetched.len());
+            if !pceltfetched.is_null() {
+                *pceltfetched = fetched.len() as u32;
+            }
+        }
+        self.next.store(start + fetched.len(), Ordering::Relaxed);
+
+        if fetched.len() == celt as usize {
+            Ok(())
+        } else {
+            Err(S_FALSE.into())
+        }
+    }
+
+    fn Skip(&self, celt: u32) -> windows::core::Result<()> {
+        let next = self.next.load(Ordering::Relaxed) + celt as usize;
+        self.next
+            .store(next.min(self.formats.len()), Ordering::Relaxed);
+
+        if next <= self.formats.len() {
+            Ok(())
+        } else {
+            Err(S_FALSE.into())
+        }
+    }
+
+    fn Reset(&self) -> windows::core::Result<()> {
+        self.next.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn Clone(&self) -> windows::core::Result<IEnumFORMATETC> {
+        let clone = Self {
+            formats: self.formats.clone(),
+            next: AtomicUsize::new(self.next.load(Ordering::Relaxed)),
+        };
+
+        Ok(clone.into())
+    }
+}
+
 #[windows::core::implement(ISequentialStream, IStream)]
 pub struct ReadWrapper {
-    inner: Mutex<Box<dyn std::io::Read>>,
+    inner: Mutex<Box<dyn Source>>,
     len: u64,
 }
 
-#[allow(non_snake_case)]
+impl ReadWrapper {
+    /// Wraps `source`, which must hold `len` bytes. Use [`SpillBuffer`](crate::SpillBuffer)
+    /// for sources that can't seek.
+    pub fn new(source: impl Source + 'static, len: u64) -> Self {
+        Self {
+            inner: Mutex::new(Box::new(source)),
+            len,
+        }
+    }
+}
+
+// Out-pointers are part of the COM signatures
+#[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
 impl ISequentialStream_Impl for ReadWrapper {
     fn Read(&self, pv: *mut ::core::ffi::c_void, cb: u32, pcbread: *mut u32) -> HRESULT {
         let mut inner = self.inner.lock();
@@ -131,15 +287,36 @@ impl ISequentialStream_Impl for ReadWrapper {
     }
 }
 
-#[allow(non_snake_case)]
+// Out-pointers are part of the COM signatures
+#[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
 impl IStream_Impl for ReadWrapper {
     fn Seek(
         &self,
-        _dlibmove: i64,
-        _dworigin: STREAM_SEEK,
-        _plibnewposition: *mut u64,
+        dlibmove: i64,
+        dworigin: STREAM_SEEK,
+        plibnewposition: *mut u64,
     ) -> ::windows::core::Result<()> {
-        Err(E_NOTIMPL.into())
+        let from = match dworigin {
+            STREAM_SEEK_SET => {
+                SeekFrom::Start(dlibmove.try_into().map_err(|_| STG_E_INVALIDFUNCTION)?)
+            }
+            STREAM_SEEK_CUR => SeekFrom::Current(dlibmove),
+            STREAM_SEEK_END => SeekFrom::End(dlibmove),
+            _ => return Err(STG_E_INVALIDFUNCTION.into()),
+        };
+
+        let pos = self.inner.lock().seek(from).map_err(|e| match e.kind() {
+            std::io::ErrorKind::InvalidInput => STG_E_INVALIDFUNCTION,
+            _ => STG_E_SEEKERROR,
+        })?;
+
+        if !plibnewposition.is_null() {
+            unsafe {
+                *plibnewposition = pos;
+            }
+        }
+
+        Ok(())
     }
 
     fn SetSize(&self, _libnewsize: u64) -> windows::core::Result<()> {
@@ -148,12 +325,55 @@ impl IStream_Impl for ReadWrapper {
 
     fn CopyTo(
         &self,
-        _pstm: Option<&IStream>,
-        _cb: u64,
-        _pcbread: *mut u64,
-        _pcbwritten: *mut u64,
+        pstm: Option<&IStream>,
+        cb: u64,
+        pcbread: *mut u64,
+        pcbwritten: *mut u64,
     ) -> windows::core::Result<()> {
-        Err(E_NOTIMPL.into())
+        let pstm = pstm.ok_or(STG_E_INVALIDPOINTER)?;
+        let mut inner = self.inner.lock();
+
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut read = 0u64;
+        let mut written = 0u64;
+        let mut result = Ok(());
+
+        while read < cb {
+            let len = buf.len().min((cb - read) as usize);
+            let n = match inner.read(&mut buf[..len]) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    result = Err(STG_E_READFAULT.into());
+                    break;
+                }
+            };
+            read += n as u64;
+
+            let mut chunk_written = 0u32;
+            let hr = unsafe { pstm.Write(buf.as_ptr() as _, n as u32, Some(&mut chunk_written)) };
+            written += chunk_written as u64;
+            if hr.is_err() {
+                result = Err(hr.into());
+                break;
+            }
+            if chunk_written as usize != n {
+                result = Err(STG_E_MEDIUMFULL.into());
+                break;
+            }
+        }
+
+        unsafe {
+            if !pcbread.is_null() {
+                *pcbread = read;
+            }
+            if !pcbwritten.is_null() {
+                *pcbwritten = written;
+            }
+        }
+
+        result
     }
 
     fn Commit(&self, _grfcommitflags: &STGC) -> windows::core::Result<()> {
@@ -183,11 +403,16 @@ impl IStream_Impl for ReadWrapper {
     }
 
     fn Stat(&self, pstatstg: *mut STATSTG, _grfstatflag: &STATFLAG) -> windows::core::Result<()> {
-        let stat = unsafe { pstatstg.as_mut().unwrap() };
-        stat.r#type = STGTY_STREAM.0 as u32;
-        stat.cbSize = self.len;
-        stat.grfMode = STGM_READ;
-        
+        let stat = unsafe { pstatstg.as_mut() }.ok_or(STG_E_INVALIDPOINTER)?;
+
+        // No name, times or class; callers asking for a name must handle a null one
+        *stat = STATSTG {
+            r#type: STGTY_STREAM.0 as u32,
+            cbSize: self.len,
+            grfMode: STGM_READ,
+            ..Default::default()
+        };
+
         Ok(())
     }
 
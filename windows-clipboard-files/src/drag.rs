@@ -0,0 +1,46 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use windows::{
+    core::HRESULT,
+    Win32::{
+        Foundation::{
+            BOOL, DRAGDROP_S_CANCEL, DRAGDROP_S_DROP, DRAGDROP_S_USEDEFAULTCURSORS, S_OK,
+        },
+        System::{
+            Ole::{IDropSource, IDropSource_Impl, DROPEFFECT},
+            SystemServices::{MK_LBUTTON, MODIFIERKEYS_FLAGS},
+        },
+    },
+};
+
+/// Drops when the left button goes up, and cancels on Escape or when told to.
+#[windows::core::implement(IDropSource)]
+pub struct DropSource {
+    cancel: Arc<AtomicBool>,
+}
+
+impl DropSource {
+    pub fn new(cancel: Arc<AtomicBool>) -> Self {
+        Self { cancel }
+    }
+}
+
+#[allow(non_snake_case)]
+impl IDropSource_Impl for DropSource {
+    fn QueryContinueDrag(&self, fescapepressed: BOOL, grfkeystate: MODIFIERKEYS_FLAGS) -> HRESULT {
+        if fescapepressed.as_bool() || self.cancel.load(Ordering::SeqCst) {
+            DRAGDROP_S_CANCEL
+        } else if grfkeystate.0 & MK_LBUTTON.0 == 0 {
+            DRAGDROP_S_DROP
+        } else {
+            S_OK
+        }
+    }
+
+    fn GiveFeedback(&self, _dweffect: DROPEFFECT) -> HRESULT {
+        DRAGDROP_S_USEDEFAULTCURSORS
+    }
+}
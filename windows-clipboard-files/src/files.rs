@@ -0,0 +1,285 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use windows::{
+    core::w,
+    Win32::{
+        Foundation::{E_POINTER, FILETIME, HGLOBAL},
+        System::{
+            Com::{
+                IDataObject, DVASPECT_CONTENT, FORMATETC, STGMEDIUM, TYMED, TYMED_HGLOBAL,
+                TYMED_ISTREAM,
+            },
+            DataExchange::RegisterClipboardFormatW,
+            Memory::{GlobalLock, GlobalSize, GlobalUnlock},
+            Ole::{OleGetClipboard, ReleaseStgMedium, CF_HDROP},
+        },
+        UI::Shell::{
+            DragQueryFileW, FD_ATTRIBUTES, FD_FILESIZE, FD_PROGRESSUI, FD_UNICODE, FD_WRITESTIME,
+            FILEDESCRIPTORW, FILEGROUPDESCRIPTORW, HDROP,
+        },
+    },
+};
+
+/// A file copied to the clipboard by another application.
+#[derive(Debug)]
+pub enum ClipboardFile {
+    /// A file or directory on disk, as copied from Explorer
+    Path(PathBuf),
+    /// A file that only exists inside the source application, like an email attachment
+    Virtual {
+        /// Path relative to the copied root, separated by `\`
+        path: String,
+        data: Vec<u8>,
+        modified: Option<SystemTime>,
+    },
+    /// A directory of virtual files, listed before its entries
+    VirtualDirectory { path: String },
+}
+
+/// Describes a virtual file or directory to offer, see
+/// [`FormatRegistry::register_files`](crate::FormatRegistry::register_files).
+#[derive(Debug, Clone)]
+pub struct FileDescriptor {
+    /// Path relative to where it gets pasted, separated by `\`
+    pub path: String,
+    pub size: u64,
+    pub directory: bool,
+    pub modified: Option<SystemTime>,
+}
+
+const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+const FILE_ATTRIBUTE_NORMAL: u32 = 0x80;
+
+/// `FILETIME` ticks of 100ns between 1601 and the Unix epoch.
+const UNIX_EPOCH_TICKS: u64 = 116_444_736_000_000_000;
+
+fn from_filetime(time: FILETIME) -> Option<SystemTime> {
+    let ticks = ((time.dwHighDateTime as u64) << 32) | time.dwLowDateTime as u64;
+    let since_epoch = ticks.checked_sub(UNIX_EPOCH_TICKS)?;
+
+    UNIX_EPOCH.checked_add(Duration::new(
+        since_epoch / 10_000_000,
+        (since_epoch % 10_000_000) as u32 * 100,
+    ))
+}
+
+fn to_filetime(time: SystemTime) -> FILETIME {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let ticks = UNIX_EPOCH_TICKS + since_epoch.as_nanos() as u64 / 100;
+
+    FILETIME {
+        dwLowDateTime: ticks as u32,
+        dwHighDateTime: (ticks >> 32) as u32,
+    }
+}
+
+/// Builds a `FileGroupDescriptorW` for `files`, asking the shell to show a progress dialog.
+///
+/// Paths longer than `MAX_PATH` are truncated.
+pub fn file_group_descriptor(files: &[FileDescriptor]) -> Vec<u8> {
+    let mut data = (files.len() as u32).to_le_bytes().to_vec();
+
+    for file in files {
+        let mut descriptor = FILEDESCRIPTORW {
+            dwFlags: (FD_ATTRIBUTES.0 | FD_FILESIZE.0 | FD_PROGRESSUI.0 | FD_UNICODE.0) as u32,
+            dwFileAttributes: if file.directory {
+                FILE_ATTRIBUTE_DIRECTORY
+            } else {
+                FILE_ATTRIBUTE_NORMAL
+            },
+            nFileSizeHigh: (file.size >> 32) as u32,
+            nFileSizeLow: file.size as u32,
+            ..Default::default()
+        };
+
+        if let Some(modified) = file.modified {
+            descriptor.dwFlags |= FD_WRITESTIME.0 as u32;
+            descriptor.ftLastWriteTime = to_filetime(modified);
+        }
+
+        let mut name = [0u16; 260];
+        for (dst, src) in name[..259].iter_mut().zip(file.path.encode_utf16()) {
+            *dst = src;
+        }
+        descriptor.cFileName = name;
+
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &descriptor as *const FILEDESCRIPTORW as *const u8,
+                std::mem::size_of::<FILEDESCRIPTORW>(),
+            )
+        };
+        data.extend_from_slice(bytes);
+    }
+
+    data
+}
+
+fn formatetc(format: u16, tymed: TYMED, lindex: i32) -> FORMATETC {
+    FORMATETC {
+        cfFormat: format,
+        ptd: std::ptr::null_mut(),
+        dwAspect: DVASPECT_CONTENT.0,
+        lindex,
+        tymed: tymed.0 as u32,
+    }
+}
+
+/// Releases the medium when dropped.
+struct Medium(STGMEDIUM);
+
+impl Drop for Medium {
+    fn drop(&mut self) {
+        unsafe { ReleaseStgMedium(&mut self.0) };
+    }
+}
+
+fn get(object: &IDataObject, format: FORMATETC) -> Option<Medium> {
+    unsafe { object.GetData(&format) }.ok().map(Medium)
+}
+
+/// Copies the contents of an `HGLOBAL`.
+unsafe fn hglobal_bytes(hglobal: HGLOBAL) -> Vec<u8> {
+    let len = GlobalSize(hglobal);
+    let ptr = GlobalLock(hglobal) as *const u8;
+    if ptr.is_null() {
+        return Vec::new();
+    }
+
+    let data = std::slice::from_raw_parts(ptr, len).to_vec();
+    let _ = GlobalUnlock(hglobal);
+    data
+}
+
+unsafe fn medium_bytes(medium: &Medium) -> windows::core::Result<Vec<u8>> {
+    if medium.0.tymed == TYMED_HGLOBAL.0 as u32 {
+        return Ok(hglobal_bytes(medium.0.u.hGlobal));
+    }
+
+    let stream = medium.0.u.pstm.as_ref().ok_or(E_POINTER)?;
+    let mut data = Vec::new();
+    let mut chunk = vec![0u8; 64 * 1024];
+    loop {
+        let mut read = 0u32;
+        stream
+            .Read(chunk.as_mut_ptr() as _, chunk.len() as u32, Some(&mut read))
+            .ok()?;
+        if read == 0 {
+            break;
+        }
+        data.extend_from_slice(&chunk[..read as usize]);
+    }
+
+    Ok(data)
+}
+
+fn hdrop_files(object: &IDataObject) -> Option<Vec<ClipboardFile>> {
+    let medium = get(object, formatetc(CF_HDROP.0, TYMED_HGLOBAL, -1))?;
+
+    unsafe {
+        let hdrop = HDROP(medium.0.u.hGlobal.0 as isize);
+        let count = DragQueryFileW(hdrop, u32::MAX, None);
+
+        let files = (0..count)
+            .map(|i| {
+                let len = DragQueryFileW(hdrop, i, None) as usize;
+                let mut name = vec![0u16; len + 1];
+                DragQueryFileW(hdrop, i, Some(&mut name));
+
+                ClipboardFile::Path(String::from_utf16_lossy(&name[..len]).into())
+            })
+            .collect();
+
+        Some(files)
+    }
+}
+
+fn virtual_files(object: &IDataObject) -> windows::core::Result<Vec<ClipboardFile>> {
+    let (descriptor_format, contents_format) = unsafe {
+        (
+            RegisterClipboardFormatW(w!("FileGroupDescriptorW")) as u16,
+            RegisterClipboardFormatW(w!("FileContents")) as u16,
+        )
+    };
+
+    let descriptors = match get(object, formatetc(descriptor_format, TYMED_HGLOBAL, -1)) {
+        Some(medium) => unsafe { hglobal_bytes(medium.0.u.hGlobal) },
+        None => return Ok(Vec::new()),
+    };
+    if descriptors.len() < std::mem::size_of::<FILEGROUPDESCRIPTORW>() {
+        return Ok(Vec::new());
+    }
+
+    let group = descriptors.as_ptr() as *const FILEGROUPDESCRIPTORW;
+    let count = unsafe { std::ptr::addr_of!((*group).cItems).read_unaligned() } as usize;
+    let available =
+        (descriptors.len() - std::mem::size_of::<u32>()) / std::mem::size_of::<FILEDESCRIPTORW>();
+
+    let mut files = Vec::new();
+    for i in 0..count.min(available) {
+        let descriptor = unsafe {
+            let first = std::ptr::addr_of!((*group).fgd) as *const FILEDESCRIPTORW;
+            first.add(i).read_unaligned()
+        };
+
+        let file_name = descriptor.cFileName;
+        let name_len = file_name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(file_name.len());
+        let path = String::from_utf16_lossy(&file_name[..name_len]);
+
+        let flags = descriptor.dwFlags as i32;
+        let attributes = descriptor.dwFileAttributes;
+        if flags & FD_ATTRIBUTES.0 != 0 && attributes & FILE_ATTRIBUTE_DIRECTORY != 0 {
+            files.push(ClipboardFile::VirtualDirectory { path });
+            continue;
+        }
+
+        let modified = if flags & FD_WRITESTIME.0 != 0 {
+            from_filetime(descriptor.ftLastWriteTime)
+        } else {
+            None
+        };
+
+        let tymed = TYMED(TYMED_ISTREAM.0 | TYMED_HGLOBAL.0);
+        let medium = match get(object, formatetc(contents_format, tymed, i as i32)) {
+            Some(m) => m,
+            // Contents the source won't give are left out rather than guessed at. Parents of
+            // the files that follow are made along with them
+            None => continue,
+        };
+
+        let data = unsafe { medium_bytes(&medium)? };
+        files.push(ClipboardFile::Virtual {
+            path,
+            data,
+            modified,
+        });
+    }
+
+    Ok(files)
+}
+
+/// Reads the files on the clipboard. Must run on an OLE thread.
+pub(crate) fn read_files() -> windows::core::Result<Vec<ClipboardFile>> {
+    let object = unsafe { OleGetClipboard()? };
+
+    match hdrop_files(&object) {
+        Some(files) => Ok(files),
+        None => virtual_files(&object),
+    }
+}
+
+/// Reads one format as it is on the clipboard, if it's there. Must run on an OLE thread.
+pub(crate) fn read_format(format: u16) -> windows::core::Result<Option<Vec<u8>>> {
+    let object = unsafe { OleGetClipboard()? };
+
+    match get(&object, formatetc(format, TYMED_HGLOBAL, -1)) {
+        Some(medium) => Ok(Some(unsafe { hglobal_bytes(medium.0.u.hGlobal) })),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,187 @@
+//! Encoders for the byte layouts Windows expects in each clipboard format.
+
+/// Encodes text as `CF_UNICODETEXT`: NUL-terminated UTF-16.
+pub fn unicode_text(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain(Some(0))
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+/// Encodes 8-bit RGBA pixels as `CF_DIB`: a `BITMAPINFOHEADER` followed by bottom-up
+/// BGRA rows. `None` if `rgba` is shorter than the image.
+pub fn dib_from_rgba(width: u32, height: u32, rgba: &[u8]) -> Option<Vec<u8>> {
+    const HEADER_LEN: u32 = 40;
+
+    let row = (width as usize).checked_mul(4)?;
+    let image_len = row.checked_mul(height as usize)?;
+    if row == 0 || rgba.len() < image_len {
+        return None;
+    }
+
+    let mut dib = Vec::with_capacity(HEADER_LEN as usize + image_len);
+    dib.extend_from_slice(&HEADER_LEN.to_le_bytes());
+    dib.extend_from_slice(&(width as i32).to_le_bytes());
+    // Positive height means bottom-up
+    dib.extend_from_slice(&(height as i32).to_le_bytes());
+    dib.extend_from_slice(&1u16.to_le_bytes());
+    dib.extend_from_slice(&32u16.to_le_bytes());
+    // BI_RGB
+    dib.extend_from_slice(&0u32.to_le_bytes());
+    dib.extend_from_slice(&u32::try_from(image_len).ok()?.to_le_bytes());
+    // Resolution and palette are unused
+    dib.extend_from_slice(&[0u8; 16]);
+
+    for line in rgba[..image_len].chunks_exact(row).rev() {
+        for pixel in line.chunks_exact(4) {
+            dib.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+        }
+    }
+
+    Some(dib)
+}
+
+fn html_header(
+    start_html: usize,
+    end_html: usize,
+    start_fragment: usize,
+    end_fragment: usize,
+) -> String {
+    format!(
+        "Version:0.9\r\nStartHTML:{:010}\r\nEndHTML:{:010}\r\nStartFragment:{:010}\r\nEndFragment:{:010}\r\n",
+        start_html, end_html, start_fragment, end_fragment
+    )
+}
+
+/// Encodes HTML as `HTML Format`, which prefixes it with byte offsets.
+///
+/// A full document keeps its own `<html>` and `<body>`, with the body marked as the
+/// fragment. Anything else is wrapped in a document of its own.
+pub fn html_format(html: &str) -> Vec<u8> {
+    const PREFIX: &str = "<html><body>\r\n<!--StartFragment-->";
+    const SUFFIX: &str = "<!--EndFragment-->\r\n</body></html>";
+
+    let lower = html.to_ascii_lowercase();
+    let body = lower.find("<body").and_then(|start| {
+        let open_end = start + lower[start..].find('>')? + 1;
+        let close = lower.rfind("</body").filter(|close| *close >= open_end)?;
+        Some((open_end, close))
+    });
+    let (before, fragment, after) = match body {
+        Some((start, end)) => (
+            format!("{}<!--StartFragment-->", &html[..start]),
+            &html[start..end],
+            format!("<!--EndFragment-->{}", &html[end..]),
+        ),
+        None => (PREFIX.to_owned(), html, SUFFIX.to_owned()),
+    };
+
+    // Offsets are fixed width, so the header length doesn't depend on them
+    let start_html = html_header(0, 0, 0, 0).len();
+    let start_fragment = start_html + before.len();
+    let end_fragment = start_fragment + fragment.len();
+    let end_html = end_fragment + after.len();
+
+    let mut out = html_header(start_html, end_html, start_fragment, end_fragment);
+    out.push_str(&before);
+    out.push_str(fragment);
+    out.push_str(&after);
+
+    let mut out = out.into_bytes();
+    out.push(0);
+    out
+}
+
+/// Reads the fragment out of `HTML Format` another application copied.
+///
+/// Falls back to the whole document if the fragment offsets are missing or out of range.
+pub fn html_fragment(data: &[u8]) -> Option<String> {
+    let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    let data = &data[..len];
+
+    // The header is ASCII, and ends where the document starts
+    let header_len = data.iter().position(|&b| b == b'<').unwrap_or(data.len());
+    let header = std::str::from_utf8(&data[..header_len]).ok()?;
+    let offset = |name: &str| -> Option<usize> {
+        header
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            // Version 1.0 allows -1 for offsets left out
+            .and_then(|value| value.trim().parse().ok())
+    };
+
+    let range = |start, end| match (offset(start), offset(end)) {
+        (Some(start), Some(end)) if start <= end && end <= data.len() => Some(start..end),
+        _ => None,
+    };
+    let range = range("StartFragment", "EndFragment")
+        .or_else(|| range("StartHTML", "EndHTML"))
+        .unwrap_or(header_len..data.len());
+
+    Some(String::from_utf8_lossy(&data[range]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The document of `HTML Format`, and what its offsets mark.
+    fn parts(data: &[u8]) -> (String, String, String) {
+        let text = std::str::from_utf8(&data[..data.len() - 1]).unwrap();
+        let offset = |name: &str| -> usize {
+            text.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+
+        (
+            text[offset("StartHTML")..offset("EndHTML")].to_owned(),
+            text[offset("StartFragment")..offset("EndFragment")].to_owned(),
+            text[..offset("StartHTML")].to_owned(),
+        )
+    }
+
+    #[test]
+    fn fragment_is_wrapped() {
+        let data = html_format("<b>bold</b>");
+        assert_eq!(data.last(), Some(&0));
+
+        let (document, fragment, header) = parts(&data);
+        assert_eq!(fragment, "<b>bold</b>");
+        assert!(document.starts_with("<html><body>"));
+        assert!(document.ends_with("</body></html>"));
+        assert!(header.starts_with("Version:0.9\r\n"));
+    }
+
+    #[test]
+    fn document_keeps_its_body() {
+        let html = "<html><head><style>b{}</style></head><BODY class=x><b>bold</b></BODY></html>";
+        let (document, fragment, _) = parts(&html_format(html));
+        assert_eq!(fragment, "<b>bold</b>");
+        assert!(document.contains("<style>b{}</style>"));
+        assert!(document.contains("<BODY class=x><!--StartFragment-->"));
+    }
+
+    #[test]
+    fn fragment_round_trips() {
+        let cases = [
+            ("<i>é</i>", "<i>é</i>"),
+            ("<html><body><p>text</p></body></html>", "<p>text</p>"),
+            ("", ""),
+        ];
+        for (html, fragment) in cases {
+            assert_eq!(html_fragment(&html_format(html)).as_deref(), Some(fragment));
+        }
+    }
+
+    #[test]
+    fn fragment_falls_back_to_the_document() {
+        let data = b"Version:1.0\r\nStartHTML:-1\r\nEndHTML:-1\r\n<p>all</p>\0";
+        assert_eq!(html_fragment(data).as_deref(), Some("<p>all</p>"));
+
+        let data = b"Version:0.9\r\nStartFragment:20\r\nEndFragment:9999\r\n<p>all</p>";
+        assert_eq!(html_fragment(data).as_deref(), Some("<p>all</p>"));
+    }
+}
@@ -1,12 +1,232 @@
+mod data_object;
+mod drag;
+mod files;
+mod formats;
+mod registry;
+mod source;
 
+use std::{
+    sync::{
+        atomic::AtomicBool,
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread::JoinHandle,
+};
 
-mod data_object;
+use data_object::ClipboardDataObject;
+use drag::DropSource;
+use windows::{
+    core::w,
+    Win32::{
+        Foundation::{DRAGDROP_S_CANCEL, DRAGDROP_S_DROP, HWND, LPARAM, WPARAM},
+        System::{
+            Com::IDataObject,
+            DataExchange::RegisterClipboardFormatW,
+            Ole::{
+                DoDragDrop, IDropSource, OleFlushClipboard, OleInitialize, OleSetClipboard,
+                OleUninitialize, CF_DIB, CF_UNICODETEXT, DROPEFFECT_COPY, DROPEFFECT_NONE,
+            },
+            Threading::GetCurrentThreadId,
+        },
+        UI::WindowsAndMessaging::{
+            DispatchMessageW, GetMessageW, PeekMessageW, PostThreadMessageW, TranslateMessage, MSG,
+            PM_NOREMOVE, WM_APP, WM_QUIT,
+        },
+    },
+};
 
-pub struct Clipboard {}
+pub use data_object::ReadWrapper;
+pub use files::{file_group_descriptor, ClipboardFile, FileDescriptor};
+pub use formats::{dib_from_rgba, html_format, html_fragment, unicode_text};
+pub use registry::{hglobal_medium, DataProvider, FormatRegistry, OpenContents};
+pub use source::{Source, SpillBuffer};
 
-impl Clipboard {}
+/// Formats that can be placed on the clipboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `CF_UNICODETEXT`, see [`unicode_text`]
+    UnicodeText,
+    /// `CF_DIB`, see [`dib_from_rgba`]
+    Dib,
+    /// The registered `PNG` format, holding an encoded PNG file
+    Png,
+    /// The registered `HTML Format`, see [`html_format`]
+    Html,
+}
 
-#[derive(Debug, thiserror::Error)]
-pub enum Error {}
+impl Format {
+    pub fn clipformat(self) -> u16 {
+        match self {
+            Format::UnicodeText => CF_UNICODETEXT.0,
+            Format::Dib => CF_DIB.0,
+            Format::Png => unsafe { RegisterClipboardFormatW(w!("PNG")) as u16 },
+            Format::Html => unsafe { RegisterClipboardFormatW(w!("HTML Format")) as u16 },
+        }
+    }
+}
+
+/// Produces the contents of a format the first time someone pastes it.
+pub type Render = Box<dyn FnOnce() -> Option<Vec<u8>> + Send>;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Handle to a thread that owns our clipboard data and renders it on demand.
+///
+/// OLE delivers render requests as window messages, so the data has to live on a thread
+/// that keeps pumping them.
+pub struct Clipboard {
+    thread_id: u32,
+    jobs: Sender<Job>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Clipboard {
+    pub fn new() -> Result<Self, Error> {
+        let (jobs_tx, jobs_rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        let thread = std::thread::spawn(move || run(jobs_rx, ready_tx));
+        let thread_id = ready_rx.recv().map_err(|_| Error::Disconnected)??;
+
+        Ok(Self {
+            thread_id,
+            jobs: jobs_tx,
+            thread: Some(thread),
+        })
+    }
+
+    /// Replaces the clipboard contents with everything `registry` offers.
+    pub fn set(&self, registry: FormatRegistry) -> Result<(), Error> {
+        let (tx, rx) = mpsc::channel();
+
+        self.run(Box::new(move || {
+            let object: IDataObject = ClipboardDataObject::new(registry).into();
+            let result = unsafe { OleSetClipboard(&object) };
+            let _ = tx.send(result.map_err(Error::from));
+        }))?;
+
+        rx.recv().map_err(|_| Error::Disconnected)?
+    }
+
+    /// Replaces the clipboard contents, rendering each format only when it is pasted.
+    pub fn set_delayed(&self, formats: Vec<(Format, Render)>) -> Result<(), Error> {
+        let mut registry = FormatRegistry::new();
+        for (format, render) in formats {
+            registry.register_delayed(format, render);
+        }
+
+        self.set(registry)
+    }
+
+    /// Reads the files another application copied, either from disk or virtual ones.
+    ///
+    /// Virtual files are read into memory in full.
+    pub fn get_files(&self) -> Result<Vec<ClipboardFile>, Error> {
+        let (tx, rx) = mpsc::channel();
+
+        self.run(Box::new(move || {
+            let _ = tx.send(files::read_files().map_err(Error::from));
+        }))?;
+
+        rx.recv().map_err(|_| Error::Disconnected)?
+    }
 
+    /// Reads the HTML fragment another application copied, if any.
+    pub fn get_html(&self) -> Result<Option<String>, Error> {
+        let (tx, rx) = mpsc::channel();
 
+        self.run(Box::new(move || {
+            let html = files::read_format(Format::Html.clipformat())
+                .map(|data| data.as_deref().and_then(html_fragment));
+            let _ = tx.send(html.map_err(Error::from));
+        }))?;
+
+        rx.recv().map_err(|_| Error::Disconnected)?
+    }
+
+    fn run(&self, job: Job) -> Result<(), Error> {
+        self.jobs.send(job).map_err(|_| Error::Disconnected)?;
+        unsafe { PostThreadMessageW(self.thread_id, WM_APP, WPARAM(0), LPARAM(0))? };
+
+        Ok(())
+    }
+}
+
+impl Drop for Clipboard {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = PostThreadMessageW(self.thread_id, WM_QUIT, WPARAM(0), LPARAM(0));
+        }
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Drags everything `registry` offers along with the cursor, until the left button is
+/// released or `cancel` is set. The button must already be down.
+///
+/// The drag runs a modal loop of its own, so it gets a thread of its own rather than
+/// holding up the clipboard's. Blocks for the whole drag, and returns whether the data was
+/// dropped somewhere.
+pub fn drag(registry: FormatRegistry, cancel: Arc<AtomicBool>) -> Result<bool, Error> {
+    let thread = std::thread::spawn(move || unsafe {
+        OleInitialize(None)?;
+
+        let object: IDataObject = ClipboardDataObject::new(registry).into();
+        let source: IDropSource = DropSource::new(cancel).into();
+
+        let mut effect = DROPEFFECT_NONE;
+        let result = match DoDragDrop(&object, &source, DROPEFFECT_COPY, &mut effect) {
+            DRAGDROP_S_DROP => Ok(effect != DROPEFFECT_NONE),
+            DRAGDROP_S_CANCEL => Ok(false),
+            e => Err(Error::from(windows::core::Error::from(e))),
+        };
+
+        drop((object, source));
+        OleUninitialize();
+        result
+    });
+
+    thread.join().map_err(|_| Error::Disconnected)?
+}
+
+fn run(jobs: Receiver<Job>, ready: Sender<Result<u32, Error>>) {
+    unsafe {
+        if let Err(e) = OleInitialize(None) {
+            let _ = ready.send(Err(e.into()));
+            return;
+        }
+
+        // Make sure the message queue exists before anyone posts to it
+        let mut msg = MSG::default();
+        PeekMessageW(&mut msg, None, 0, 0, PM_NOREMOVE);
+        let _ = ready.send(Ok(GetCurrentThreadId()));
+
+        while GetMessageW(&mut msg, None, 0, 0).as_bool() {
+            if msg.hwnd == HWND(0) && msg.message == WM_APP {
+                while let Ok(job) = jobs.try_recv() {
+                    job();
+                }
+                continue;
+            }
+
+            TranslateMessage(&msg);
+            DispatchMessageW(&msg);
+        }
+
+        // Render whatever is still on the clipboard so it outlives us
+        let _ = OleFlushClipboard();
+        OleUninitialize();
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Clipboard thread exited")]
+    Disconnected,
+    #[error(transparent)]
+    Windows(#[from] windows::core::Error),
+}
@@ -0,0 +1,216 @@
+use std::mem::ManuallyDrop;
+
+use parking_lot::Mutex;
+use windows::{
+    core::w,
+    Win32::{
+        Foundation::{
+            GlobalFree, DV_E_DVASPECT, DV_E_FORMATETC, DV_E_LINDEX, DV_E_TYMED, E_FAIL,
+            E_OUTOFMEMORY,
+        },
+        System::{
+            Com::{
+                IStream, DVASPECT_CONTENT, FORMATETC, STGMEDIUM, STGMEDIUM_0, TYMED, TYMED_HGLOBAL,
+                TYMED_ISTREAM,
+            },
+            DataExchange::RegisterClipboardFormatW,
+            Memory::{GlobalAlloc, GlobalLock, GlobalUnlock, GMEM_MOVEABLE},
+        },
+    },
+};
+
+use crate::{files, FileDescriptor, Format, ReadWrapper, Render, Source};
+
+/// Opens the contents of a virtual file, once for every paste.
+pub type OpenContents = Box<dyn FnMut() -> std::io::Result<Box<dyn Source>> + Send>;
+
+/// Supplies the data behind one registered format.
+pub trait DataProvider: Send {
+    /// Produces the data of item `lindex`, or of the whole object for `-1`.
+    ///
+    /// `tymed` is a single medium out of those this provider was registered with.
+    fn get(&mut self, tymed: TYMED, lindex: i32) -> windows::core::Result<STGMEDIUM>;
+}
+
+enum Delayed {
+    Pending(Render),
+    Rendered(Vec<u8>),
+    Failed,
+}
+
+impl DataProvider for Delayed {
+    fn get(&mut self, _tymed: TYMED, _lindex: i32) -> windows::core::Result<STGMEDIUM> {
+        // Render on first use and keep the result for later pastes
+        if let Delayed::Pending(_) = self {
+            if let Delayed::Pending(render) = std::mem::replace(self, Delayed::Failed) {
+                if let Some(data) = render() {
+                    *self = Delayed::Rendered(data);
+                }
+            }
+        }
+
+        match self {
+            Delayed::Rendered(data) => hglobal_medium(data),
+            _ => Err(E_FAIL.into()),
+        }
+    }
+}
+
+/// Serves `FileContents` as a stream per file, chosen by `lindex`.
+struct Contents {
+    files: Vec<(u64, Option<OpenContents>)>,
+}
+
+impl DataProvider for Contents {
+    fn get(&mut self, _tymed: TYMED, lindex: i32) -> windows::core::Result<STGMEDIUM> {
+        let (len, open) = usize::try_from(lindex)
+            .ok()
+            .and_then(|i| self.files.get_mut(i))
+            .ok_or(DV_E_LINDEX)?;
+        // Directories have no contents
+        let open = open.as_mut().ok_or(DV_E_LINDEX)?;
+
+        let source = open().map_err(|_| E_FAIL)?;
+        let stream: IStream = ReadWrapper::new(source, *len).into();
+
+        Ok(STGMEDIUM {
+            tymed: TYMED_ISTREAM.0 as u32,
+            u: STGMEDIUM_0 {
+                pstm: ManuallyDrop::new(Some(stream)),
+            },
+            pUnkForRelease: ManuallyDrop::new(None),
+        })
+    }
+}
+
+struct Entry {
+    format: u16,
+    tymed: TYMED,
+    provider: Mutex<Box<dyn DataProvider>>,
+}
+
+impl Entry {
+    fn formatetc(&self) -> FORMATETC {
+        FORMATETC {
+            cfFormat: self.format,
+            ptd: std::ptr::null_mut(),
+            dwAspect: DVASPECT_CONTENT.0,
+            lindex: -1,
+            tymed: self.tymed.0 as u32,
+        }
+    }
+}
+
+/// Formats offered by a data object and where their data comes from.
+#[derive(Default)]
+pub struct FormatRegistry {
+    entries: Vec<Entry>,
+}
+
+impl FormatRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Offers `format` in any of the mediums in `tymed`, a combination of `TYMED_*` flags.
+    ///
+    /// Formats are enumerated in registration order, so register the richest first.
+    pub fn register(&mut self, format: u16, tymed: TYMED, provider: impl DataProvider + 'static) {
+        self.entries.push(Entry {
+            format,
+            tymed,
+            provider: Mutex::new(Box::new(provider)),
+        });
+    }
+
+    /// Offers a well-known format in an `HGLOBAL`, rendered the first time it is requested.
+    pub fn register_delayed(&mut self, format: Format, render: Render) {
+        self.register(format.clipformat(), TYMED_HGLOBAL, Delayed::Pending(render));
+    }
+
+    /// Offers virtual files, like a zip viewer or mail client does.
+    ///
+    /// Each directory must come before its entries and have no contents. Files are read
+    /// from their source only when they are pasted.
+    pub fn register_files(&mut self, files: Vec<(FileDescriptor, Option<OpenContents>)>) {
+        let (descriptor_format, contents_format) = unsafe {
+            (
+                RegisterClipboardFormatW(w!("FileGroupDescriptorW")) as u16,
+                RegisterClipboardFormatW(w!("FileContents")) as u16,
+            )
+        };
+
+        let descriptors: Vec<_> = files.iter().map(|(d, _)| d.clone()).collect();
+        let descriptor = files::file_group_descriptor(&descriptors);
+        self.register(
+            descriptor_format,
+            TYMED_HGLOBAL,
+            Delayed::Rendered(descriptor),
+        );
+
+        let contents = Contents {
+            files: files.into_iter().map(|(d, open)| (d.size, open)).collect(),
+        };
+        self.register(contents_format, TYMED_ISTREAM, contents);
+    }
+
+    pub(crate) fn formats(&self) -> Vec<FORMATETC> {
+        self.entries.iter().map(Entry::formatetc).collect()
+    }
+
+    fn find(&self, wanted: &FORMATETC) -> windows::core::Result<&Entry> {
+        let mut candidates = self
+            .entries
+            .iter()
+            .filter(|e| e.format == wanted.cfFormat)
+            .peekable();
+        if candidates.peek().is_none() {
+            return Err(DV_E_FORMATETC.into());
+        }
+
+        if wanted.dwAspect != DVASPECT_CONTENT.0 {
+            return Err(DV_E_DVASPECT.into());
+        }
+
+        candidates
+            .find(|e| wanted.tymed & e.tymed.0 as u32 != 0)
+            .ok_or_else(|| DV_E_TYMED.into())
+    }
+
+    /// Checks whether [`get`](Self::get) would find a provider for `wanted`.
+    pub(crate) fn query(&self, wanted: &FORMATETC) -> windows::core::Result<()> {
+        self.find(wanted).map(|_| ())
+    }
+
+    pub(crate) fn get(&self, wanted: &FORMATETC) -> windows::core::Result<STGMEDIUM> {
+        let entry = self.find(wanted)?;
+
+        // Hand out the first medium both sides support
+        let common = wanted.tymed as i32 & entry.tymed.0;
+        let tymed = TYMED(common & -common);
+
+        entry.provider.lock().get(tymed, wanted.lindex)
+    }
+}
+
+/// Copies `data` into a newly allocated `HGLOBAL` medium.
+pub fn hglobal_medium(data: &[u8]) -> windows::core::Result<STGMEDIUM> {
+    let hglobal = unsafe { GlobalAlloc(GMEM_MOVEABLE, data.len())? };
+    unsafe {
+        let ptr = GlobalLock(hglobal) as *mut u8;
+        if ptr.is_null() {
+            let _ = GlobalFree(hglobal);
+            return Err(E_OUTOFMEMORY.into());
+        }
+
+        std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+        // Fails with NO_ERROR once the lock count reaches zero
+        let _ = GlobalUnlock(hglobal);
+    }
+
+    Ok(STGMEDIUM {
+        tymed: TYMED_HGLOBAL.0 as u32,
+        u: STGMEDIUM_0 { hGlobal: hglobal },
+        pUnkForRelease: ManuallyDrop::new(None),
+    })
+}
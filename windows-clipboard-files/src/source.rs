@@ -0,0 +1,191 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+/// Bytes behind a [`ReadWrapper`](crate::ReadWrapper) stream.
+pub trait Source: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> Source for T {}
+
+/// Data kept in memory up to this size before moving to a temporary file.
+const SPILL_THRESHOLD: usize = 8 * 1024 * 1024;
+
+const CHUNK_LEN: usize = 64 * 1024;
+
+enum Store {
+    Memory(Vec<u8>),
+    File(File),
+}
+
+/// Makes a forward-only reader, like a network stream, seekable.
+///
+/// Everything read from the inner reader is kept, first in memory and then in a temporary
+/// file, so seeking back never needs the data again. Seeking forward reads ahead.
+pub struct SpillBuffer<R> {
+    inner: R,
+    store: Store,
+    /// Bytes read from `inner` so far
+    filled: u64,
+    pos: u64,
+    eof: bool,
+}
+
+impl<R: Read> SpillBuffer<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            store: Store::Memory(Vec::new()),
+            filled: 0,
+            pos: 0,
+            eof: false,
+        }
+    }
+
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        match &mut self.store {
+            Store::Memory(buf) if buf.len() + data.len() > SPILL_THRESHOLD => {
+                let mut file = tempfile::tempfile()?;
+                file.write_all(buf)?;
+                file.write_all(data)?;
+                self.store = Store::File(file);
+            }
+            Store::Memory(buf) => buf.extend_from_slice(data),
+            Store::File(file) => {
+                file.seek(SeekFrom::End(0))?;
+                file.write_all(data)?;
+            }
+        }
+
+        self.filled += data.len() as u64;
+        Ok(())
+    }
+
+    /// Reads from the inner reader until `offset` is buffered or it runs out.
+    fn fill_to(&mut self, offset: u64) -> io::Result<()> {
+        let mut chunk = vec![0u8; CHUNK_LEN];
+
+        while self.filled < offset && !self.eof {
+            let n = match self.inner.read(&mut chunk) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            if n == 0 {
+                self.eof = true;
+            } else {
+                self.append(&chunk[..n])?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for SpillBuffer<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill_to(self.pos + 1)?;
+        if self.pos >= self.filled {
+            return Ok(0);
+        }
+
+        let len = buf.len().min((self.filled - self.pos) as usize);
+        match &mut self.store {
+            Store::Memory(data) => {
+                let start = self.pos as usize;
+                buf[..len].copy_from_slice(&data[start..start + len]);
+            }
+            Store::File(file) => {
+                file.seek(SeekFrom::Start(self.pos))?;
+                file.read_exact(&mut buf[..len])?;
+            }
+        }
+
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read> Seek for SpillBuffer<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => (n, 0),
+            SeekFrom::Current(n) => (self.pos, n),
+            SeekFrom::End(n) => {
+                self.fill_to(u64::MAX)?;
+                (self.filled, n)
+            }
+        };
+
+        self.pos = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Seek before start of stream")
+        })?;
+
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `len` bytes that differ by offset, so reads from the wrong place show.
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn read_at(buffer: &mut SpillBuffer<&[u8]>, offset: u64, len: usize) -> Vec<u8> {
+        buffer.seek(SeekFrom::Start(offset)).unwrap();
+        let mut out = vec![0u8; len];
+        buffer.read_exact(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn seeks_back_in_memory() {
+        let data = data(1000);
+        let mut buffer = SpillBuffer::new(data.as_slice());
+
+        assert_eq!(read_at(&mut buffer, 900, 100), data[900..]);
+        assert_eq!(read_at(&mut buffer, 10, 20), data[10..30]);
+        assert!(matches!(buffer.store, Store::Memory(_)));
+    }
+
+    #[test]
+    fn spills_to_a_file() {
+        let data = data(SPILL_THRESHOLD + 3 * CHUNK_LEN + 7);
+        let mut buffer = SpillBuffer::new(data.as_slice());
+
+        let mut all = Vec::new();
+        buffer.read_to_end(&mut all).unwrap();
+        assert!(all == data);
+        assert!(matches!(buffer.store, Store::File(_)));
+
+        assert_eq!(read_at(&mut buffer, 5, 10), data[5..15]);
+        let end = data.len() - 100;
+        assert_eq!(read_at(&mut buffer, end as u64, 100), data[end..]);
+    }
+
+    #[test]
+    fn seek_from_end_reads_everything() {
+        let data = data(3 * CHUNK_LEN);
+        let mut buffer = SpillBuffer::new(data.as_slice());
+
+        assert_eq!(
+            buffer.seek(SeekFrom::End(-1)).unwrap(),
+            data.len() as u64 - 1
+        );
+        assert_eq!(
+            buffer.seek(SeekFrom::Current(-9)).unwrap(),
+            data.len() as u64 - 10
+        );
+        assert!(buffer
+            .seek(SeekFrom::Current(-(data.len() as i64)))
+            .is_err());
+
+        // Past the end reads nothing
+        buffer.seek(SeekFrom::End(10)).unwrap();
+        assert_eq!(buffer.read(&mut [0u8; 8]).unwrap(), 0);
+    }
+}